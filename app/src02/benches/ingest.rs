@@ -0,0 +1,94 @@
+//! Usage ingestion throughput: one INSERT per usage vs. batched writes.
+//!
+//! Run with `cargo bench --bench ingest` (or `make bench`). Set
+//! `INGEST_BENCH_USAGES` to change the number of usages per scenario.
+//! A file-backed database is used so commit costs are realistic.
+
+use sqlx::SqlitePool;
+use src02::ingest::{IngestConfig, UsageIngestor};
+use src02::models::{Product, Service, ServiceUsage, User, UserId};
+use src02::persistence;
+use std::time::{Duration, Instant};
+
+fn usages(n: usize) -> Vec<ServiceUsage> {
+    (0..n)
+        .map(|i| {
+            let user = UserId(format!("u-{}", i % 50));
+            ServiceUsage::new(&user, &"s-1".into(), &"p-1".into(), None)
+        })
+        .collect()
+}
+
+async fn fresh_pool(dir: &tempfile::TempDir, name: &str) -> SqlitePool {
+    let url = format!("sqlite:{}", dir.path().join(name).display());
+    let pool = persistence::connect(&url).await.expect("open bench db");
+    persistence::init_db(&pool).await.expect("migrate bench db");
+    // usages must reference existing users and products
+    for i in 0..50 {
+        let id = format!("u-{}", i);
+        persistence::save_user(&pool, &User::new(&id, &id, None))
+            .await
+            .expect("seed user");
+    }
+    let svc = Service::new("s-1", "SaaS", vec![Product::new("p-1", "Email", 500)]);
+    persistence::save_service(&pool, &svc)
+        .await
+        .expect("seed service");
+    pool
+}
+
+fn report(label: &str, n: usize, elapsed: Duration) -> f64 {
+    let rate = n as f64 / elapsed.as_secs_f64();
+    println!(
+        "{:<28} {:>7} usages in {:>9.2?}  {:>12.0} usages/s",
+        label, n, elapsed, rate
+    );
+    rate
+}
+
+#[tokio::main]
+async fn main() {
+    let n: usize = std::env::var("INGEST_BENCH_USAGES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2000);
+    let dir = tempfile::tempdir().expect("temp dir");
+    let data = usages(n);
+
+    let pool = fresh_pool(&dir, "single.db").await;
+    let start = Instant::now();
+    for u in &data {
+        persistence::save_usage(&pool, u).await.expect("insert");
+    }
+    let single = report("save_usage (one per call)", n, start.elapsed());
+
+    let pool = fresh_pool(&dir, "batch.db").await;
+    let start = Instant::now();
+    persistence::save_usages(&pool, &data)
+        .await
+        .expect("batch insert");
+    let batch = report("save_usages (one batch)", n, start.elapsed());
+
+    let pool = fresh_pool(&dir, "ingestor.db").await;
+    let ingestor = UsageIngestor::spawn(
+        pool,
+        IngestConfig {
+            max_batch: 500,
+            flush_interval: Duration::from_millis(50),
+            queue_capacity: 5000,
+        },
+    );
+    let start = Instant::now();
+    for u in data {
+        ingestor.record(u).await.expect("record");
+    }
+    let stats = ingestor.shutdown().await.expect("shutdown");
+    let buffered = report("UsageIngestor (batch 500)", n, start.elapsed());
+    assert_eq!(stats.usages_written, n);
+
+    println!(
+        "\nspeedup vs one-per-call: batch {:.1}x, ingestor {:.1}x",
+        batch / single,
+        buffered / single
+    );
+}
@@ -0,0 +1,236 @@
+//! Organizations: billing accounts shared by several users.
+//!
+//! An `Account` has members with a `Role` and its own payment methods. A usage
+//! attributed to an account (`ServiceUsage::account_id`) is paid by the
+//! account, not by the user who made it. Changes are checked against the
+//! acting user's role and return a new account, like `PrepaidWallet`.
+
+use crate::models::{PaymentMethod, UserId};
+use crate::policy::{PaymentContext, PaymentPolicy};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct AccountId(pub String);
+impl From<&str> for AccountId {
+    fn from(s: &str) -> Self {
+        AccountId(s.to_string())
+    }
+}
+
+/// What a member may do: owners manage members and billing, billing admins
+/// manage payment methods, every member may bill usage to the account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Owner,
+    BillingAdmin,
+    Member,
+}
+
+impl Role {
+    /// Lowercase name as stored in the database, e.g. `"billing_admin"`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Owner => "owner",
+            Role::BillingAdmin => "billing_admin",
+            Role::Member => "member",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Role> {
+        match s {
+            "owner" => Some(Role::Owner),
+            "billing_admin" => Some(Role::BillingAdmin),
+            "member" => Some(Role::Member),
+            _ => None,
+        }
+    }
+
+    pub fn can_manage_members(&self) -> bool {
+        *self == Role::Owner
+    }
+
+    pub fn can_manage_billing(&self) -> bool {
+        matches!(self, Role::Owner | Role::BillingAdmin)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Membership {
+    pub user_id: UserId,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccountError {
+    NotAMember(UserId),
+    /// `actor`'s role does not allow `action`.
+    NotPermitted {
+        actor: UserId,
+        action: &'static str,
+    },
+    /// The change would leave the account without an owner.
+    LastOwner,
+    NoSuchPaymentMethod(usize),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::NotAMember(u) => write!(f, "{} is not a member of the account", u.0),
+            AccountError::NotPermitted { actor, action } => {
+                write!(f, "{} may not {}", actor.0, action)
+            }
+            AccountError::LastOwner => write!(f, "an account needs at least one owner"),
+            AccountError::NoSuchPaymentMethod(i) => write!(f, "no payment method #{}", i),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub id: AccountId,
+    pub name: String,
+    /// In the order they joined.
+    pub members: Vec<Membership>,
+    /// In order of preference; the first one is the default.
+    pub payment_methods: Vec<PaymentMethod>,
+}
+
+impl Account {
+    pub fn new(id: &str, name: &str, owner: &UserId) -> Self {
+        Account {
+            id: AccountId(id.to_string()),
+            name: name.to_string(),
+            members: vec![Membership {
+                user_id: owner.clone(),
+                role: Role::Owner,
+            }],
+            payment_methods: Vec::new(),
+        }
+    }
+
+    pub fn role_of(&self, user_id: &UserId) -> Option<Role> {
+        self.members
+            .iter()
+            .find(|m| &m.user_id == user_id)
+            .map(|m| m.role)
+    }
+
+    pub fn is_member(&self, user_id: &UserId) -> bool {
+        self.role_of(user_id).is_some()
+    }
+
+    pub fn default_payment(&self) -> Option<&PaymentMethod> {
+        self.payment_methods.first()
+    }
+
+    /// Add `user_id` with `role`, or change the role of an existing member.
+    pub fn set_member(
+        &self,
+        actor: &UserId,
+        user_id: &UserId,
+        role: Role,
+    ) -> Result<Account, AccountError> {
+        self.require(actor, Role::can_manage_members, "manage members")?;
+        let mut next = self.clone();
+        match next.members.iter_mut().find(|m| &m.user_id == user_id) {
+            Some(m) => m.role = role,
+            None => next.members.push(Membership {
+                user_id: user_id.clone(),
+                role,
+            }),
+        }
+        next.check_owner()?;
+        Ok(next)
+    }
+
+    /// Remove a member. Members may always leave; removing someone else
+    /// takes an owner.
+    pub fn remove_member(&self, actor: &UserId, user_id: &UserId) -> Result<Account, AccountError> {
+        if actor != user_id {
+            self.require(actor, Role::can_manage_members, "manage members")?;
+        }
+        if !self.is_member(user_id) {
+            return Err(AccountError::NotAMember(user_id.clone()));
+        }
+        let mut next = self.clone();
+        next.members.retain(|m| &m.user_id != user_id);
+        next.check_owner()?;
+        Ok(next)
+    }
+
+    /// Add a payment method; `as_default` puts it first.
+    pub fn add_payment_method(
+        &self,
+        actor: &UserId,
+        method: PaymentMethod,
+        as_default: bool,
+    ) -> Result<Account, AccountError> {
+        self.require(actor, Role::can_manage_billing, "manage payment methods")?;
+        let mut next = self.clone();
+        if as_default {
+            next.payment_methods.insert(0, method);
+        } else {
+            next.payment_methods.push(method);
+        }
+        Ok(next)
+    }
+
+    pub fn remove_payment_method(
+        &self,
+        actor: &UserId,
+        index: usize,
+    ) -> Result<Account, AccountError> {
+        self.require(actor, Role::can_manage_billing, "manage payment methods")?;
+        if index >= self.payment_methods.len() {
+            return Err(AccountError::NoSuchPaymentMethod(index));
+        }
+        let mut next = self.clone();
+        next.payment_methods.remove(index);
+        Ok(next)
+    }
+
+    fn require(
+        &self,
+        actor: &UserId,
+        allowed: fn(&Role) -> bool,
+        action: &'static str,
+    ) -> Result<(), AccountError> {
+        match self.role_of(actor) {
+            None => Err(AccountError::NotAMember(actor.clone())),
+            Some(role) if allowed(&role) => Ok(()),
+            Some(_) => Err(AccountError::NotPermitted {
+                actor: actor.clone(),
+                action,
+            }),
+        }
+    }
+
+    fn check_owner(&self) -> Result<(), AccountError> {
+        if self.members.iter().any(|m| m.role == Role::Owner) {
+            Ok(())
+        } else {
+            Err(AccountError::LastOwner)
+        }
+    }
+}
+
+pub type AccountMap = HashMap<AccountId, Account>;
+
+/// Payment for a usage billed to `account`: the usage's own method, else the
+/// account's default. The member's personal default is never used. See
+/// `policy` for service restrictions and the reasons behind a choice.
+pub fn resolve_payment_for_account(
+    account: &Account,
+    usage_payment: Option<PaymentMethod>,
+) -> Option<PaymentMethod> {
+    let ctx = PaymentContext::new()
+        .account(account)
+        .usage_payment(usage_payment.as_ref());
+    PaymentPolicy::default().resolve(&ctx).payment
+}
@@ -0,0 +1,1062 @@
+//! HTTP API over a `Repository`: users and their profiles, services and
+//! products, usages, payment resolution, credit notes, invoices and dunning
+//! as JSON.
+//!
+//! Bodies are the serde models (`User`, `Profile`, `BillingAddress`,
+//! `Service`, `Product`, `ServiceUsage`, `PaymentMethod`). A card payment
+//! method names a card vaulted through `POST /cards` by its token; one naming
+//! any other token is refused with `422`. Errors are `{"error": "..."}` with a
+//! status per kind (see `ApiError`); a rejected usage also carries the
+//! `UsageViolation`. `router` builds the routes, the `src02-server` binary
+//! serves them.
+//!
+//! `POST /usages` takes an `Idempotency-Key` header (or an `idempotency_key`
+//! field): a retry with the same key answers `200` with the usage recorded
+//! the first time instead of `201` with a new one. A `201` also lists the
+//! budget `alerts` the usage fired (`RecordedUsage`). A user suspended from
+//! the service by an unpaid dunning case gets `402`.
+//!
+//! Product listings are served from a `SharedCatalog`, loaded from the
+//! repository on first use and reloaded after every catalog write through
+//! the API. `POST /catalog/reload` picks up changes made elsewhere (the CLI,
+//! another server) without a restart.
+//!
+//! | Method | Path                          |                                        |
+//! |--------|-------------------------------|----------------------------------------|
+//! | GET    | `/health`                     | `{"status": "ok"}`                     |
+//! | POST   | `/cards`                      | vault a card (`NewCard`), once         |
+//! | GET    | `/cards/{token}`              | a vaulted card's masked data           |
+//! | GET    | `/users`                      | every user                             |
+//! | POST   | `/users`                      | add or replace a user                  |
+//! | GET    | `/users/{id}`                 | one user                               |
+//! | DELETE | `/users/{id}`                 | erase (pseudonymize) the user          |
+//! | GET    | `/users/{id}/export`          | everything held about the user         |
+//! | GET    | `/users/{id}/payment`         | the user's default payment method      |
+//! | PUT    | `/users/{id}/profile`         | replace the `Profile` at its revision  |
+//! | POST   | `/users/{id}/payment-methods` | save a method (`NewPaymentMethod`)     |
+//! | DELETE | `/users/{id}/payment-methods/{i}` | remove saved method `i`            |
+//! | POST   | `/users/{id}/payment-methods/{i}/default` | make method `i` the default |
+//! | POST   | `/users/{id}/addresses`       | add a `BillingAddress`                 |
+//! | DELETE | `/users/{id}/addresses/{i}`   | remove billing address `i`             |
+//! | GET    | `/services`                   | services with their products           |
+//! | POST   | `/services`                   | add or replace a service               |
+//! | GET    | `/services/{id}`              | one service                            |
+//! | GET    | `/services/{id}/products`     | a service's products                   |
+//! | POST   | `/services/{id}/products`     | add or replace a product               |
+//! | GET    | `/products`                   | every product                          |
+//! | GET    | `/catalog`                    | version of the served catalog          |
+//! | POST   | `/catalog/reload`             | reload it from the repository          |
+//! | GET    | `/usages`                     | one page of usages (`UsageParams`)     |
+//! | POST   | `/usages`                     | validate, check budgets, record        |
+//! | GET    | `/usages/aggregate`           | count and total of matching usages     |
+//! | POST   | `/payments/resolve`           | the payment method a usage would use,  |
+//! |        |                               | with the reasons (`policy::Decision`)  |
+//! | GET    | `/credit-notes`               | every credit note                      |
+//! | POST   | `/credit-notes`               | issue a credit note (`NewCreditNote`)  |
+//! | GET    | `/credit/{kind}/{id}`         | a user's or account's credit balance   |
+//! | POST   | `/invoices`                   | a payer's invoice for a period, less   |
+//! |        |                               | its credit (`InvoiceRequest`)          |
+//! | GET    | `/dunning/cases`              | every dunning case                     |
+//! | POST   | `/dunning/cases`              | open a case for a failed charge        |
+//! |        |                               | (`NewDunningCase`)                     |
+//! | GET    | `/dunning/cases/{id}`         | one case                               |
+//! | POST   | `/dunning/cases/{id}/attempts`| record a retry or payment (`Attempt`)  |
+//! | GET    | `/dunning/events`             | every dunning event, oldest first      |
+
+use crate::account::AccountId;
+use crate::budget::{self, BudgetAlert, BudgetError};
+use crate::catalog::{Catalog, CatalogError, DuplicatePolicy};
+use crate::clock::{Clock, SystemClock};
+use crate::credit::{self, CreditError, CreditNote, Invoice, Payer, Settlement};
+use crate::dunning::{
+    self, Attempt, DunningCase, DunningCaseId, DunningError, DunningEvent, DunningSchedule,
+    FailedCharge, Transition,
+};
+use crate::idempotency::{IdempotencyKey, Recorded, DEFAULT_RETENTION};
+use crate::models::{
+    PaymentMethod, Product, Profile, Service, ServiceId, ServiceUsage, User, UserId,
+};
+use crate::payment::PaymentKind;
+use crate::persistence::PersistenceError;
+use crate::policy::{PaymentContext, PaymentPolicy};
+use crate::privacy::{self, ErasureReport, UserExport};
+use crate::profile::{self, BillingAddress, ProfileError, ProfileUpdate};
+use crate::query::{Cursor, UsageQuery};
+use crate::reporting::ReportPeriod;
+use crate::repository::{Repository, RepositoryError};
+use crate::shared_catalog::{CatalogSnapshot, SharedCatalog};
+use crate::usage::resolve_payment_for_usage;
+use crate::validation::{self, UsageViolation};
+use crate::vault::{CardError, CardExpiry, CardToken, CardVault, StoredCard};
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::{FromRef, Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::sync::Arc;
+
+type Repo = Arc<dyn Repository>;
+type ApiResult<T> = Result<T, ApiError>;
+
+/// Request header carrying a usage's idempotency key.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+#[derive(Clone)]
+struct ApiState {
+    repo: Repo,
+    /// How long `POST /usages` remembers an idempotency key.
+    key_retention: Duration,
+    /// Time for dunning cases.
+    clock: Arc<dyn Clock>,
+    /// Services and products for the read routes; version 0 until loaded.
+    catalog: SharedCatalog,
+    /// Held from the budget check of `POST /usages` until the usage is stored.
+    usage_locks: budget::UsageLocks,
+}
+
+impl FromRef<ApiState> for Repo {
+    fn from_ref(state: &ApiState) -> Repo {
+        state.repo.clone()
+    }
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// 400: a malformed body, query string or filter value.
+    BadRequest(String),
+    /// 404: the user, service or product in the path does not exist.
+    NotFound(String),
+    /// 422: the usage (or payment request) fails validation.
+    Invalid(UsageViolation),
+    /// 409 for a product offered twice, 404 for an unknown service or
+    /// product.
+    Catalog(CatalogError),
+    /// 409 when a hard-limit budget refuses the usage, else 500.
+    Budget(BudgetError),
+    /// 404 for an unknown usage, 409 when the usage's credit notes would
+    /// exceed its price, 400 for a zero amount, 422 when there is no price
+    /// or refund method, else 500.
+    Credit(CreditError),
+    /// 404 for an unknown case, user or service, 409 for a case that cannot
+    /// take the attempt or changed meanwhile, 402 for a suspended user, else
+    /// 500.
+    Dunning(DunningError),
+    /// 404 for an unknown user or a saved payment method or address that
+    /// is not there, 409 for an email another user has, 422 for an invalid
+    /// value, else 500.
+    Profile(ProfileError),
+    /// 422: the card cannot be vaulted.
+    Card(CardError),
+    /// 409 for an email another user has or a product another service
+    /// offers, 422 when the database refuses the write, else 500.
+    Repository(RepositoryError),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(m) | ApiError::NotFound(m) => write!(f, "{}", m),
+            ApiError::Invalid(v) => write!(f, "{}", v),
+            ApiError::Catalog(e) => write!(f, "{}", e),
+            ApiError::Budget(e) => write!(f, "{}", e),
+            ApiError::Credit(e) => write!(f, "{}", e),
+            ApiError::Dunning(e) => write!(f, "{}", e),
+            ApiError::Profile(e) => write!(f, "{}", e),
+            ApiError::Card(e) => write!(f, "{}", e),
+            ApiError::Repository(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApiError::BadRequest(_) | ApiError::NotFound(_) => None,
+            ApiError::Invalid(v) => Some(v),
+            ApiError::Catalog(e) => Some(e),
+            ApiError::Budget(e) => Some(e),
+            ApiError::Credit(e) => Some(e),
+            ApiError::Dunning(e) => Some(e),
+            ApiError::Profile(e) => Some(e),
+            ApiError::Card(e) => Some(e),
+            ApiError::Repository(e) => Some(e),
+        }
+    }
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Invalid(_) | ApiError::Card(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Catalog(CatalogError::DuplicateProduct { .. }) => StatusCode::CONFLICT,
+            ApiError::Catalog(_) => StatusCode::NOT_FOUND,
+            ApiError::Budget(BudgetError::LimitExceeded { .. }) => StatusCode::CONFLICT,
+            ApiError::Credit(CreditError::UnknownUsage { .. }) => StatusCode::NOT_FOUND,
+            ApiError::Credit(CreditError::ExceedsUsage { .. }) => StatusCode::CONFLICT,
+            ApiError::Credit(CreditError::ZeroAmount) => StatusCode::BAD_REQUEST,
+            ApiError::Credit(
+                CreditError::UnknownPrice { .. } | CreditError::NoRefundMethod { .. },
+            ) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Dunning(
+                DunningError::UnknownCase { .. }
+                | DunningError::UnknownUser { .. }
+                | DunningError::UnknownService { .. },
+            ) => StatusCode::NOT_FOUND,
+            ApiError::Dunning(
+                DunningError::NotRetrying { .. }
+                | DunningError::AlreadySettled { .. }
+                | DunningError::Conflict { .. },
+            ) => StatusCode::CONFLICT,
+            ApiError::Dunning(DunningError::Suspended { .. }) => StatusCode::PAYMENT_REQUIRED,
+            ApiError::Profile(
+                ProfileError::UnknownUser(_)
+                | ProfileError::NoSuchPaymentMethod(_)
+                | ProfileError::NoSuchAddress(_),
+            ) => StatusCode::NOT_FOUND,
+            ApiError::Profile(ProfileError::EmailTaken(_) | ProfileError::Stale(_)) => {
+                StatusCode::CONFLICT
+            }
+            ApiError::Profile(
+                ProfileError::InvalidEmail(_)
+                | ProfileError::InvalidLocale(_)
+                | ProfileError::InvalidTimezone(_)
+                | ProfileError::EmptyAddressField(_)
+                | ProfileError::InvalidCountry(_),
+            ) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Budget(BudgetError::Repository(e))
+            | ApiError::Credit(CreditError::Repository(e))
+            | ApiError::Dunning(DunningError::Repository(e))
+            | ApiError::Profile(ProfileError::Repository(e))
+            | ApiError::Repository(e) => match e {
+                RepositoryError::EmailTaken(_) | RepositoryError::DuplicateProduct { .. } => {
+                    StatusCode::CONFLICT
+                }
+                RepositoryError::Persistence(PersistenceError::Constraint { .. }) => {
+                    StatusCode::UNPROCESSABLE_ENTITY
+                }
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+        }
+    }
+}
+
+/// The message of a `5xx` `ApiError`, kept in the response's extensions so
+/// whoever serves the routes can log it (the `src02-server` binary does).
+#[derive(Debug, Clone)]
+pub struct ServerError(pub String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let body = match &self {
+            ApiError::Invalid(v) => json!({ "error": self.to_string(), "violation": v }),
+            _ => json!({ "error": self.to_string() }),
+        };
+        let mut response = (status, Json(body)).into_response();
+        if status.is_server_error() {
+            response
+                .extensions_mut()
+                .insert(ServerError(self.to_string()));
+        }
+        response
+    }
+}
+
+impl From<RepositoryError> for ApiError {
+    fn from(e: RepositoryError) -> Self {
+        ApiError::Repository(e)
+    }
+}
+
+impl From<CatalogError> for ApiError {
+    fn from(e: CatalogError) -> Self {
+        ApiError::Catalog(e)
+    }
+}
+
+impl From<BudgetError> for ApiError {
+    fn from(e: BudgetError) -> Self {
+        ApiError::Budget(e)
+    }
+}
+
+impl From<CreditError> for ApiError {
+    fn from(e: CreditError) -> Self {
+        ApiError::Credit(e)
+    }
+}
+
+impl From<DunningError> for ApiError {
+    fn from(e: DunningError) -> Self {
+        ApiError::Dunning(e)
+    }
+}
+
+impl From<ProfileError> for ApiError {
+    fn from(e: ProfileError) -> Self {
+        ApiError::Profile(e)
+    }
+}
+
+impl From<CardError> for ApiError {
+    fn from(e: CardError) -> Self {
+        ApiError::Card(e)
+    }
+}
+
+impl From<UsageViolation> for ApiError {
+    fn from(v: UsageViolation) -> Self {
+        ApiError::Invalid(v)
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(r: JsonRejection) -> Self {
+        ApiError::BadRequest(r.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(r: QueryRejection) -> Self {
+        ApiError::BadRequest(r.body_text())
+    }
+}
+
+/// Routes over `repo`, which must already be initialized; idempotency keys
+/// are remembered for `idempotency::DEFAULT_RETENTION`.
+pub fn router(repo: Repo) -> Router {
+    router_with_key_retention(repo, DEFAULT_RETENTION)
+}
+
+/// `router`, remembering idempotency keys for `key_retention`.
+pub fn router_with_key_retention(repo: Repo, key_retention: Duration) -> Router {
+    router_with_clock(repo, key_retention, Arc::new(SystemClock))
+}
+
+/// `router_with_key_retention`, timing dunning cases by `clock`.
+pub fn router_with_clock(repo: Repo, key_retention: Duration, clock: Arc<dyn Clock>) -> Router {
+    router_with_catalog(repo, key_retention, clock, SharedCatalog::default())
+}
+
+/// `router_with_clock`, serving products from `catalog`. The handle stays
+/// shared with the caller, which may reload it too; an unloaded catalog
+/// (version 0) is loaded from `repo` on first use.
+pub fn router_with_catalog(
+    repo: Repo,
+    key_retention: Duration,
+    clock: Arc<dyn Clock>,
+    catalog: SharedCatalog,
+) -> Router {
+    Router::new()
+        .route("/health", get(health))
+        .route("/cards", post(vault_card))
+        .route("/cards/{token}", get(get_card))
+        .route("/users", get(list_users).post(put_user))
+        .route("/users/{id}", get(get_user).delete(erase_user))
+        .route("/users/{id}/export", get(export_user))
+        .route("/users/{id}/payment", get(user_payment))
+        .route("/users/{id}/profile", put(put_profile))
+        .route("/users/{id}/payment-methods", post(add_payment_method))
+        .route(
+            "/users/{id}/payment-methods/{index}",
+            delete(remove_payment_method),
+        )
+        .route(
+            "/users/{id}/payment-methods/{index}/default",
+            post(set_default_payment),
+        )
+        .route("/users/{id}/addresses", post(add_address))
+        .route("/users/{id}/addresses/{index}", delete(remove_address))
+        .route("/services", get(list_services).post(put_service))
+        .route("/services/{id}", get(get_service))
+        .route(
+            "/services/{id}/products",
+            get(service_products).post(put_product),
+        )
+        .route("/products", get(list_products))
+        .route("/catalog", get(catalog_version))
+        .route("/catalog/reload", post(reload_catalog))
+        .route("/usages", get(list_usages).post(record_usage))
+        .route("/usages/aggregate", get(aggregate_usages))
+        .route("/payments/resolve", post(resolve_payment))
+        .route(
+            "/credit-notes",
+            get(list_credit_notes).post(issue_credit_note),
+        )
+        .route("/credit/{kind}/{id}", get(credit_balance))
+        .route("/invoices", post(invoice))
+        .route(
+            "/dunning/cases",
+            get(list_dunning_cases).post(open_dunning_case),
+        )
+        .route("/dunning/cases/{id}", get(get_dunning_case))
+        .route("/dunning/cases/{id}/attempts", post(record_attempt))
+        .route("/dunning/events", get(list_dunning_events))
+        .with_state(ApiState {
+            repo,
+            key_retention,
+            clock,
+            catalog,
+            usage_locks: budget::UsageLocks::new(),
+        })
+}
+
+async fn health() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+/// Body of `POST /cards`. Not `Debug`: it holds the full card number, which
+/// is dropped once the card is vaulted.
+#[derive(Deserialize)]
+pub struct NewCard {
+    pub number: String,
+    pub expiry: CardExpiry,
+    pub holder: String,
+}
+
+async fn vault_card(
+    State(repo): State<Repo>,
+    body: Result<Json<NewCard>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<StoredCard>)> {
+    let Json(new) = body?;
+    let card = CardVault::new().tokenize(&new.number, new.expiry, &new.holder)?;
+    repo.save_card(&card).await?;
+    Ok((StatusCode::CREATED, Json(card)))
+}
+
+async fn get_card(
+    State(repo): State<Repo>,
+    Path(token): Path<String>,
+) -> ApiResult<Json<StoredCard>> {
+    let unknown = || ApiError::NotFound(format!("unknown card {}", token));
+    let parsed = CardToken::parse(token.clone()).map_err(|_| unknown())?;
+    repo.get_card(&parsed).await?.map(Json).ok_or_else(unknown)
+}
+
+async fn find_user(repo: &dyn Repository, id: &str) -> ApiResult<User> {
+    repo.get_user(&UserId(id.to_string()))
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("unknown user {}", id)))
+}
+
+async fn find_service(repo: &dyn Repository, id: &str) -> ApiResult<Service> {
+    repo.get_services()
+        .await?
+        .into_iter()
+        .find(|s| s.id.0 == id)
+        .ok_or_else(|| ApiError::NotFound(format!("unknown service {}", id)))
+}
+
+async fn list_users(State(repo): State<Repo>) -> ApiResult<Json<Vec<User>>> {
+    Ok(Json(repo.get_users().await?))
+}
+
+async fn get_user(State(repo): State<Repo>, Path(id): Path<String>) -> ApiResult<Json<User>> {
+    Ok(Json(find_user(repo.as_ref(), &id).await?))
+}
+
+async fn put_user(
+    State(repo): State<Repo>,
+    body: Result<Json<User>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<User>)> {
+    let Json(user) = body?;
+    repo.save_user(&user).await?;
+    // as stored, at the store's revision
+    let user = find_user(repo.as_ref(), &user.id.0).await?;
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+async fn export_user(
+    State(repo): State<Repo>,
+    Path(id): Path<String>,
+) -> ApiResult<Json<UserExport>> {
+    repo.export_user(&UserId(id.clone()))
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound(format!("unknown user {}", id)))
+}
+
+async fn erase_user(
+    State(repo): State<Repo>,
+    Path(id): Path<String>,
+) -> ApiResult<Json<ErasureReport>> {
+    repo.erase_user(&UserId(id.clone()), &privacy::pseudonym())
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound(format!("unknown user {}", id)))
+}
+
+async fn user_payment(State(repo): State<Repo>, Path(id): Path<String>) -> ApiResult<Json<Value>> {
+    let user = find_user(repo.as_ref(), &id).await?;
+    let payment = resolve_payment_for_usage(&user, None)
+        .ok_or(UsageViolation::NoPaymentMethod { user_id: user.id })?;
+    Ok(Json(json!({ "payment": payment })))
+}
+
+async fn put_profile(
+    State(repo): State<Repo>,
+    Path(id): Path<String>,
+    body: Result<Json<Profile>, JsonRejection>,
+) -> ApiResult<Json<User>> {
+    let Json(profile) = body?;
+    let user_id = UserId(id);
+    // the body is the profile as read, at its revision; it is stored as the next
+    let next = Profile {
+        revision: profile.revision.saturating_add(1),
+        ..profile
+    };
+    let updated = repo
+        .update_profile(&user_id, &next)
+        .await
+        .map_err(ProfileError::from)?;
+    match updated {
+        ProfileUpdate::Updated(user) => Ok(Json(user)),
+        ProfileUpdate::Stale => Err(ProfileError::Stale(user_id).into()),
+        ProfileUpdate::UnknownUser => Err(ProfileError::UnknownUser(user_id).into()),
+    }
+}
+
+/// Body of `POST /users/{id}/payment-methods`.
+#[derive(Debug, Deserialize)]
+pub struct NewPaymentMethod {
+    pub method: PaymentMethod,
+    /// Make it the default; the user's first method is the default anyway.
+    #[serde(default)]
+    pub default: bool,
+}
+
+async fn add_payment_method(
+    State(repo): State<Repo>,
+    Path(id): Path<String>,
+    body: Result<Json<NewPaymentMethod>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<User>)> {
+    let Json(new) = body?;
+    let user = profile::update(repo.as_ref(), &UserId(id), |p| {
+        Ok(p.add_payment_method(new.method.clone(), new.default))
+    })
+    .await?;
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+async fn remove_payment_method(
+    State(repo): State<Repo>,
+    Path((id, index)): Path<(String, usize)>,
+) -> ApiResult<Json<User>> {
+    let user = profile::update(repo.as_ref(), &UserId(id), |p| {
+        p.remove_payment_method(index)
+    })
+    .await?;
+    Ok(Json(user))
+}
+
+async fn set_default_payment(
+    State(repo): State<Repo>,
+    Path((id, index)): Path<(String, usize)>,
+) -> ApiResult<Json<User>> {
+    let user =
+        profile::update(repo.as_ref(), &UserId(id), |p| p.set_default_payment(index)).await?;
+    Ok(Json(user))
+}
+
+async fn add_address(
+    State(repo): State<Repo>,
+    Path(id): Path<String>,
+    body: Result<Json<BillingAddress>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<User>)> {
+    let Json(address) = body?;
+    let user = profile::update(repo.as_ref(), &UserId(id), |p| {
+        Ok(p.add_address(address.clone()))
+    })
+    .await?;
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+async fn remove_address(
+    State(repo): State<Repo>,
+    Path((id, index)): Path<(String, usize)>,
+) -> ApiResult<Json<User>> {
+    let user = profile::update(repo.as_ref(), &UserId(id), |p| p.remove_address(index)).await?;
+    Ok(Json(user))
+}
+
+async fn list_services(State(repo): State<Repo>) -> ApiResult<Json<Vec<Service>>> {
+    Ok(Json(repo.get_services().await?))
+}
+
+async fn get_service(State(repo): State<Repo>, Path(id): Path<String>) -> ApiResult<Json<Service>> {
+    Ok(Json(find_service(repo.as_ref(), &id).await?))
+}
+
+/// Products are keyed by id alone in storage, so a service may not take a
+/// product another service already offers.
+async fn put_service(
+    State(state): State<ApiState>,
+    body: Result<Json<Service>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<Service>)> {
+    let Json(service) = body?;
+    let repo = state.repo.as_ref();
+    Catalog::from_services(DuplicatePolicy::Reject, repo.get_services().await?)?
+        .add_service(service.clone())?;
+    repo.save_service(&service).await?;
+    state.catalog.reload_from_repository(repo).await?;
+    Ok((StatusCode::CREATED, Json(service)))
+}
+
+/// The shared catalog, loaded from the repository if it never was.
+async fn catalog(state: &ApiState) -> ApiResult<Arc<CatalogSnapshot>> {
+    let snapshot = state.catalog.load();
+    if snapshot.version > 0 {
+        return Ok(snapshot);
+    }
+    Ok(state
+        .catalog
+        .reload_from_repository(state.repo.as_ref())
+        .await?)
+}
+
+async fn service_products(
+    State(state): State<ApiState>,
+    Path(id): Path<String>,
+) -> ApiResult<Response> {
+    let snapshot = catalog(&state).await?;
+    let products = snapshot
+        .catalog
+        .list_products_for_service(&ServiceId(id.clone()))
+        .ok_or_else(|| ApiError::NotFound(format!("unknown service {}", id)))?;
+    Ok(Json(products).into_response())
+}
+
+/// Add a product, or replace the one with the same id (price changes
+/// included).
+async fn put_product(
+    State(state): State<ApiState>,
+    Path(id): Path<String>,
+    body: Result<Json<Product>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<Product>)> {
+    let Json(product) = body?;
+    let repo = state.repo.as_ref();
+    let catalog = Catalog::from_services(DuplicatePolicy::Reject, repo.get_services().await?)?;
+    let service_id = ServiceId(id);
+    let catalog = match catalog.get_product(&service_id, &product.id) {
+        Some(_) => catalog.update_product(&service_id, product.clone())?,
+        None => catalog.add_product(&service_id, product.clone())?,
+    };
+    repo.save_service(&catalog.services()[&service_id]).await?;
+    state.catalog.reload_from_repository(repo).await?;
+    Ok((StatusCode::CREATED, Json(product)))
+}
+
+async fn list_products(State(state): State<ApiState>) -> ApiResult<Response> {
+    let snapshot = catalog(&state).await?;
+    let products: Vec<&Product> = snapshot.catalog.list_all_products().collect();
+    Ok(Json(products).into_response())
+}
+
+fn catalog_summary(snapshot: &CatalogSnapshot) -> Value {
+    json!({
+        "version": snapshot.version,
+        "loaded_at": snapshot.loaded_at,
+        "services": snapshot.catalog.services().len(),
+        "products": snapshot.catalog.list_all_products().count(),
+    })
+}
+
+async fn catalog_version(State(state): State<ApiState>) -> ApiResult<Json<Value>> {
+    Ok(Json(catalog_summary(&*catalog(&state).await?)))
+}
+
+async fn reload_catalog(State(state): State<ApiState>) -> ApiResult<Json<Value>> {
+    let snapshot = state
+        .catalog
+        .reload_from_repository(state.repo.as_ref())
+        .await?;
+    Ok(Json(catalog_summary(&snapshot)))
+}
+
+/// Filters of `GET /usages` and `GET /usages/aggregate`, as query
+/// parameters; times are RFC 3339.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct UsageParams {
+    pub user: Option<String>,
+    pub service: Option<String>,
+    pub product: Option<String>,
+    pub account: Option<String>,
+    /// `card`, `paypal`, `sepa_debit`, `bank_transfer` or `prepaid`
+    pub payment_kind: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// `next_cursor` of the previous page
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl UsageParams {
+    pub fn to_query(&self) -> ApiResult<UsageQuery> {
+        let payment_kind = match &self.payment_kind {
+            Some(k) => Some(
+                PaymentKind::parse(k)
+                    .ok_or_else(|| ApiError::BadRequest(format!("unknown payment kind {:?}", k)))?,
+            ),
+            None => None,
+        };
+        let after = match &self.cursor {
+            Some(c) => Some(Cursor::parse(c).map_err(|e| ApiError::BadRequest(e.to_string()))?),
+            None => None,
+        };
+        Ok(UsageQuery {
+            user_id: self.user.as_deref().map(UserId::from),
+            service_id: self.service.as_deref().map(ServiceId::from),
+            product_id: self.product.as_deref().map(Into::into),
+            payment_kind,
+            account_id: self.account.as_deref().map(AccountId::from),
+            from: self.from,
+            until: self.until,
+            after,
+            limit: self.limit,
+        })
+    }
+}
+
+async fn list_usages(
+    State(repo): State<Repo>,
+    params: Result<Query<UsageParams>, QueryRejection>,
+) -> ApiResult<Json<Value>> {
+    let Query(params) = params?;
+    let page = repo.query_usages(&params.to_query()?).await?;
+    let next = page.next_cursor.map(|c| c.encode());
+    Ok(Json(json!({ "items": page.items, "next_cursor": next })))
+}
+
+async fn aggregate_usages(
+    State(repo): State<Repo>,
+    params: Result<Query<UsageParams>, QueryRejection>,
+) -> ApiResult<Json<Value>> {
+    let Query(params) = params?;
+    let agg = repo.aggregate_usages(&params.to_query()?).await?;
+    Ok(Json(json!(agg)))
+}
+
+/// A usage to record; `occurred_at` defaults to now. `idempotency_key` may
+/// also come from the `Idempotency-Key` header.
+#[derive(Debug, Deserialize)]
+pub struct NewUsage {
+    pub user_id: UserId,
+    pub service_id: ServiceId,
+    pub product_id: crate::models::ProductId,
+    #[serde(default)]
+    pub payment_used: Option<PaymentMethod>,
+    #[serde(default)]
+    pub occurred_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub account_id: Option<AccountId>,
+    #[serde(default)]
+    pub idempotency_key: Option<IdempotencyKey>,
+}
+
+impl NewUsage {
+    pub fn into_usage(self) -> ServiceUsage {
+        ServiceUsage {
+            user_id: self.user_id,
+            service_id: self.service_id,
+            product_id: self.product_id,
+            payment_used: self.payment_used,
+            occurred_at: self.occurred_at.unwrap_or_else(Utc::now),
+            account_id: self.account_id,
+            idempotency_key: self.idempotency_key,
+        }
+    }
+}
+
+/// The key of the `Idempotency-Key` header and the body, which must agree.
+fn idempotency_key(headers: &HeaderMap, new: &NewUsage) -> ApiResult<Option<IdempotencyKey>> {
+    let header = match headers.get(IDEMPOTENCY_KEY_HEADER) {
+        Some(v) => match v.to_str() {
+            Ok(k) if !k.trim().is_empty() => Some(IdempotencyKey::from(k)),
+            _ => {
+                return Err(ApiError::BadRequest(format!(
+                    "invalid {} header",
+                    IDEMPOTENCY_KEY_HEADER
+                )))
+            }
+        },
+        None => None,
+    };
+    match (header, &new.idempotency_key) {
+        (Some(h), Some(b)) if &h != b => Err(ApiError::BadRequest(format!(
+            "{} header {:?} differs from idempotency_key {:?}",
+            IDEMPOTENCY_KEY_HEADER, h.0, b.0
+        ))),
+        (Some(h), _) => Ok(Some(h)),
+        (None, body) => Ok(body.clone()),
+    }
+}
+
+/// `201` body of `POST /usages`: the usage, plus the budget alerts recording
+/// it fired, if any.
+#[derive(Debug, Serialize)]
+pub struct RecordedUsage {
+    #[serde(flatten)]
+    pub usage: ServiceUsage,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub alerts: Vec<BudgetAlert>,
+}
+
+/// Validate like `src02 usage record`, refuse usages of a suspended user or
+/// over a hard-limit budget, then store it. A replayed key answers with the
+/// stored usage, without checking anything again.
+async fn record_usage(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    body: Result<Json<NewUsage>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<RecordedUsage>)> {
+    let Json(mut new) = body?;
+    new.idempotency_key = idempotency_key(&headers, &new)?;
+    let repo = state.repo.as_ref();
+    if let Some(key) = &new.idempotency_key {
+        if let Some(stored) = repo.find_idempotent_usage(key, state.key_retention).await? {
+            return Ok((StatusCode::OK, Json(RecordedUsage::replayed(stored.usage))));
+        }
+    }
+    let usage = new.into_usage();
+    let refs = validation::load_references(repo).await?;
+    validation::validate_usage(&usage, &refs)?;
+    dunning::check_access(repo, &usage).await?;
+    let _locked = state.usage_locks.lock(&usage.user_id).await;
+    let budgets = repo.get_budgets().await?;
+    let alerts = budget::check_usage(repo, &budgets, &refs.catalog, &usage).await?;
+    let recorded = repo.record_usage(&usage, state.key_retention).await?;
+    if let Recorded::Replayed(stored) = recorded {
+        return Ok((StatusCode::OK, Json(RecordedUsage::replayed(stored.usage))));
+    }
+    Ok((StatusCode::CREATED, Json(RecordedUsage { usage, alerts })))
+}
+
+impl RecordedUsage {
+    /// A replay fires no alerts: they went out with the first answer.
+    fn replayed(usage: ServiceUsage) -> Self {
+        RecordedUsage {
+            usage,
+            alerts: Vec::new(),
+        }
+    }
+}
+
+/// Who pays: the user, or an account they bill to, the method named on the
+/// usage, if any, and the service used, whose accepted kinds then apply.
+#[derive(Debug, Deserialize)]
+pub struct PaymentRequest {
+    pub user_id: UserId,
+    #[serde(default)]
+    pub account_id: Option<AccountId>,
+    #[serde(default)]
+    pub payment_used: Option<PaymentMethod>,
+    #[serde(default)]
+    pub service_id: Option<ServiceId>,
+}
+
+/// The method a usage would be paid with under the default
+/// `policy::PaymentPolicy`, with the trail of candidates tried. When none
+/// can pay, the `422` body carries the trail next to the violation.
+async fn resolve_payment(
+    State(repo): State<Repo>,
+    body: Result<Json<PaymentRequest>, JsonRejection>,
+) -> ApiResult<Response> {
+    let Json(req) = body?;
+    let user = find_user(repo.as_ref(), &req.user_id.0).await?;
+    let service = match &req.service_id {
+        Some(service_id) => Some(
+            repo.get_services()
+                .await?
+                .into_iter()
+                .find(|s| &s.id == service_id)
+                .ok_or_else(|| UsageViolation::UnknownService {
+                    service_id: service_id.clone(),
+                })?,
+        ),
+        None => None,
+    };
+    let account = match &req.account_id {
+        Some(account_id) => {
+            let account = repo
+                .get_accounts()
+                .await?
+                .into_iter()
+                .find(|a| &a.id == account_id)
+                .ok_or_else(|| UsageViolation::UnknownAccount {
+                    account_id: account_id.clone(),
+                })?;
+            if !account.is_member(&user.id) {
+                return Err(UsageViolation::NotAMember {
+                    account_id: account_id.clone(),
+                    user_id: user.id,
+                }
+                .into());
+            }
+            Some(account)
+        }
+        None => None,
+    };
+    let mut ctx = PaymentContext::new()
+        .user(&user)
+        .usage_payment(req.payment_used.as_ref());
+    if let Some(service) = &service {
+        ctx = ctx.service(service);
+    }
+    if let Some(account) = &account {
+        ctx = ctx.account(account);
+    }
+    let decision = PaymentPolicy::default().resolve(&ctx);
+    if decision.payment.is_none() {
+        let violation = validation::payment_violation(&decision, &user.id, req.account_id.as_ref());
+        let body = json!({
+            "error": violation.to_string(),
+            "violation": violation,
+            "trail": decision.trail,
+        });
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response());
+    }
+    Ok(Json(decision).into_response())
+}
+
+async fn list_credit_notes(State(repo): State<Repo>) -> ApiResult<Json<Vec<CreditNote>>> {
+    Ok(Json(repo.get_credit_notes().await?))
+}
+
+/// A credit note to issue; `amount_cents` defaults to what earlier notes
+/// left of the usage, `settlement` to `credit`.
+#[derive(Debug, Deserialize)]
+pub struct NewCreditNote {
+    pub usage_id: i64,
+    #[serde(default)]
+    pub amount_cents: Option<u64>,
+    pub reason: String,
+    #[serde(default = "default_settlement")]
+    pub settlement: Settlement,
+}
+
+fn default_settlement() -> Settlement {
+    Settlement::Credit
+}
+
+async fn issue_credit_note(
+    State(repo): State<Repo>,
+    body: Result<Json<NewCreditNote>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<CreditNote>)> {
+    let Json(new) = body?;
+    let note = credit::issue(
+        repo.as_ref(),
+        new.usage_id,
+        new.amount_cents,
+        &new.reason,
+        new.settlement,
+    )
+    .await?;
+    Ok((StatusCode::CREATED, Json(note)))
+}
+
+async fn credit_balance(
+    State(repo): State<Repo>,
+    Path((kind, id)): Path<(String, String)>,
+) -> ApiResult<Json<Value>> {
+    let payer = Payer::parse(&kind, &id)
+        .ok_or_else(|| ApiError::NotFound(format!("unknown payer kind {:?}", kind)))?;
+    let balance_cents = credit::credit_balance(repo.as_ref(), &payer).await?;
+    Ok(Json(
+        json!({ "payer": payer, "balance_cents": balance_cents }),
+    ))
+}
+
+/// Whom to invoice, e.g. `{"kind": "account", "id": "acc-1"}`, for `from`
+/// (inclusive) to `until` (exclusive).
+#[derive(Debug, Deserialize)]
+pub struct InvoiceRequest {
+    pub payer: Payer,
+    pub from: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+}
+
+/// The payer's invoice; the first one for a period charges its credit
+/// balance, later ones show the same amount.
+async fn invoice(
+    State(repo): State<Repo>,
+    body: Result<Json<InvoiceRequest>, JsonRejection>,
+) -> ApiResult<Json<Invoice>> {
+    let Json(req) = body?;
+    if req.until <= req.from {
+        return Err(ApiError::BadRequest("until must be after from".to_string()));
+    }
+    let period = ReportPeriod::new(req.from, req.until);
+    Ok(Json(
+        credit::invoice(repo.as_ref(), &req.payer, period).await?,
+    ))
+}
+
+async fn list_dunning_cases(State(repo): State<Repo>) -> ApiResult<Json<Vec<DunningCase>>> {
+    Ok(Json(repo.get_dunning_cases().await?))
+}
+
+async fn get_dunning_case(
+    State(repo): State<Repo>,
+    Path(id): Path<String>,
+) -> ApiResult<Json<DunningCase>> {
+    let case_id = DunningCaseId(id);
+    Ok(Json(dunning::find_case(repo.as_ref(), &case_id).await?))
+}
+
+/// A failed charge; `retry_after_hours` defaults to
+/// `dunning::DEFAULT_RETRY_HOURS`.
+#[derive(Debug, Deserialize)]
+pub struct NewDunningCase {
+    #[serde(flatten)]
+    pub charge: FailedCharge,
+    #[serde(default)]
+    pub retry_after_hours: Option<Vec<u32>>,
+}
+
+async fn open_dunning_case(
+    State(state): State<ApiState>,
+    body: Result<Json<NewDunningCase>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<Transition>)> {
+    let Json(new) = body?;
+    let schedule = match &new.retry_after_hours {
+        Some(hours) => DunningSchedule::new(hours),
+        None => DunningSchedule::default(),
+    };
+    let opened = dunning::open(
+        state.repo.as_ref(),
+        state.clock.as_ref(),
+        &new.charge,
+        schedule,
+    )
+    .await?;
+    Ok((StatusCode::CREATED, Json(opened)))
+}
+
+/// `{"outcome": "paid"}`, or `{"outcome": "failed", "reason": "..."}`.
+async fn record_attempt(
+    State(state): State<ApiState>,
+    Path(id): Path<String>,
+    body: Result<Json<Attempt>, JsonRejection>,
+) -> ApiResult<Json<Transition>> {
+    let Json(attempt) = body?;
+    let recorded = dunning::record_attempt(
+        state.repo.as_ref(),
+        state.clock.as_ref(),
+        &DunningCaseId(id),
+        &attempt,
+    )
+    .await?;
+    Ok(Json(recorded))
+}
+
+async fn list_dunning_events(State(repo): State<Repo>) -> ApiResult<Json<Vec<DunningEvent>>> {
+    Ok(Json(repo.get_dunning_events().await?))
+}
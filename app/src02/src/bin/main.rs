@@ -1,27 +1,686 @@
-use clap::Parser;
+mod output;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
 use dotenvy::dotenv;
+use output::{Format, Table};
+use serde_json::json;
+use src02::account::{Account, AccountId, Role};
+use src02::budget::{self, BillingPeriod, Budget, BudgetStatus};
+use src02::catalog::{Catalog, DuplicatePolicy};
+use src02::catalog_file;
+use src02::clock::SystemClock;
+use src02::credit::{self, CreditNote, Payer, Settlement};
+use src02::dunning::{
+    self, Attempt, DunningCase, DunningCaseId, DunningEvent, DunningSchedule, FailedCharge,
+};
+use src02::idempotency::{IdempotencyKey, Recorded};
+use src02::migrations::{self, MigrateOptions};
+use src02::models::{PaymentMethod, Product, Service, ServiceId, ServiceUsage, User, UserId};
+use src02::payment::PaymentKind;
+use src02::policy::{PaymentContext, PaymentPolicy};
+use src02::privacy;
+use src02::profile::{self, BillingAddress, Email, Locale, Timezone};
+use src02::query::{Cursor, UsageAggregate, UsageQuery};
+use src02::reporting::{self, ReportPeriod, RevenueRow, Section};
+use src02::repository::Repository;
+use src02::validation;
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+type CliResult = Result<(), Box<dyn Error>>;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Args {
-    /// Database URL (sqlite). Use `sqlite::memory:` for in-memory.
-    #[arg(long)]
+    /// Storage URL: `sqlite:<file>`, `sqlite::memory:`, `sled:<dir>` or `memory:`.
+    #[arg(long, global = true)]
     db_url: Option<String>,
 
-    /// Initialize DB schema
-    #[arg(long, default_value_t = false)]
-    init_db: bool,
+    /// Output format
+    #[arg(long, global = true, value_enum, default_value_t = Format::Table)]
+    format: Format,
 
     /// Run demo printing to stdout
     #[arg(long, default_value_t = false)]
     demo: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Apply pending schema migrations and print what was done
+    Migrate {
+        /// Only list the pending migrations, do not apply them
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Add, list and show users and edit their profiles
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Add and list services
+    #[command(subcommand)]
+    Service(ServiceCommand),
+    /// Add products to a service
+    #[command(subcommand)]
+    Product(ProductCommand),
+    /// Check catalog files and sync them into storage
+    #[command(subcommand)]
+    Catalog(CatalogCommand),
+    /// Organization accounts that pay for their members' usages
+    #[command(subcommand)]
+    Account(AccountCommand),
+    /// Spending limits per user and billing period
+    #[command(subcommand)]
+    Budget(BudgetCommand),
+    /// Record and list usages
+    #[command(subcommand)]
+    Usage(UsageCommand),
+    /// Which payment method would pay, and why
+    #[command(subcommand)]
+    Payment(PaymentCommand),
+    /// Credit notes that reverse usages, as refunds or account credit
+    #[command(subcommand)]
+    Credit(CreditCommand),
+    /// Failed charges: retries, suspension and reinstatement
+    #[command(subcommand)]
+    Dunning(DunningCommand),
+    /// A user's or account's usages for a period, less its credit balance;
+    /// the balance is charged the first time a period is invoiced
+    Invoice {
+        #[command(flatten)]
+        payer: PayerArgs,
+        /// Calendar month (YYYY-MM); defaults to the current month
+        #[arg(long, value_parser = parse_month, conflicts_with_all = ["from", "until"])]
+        month: Option<ReportPeriod>,
+        /// Inclusive start (RFC 3339 or YYYY-MM-DD), instead of --month
+        #[arg(long, value_parser = parse_time, requires = "until")]
+        from: Option<DateTime<Utc>>,
+        /// Exclusive end (RFC 3339 or YYYY-MM-DD)
+        #[arg(long, value_parser = parse_time, requires = "from")]
+        until: Option<DateTime<Utc>>,
+    },
+    /// Usage count and amount per user, service or account
+    Report {
+        #[command(flatten)]
+        filter: UsageFilter,
+        /// Group rows by
+        #[arg(long, value_enum, default_value_t = GroupBy::User)]
+        by: GroupBy,
+    },
+    /// Revenue per service, product or user, compared with the previous period
+    Revenue {
+        /// Calendar month (YYYY-MM); defaults to the current month
+        #[arg(long, value_parser = parse_month, conflicts_with_all = ["from", "until"])]
+        month: Option<ReportPeriod>,
+        /// Inclusive start (RFC 3339 or YYYY-MM-DD), instead of --month
+        #[arg(long, value_parser = parse_time, requires = "until")]
+        from: Option<DateTime<Utc>>,
+        /// Exclusive end (RFC 3339 or YYYY-MM-DD)
+        #[arg(long, value_parser = parse_time, requires = "from")]
+        until: Option<DateTime<Utc>>,
+        /// Rows to print; JSON always has every section
+        #[arg(long, value_enum, default_value_t = RevenueBy::Service)]
+        by: RevenueBy,
+        /// How many users `--by top` lists
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+    /// Check every stored usage against users and catalog; exits non-zero
+    /// if any violation is found
+    Validate,
+}
+
+#[derive(Subcommand, Debug)]
+enum UserCommand {
+    /// Add a user, or replace one with the same id
+    Add {
+        id: String,
+        name: String,
+        #[command(flatten)]
+        payment: PaymentArgs,
+        /// Must not belong to another user
+        #[arg(long, value_parser = parse_email)]
+        email: Option<Email>,
+    },
+    List,
+    /// A user with their profile and the count and amount of their usages
+    Show {
+        id: String,
+    },
+    /// Change a user's name, email, locale or timezone; fields not given
+    /// are kept
+    Profile {
+        id: String,
+        #[arg(long)]
+        name: Option<String>,
+        /// Must not belong to another user
+        #[arg(long, value_parser = parse_email)]
+        email: Option<Email>,
+        /// e.g. en or pt-BR
+        #[arg(long, value_parser = parse_locale)]
+        locale: Option<Locale>,
+        /// e.g. Europe/Rome
+        #[arg(long, value_parser = parse_timezone)]
+        timezone: Option<Timezone>,
+    },
+    /// Save a payment method for a user
+    AddPayment {
+        id: String,
+        #[command(flatten)]
+        payment: PaymentArgs,
+        /// Make it the user's default payment method
+        #[arg(long, default_value_t = false)]
+        default: bool,
+    },
+    /// Remove a saved payment method by its number in `user show`; removing
+    /// the default makes the next one the default
+    RemovePayment {
+        id: String,
+        index: usize,
+    },
+    /// Make a saved payment method the default
+    DefaultPayment {
+        id: String,
+        index: usize,
+    },
+    /// Add a billing address
+    AddAddress {
+        id: String,
+        line1: String,
+        city: String,
+        postal_code: String,
+        /// Two-letter country code
+        country: String,
+        #[arg(long)]
+        line2: Option<String>,
+        #[arg(long)]
+        region: Option<String>,
+        /// Name for the address, e.g. office
+        #[arg(long)]
+        label: Option<String>,
+    },
+    /// Remove a billing address by its number in `user show`
+    RemoveAddress {
+        id: String,
+        index: usize,
+    },
+    /// Write everything held about a user as a JSON archive (payment
+    /// methods masked); recorded in the privacy audit
+    Export {
+        id: String,
+        /// File to write; stdout if omitted
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Pseudonymize a user: profile, payment details and budgets are
+    /// removed, usages are kept under a random id for accounting
+    Erase {
+        id: String,
+        /// Required; erasure cannot be undone
+        #[arg(long, default_value_t = false)]
+        confirm: bool,
+    },
+    /// Every export and erasure
+    Audit,
+}
+
+/// Default payment method; at most one of the kinds may be given.
+#[derive(clap::Args, Debug)]
+struct PaymentArgs {
+    /// PayPal account
+    #[arg(long, group = "default_payment")]
+    paypal: Option<String>,
+    /// SEPA direct debit IBAN (the user's name is the account holder)
+    #[arg(long, group = "default_payment", requires = "mandate")]
+    sepa_iban: Option<String>,
+    /// Bank transfer reference
+    #[arg(long, group = "default_payment")]
+    bank_transfer: Option<String>,
+    /// SEPA mandate reference
+    #[arg(long)]
+    mandate: Option<String>,
+    /// Payment terms for --bank-transfer
+    #[arg(long, default_value_t = 30)]
+    terms_days: u32,
+}
+
+#[derive(Subcommand, Debug)]
+enum ServiceCommand {
+    /// Add a service, or rename one with the same id (its products and
+    /// accepted payment kinds are kept)
+    Add { id: String, name: String },
+    /// Services with their products
+    List,
+    /// Restrict the payment kinds a service accepts; none accepts every kind
+    Accept {
+        id: String,
+        /// card, paypal, sepa_debit, bank_transfer or prepaid
+        #[arg(value_parser = parse_kind)]
+        kinds: Vec<PaymentKind>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ProductCommand {
+    /// Add a product to an existing service, or replace the name and base
+    /// price of one with the same id (its price changes are kept)
+    Add {
+        service_id: String,
+        id: String,
+        name: String,
+        price_cents: u64,
+    },
+    /// Change a product's price from a given time on; past usages keep the
+    /// price they were made at
+    Price {
+        service_id: String,
+        id: String,
+        price_cents: u64,
+        /// When the price takes effect (RFC 3339 or YYYY-MM-DD); defaults to now
+        #[arg(long, value_parser = parse_time)]
+        from: Option<DateTime<Utc>>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CatalogCommand {
+    /// Parse and check a .toml, .json or .yaml catalog file
+    Check { file: PathBuf },
+    /// Save the file's services and products; stored entries it does not
+    /// list are kept
+    Sync {
+        file: PathBuf,
+        /// Only print what would change
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AccountCommand {
+    /// Create an account with a single owner, or rename one with the same id
+    Add {
+        id: String,
+        name: String,
+        /// The user who owns the account
+        #[arg(long)]
+        owner: String,
+    },
+    List,
+    /// An account with its members, payment methods and usage total
+    Show {
+        id: String,
+    },
+    /// Add a member, or change the role of one
+    Member {
+        account_id: String,
+        user_id: String,
+        /// owner, billing_admin or member
+        #[arg(long, value_parser = parse_role, default_value = "member")]
+        role: Role,
+        /// The user making the change; must be an owner
+        #[arg(long = "as")]
+        actor: String,
+    },
+    /// Remove a member; members may remove themselves
+    RemoveMember {
+        account_id: String,
+        user_id: String,
+        #[arg(long = "as")]
+        actor: String,
+    },
+    /// Add a payment method to the account
+    Payment {
+        account_id: String,
+        /// The user making the change; must be an owner or billing admin
+        #[arg(long = "as")]
+        actor: String,
+        #[command(flatten)]
+        payment: PaymentArgs,
+        /// Make it the account's default payment method
+        #[arg(long, default_value_t = false)]
+        default: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum BudgetCommand {
+    /// Add a budget, or replace one with the same id
+    Set {
+        id: String,
+        user_id: String,
+        limit_cents: u64,
+        /// Only count usages of this service
+        #[arg(long)]
+        service: Option<String>,
+        /// daily, weekly or monthly
+        #[arg(long, value_parser = parse_period, default_value = "monthly")]
+        period: BillingPeriod,
+        /// Refuse usages that would go over the limit instead of only alerting
+        #[arg(long, default_value_t = false)]
+        hard_limit: bool,
+        /// Alert at these percentages of the limit
+        #[arg(long, value_delimiter = ',', default_values_t = budget::DEFAULT_THRESHOLDS)]
+        thresholds: Vec<u8>,
+    },
+    /// Budgets with their spend and projection for the current period
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum UsageCommand {
+    /// Record that a user used a product of a service
+    Record {
+        user_id: String,
+        service_id: String,
+        product_id: String,
+        /// When it happened (RFC 3339 or YYYY-MM-DD); defaults to now
+        #[arg(long, value_parser = parse_time)]
+        at: Option<DateTime<Utc>>,
+        /// Bill the usage to this account, which the user must be a member of
+        #[arg(long)]
+        account: Option<String>,
+        /// Record the usage at most once per key; a retry with the same key
+        /// prints the usage recorded the first time
+        #[arg(long)]
+        idempotency_key: Option<String>,
+        /// How long a key is remembered, in hours
+        #[arg(long, default_value_t = 24, requires = "idempotency_key")]
+        key_retention_hours: u32,
+        /// Payment for this usage; defaults to the account's default payment,
+        /// or the user's without --account
+        #[command(flatten)]
+        payment: PaymentArgs,
+    },
+    /// One page of usages, oldest first
+    List {
+        #[command(flatten)]
+        filter: UsageFilter,
+        /// Page size
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Continue after this cursor (printed with the previous page)
+        #[arg(long, value_parser = parse_cursor)]
+        cursor: Option<Cursor>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PaymentCommand {
+    /// Resolve the payment method for a usage under the default policy and
+    /// list every candidate tried; exits non-zero if none can pay
+    Resolve {
+        user_id: String,
+        /// Only methods this service accepts qualify
+        #[arg(long)]
+        service: Option<String>,
+        /// Bill the usage to this account, which the user must be a member of
+        #[arg(long)]
+        account: Option<String>,
+        /// Payment named on the usage
+        #[command(flatten)]
+        payment: PaymentArgs,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CreditCommand {
+    /// Reverse all or part of a usage; fails if its credit notes would add
+    /// up to more than its price
+    Issue {
+        usage_id: i64,
+        /// Why the usage is corrected
+        #[arg(long)]
+        reason: String,
+        /// Amount in cents; defaults to what earlier notes left of the usage
+        #[arg(long)]
+        amount: Option<u64>,
+        /// Refund to the method the usage was paid with instead of crediting
+        /// the payer's balance
+        #[arg(long, default_value_t = false)]
+        refund: bool,
+    },
+    /// Credit notes, oldest first
+    List {
+        /// Only the notes of this usage
+        #[arg(long)]
+        usage: Option<i64>,
+    },
+    /// Credit left for a user's or account's next invoices
+    Balance {
+        #[command(flatten)]
+        payer: PayerArgs,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DunningCommand {
+    /// Record a charge that failed and schedule its retries
+    Open {
+        user_id: String,
+        service_id: String,
+        amount_cents: u64,
+        /// Why the charge failed
+        #[arg(long)]
+        reason: String,
+        /// Hours before each retry, each counted from the attempt before;
+        /// the user is suspended from the service when the last one fails
+        #[arg(long, value_delimiter = ',', default_values_t = dunning::DEFAULT_RETRY_HOURS)]
+        retry_hours: Vec<u32>,
+        /// The method that was charged
+        #[command(flatten)]
+        payment: PaymentArgs,
+    },
+    /// Record how a retry went, or that the case was paid another way;
+    /// paying a suspended case reinstates the user
+    Retry {
+        case_id: String,
+        #[command(flatten)]
+        outcome: OutcomeArgs,
+    },
+    /// Cases, oldest first
+    List {
+        /// Only this user's cases
+        #[arg(long)]
+        user: Option<String>,
+        /// Only cases with a retry due now
+        #[arg(long, default_value_t = false)]
+        due: bool,
+    },
+    /// Notification events, oldest first
+    Events {
+        /// Only the events of this case
+        #[arg(long)]
+        case: Option<String>,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+#[group(required = true, multiple = false)]
+struct OutcomeArgs {
+    /// The charge went through
+    #[arg(long, default_value_t = false)]
+    paid: bool,
+    /// The charge failed, for this reason
+    #[arg(long)]
+    failed: Option<String>,
+}
+
+impl OutcomeArgs {
+    fn to_attempt(&self) -> Attempt {
+        match &self.failed {
+            Some(reason) => Attempt::Failed {
+                reason: reason.clone(),
+            },
+            None => Attempt::Paid,
+        }
+    }
+}
+
+/// A user (for usages not billed to an account) or an account.
+#[derive(clap::Args, Debug)]
+#[group(required = true, multiple = false)]
+struct PayerArgs {
+    #[arg(long)]
+    user: Option<String>,
+    #[arg(long)]
+    account: Option<String>,
+}
+
+impl PayerArgs {
+    fn to_payer(&self) -> Payer {
+        match (&self.user, &self.account) {
+            (_, Some(account)) => Payer::Account(account.as_str().into()),
+            (Some(user), None) => Payer::User(user.as_str().into()),
+            (None, None) => unreachable!("clap requires --user or --account"),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct UsageFilter {
+    #[arg(long)]
+    user: Option<String>,
+    #[arg(long)]
+    service: Option<String>,
+    #[arg(long)]
+    product: Option<String>,
+    /// card, paypal, sepa_debit, bank_transfer or prepaid
+    #[arg(long, value_parser = parse_kind)]
+    payment_kind: Option<PaymentKind>,
+    /// Usages billed to this account
+    #[arg(long)]
+    account: Option<String>,
+    /// Inclusive start (RFC 3339 or YYYY-MM-DD)
+    #[arg(long, value_parser = parse_time)]
+    from: Option<DateTime<Utc>>,
+    /// Exclusive end (RFC 3339 or YYYY-MM-DD)
+    #[arg(long, value_parser = parse_time)]
+    until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum GroupBy {
+    User,
+    Service,
+    Account,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum RevenueBy {
+    Service,
+    Product,
+    User,
+    /// The users with the most revenue
+    Top,
+}
+
+impl RevenueBy {
+    fn section(self) -> Section {
+        match self {
+            RevenueBy::Service => Section::Service,
+            RevenueBy::Product => Section::Product,
+            RevenueBy::User => Section::User,
+            RevenueBy::Top => Section::TopConsumers,
+        }
+    }
+}
+
+impl UsageFilter {
+    fn to_query(&self) -> UsageQuery {
+        UsageQuery {
+            user_id: self.user.as_deref().map(UserId::from),
+            service_id: self.service.as_deref().map(Into::into),
+            product_id: self.product.as_deref().map(Into::into),
+            payment_kind: self.payment_kind,
+            account_id: self.account.as_deref().map(AccountId::from),
+            from: self.from,
+            until: self.until,
+            ..UsageQuery::default()
+        }
+    }
+}
+
+impl PaymentArgs {
+    fn to_method(&self, holder: &str) -> Result<Option<PaymentMethod>, Box<dyn Error>> {
+        if let Some(account) = &self.paypal {
+            return Ok(Some(PaymentMethod::paypal(account)));
+        }
+        if let Some(iban) = &self.sepa_iban {
+            let mandate = self.mandate.as_deref().unwrap_or_default();
+            return Ok(Some(PaymentMethod::sepa_debit(iban, holder, mandate)?));
+        }
+        if let Some(reference) = &self.bank_transfer {
+            return Ok(Some(PaymentMethod::bank_transfer(
+                reference,
+                self.terms_days,
+            )?));
+        }
+        Ok(None)
+    }
+}
+
+fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.to_utc())
+        .or_else(|_| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .map(|d| d.and_hms_opt(0, 0, 0).expect("midnight").and_utc())
+        })
+        .map_err(|_| format!("expected RFC 3339 or YYYY-MM-DD, got {:?}", s))
+}
+
+fn parse_email(s: &str) -> Result<Email, String> {
+    Email::parse(s).map_err(|e| e.to_string())
+}
+
+fn parse_locale(s: &str) -> Result<Locale, String> {
+    Locale::parse(s).map_err(|e| e.to_string())
+}
+
+fn parse_timezone(s: &str) -> Result<Timezone, String> {
+    Timezone::parse(s).map_err(|e| e.to_string())
+}
+
+fn parse_kind(s: &str) -> Result<PaymentKind, String> {
+    PaymentKind::parse(s).ok_or_else(|| format!("unknown payment kind {:?}", s))
+}
+
+fn parse_month(s: &str) -> Result<ReportPeriod, String> {
+    s.split_once('-')
+        .and_then(|(y, m)| ReportPeriod::month(y.parse().ok()?, m.parse().ok()?))
+        .ok_or_else(|| format!("expected YYYY-MM, got {:?}", s))
+}
+
+fn parse_period(s: &str) -> Result<BillingPeriod, String> {
+    BillingPeriod::parse(s).ok_or_else(|| format!("unknown period {:?}", s))
+}
+
+fn parse_role(s: &str) -> Result<Role, String> {
+    Role::parse(s).ok_or_else(|| format!("unknown role {:?}", s))
+}
+
+fn parse_cursor(s: &str) -> Result<Cursor, String> {
+    Cursor::parse(s).map_err(|e| e.to_string())
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> ExitCode {
     dotenv().ok();
-    let args = Args::parse();
+    match run(Args::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
+async fn run(args: Args) -> CliResult {
     if args.demo {
         src02::run_demo().await;
         return Ok(());
@@ -33,14 +692,1124 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .or_else(|| std::env::var("DB_URL").ok())
         .unwrap_or_else(|| "sqlite::memory:".to_string());
 
-    println!("Using DB URL: {}", db_url);
+    // stderr, so JSON output on stdout stays parseable
+    eprintln!("Using DB URL: {}", db_url);
+
+    if let Some(Command::Migrate { dry_run }) = args.command {
+        return run_migrate(&db_url, dry_run).await;
+    }
 
-    let pool = sqlx::SqlitePool::connect(&db_url).await?;
+    // forward-migrate on startup; fails if the database is newer than us
+    let repo = src02::repository::open(&db_url).await?;
+    repo.init().await?;
 
-    if args.init_db {
-        src02::persistence::init_db(&pool).await?;
-        println!("DB initialized at {}", db_url);
+    let format = args.format;
+    match args.command {
+        None | Some(Command::Migrate { .. }) => Ok(()),
+        Some(Command::User(cmd)) => run_user(repo.as_ref(), cmd, format).await,
+        Some(Command::Service(cmd)) => run_service(repo.as_ref(), cmd, format).await,
+        Some(Command::Product(cmd)) => run_product(repo.as_ref(), cmd, format).await,
+        Some(Command::Catalog(cmd)) => run_catalog(repo.as_ref(), cmd, format).await,
+        Some(Command::Account(cmd)) => run_account(repo.as_ref(), cmd, format).await,
+        Some(Command::Budget(cmd)) => run_budget(repo.as_ref(), cmd, format).await,
+        Some(Command::Usage(cmd)) => run_usage(repo.as_ref(), cmd, format).await,
+        Some(Command::Payment(cmd)) => run_payment(repo.as_ref(), cmd, format).await,
+        Some(Command::Credit(cmd)) => run_credit(repo.as_ref(), cmd, format).await,
+        Some(Command::Dunning(cmd)) => run_dunning(repo.as_ref(), cmd, format).await,
+        Some(Command::Invoice {
+            payer,
+            month,
+            from,
+            until,
+        }) => {
+            let period = report_period(month, from, until);
+            run_invoice(repo.as_ref(), &payer.to_payer(), period, format).await
+        }
+        Some(Command::Report { filter, by }) => {
+            run_report(repo.as_ref(), &filter, by, format).await
+        }
+        Some(Command::Revenue {
+            month,
+            from,
+            until,
+            by,
+            top,
+        }) => {
+            let period = report_period(month, from, until);
+            run_revenue(repo.as_ref(), period, by, top, format).await
+        }
+        Some(Command::Validate) => run_validate(repo.as_ref(), format).await,
     }
+}
 
+/// `--month`, or `--from`/`--until`, or else the current month.
+fn report_period(
+    month: Option<ReportPeriod>,
+    from: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+) -> ReportPeriod {
+    match (month, from, until) {
+        (Some(m), _, _) => m,
+        (None, Some(from), Some(until)) => ReportPeriod::new(from, until),
+        _ => {
+            let (from, until) = BillingPeriod::Monthly.bounds(Utc::now());
+            ReportPeriod::new(from, until)
+        }
+    }
+}
+
+async fn run_migrate(db_url: &str, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if !db_url.starts_with("sqlite:") {
+        // schemaless backends only need their storage prepared
+        if !dry_run {
+            src02::repository::open(db_url).await?.init().await?;
+        }
+        println!("{} has no schema migrations", db_url);
+        return Ok(());
+    }
+
+    // a dry run must not create the database file
+    let pool = if dry_run {
+        sqlx::SqlitePool::connect(db_url).await?
+    } else {
+        src02::persistence::connect(db_url).await?
+    };
+    let report = migrations::migrate(&pool, MigrateOptions { dry_run }).await?;
+    if report.is_up_to_date() {
+        println!("Schema is up to date (version {})", report.from_version);
+        return Ok(());
+    }
+    let verb = if report.dry_run {
+        "Would apply"
+    } else {
+        "Applied"
+    };
+    for (version, name) in &report.applied {
+        println!("{} migration {:03} {}", verb, version, name);
+    }
+    println!(
+        "Schema version {} -> {}{}",
+        report.from_version,
+        report.to_version,
+        if report.dry_run { " (dry run)" } else { "" }
+    );
+    Ok(())
+}
+
+fn users_table(users: &[User]) -> Table {
+    users
+        .iter()
+        .fold(Table::new(&["ID", "NAME", "PAYMENT"]), |t, u| {
+            t.row(vec![
+                u.id.0.clone(),
+                u.profile.display_name.clone(),
+                output::payment(u.profile.default_payment()),
+            ])
+        })
+}
+
+async fn find_user(repo: &dyn Repository, id: &str) -> Result<User, Box<dyn Error>> {
+    repo.get_user(&UserId(id.to_string()))
+        .await?
+        .ok_or_else(|| format!("no user {:?}", id).into())
+}
+
+fn address_line(a: &BillingAddress) -> String {
+    let parts = [
+        a.label.as_deref().map(|l| format!("{}:", l)),
+        Some(a.line1.clone()),
+        a.line2.clone(),
+        Some(format!("{} {}", a.postal_code, a.city)),
+        a.region.clone(),
+        Some(a.country.clone()),
+    ];
+    parts.into_iter().flatten().collect::<Vec<_>>().join(" ")
+}
+
+fn addresses_table(addresses: &[BillingAddress]) -> Table {
+    (0..)
+        .zip(addresses)
+        .fold(Table::new(&["#", "ADDRESS"]), |t, (i, a)| {
+            t.row(vec![i.to_string(), address_line(a)])
+        })
+}
+
+async fn find_service(repo: &dyn Repository, id: &str) -> Result<Service, Box<dyn Error>> {
+    repo.get_services()
+        .await?
+        .into_iter()
+        .find(|s| s.id.0 == id)
+        .ok_or_else(|| format!("no service {:?}", id).into())
+}
+
+async fn run_user(repo: &dyn Repository, cmd: UserCommand, format: Format) -> CliResult {
+    match cmd {
+        UserCommand::Add {
+            id,
+            name,
+            payment,
+            email,
+        } => {
+            let mut user = User::new(&id, &name, payment.to_method(&name)?);
+            if let Some(email) = email {
+                user = user.with_email(email);
+            }
+            repo.save_user(&user).await?;
+            output::print(format, &user, || users_table(std::slice::from_ref(&user)))?;
+        }
+        UserCommand::List => {
+            let users = repo.get_users().await?;
+            output::print(format, &users, || users_table(&users))?;
+        }
+        UserCommand::Show { id } => {
+            let user = find_user(repo, &id).await?;
+            let usage = repo
+                .aggregate_usages(&UsageQuery::new().user(&user.id))
+                .await?;
+            output::print(format, &json!({ "user": user, "usage": usage }), || {
+                let profile = &user.profile;
+                let table = Table::new(&["FIELD", "VALUE"])
+                    .row(vec!["id".into(), user.id.0.clone()])
+                    .row(vec!["name".into(), profile.display_name.clone()])
+                    .row(vec![
+                        "email".into(),
+                        output::optional(profile.email.as_ref()),
+                    ])
+                    .row(vec![
+                        "locale".into(),
+                        output::optional(profile.locale.as_ref()),
+                    ])
+                    .row(vec![
+                        "timezone".into(),
+                        output::optional(profile.timezone.as_ref()),
+                    ]);
+                let table = (0..)
+                    .zip(&profile.payment_methods)
+                    .fold(table, |t, (i, pm)| {
+                        let default = if i == 0 { " (default)" } else { "" };
+                        t.row(vec![
+                            format!("payment #{}", i),
+                            format!("{}{}", output::payment(Some(pm)), default),
+                        ])
+                    });
+                (0..)
+                    .zip(&profile.billing_addresses)
+                    .fold(table, |t, (i, a)| {
+                        t.row(vec![format!("address #{}", i), address_line(a)])
+                    })
+                    .row(vec!["usages".into(), usage.count.to_string()])
+                    .row(vec!["total".into(), output::cents(usage.total_cents)])
+            })?;
+        }
+        UserCommand::Profile {
+            id,
+            name,
+            email,
+            locale,
+            timezone,
+        } => {
+            let user = profile::update(repo, &UserId(id), |p| {
+                let mut p = p.clone();
+                if let Some(name) = &name {
+                    p.display_name = name.clone();
+                }
+                p.email = email.clone().or(p.email);
+                p.locale = locale.clone().or(p.locale);
+                p.timezone = timezone.clone().or(p.timezone);
+                Ok(p)
+            })
+            .await?;
+            output::print(format, &user, || users_table(std::slice::from_ref(&user)))?;
+        }
+        UserCommand::AddPayment {
+            id,
+            payment,
+            default,
+        } => {
+            let user = find_user(repo, &id).await?;
+            let method = payment
+                .to_method(&user.profile.display_name)?
+                .ok_or("give a payment method, e.g. --paypal or --bank-transfer")?;
+            let user = profile::update(repo, &user.id, |p| {
+                Ok(p.add_payment_method(method.clone(), default))
+            })
+            .await?;
+            output::print(format, &user, || users_table(std::slice::from_ref(&user)))?;
+        }
+        UserCommand::RemovePayment { id, index } => {
+            let user =
+                profile::update(repo, &UserId(id), |p| p.remove_payment_method(index)).await?;
+            output::print(format, &user, || users_table(std::slice::from_ref(&user)))?;
+        }
+        UserCommand::DefaultPayment { id, index } => {
+            let user = profile::update(repo, &UserId(id), |p| p.set_default_payment(index)).await?;
+            output::print(format, &user, || users_table(std::slice::from_ref(&user)))?;
+        }
+        UserCommand::AddAddress {
+            id,
+            line1,
+            city,
+            postal_code,
+            country,
+            line2,
+            region,
+            label,
+        } => {
+            let address = BillingAddress {
+                label,
+                line2,
+                region,
+                ..BillingAddress::new(&line1, &city, &postal_code, &country)?
+            };
+            let user =
+                profile::update(repo, &UserId(id), |p| Ok(p.add_address(address.clone()))).await?;
+            output::print(format, &user.profile.billing_addresses, || {
+                addresses_table(&user.profile.billing_addresses)
+            })?;
+        }
+        UserCommand::RemoveAddress { id, index } => {
+            let user = profile::update(repo, &UserId(id), |p| p.remove_address(index)).await?;
+            output::print(format, &user.profile.billing_addresses, || {
+                addresses_table(&user.profile.billing_addresses)
+            })?;
+        }
+        UserCommand::Export { id, out } => {
+            let export = repo
+                .export_user(&UserId(id.clone()))
+                .await?
+                .ok_or_else(|| format!("no user {:?}", id))?;
+            let archive = serde_json::to_string_pretty(&export)?;
+            match out {
+                Some(path) => {
+                    std::fs::write(&path, archive + "\n")?;
+                    eprintln!(
+                        "exported {} usages of {} to {}",
+                        export.usages.len(),
+                        id,
+                        path.display()
+                    );
+                }
+                None => println!("{}", archive),
+            }
+        }
+        UserCommand::Erase { id, confirm } => {
+            if !confirm {
+                return Err(format!("erasing {} cannot be undone; pass --confirm", id).into());
+            }
+            let report = repo
+                .erase_user(&UserId(id.clone()), &privacy::pseudonym())
+                .await?
+                .ok_or_else(|| format!("no user {:?}", id))?;
+            output::print(format, &report, || {
+                Table::new(&["FIELD", "VALUE"])
+                    .row(vec!["user".into(), report.user_id.0.clone()])
+                    .row(vec!["pseudonym".into(), report.pseudonym.0.clone()])
+                    .row(vec!["usages".into(), report.usages.to_string()])
+                    .row(vec!["memberships".into(), report.memberships.to_string()])
+                    .row(vec![
+                        "budgets deleted".into(),
+                        report.budgets_deleted.to_string(),
+                    ])
+            })?;
+        }
+        UserCommand::Audit => {
+            let records = repo.get_privacy_audit().await?;
+            output::print(format, &records, || {
+                records.iter().fold(
+                    Table::new(&["AT", "ACTION", "SUBJECT", "USAGES"]),
+                    |t, r| {
+                        t.row(vec![
+                            output::time(r.at),
+                            r.action.as_str().to_string(),
+                            r.subject.0.clone(),
+                            r.usages.to_string(),
+                        ])
+                    },
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn services_table(services: &[Service]) -> Table {
+    let header = Table::new(&["SERVICE", "NAME", "PRODUCT", "PRODUCT NAME", "PRICE"]);
+    services.iter().fold(header, |t, s| {
+        if s.products.is_empty() {
+            return t.row(vec![s.id.0.clone(), s.name.clone()]);
+        }
+        s.products.iter().fold(t, |t, p| {
+            t.row(vec![
+                s.id.0.clone(),
+                s.name.clone(),
+                p.id.0.clone(),
+                p.name.clone(),
+                output::cents(p.current_price()),
+            ])
+        })
+    })
+}
+
+async fn run_service(repo: &dyn Repository, cmd: ServiceCommand, format: Format) -> CliResult {
+    match cmd {
+        ServiceCommand::Add { id, name } => {
+            let service = match find_service(repo, &id).await {
+                Ok(existing) => Service { name, ..existing },
+                Err(_) => Service::new(&id, &name, Vec::new()),
+            };
+            repo.save_service(&service).await?;
+            output::print(format, &service, || {
+                services_table(std::slice::from_ref(&service))
+            })?;
+        }
+        ServiceCommand::List => {
+            let services = repo.get_services().await?;
+            output::print(format, &services, || services_table(&services))?;
+        }
+        ServiceCommand::Accept { id, kinds } => {
+            let service = find_service(repo, &id).await?.accepting(&kinds);
+            repo.save_service(&service).await?;
+            let accepted: Vec<&str> = match service.accepted_payments.as_slice() {
+                [] => vec!["any"],
+                kinds => kinds.iter().map(|k| k.as_str()).collect(),
+            };
+            output::print(format, &service, || {
+                Table::new(&["SERVICE", "ACCEPTS"])
+                    .row(vec![service.id.0.clone(), accepted.join(", ")])
+            })?;
+        }
+    }
+    Ok(())
+}
+
+async fn run_product(repo: &dyn Repository, cmd: ProductCommand, format: Format) -> CliResult {
+    // products are keyed by id alone in storage, so one id per catalog
+    let catalog = Catalog::from_services(DuplicatePolicy::Reject, repo.get_services().await?)?;
+    let (service_id, catalog) = match cmd {
+        ProductCommand::Add {
+            service_id,
+            id,
+            name,
+            price_cents,
+        } => {
+            let service_id = ServiceId::from(service_id.as_str());
+            let product = Product::new(&id, &name, price_cents);
+            let catalog = match catalog.get_product(&service_id, &product.id) {
+                Some(existing) => catalog.update_product(
+                    &service_id,
+                    Product {
+                        price_changes: existing.price_changes.clone(),
+                        ..product
+                    },
+                )?,
+                None => catalog.add_product(&service_id, product)?,
+            };
+            (service_id, catalog)
+        }
+        ProductCommand::Price {
+            service_id,
+            id,
+            price_cents,
+            from,
+        } => {
+            let service_id = ServiceId::from(service_id.as_str());
+            let from = from.unwrap_or_else(Utc::now);
+            let catalog =
+                catalog.schedule_price(&service_id, &id.as_str().into(), from, price_cents)?;
+            (service_id, catalog)
+        }
+    };
+    let service = catalog.services()[&service_id].clone();
+    repo.save_service(&service).await?;
+    output::print(format, &service, || {
+        services_table(std::slice::from_ref(&service))
+    })?;
+    Ok(())
+}
+
+async fn run_catalog(repo: &dyn Repository, cmd: CatalogCommand, format: Format) -> CliResult {
+    match cmd {
+        CatalogCommand::Check { file } => {
+            let catalog = catalog_file::load(&file)?;
+            let mut services: Vec<Service> = catalog.services().values().cloned().collect();
+            services.sort_by(|a, b| a.id.cmp(&b.id));
+            output::print(format, &services, || services_table(&services))?;
+        }
+        CatalogCommand::Sync { file, dry_run } => {
+            let catalog = catalog_file::load(&file)?;
+            let diff = catalog_file::sync(repo, &catalog, dry_run).await?;
+            output::print(format, &json!({ "dry_run": dry_run, "diff": diff }), || {
+                diff.changes
+                    .iter()
+                    .fold(Table::new(&["CHANGE"]), |t, c| t.row(vec![c.to_string()]))
+            })?;
+            if format == Format::Table {
+                let verb = if dry_run { "would save" } else { "saved" };
+                println!("{} {} services", verb, diff.writes.len());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn accounts_table(accounts: &[Account]) -> Table {
+    accounts
+        .iter()
+        .fold(Table::new(&["ID", "NAME", "MEMBERS", "PAYMENT"]), |t, a| {
+            t.row(vec![
+                a.id.0.clone(),
+                a.name.clone(),
+                a.members.len().to_string(),
+                output::payment(a.default_payment()),
+            ])
+        })
+}
+
+async fn find_account(repo: &dyn Repository, id: &str) -> Result<Account, Box<dyn Error>> {
+    repo.get_accounts()
+        .await?
+        .into_iter()
+        .find(|a| a.id.0 == id)
+        .ok_or_else(|| format!("no account {:?}", id).into())
+}
+
+async fn run_account(repo: &dyn Repository, cmd: AccountCommand, format: Format) -> CliResult {
+    let account = match cmd {
+        AccountCommand::Add { id, name, owner } => {
+            let owner = find_user(repo, &owner).await?;
+            match find_account(repo, &id).await {
+                Ok(existing) => Account { name, ..existing },
+                Err(_) => Account::new(&id, &name, &owner.id),
+            }
+        }
+        AccountCommand::List => {
+            let accounts = repo.get_accounts().await?;
+            output::print(format, &accounts, || accounts_table(&accounts))?;
+            return Ok(());
+        }
+        AccountCommand::Show { id } => {
+            let account = find_account(repo, &id).await?;
+            let usage = repo
+                .aggregate_usages(&UsageQuery::new().account(&account.id))
+                .await?;
+            output::print(
+                format,
+                &json!({ "account": account, "usage": usage }),
+                || {
+                    let table = Table::new(&["FIELD", "VALUE"])
+                        .row(vec!["id".into(), account.id.0.clone()])
+                        .row(vec!["name".into(), account.name.clone()]);
+                    let table = account.members.iter().fold(table, |t, m| {
+                        t.row(vec![
+                            "member".into(),
+                            format!("{} ({})", m.user_id.0, m.role.as_str()),
+                        ])
+                    });
+                    let table = account.payment_methods.iter().fold(table, |t, pm| {
+                        t.row(vec!["payment".into(), output::payment(Some(pm))])
+                    });
+                    table
+                        .row(vec!["usages".into(), usage.count.to_string()])
+                        .row(vec!["total".into(), output::cents(usage.total_cents)])
+                },
+            )?;
+            return Ok(());
+        }
+        AccountCommand::Member {
+            account_id,
+            user_id,
+            role,
+            actor,
+        } => {
+            let user = find_user(repo, &user_id).await?;
+            find_account(repo, &account_id).await?.set_member(
+                &actor.as_str().into(),
+                &user.id,
+                role,
+            )?
+        }
+        AccountCommand::RemoveMember {
+            account_id,
+            user_id,
+            actor,
+        } => find_account(repo, &account_id)
+            .await?
+            .remove_member(&actor.as_str().into(), &user_id.as_str().into())?,
+        AccountCommand::Payment {
+            account_id,
+            actor,
+            payment,
+            default,
+        } => {
+            let account = find_account(repo, &account_id).await?;
+            let method = payment
+                .to_method(&account.name)?
+                .ok_or("give a payment method, e.g. --paypal or --bank-transfer")?;
+            account.add_payment_method(&actor.as_str().into(), method, default)?
+        }
+    };
+    repo.save_account(&account).await?;
+    output::print(format, &account, || {
+        accounts_table(std::slice::from_ref(&account))
+    })?;
+    Ok(())
+}
+
+fn budgets_table(statuses: &[BudgetStatus]) -> Table {
+    let header = Table::new(&[
+        "ID",
+        "USER",
+        "SERVICE",
+        "PERIOD",
+        "MODE",
+        "LIMIT",
+        "SPENT",
+        "PROJECTED",
+        "USED",
+    ]);
+    statuses.iter().fold(header, |t, s| {
+        let b = &s.budget;
+        t.row(vec![
+            b.id.0.clone(),
+            b.user_id.0.clone(),
+            b.service_id
+                .as_ref()
+                .map(|s| s.0.clone())
+                .unwrap_or_else(|| "*".to_string()),
+            b.period.as_str().to_string(),
+            b.enforcement.as_str().to_string(),
+            output::cents(b.limit_cents),
+            output::cents(s.spent_cents),
+            output::cents(s.projected_cents),
+            format!("{}%", s.percent_used()),
+        ])
+    })
+}
+
+async fn run_budget(repo: &dyn Repository, cmd: BudgetCommand, format: Format) -> CliResult {
+    let now = Utc::now();
+    match cmd {
+        BudgetCommand::Set {
+            id,
+            user_id,
+            limit_cents,
+            service,
+            period,
+            hard_limit,
+            thresholds,
+        } => {
+            let user = find_user(repo, &user_id).await?;
+            let budget =
+                Budget::new(&id, &user.id, period, limit_cents).with_thresholds(&thresholds);
+            let budget = match service {
+                Some(s) => budget.for_service(&find_service(repo, &s).await?.id),
+                None => budget,
+            };
+            let budget = if hard_limit {
+                budget.hard_limit()
+            } else {
+                budget
+            };
+            repo.save_budget(&budget).await?;
+            let status = budget::statuses(repo, std::slice::from_ref(&budget), now).await?;
+            output::print(format, &status[0], || budgets_table(&status))?;
+        }
+        BudgetCommand::List => {
+            let statuses = budget::statuses(repo, &repo.get_budgets().await?, now).await?;
+            output::print(format, &statuses, || budgets_table(&statuses))?;
+        }
+    }
     Ok(())
 }
+
+fn recorded_table(usage: &ServiceUsage) -> Table {
+    Table::new(&["TIME", "USER", "SERVICE", "PRODUCT", "ACCOUNT"]).row(vec![
+        output::time(usage.occurred_at),
+        usage.user_id.0.clone(),
+        usage.service_id.0.clone(),
+        usage.product_id.0.clone(),
+        output::account(usage.account_id.as_ref()),
+    ])
+}
+
+async fn run_usage(repo: &dyn Repository, cmd: UsageCommand, format: Format) -> CliResult {
+    match cmd {
+        UsageCommand::Record {
+            user_id,
+            service_id,
+            product_id,
+            at,
+            account,
+            idempotency_key,
+            key_retention_hours,
+            payment,
+        } => {
+            let retention = chrono::Duration::hours(key_retention_hours.into());
+            if let Some(key) = &idempotency_key {
+                let key = IdempotencyKey::from(key.as_str());
+                if let Some(stored) = repo.find_idempotent_usage(&key, retention).await? {
+                    eprintln!("replayed: key {} was already recorded", key.0);
+                    output::print(format, &stored.usage, || recorded_table(&stored.usage))?;
+                    return Ok(());
+                }
+            }
+            let refs = validation::load_references(repo).await?;
+            let user_id = UserId::from(user_id.as_str());
+            let account_id = account.as_deref().map(AccountId::from);
+            // the payer holds the SEPA mandate: the account if there is one
+            let holder = match &account_id {
+                Some(a) => refs.accounts.get(a).map(|a| a.name.as_str()),
+                None => refs
+                    .users
+                    .get(&user_id)
+                    .map(|u| u.profile.display_name.as_str()),
+            }
+            .unwrap_or_default();
+            let usage = ServiceUsage::new(
+                &user_id,
+                &service_id.as_str().into(),
+                &product_id.as_str().into(),
+                payment.to_method(holder)?,
+            );
+            let usage = match at {
+                Some(t) => usage.at(t),
+                None => usage,
+            };
+            let usage = match &account_id {
+                Some(a) => usage.for_account(a),
+                None => usage,
+            };
+            let usage = match &idempotency_key {
+                Some(k) => usage.with_idempotency_key(k),
+                None => usage,
+            };
+            validation::validate_usage(&usage, &refs)?;
+            dunning::check_access(repo, &usage).await?;
+            // one usage per process: nothing here to hold `budget::UsageLocks`
+            // against, so a server or another CLI recording for the same user
+            // at the same moment can still pass a hard limit with it
+            let budgets = repo.get_budgets().await?;
+            let alerts = budget::check_usage(repo, &budgets, &refs.catalog, &usage).await?;
+            let recorded = repo.record_usage(&usage, retention).await?;
+            // a concurrent writer may have taken the key since the lookup
+            match (&recorded, &usage.idempotency_key) {
+                (Recorded::Replayed(_), Some(key)) => {
+                    eprintln!("replayed: key {} was already recorded", key.0)
+                }
+                _ => {
+                    for alert in &alerts {
+                        eprintln!("alert: {}", alert);
+                    }
+                }
+            }
+            let usage = recorded.into_record().usage;
+            output::print(format, &usage, || recorded_table(&usage))?;
+        }
+        UsageCommand::List {
+            filter,
+            limit,
+            cursor,
+        } => {
+            let query = UsageQuery {
+                limit,
+                after: cursor,
+                ..filter.to_query()
+            };
+            let page = repo.query_usages(&query).await?;
+            let next = page.next_cursor.map(|c| c.encode());
+            let value = json!({ "items": page.items, "next_cursor": next });
+            output::print(format, &value, || {
+                let header = Table::new(&[
+                    "ID", "TIME", "USER", "SERVICE", "PRODUCT", "ACCOUNT", "PAYMENT",
+                ]);
+                page.items.iter().fold(header, |t, r| {
+                    t.row(vec![
+                        r.id.to_string(),
+                        output::time(r.usage.occurred_at),
+                        r.usage.user_id.0.clone(),
+                        r.usage.service_id.0.clone(),
+                        r.usage.product_id.0.clone(),
+                        output::account(r.usage.account_id.as_ref()),
+                        output::payment(r.usage.payment_used.as_ref()),
+                    ])
+                })
+            })?;
+            if let (Format::Table, Some(next)) = (format, &next) {
+                println!("more: --cursor {}", next);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_payment(repo: &dyn Repository, cmd: PaymentCommand, format: Format) -> CliResult {
+    let PaymentCommand::Resolve {
+        user_id,
+        service,
+        account,
+        payment,
+    } = cmd;
+    let user = find_user(repo, &user_id).await?;
+    let service = match service {
+        Some(id) => Some(find_service(repo, &id).await?),
+        None => None,
+    };
+    let account = match account {
+        Some(id) => {
+            let account = find_account(repo, &id).await?;
+            if !account.is_member(&user.id) {
+                return Err(format!("{} is not a member of account {}", user.id.0, id).into());
+            }
+            Some(account)
+        }
+        None => None,
+    };
+    // the payer holds the SEPA mandate: the account if there is one
+    let holder = match &account {
+        Some(a) => a.name.as_str(),
+        None => user.profile.display_name.as_str(),
+    };
+    let named = payment.to_method(holder)?;
+    let mut ctx = PaymentContext::new()
+        .user(&user)
+        .usage_payment(named.as_ref());
+    if let Some(service) = &service {
+        ctx = ctx.service(service);
+    }
+    if let Some(account) = &account {
+        ctx = ctx.account(account);
+    }
+    let decision = PaymentPolicy::default().resolve(&ctx);
+    output::print(format, &decision, || {
+        let header = Table::new(&["SOURCE", "PAYMENT", "OUTCOME"]);
+        decision.trail.iter().fold(header, |t, step| {
+            t.row(vec![
+                step.source.to_string(),
+                step.payment.clone().unwrap_or_else(|| "-".to_string()),
+                step.outcome.to_string(),
+            ])
+        })
+    })?;
+    if decision.payment.is_none() {
+        let account_id = account.as_ref().map(|a| &a.id);
+        return Err(validation::payment_violation(&decision, &user.id, account_id).into());
+    }
+    Ok(())
+}
+
+fn credit_notes_table(notes: &[CreditNote]) -> Table {
+    let header = Table::new(&[
+        "ID",
+        "USAGE",
+        "PAYER",
+        "AMOUNT",
+        "SETTLEMENT",
+        "REFUND TO",
+        "REASON",
+    ]);
+    notes.iter().fold(header, |t, n| {
+        t.row(vec![
+            n.id.0.clone(),
+            n.usage_id.to_string(),
+            n.payer.to_string(),
+            output::cents(n.amount_cents),
+            n.settlement.as_str().to_string(),
+            output::payment(n.refund_to.as_ref()),
+            n.reason.clone(),
+        ])
+    })
+}
+
+async fn run_credit(repo: &dyn Repository, cmd: CreditCommand, format: Format) -> CliResult {
+    match cmd {
+        CreditCommand::Issue {
+            usage_id,
+            reason,
+            amount,
+            refund,
+        } => {
+            let settlement = if refund {
+                Settlement::Refund
+            } else {
+                Settlement::Credit
+            };
+            let note = credit::issue(repo, usage_id, amount, &reason, settlement).await?;
+            output::print(format, &note, || {
+                credit_notes_table(std::slice::from_ref(&note))
+            })?;
+        }
+        CreditCommand::List { usage } => {
+            let mut notes = repo.get_credit_notes().await?;
+            notes.retain(|n| usage.is_none_or(|id| n.usage_id == id));
+            output::print(format, &notes, || credit_notes_table(&notes))?;
+        }
+        CreditCommand::Balance { payer } => {
+            let payer = payer.to_payer();
+            let balance_cents = credit::credit_balance(repo, &payer).await?;
+            let value = json!({ "payer": payer, "balance_cents": balance_cents });
+            output::print(format, &value, || {
+                Table::new(&["PAYER", "BALANCE"])
+                    .row(vec![payer.to_string(), output::cents(balance_cents)])
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn dunning_cases_table(cases: &[DunningCase]) -> Table {
+    let header = Table::new(&[
+        "ID",
+        "USER",
+        "SERVICE",
+        "AMOUNT",
+        "STATE",
+        "ATTEMPTS",
+        "NEXT RETRY",
+        "REASON",
+    ]);
+    cases.iter().fold(header, |t, c| {
+        t.row(vec![
+            c.id.0.clone(),
+            c.user_id.0.clone(),
+            c.service_id.0.clone(),
+            output::cents(c.amount_cents),
+            c.state.as_str().to_string(),
+            c.attempts.to_string(),
+            c.next_attempt_at.map(output::time).unwrap_or_default(),
+            c.reason.clone(),
+        ])
+    })
+}
+
+fn dunning_events_table(events: &[DunningEvent]) -> Table {
+    let header = Table::new(&["AT", "CASE", "STAGE", "ATTEMPT", "NEXT RETRY", "REASON"]);
+    events.iter().fold(header, |t, e| {
+        t.row(vec![
+            output::time(e.at),
+            e.case_id.0.clone(),
+            e.stage.as_str().to_string(),
+            e.attempt.to_string(),
+            e.next_attempt_at.map(output::time).unwrap_or_default(),
+            e.reason.clone().unwrap_or_default(),
+        ])
+    })
+}
+
+async fn run_dunning(repo: &dyn Repository, cmd: DunningCommand, format: Format) -> CliResult {
+    match cmd {
+        DunningCommand::Open {
+            user_id,
+            service_id,
+            amount_cents,
+            reason,
+            retry_hours,
+            payment,
+        } => {
+            let user = find_user(repo, &user_id).await?;
+            let charge = FailedCharge {
+                user_id: user.id,
+                service_id: service_id.as_str().into(),
+                amount_cents,
+                payment: payment.to_method(&user.profile.display_name)?,
+                reason,
+            };
+            let schedule = DunningSchedule::new(&retry_hours);
+            let opened = dunning::open(repo, &SystemClock, &charge, schedule).await?;
+            output::print(format, &opened, || dunning_events_table(&opened.events))?;
+        }
+        DunningCommand::Retry { case_id, outcome } => {
+            let case_id = DunningCaseId::from(case_id.as_str());
+            let recorded =
+                dunning::record_attempt(repo, &SystemClock, &case_id, &outcome.to_attempt())
+                    .await?;
+            output::print(format, &recorded, || dunning_events_table(&recorded.events))?;
+        }
+        DunningCommand::List { user, due } => {
+            let now = Utc::now();
+            let mut cases = repo.get_dunning_cases().await?;
+            cases.retain(|c| {
+                user.as_ref().is_none_or(|u| &c.user_id.0 == u) && (!due || c.is_due(now))
+            });
+            output::print(format, &cases, || dunning_cases_table(&cases))?;
+        }
+        DunningCommand::Events { case } => {
+            let mut events = repo.get_dunning_events().await?;
+            events.retain(|e| case.as_ref().is_none_or(|c| &e.case_id.0 == c));
+            output::print(format, &events, || dunning_events_table(&events))?;
+        }
+    }
+    Ok(())
+}
+
+async fn run_invoice(
+    repo: &dyn Repository,
+    payer: &Payer,
+    period: ReportPeriod,
+    format: Format,
+) -> CliResult {
+    let invoice = credit::invoice(repo, payer, period).await?;
+    output::print(format, &invoice, || {
+        let header = Table::new(&["USAGE", "TIME", "USER", "SERVICE", "PRODUCT", "AMOUNT"]);
+        let t = invoice.lines.iter().fold(header, |t, l| {
+            t.row(vec![
+                l.usage_id.to_string(),
+                output::time(l.occurred_at),
+                l.user_id.0.clone(),
+                l.service_id.0.clone(),
+                l.product_id.0.clone(),
+                output::cents(l.amount_cents),
+            ])
+        });
+        let blank = || vec![String::new(); 4];
+        [
+            ("subtotal", invoice.subtotal_cents),
+            ("credit", invoice.credit_applied_cents),
+            ("total", invoice.total_cents),
+        ]
+        .into_iter()
+        .fold(t, |t, (label, cents)| {
+            let mut row = blank();
+            row.push(label.to_string());
+            row.push(output::cents(cents));
+            t.row(row)
+        })
+    })?;
+    Ok(())
+}
+
+async fn run_report(
+    repo: &dyn Repository,
+    filter: &UsageFilter,
+    by: GroupBy,
+    format: Format,
+) -> CliResult {
+    let base = filter.to_query();
+    let groups: Vec<(String, String, UsageQuery)> = match by {
+        GroupBy::User => repo
+            .get_users()
+            .await?
+            .into_iter()
+            .filter(|u| base.user_id.as_ref().is_none_or(|id| id == &u.id))
+            .map(|u| {
+                let q = base.clone().user(&u.id);
+                (u.id.0, u.profile.display_name, q)
+            })
+            .collect(),
+        GroupBy::Service => repo
+            .get_services()
+            .await?
+            .into_iter()
+            .filter(|s| base.service_id.as_ref().is_none_or(|id| id == &s.id))
+            .map(|s| {
+                let q = base.clone().service(&s.id);
+                (s.id.0, s.name, q)
+            })
+            .collect(),
+        GroupBy::Account => repo
+            .get_accounts()
+            .await?
+            .into_iter()
+            .filter(|a| base.account_id.as_ref().is_none_or(|id| id == &a.id))
+            .map(|a| {
+                let q = base.clone().account(&a.id);
+                (a.id.0, a.name, q)
+            })
+            .collect(),
+    };
+
+    let mut rows = Vec::new();
+    for (id, name, q) in groups {
+        let agg = repo.aggregate_usages(&q).await?;
+        if agg.count > 0 {
+            rows.push((id, name, agg));
+        }
+    }
+    let total = repo.aggregate_usages(&base).await?;
+
+    let value = json!({
+        "rows": rows
+            .iter()
+            .map(|(id, name, agg)| json!({ "id": id, "name": name, "count": agg.count, "total_cents": agg.total_cents }))
+            .collect::<Vec<_>>(),
+        "total": total,
+    });
+    output::print(format, &value, || {
+        let header = Table::new(&["ID", "NAME", "USAGES", "TOTAL"]);
+        let row = |t: Table, id: &str, name: &str, agg: &UsageAggregate| {
+            t.row(vec![
+                id.to_string(),
+                name.to_string(),
+                agg.count.to_string(),
+                output::cents(agg.total_cents),
+            ])
+        };
+        let table = rows
+            .iter()
+            .fold(header, |t, (id, name, agg)| row(t, id, name, agg));
+        row(table, "TOTAL", "", &total)
+    })?;
+    Ok(())
+}
+
+fn revenue_table(rows: &[RevenueRow], total: &RevenueRow) -> Table {
+    let header = Table::new(&[
+        "ID", "NAME", "USAGES", "REVENUE", "PREVIOUS", "CHANGE", "CHANGE %",
+    ]);
+    rows.iter().chain([total]).fold(header, |t, r| {
+        let change = match r.change_cents {
+            c if c < 0 => format!("-{}", output::cents(c.unsigned_abs())),
+            c => format!("+{}", output::cents(c as u64)),
+        };
+        t.row(vec![
+            r.id.clone(),
+            r.name.clone(),
+            r.usages.to_string(),
+            output::cents(r.revenue_cents),
+            output::cents(r.previous_revenue_cents),
+            change,
+            r.change_percent
+                .map(|p| format!("{:+.1}%", p))
+                .unwrap_or_else(|| "-".to_string()),
+        ])
+    })
+}
+
+async fn run_revenue(
+    repo: &dyn Repository,
+    period: ReportPeriod,
+    by: RevenueBy,
+    top: usize,
+    format: Format,
+) -> CliResult {
+    let report = reporting::build(repo, period, top).await?;
+    let rows = report.section(by.section());
+    match format {
+        // raw cents, for spreadsheets
+        Format::Csv => reporting::write_csv(rows, std::io::stdout().lock())?,
+        _ => output::print(format, &report, || revenue_table(rows, &report.total))?,
+    }
+    Ok(())
+}
+
+async fn run_validate(repo: &dyn Repository, format: Format) -> CliResult {
+    let violations = validation::audit(repo).await?;
+    output::print(format, &violations, || {
+        let header = Table::new(&["USAGE", "USER", "SERVICE", "PRODUCT", "PROBLEM"]);
+        violations.iter().fold(header, |t, v| {
+            v.problems.iter().fold(t, |t, p| {
+                t.row(vec![
+                    v.usage_id.to_string(),
+                    v.usage.user_id.0.clone(),
+                    v.usage.service_id.0.clone(),
+                    v.usage.product_id.0.clone(),
+                    p.to_string(),
+                ])
+            })
+        })
+    })?;
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} usages failed validation", violations.len()).into())
+    }
+}
@@ -0,0 +1,119 @@
+//! Table, JSON and CSV rendering for the CLI.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+use src02::account::AccountId;
+use src02::models::PaymentMethod;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Table,
+    Json,
+    /// The table's rows as CSV, header first
+    Csv,
+}
+
+/// Plain text table with left-aligned, space-padded columns.
+pub struct Table {
+    header: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(header: &[&'static str]) -> Self {
+        Table {
+            header: header.to_vec(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn row(mut self, cells: Vec<String>) -> Self {
+        self.rows.push(cells);
+        self
+    }
+
+    pub fn write_csv<W: std::io::Write>(&self, writer: W) -> Result<(), csv::Error> {
+        let mut w = csv::Writer::from_writer(writer);
+        w.write_record(&self.header)?;
+        for row in &self.rows {
+            // short rows (e.g. a service without products) keep every column
+            let cells = (0..self.header.len()).map(|i| row.get(i).map_or("", String::as_str));
+            w.write_record(cells)?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn render(&self) -> String {
+        let widths: Vec<usize> = (0..self.header.len())
+            .map(|i| {
+                self.rows
+                    .iter()
+                    .filter_map(|r| r.get(i))
+                    .map(|c| c.chars().count())
+                    .chain([self.header[i].len()])
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        let line = |cells: Vec<&str>| {
+            cells
+                .iter()
+                .zip(&widths)
+                .map(|(c, w)| format!("{:<w$}", c, w = w))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        };
+        let mut out = vec![line(self.header.clone())];
+        out.extend(
+            self.rows
+                .iter()
+                .map(|r| line(r.iter().map(String::as_str).collect())),
+        );
+        out.join("\n")
+    }
+}
+
+/// Print `value` as pretty JSON or the table `table` builds, rendered or as CSV.
+pub fn print<T: Serialize + ?Sized>(
+    format: Format,
+    value: &T,
+    table: impl FnOnce() -> Table,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(value)?),
+        Format::Table => println!("{}", table().render()),
+        Format::Csv => table().write_csv(std::io::stdout().lock())?,
+    }
+    Ok(())
+}
+
+pub fn time(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+pub fn cents(amount: u64) -> String {
+    format!("{}.{:02}", amount / 100, amount % 100)
+}
+
+/// The account a usage is billed to, `-` for the user themselves.
+pub fn account(id: Option<&AccountId>) -> String {
+    id.map(|a| a.0.clone()).unwrap_or_else(|| "-".to_string())
+}
+
+/// A value that may be unset, `-` if it is.
+pub fn optional<T: std::fmt::Display>(value: Option<&T>) -> String {
+    value.map(T::to_string).unwrap_or_else(|| "-".to_string())
+}
+
+/// `PaymentMethod::masked`, except that operators see PayPal accounts in full.
+pub fn payment(pm: Option<&PaymentMethod>) -> String {
+    match pm {
+        None => "-".to_string(),
+        Some(PaymentMethod::Paypal { account }) => format!("paypal {}", account),
+        Some(pm) => pm.masked(),
+    }
+}
@@ -0,0 +1,117 @@
+use axum::extract::Request;
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use clap::Parser;
+use dotenvy::dotenv;
+use src02::api::ServerError;
+use src02::clock::SystemClock;
+use src02::shared_catalog::SharedCatalog;
+use std::error::Error;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+/// Serve the src02 HTTP API (see `src02::api`).
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// Storage URL: `sqlite:<file>`, `sqlite::memory:`, `sled:<dir>` or `memory:`.
+    #[arg(long)]
+    db_url: Option<String>,
+
+    /// Address to listen on
+    #[arg(long)]
+    listen: Option<String>,
+
+    /// How long `POST /usages` remembers an idempotency key, in hours
+    #[arg(long)]
+    key_retention_hours: Option<u32>,
+
+    /// Reload the served catalog from storage every N seconds, to pick up
+    /// changes made by other processes (default: only on `POST /catalog/reload`)
+    #[arg(long)]
+    catalog_reload_secs: Option<u64>,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    dotenv().ok();
+    match run(Args::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    // CLI -> ENV -> default, like the src02 CLI
+    let db_url = args
+        .db_url
+        .or_else(|| std::env::var("DB_URL").ok())
+        .unwrap_or_else(|| "sqlite::memory:".to_string());
+    let listen = args
+        .listen
+        .or_else(|| std::env::var("LISTEN_ADDR").ok())
+        .unwrap_or_else(|| "127.0.0.1:8080".to_string());
+
+    let key_retention = match args.key_retention_hours {
+        Some(h) => Some(h),
+        None => std::env::var("IDEMPOTENCY_RETENTION_HOURS")
+            .ok()
+            .map(|h| h.parse::<u32>())
+            .transpose()?,
+    }
+    .map(|h| chrono::Duration::hours(h.into()))
+    .unwrap_or(src02::idempotency::DEFAULT_RETENTION);
+
+    let catalog_reload = match args.catalog_reload_secs {
+        Some(s) => Some(s),
+        None => std::env::var("CATALOG_RELOAD_SECS")
+            .ok()
+            .map(|s| s.parse::<u64>())
+            .transpose()?,
+    };
+
+    let repo: Arc<dyn src02::repository::Repository> =
+        Arc::from(src02::repository::open(&db_url).await?);
+    repo.init().await?;
+    let catalog = SharedCatalog::default();
+    catalog.reload_from_repository(repo.as_ref()).await?;
+    if let Some(secs) = catalog_reload.filter(|&s| s > 0) {
+        let (repo, catalog) = (repo.clone(), catalog.clone());
+        tokio::spawn(async move {
+            let mut every = tokio::time::interval(std::time::Duration::from_secs(secs));
+            every.tick().await; // the first tick is immediate; loaded above
+            loop {
+                every.tick().await;
+                // a failed reload keeps serving the previous catalog
+                if let Err(e) = catalog.reload_from_repository(repo.as_ref()).await {
+                    eprintln!("catalog reload failed: {}", e);
+                }
+            }
+        });
+    }
+
+    let listener = tokio::net::TcpListener::bind(&listen).await?;
+    eprintln!("Using DB URL: {}", db_url);
+    eprintln!("Listening on http://{}", listener.local_addr()?);
+    let app = src02::api::router_with_catalog(repo, key_retention, Arc::new(SystemClock), catalog)
+        .layer(middleware::from_fn(log_server_errors));
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// Log the requests that failed on the server's side; clients only get the
+/// message in the body.
+async fn log_server_errors(request: Request, next: Next) -> Response {
+    let (method, uri) = (request.method().clone(), request.uri().clone());
+    let response = next.run(request).await;
+    if response.status().is_server_error() {
+        match response.extensions().get::<ServerError>() {
+            Some(ServerError(e)) => eprintln!("{} {}: {}: {}", method, uri, response.status(), e),
+            None => eprintln!("{} {}: {}", method, uri, response.status()),
+        }
+    }
+    response
+}
@@ -0,0 +1,379 @@
+//! Spending limits per user, optionally narrowed to one service, for a
+//! billing period.
+//!
+//! Spend is what the period's usages cost at the price in effect when each
+//! occurred (`Repository::aggregate_usages`). Recording a usage that makes the
+//! spend cross one of a budget's thresholds yields a `BudgetAlert`; a usage
+//! that would take a `HardLimit` budget over its limit is refused with
+//! `BudgetError::LimitExceeded` before anything is written.
+//!
+//! `check_usage` and the write after it are two steps: two usages recorded at
+//! once could both pass a limit only one of them fits under. Writers hold the
+//! user's `UsageLocks` lock across both; the API does. Writers that do not
+//! share the same `UsageLocks`, such as another process on the same store,
+//! are not serialized.
+
+use crate::catalog::Catalog;
+use crate::models::{ServiceId, ServiceUsage, UserId};
+use crate::query::UsageQuery;
+use crate::repository::{Repository, RepositoryError};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// Alert thresholds used when a budget does not set its own.
+pub const DEFAULT_THRESHOLDS: [u8; 3] = [50, 80, 100];
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct BudgetId(pub String);
+impl From<&str> for BudgetId {
+    fn from(s: &str) -> Self {
+        BudgetId(s.to_string())
+    }
+}
+
+/// Calendar periods in UTC; weeks start on Monday.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BillingPeriod {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl BillingPeriod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BillingPeriod::Daily => "daily",
+            BillingPeriod::Weekly => "weekly",
+            BillingPeriod::Monthly => "monthly",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<BillingPeriod> {
+        match s {
+            "daily" => Some(BillingPeriod::Daily),
+            "weekly" => Some(BillingPeriod::Weekly),
+            "monthly" => Some(BillingPeriod::Monthly),
+            _ => None,
+        }
+    }
+
+    /// The period containing `at`: `(start inclusive, end exclusive)`.
+    pub fn bounds(&self, at: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let day = at.date_naive();
+        let (start, end) = match self {
+            BillingPeriod::Daily => (day, day + Duration::days(1)),
+            BillingPeriod::Weekly => {
+                let monday = day - Duration::days(day.weekday().num_days_from_monday() as i64);
+                (monday, monday + Duration::days(7))
+            }
+            BillingPeriod::Monthly => {
+                let first = day.with_day(1).expect("every month has a first day");
+                let next = match first.month() {
+                    12 => NaiveDate::from_ymd_opt(first.year() + 1, 1, 1),
+                    m => NaiveDate::from_ymd_opt(first.year(), m + 1, 1),
+                }
+                .expect("first of the next month");
+                (first, next)
+            }
+        };
+        let midnight = |d: NaiveDate| d.and_hms_opt(0, 0, 0).expect("midnight").and_utc();
+        (midnight(start), midnight(end))
+    }
+}
+
+/// What happens when a usage would take spend over the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Enforcement {
+    /// Only alert; the usage is recorded.
+    #[default]
+    Alert,
+    /// Refuse the usage.
+    HardLimit,
+}
+
+impl Enforcement {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Enforcement::Alert => "alert",
+            Enforcement::HardLimit => "hard_limit",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Enforcement> {
+        match s {
+            "alert" => Some(Enforcement::Alert),
+            "hard_limit" => Some(Enforcement::HardLimit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Budget {
+    pub id: BudgetId,
+    pub user_id: UserId,
+    /// Only usages of this service count; `None` counts every service.
+    pub service_id: Option<ServiceId>,
+    pub period: BillingPeriod,
+    pub limit_cents: u64,
+    pub enforcement: Enforcement,
+    /// Percentages of `limit_cents`, ascending.
+    pub thresholds: Vec<u8>,
+}
+
+impl Budget {
+    pub fn new(id: &str, user_id: &UserId, period: BillingPeriod, limit_cents: u64) -> Self {
+        Budget {
+            id: BudgetId(id.to_string()),
+            user_id: user_id.clone(),
+            service_id: None,
+            period,
+            limit_cents,
+            enforcement: Enforcement::Alert,
+            thresholds: DEFAULT_THRESHOLDS.to_vec(),
+        }
+    }
+
+    pub fn for_service(self, service_id: &ServiceId) -> Self {
+        Budget {
+            service_id: Some(service_id.clone()),
+            ..self
+        }
+    }
+
+    pub fn hard_limit(self) -> Self {
+        Budget {
+            enforcement: Enforcement::HardLimit,
+            ..self
+        }
+    }
+
+    /// Replace the alert thresholds; they are sorted and deduplicated.
+    pub fn with_thresholds(self, thresholds: &[u8]) -> Self {
+        let mut thresholds = thresholds.to_vec();
+        thresholds.sort_unstable();
+        thresholds.dedup();
+        Budget { thresholds, ..self }
+    }
+
+    /// Whether `usage` counts against this budget (its period aside).
+    pub fn covers(&self, usage: &ServiceUsage) -> bool {
+        usage.user_id == self.user_id
+            && self
+                .service_id
+                .as_ref()
+                .is_none_or(|s| &usage.service_id == s)
+    }
+
+    /// Usages that count against this budget in the period containing `at`.
+    pub fn query_at(&self, at: DateTime<Utc>) -> UsageQuery {
+        let (from, until) = self.period.bounds(at);
+        let q = UsageQuery::new()
+            .user(&self.user_id)
+            .from(from)
+            .until(until);
+        match &self.service_id {
+            Some(s) => q.service(s),
+            None => q,
+        }
+    }
+
+    /// Thresholds crossed by spend going from `before` to `after`.
+    pub fn crossed(&self, before: u64, after: u64) -> Vec<u8> {
+        let reached =
+            |spent: u64, t: u8| spent as u128 * 100 >= self.limit_cents as u128 * t as u128;
+        self.thresholds
+            .iter()
+            .copied()
+            .filter(|&t| !reached(before, t) && reached(after, t))
+            .collect()
+    }
+}
+
+/// Fired when recorded spend crosses one of a budget's thresholds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BudgetAlert {
+    pub budget_id: BudgetId,
+    pub user_id: UserId,
+    pub threshold_percent: u8,
+    pub spent_cents: u64,
+    pub limit_cents: u64,
+    pub period_start: DateTime<Utc>,
+}
+
+impl fmt::Display for BudgetAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "budget {} of {} reached {}%: {}¢ of {}¢",
+            self.budget_id.0,
+            self.user_id.0,
+            self.threshold_percent,
+            self.spent_cents,
+            self.limit_cents
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum BudgetError {
+    /// The usage would take a hard-limit budget over its limit.
+    LimitExceeded {
+        budget_id: BudgetId,
+        limit_cents: u64,
+        spent_cents: u64,
+        price_cents: u64,
+    },
+    Repository(RepositoryError),
+}
+
+impl fmt::Display for BudgetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BudgetError::LimitExceeded {
+                budget_id,
+                limit_cents,
+                spent_cents,
+                price_cents,
+            } => write!(
+                f,
+                "budget {} exceeded: {}¢ spent + {}¢ is over the {}¢ limit",
+                budget_id.0, spent_cents, price_cents, limit_cents
+            ),
+            BudgetError::Repository(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for BudgetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BudgetError::LimitExceeded { .. } => None,
+            BudgetError::Repository(e) => Some(e),
+        }
+    }
+}
+
+impl From<RepositoryError> for BudgetError {
+    fn from(e: RepositoryError) -> Self {
+        BudgetError::Repository(e)
+    }
+}
+
+/// A budget's spend in the period containing `at`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BudgetStatus {
+    pub budget: Budget,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub spent_cents: u64,
+    /// Spend at the end of the period if it continues at the rate so far.
+    pub projected_cents: u64,
+}
+
+impl BudgetStatus {
+    pub fn new(budget: &Budget, spent_cents: u64, at: DateTime<Utc>) -> Self {
+        let (period_start, period_end) = budget.period.bounds(at);
+        let elapsed = (at - period_start).num_seconds().max(1) as u128;
+        let length = (period_end - period_start).num_seconds() as u128;
+        let projected = (spent_cents as u128 * length / elapsed).max(spent_cents as u128);
+        BudgetStatus {
+            budget: budget.clone(),
+            period_start,
+            period_end,
+            spent_cents,
+            projected_cents: u64::try_from(projected).unwrap_or(u64::MAX),
+        }
+    }
+
+    /// Spend as a percentage of the limit (0 for a zero limit with no spend).
+    pub fn percent_used(&self) -> u64 {
+        match self.budget.limit_cents {
+            0 if self.spent_cents == 0 => 0,
+            0 => u64::MAX,
+            limit => self.spent_cents.saturating_mul(100) / limit,
+        }
+    }
+}
+
+/// Spend of every budget in the period containing `at`.
+pub async fn statuses(
+    repo: &dyn Repository,
+    budgets: &[Budget],
+    at: DateTime<Utc>,
+) -> Result<Vec<BudgetStatus>, RepositoryError> {
+    let mut out = Vec::with_capacity(budgets.len());
+    for b in budgets {
+        let spent = repo.aggregate_usages(&b.query_at(at)).await?.total_cents;
+        out.push(BudgetStatus::new(b, spent, at));
+    }
+    Ok(out)
+}
+
+/// Check `usage` against every budget covering it, before it is saved.
+/// Returns the alerts recording it fires, or `LimitExceeded` for the first
+/// hard-limit budget it would overrun.
+pub async fn check_usage(
+    repo: &dyn Repository,
+    budgets: &[Budget],
+    catalog: &Catalog,
+    usage: &ServiceUsage,
+) -> Result<Vec<BudgetAlert>, BudgetError> {
+    let price = catalog.usage_price(usage).unwrap_or(0);
+    let mut alerts = Vec::new();
+    for b in budgets.iter().filter(|b| b.covers(usage)) {
+        let spent = repo
+            .aggregate_usages(&b.query_at(usage.occurred_at))
+            .await?
+            .total_cents;
+        let after = spent.saturating_add(price);
+        if b.enforcement == Enforcement::HardLimit && after > b.limit_cents {
+            return Err(BudgetError::LimitExceeded {
+                budget_id: b.id.clone(),
+                limit_cents: b.limit_cents,
+                spent_cents: spent,
+                price_cents: price,
+            });
+        }
+        let (period_start, _) = b.period.bounds(usage.occurred_at);
+        alerts.extend(b.crossed(spent, after).into_iter().map(|t| BudgetAlert {
+            budget_id: b.id.clone(),
+            user_id: b.user_id.clone(),
+            threshold_percent: t,
+            spent_cents: after,
+            limit_cents: b.limit_cents,
+            period_start,
+        }));
+    }
+    Ok(alerts)
+}
+
+/// One lock per user around `check_usage` and the write after it. Clones
+/// share the locks.
+#[derive(Debug, Clone, Default)]
+pub struct UsageLocks {
+    users: Arc<Mutex<HashMap<UserId, Arc<AsyncMutex<()>>>>>,
+}
+
+impl UsageLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait for `user_id`'s lock; dropping the guard releases it.
+    pub async fn lock(&self, user_id: &UserId) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+            // forget the locks nobody holds or waits for
+            users.retain(|_, l| Arc::strong_count(l) > 1);
+            users.entry(user_id.clone()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+}
@@ -1,29 +1,335 @@
-use crate::models::{Product, Service, ServiceId, ServiceMap};
+use crate::models::{Product, ProductId, Service, ServiceId, ServiceMap, ServiceUsage};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fmt;
 
+/// Whether one `ProductId` may be offered by more than one service.
+///
+/// Storage keys products by id alone, so `Repository::save_service` refuses
+/// a product another service already offers (`RepositoryError::DuplicateProduct`)
+/// whatever the policy; `Allow` is for reading catalogs stored before that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    #[default]
+    Reject,
+    Allow,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CatalogError {
+    UnknownService(ServiceId),
+    UnknownProduct {
+        service_id: ServiceId,
+        product_id: ProductId,
+    },
+    /// The product is already offered by `existing` (possibly the same service).
+    DuplicateProduct {
+        product_id: ProductId,
+        existing: ServiceId,
+    },
+}
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CatalogError::UnknownService(id) => write!(f, "unknown service {}", id.0),
+            CatalogError::UnknownProduct {
+                service_id,
+                product_id,
+            } => write!(
+                f,
+                "service {} has no product {}",
+                service_id.0, product_id.0
+            ),
+            CatalogError::DuplicateProduct {
+                product_id,
+                existing,
+            } => write!(
+                f,
+                "product {} is already offered by service {}",
+                product_id.0, existing.0
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CatalogError {}
+
+/// Services and their products, plus an index from every `ProductId` to the
+/// services offering it. All changes go through methods so the index never
+/// goes stale; like `UsageLog::add_usage` they return an updated copy and
+/// leave `self` untouched, also when the change is rejected.
 #[derive(Debug, Clone, Default)]
 pub struct Catalog {
-    pub services: ServiceMap,
+    services: ServiceMap,
+    /// Service ids sorted by id, so lookups are deterministic.
+    product_index: HashMap<ProductId, Vec<ServiceId>>,
+    policy: DuplicatePolicy,
 }
 
 impl Catalog {
+    pub fn new(policy: DuplicatePolicy) -> Self {
+        Catalog {
+            policy,
+            ..Catalog::default()
+        }
+    }
+
+    /// Build a catalog from stored services, checking them against `policy`.
+    pub fn from_services(
+        policy: DuplicatePolicy,
+        services: impl IntoIterator<Item = Service>,
+    ) -> Result<Self, CatalogError> {
+        let mut catalog = Catalog::new(policy);
+        for service in services {
+            catalog.insert_service(service)?;
+        }
+        Ok(catalog)
+    }
+
+    /// Insert a service, or replace the one with the same id, without
+    /// checking the duplicate policy: a product offered twice is indexed
+    /// under both services. Use `add_service` to have the policy enforced.
     pub fn with_service(mut self, service: Service) -> Self {
+        if let Some(old) = self.services.remove(&service.id) {
+            self.unindex(&old);
+        }
+        self.index(&service);
         self.services.insert(service.id.clone(), service);
         self
     }
 
-    pub fn list_products_for_service(&self, service_id: &ServiceId) -> Option<Vec<Product>> {
-        self.services.get(service_id).map(|s| s.products.clone())
+    /// Insert a service, or replace the one with the same id (products included).
+    pub fn add_service(&self, service: Service) -> Result<Catalog, CatalogError> {
+        let mut next = self.clone();
+        next.insert_service(service)?;
+        Ok(next)
+    }
+
+    pub fn rename_service(
+        &self,
+        service_id: &ServiceId,
+        name: &str,
+    ) -> Result<Catalog, CatalogError> {
+        let mut next = self.clone();
+        next.service_mut(service_id)?.name = name.to_string();
+        Ok(next)
+    }
+
+    pub fn remove_service(&self, service_id: &ServiceId) -> Result<Catalog, CatalogError> {
+        let mut next = self.clone();
+        let old = next
+            .services
+            .remove(service_id)
+            .ok_or_else(|| CatalogError::UnknownService(service_id.clone()))?;
+        next.unindex(&old);
+        Ok(next)
+    }
+
+    /// Add a new product to an existing service.
+    pub fn add_product(
+        &self,
+        service_id: &ServiceId,
+        product: Product,
+    ) -> Result<Catalog, CatalogError> {
+        if !self.services.contains_key(service_id) {
+            return Err(CatalogError::UnknownService(service_id.clone()));
+        }
+        let offered_here = self.product_services(&product.id).contains(service_id);
+        if offered_here {
+            return Err(CatalogError::DuplicateProduct {
+                product_id: product.id,
+                existing: service_id.clone(),
+            });
+        }
+        self.check_duplicate(&product.id)?;
+        let mut next = self.clone();
+        next.index_one(&product.id, service_id);
+        next.service_mut(service_id)?.products.push(product);
+        Ok(next)
+    }
+
+    /// Replace the product with the same id in `service_id` (name and price).
+    pub fn update_product(
+        &self,
+        service_id: &ServiceId,
+        product: Product,
+    ) -> Result<Catalog, CatalogError> {
+        let mut next = self.clone();
+        let slot = next
+            .service_mut(service_id)?
+            .products
+            .iter_mut()
+            .find(|p| p.id == product.id)
+            .ok_or_else(|| CatalogError::UnknownProduct {
+                service_id: service_id.clone(),
+                product_id: product.id.clone(),
+            })?;
+        *slot = product;
+        Ok(next)
+    }
+
+    /// Schedule (or, with a past `effective_from`, record) a price change.
+    pub fn schedule_price(
+        &self,
+        service_id: &ServiceId,
+        product_id: &ProductId,
+        effective_from: DateTime<Utc>,
+        price_cents: u64,
+    ) -> Result<Catalog, CatalogError> {
+        let product = self
+            .get_product(service_id, product_id)
+            .ok_or_else(|| CatalogError::UnknownProduct {
+                service_id: service_id.clone(),
+                product_id: product_id.clone(),
+            })?
+            .clone()
+            .with_price_change(effective_from, price_cents);
+        self.update_product(service_id, product)
+    }
+
+    pub fn remove_product(
+        &self,
+        service_id: &ServiceId,
+        product_id: &ProductId,
+    ) -> Result<Catalog, CatalogError> {
+        let mut next = self.clone();
+        let products = &mut next.service_mut(service_id)?.products;
+        let before = products.len();
+        products.retain(|p| &p.id != product_id);
+        if products.len() == before {
+            return Err(CatalogError::UnknownProduct {
+                service_id: service_id.clone(),
+                product_id: product_id.clone(),
+            });
+        }
+        next.unindex_one(product_id, service_id);
+        Ok(next)
+    }
+
+    pub fn policy(&self) -> DuplicatePolicy {
+        self.policy
+    }
+
+    pub fn services(&self) -> &ServiceMap {
+        &self.services
+    }
+
+    pub fn list_products_for_service(&self, service_id: &ServiceId) -> Option<&[Product]> {
+        self.services.get(service_id).map(|s| s.products.as_slice())
+    }
+
+    /// Every product of every service, borrowed; services in no particular
+    /// order.
+    pub fn list_all_products(&self) -> impl Iterator<Item = &Product> + '_ {
+        self.services.values().flat_map(|s| s.products.iter())
+    }
+
+    pub fn get_service(&self, service_id: &ServiceId) -> Option<&Service> {
+        self.services.get(service_id)
     }
 
-    pub fn list_all_products(&self) -> Vec<Product> {
+    /// `product_id` as offered by `service_id`.
+    pub fn get_product(&self, service_id: &ServiceId, product_id: &ProductId) -> Option<&Product> {
         self.services
-            .values()
-            .flat_map(|s| s.products.clone())
-            .collect()
+            .get(service_id)?
+            .products
+            .iter()
+            .find(|p| &p.id == product_id)
+    }
+
+    /// What `usage` costs: its product's price when it occurred.
+    pub fn usage_price(&self, usage: &ServiceUsage) -> Option<u64> {
+        self.get_product(&usage.service_id, &usage.product_id)
+            .map(|p| p.price_at(usage.occurred_at))
+    }
+
+    /// Services offering `product_id`, sorted by id; empty if none does.
+    pub fn product_services(&self, product_id: &ProductId) -> &[ServiceId] {
+        self.product_index
+            .get(product_id)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
+    /// Look a product up without knowing its service. Under
+    /// `DuplicatePolicy::Allow` the service with the smallest id wins; use
+    /// `product_services` to see all of them.
+    pub fn find_product(&self, product_id: &ProductId) -> Option<(&Service, &Product)> {
+        let service = self
+            .services
+            .get(self.product_services(product_id).first()?)?;
+        let product = service.products.iter().find(|p| &p.id == product_id)?;
+        Some((service, product))
+    }
+
+    fn insert_service(&mut self, service: Service) -> Result<(), CatalogError> {
+        // check against the catalog without the service being replaced
+        let old = self.services.remove(&service.id);
+        if let Some(old) = &old {
+            self.unindex(old);
+        }
+        let checked = service.products.iter().enumerate().try_for_each(|(i, p)| {
+            if service.products[..i].iter().any(|q| q.id == p.id) {
+                return Err(CatalogError::DuplicateProduct {
+                    product_id: p.id.clone(),
+                    existing: service.id.clone(),
+                });
+            }
+            self.check_duplicate(&p.id)
+        });
+        if let Err(e) = checked {
+            if let Some(old) = old {
+                self.index(&old);
+                self.services.insert(old.id.clone(), old);
+            }
+            return Err(e);
+        }
+        self.index(&service);
+        self.services.insert(service.id.clone(), service);
+        Ok(())
+    }
+
+    fn service_mut(&mut self, service_id: &ServiceId) -> Result<&mut Service, CatalogError> {
+        self.services
+            .get_mut(service_id)
+            .ok_or_else(|| CatalogError::UnknownService(service_id.clone()))
+    }
+
+    fn check_duplicate(&self, product_id: &ProductId) -> Result<(), CatalogError> {
+        match (self.policy, self.product_services(product_id).first()) {
+            (DuplicatePolicy::Reject, Some(existing)) => Err(CatalogError::DuplicateProduct {
+                product_id: product_id.clone(),
+                existing: existing.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn index(&mut self, service: &Service) {
+        for p in &service.products {
+            self.index_one(&p.id, &service.id);
+        }
+    }
+
+    fn unindex(&mut self, service: &Service) {
+        for p in &service.products {
+            self.unindex_one(&p.id, &service.id);
+        }
+    }
+
+    fn index_one(&mut self, product_id: &ProductId, service_id: &ServiceId) {
+        let ids = self.product_index.entry(product_id.clone()).or_default();
+        let at = ids.partition_point(|s| s.0 < service_id.0);
+        ids.insert(at, service_id.clone());
     }
 
-    #[allow(dead_code)]
-    pub fn get_service(&self, service_id: &ServiceId) -> Option<Service> {
-        self.services.get(service_id).cloned()
+    fn unindex_one(&mut self, product_id: &ProductId, service_id: &ServiceId) {
+        if let Some(ids) = self.product_index.get_mut(product_id) {
+            ids.retain(|s| s != service_id);
+            if ids.is_empty() {
+                self.product_index.remove(product_id);
+            }
+        }
     }
 }
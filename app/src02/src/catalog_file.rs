@@ -0,0 +1,825 @@
+//! Catalog definitions in TOML, JSON or YAML files.
+//!
+//! ```toml
+//! [[services]]
+//! id = "s-1"
+//! name = "SaaS Platform"
+//!
+//! [[services.products]]
+//! id = "p-1"
+//! name = "Email Support"
+//! price_cents = 500
+//! ```
+//!
+//! A service may restrict the payment kinds it accepts (see `policy`):
+//!
+//! ```toml
+//! accepts = ["bank_transfer"]
+//! ```
+//!
+//! A product may list effective-dated prices; `price_cents` applies before
+//! the first of them:
+//!
+//! ```toml
+//! prices = [{ from = "2026-01-01", price_cents = 600 }]
+//! ```
+//!
+//! JSON and YAML files have the same shape. Syntax and type errors point at a
+//! line and column; schema problems (duplicate ids, empty names, ...) name the
+//! field path, e.g. `services[1].products[0].id`, and are all reported at once.
+//!
+//! `diff` compares a loaded catalog with a stored one and `sync` writes the
+//! difference through `Repository::save_service`. Sync never deletes: stored
+//! services and products missing from the file are listed and kept. Nor does
+//! it move a product to another service while stored usages still name its
+//! old one; they would lose their price.
+
+use crate::catalog::{Catalog, DuplicatePolicy};
+use crate::models::{Product, ProductId, Service, ServiceId};
+use crate::payment::PaymentKind;
+use crate::query::UsageQuery;
+use crate::repository::{Repository, RepositoryError};
+use crate::validation;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl FileFormat {
+    /// Format by file extension: `.toml`, `.json`, `.yaml` or `.yml`.
+    pub fn from_path(path: &Path) -> Option<FileFormat> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "toml" => Some(FileFormat::Toml),
+            "json" => Some(FileFormat::Json),
+            "yaml" | "yml" => Some(FileFormat::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// One thing wrong with a catalog file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// Field path such as `services[0].products[1].price_cents`; empty for the document itself.
+    pub path: String,
+    /// 1-based position, known for syntax and type errors.
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, "{}:{}: ", line, column)?;
+        }
+        if !self.path.is_empty() {
+            write!(f, "{}: ", self.path)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Debug)]
+pub enum CatalogFileError {
+    Io {
+        file: PathBuf,
+        source: io::Error,
+    },
+    UnknownFormat(PathBuf),
+    Invalid {
+        file: Option<PathBuf>,
+        problems: Vec<Problem>,
+    },
+    Repository(RepositoryError),
+}
+
+impl fmt::Display for CatalogFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CatalogFileError::Io { file, source } => {
+                write!(f, "cannot read {}: {}", file.display(), source)
+            }
+            CatalogFileError::UnknownFormat(file) => write!(
+                f,
+                "{}: expected a .toml, .json, .yaml or .yml file",
+                file.display()
+            ),
+            CatalogFileError::Invalid { file, problems } => {
+                let name = file
+                    .as_ref()
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_else(|| "catalog".to_string());
+                let lines: Vec<String> = problems
+                    .iter()
+                    .map(|p| match p.line {
+                        Some(_) => format!("{}:{}", name, p),
+                        None => format!("{}: {}", name, p),
+                    })
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            CatalogFileError::Repository(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CatalogFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CatalogFileError::Io { source, .. } => Some(source),
+            CatalogFileError::Repository(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<RepositoryError> for CatalogFileError {
+    fn from(e: RepositoryError) -> Self {
+        CatalogFileError::Repository(e)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CatalogDoc {
+    services: Vec<ServiceDoc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServiceDoc {
+    id: String,
+    name: String,
+    /// Payment kinds the service accepts; all of them if omitted.
+    #[serde(default)]
+    accepts: Vec<PaymentKind>,
+    #[serde(default)]
+    products: Vec<ProductDoc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProductDoc {
+    id: String,
+    name: String,
+    price_cents: u64,
+    #[serde(default)]
+    prices: Vec<PriceDoc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PriceDoc {
+    /// RFC 3339, or YYYY-MM-DD for midnight UTC.
+    from: String,
+    price_cents: u64,
+}
+
+fn parse_from(s: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.to_utc())
+        .or_else(|_| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .map(|d| d.and_hms_opt(0, 0, 0).expect("midnight").and_utc())
+        })
+        .ok()
+}
+
+/// Read and check the catalog file at `path`; the format follows the extension.
+pub fn load(path: impl AsRef<Path>) -> Result<Catalog, CatalogFileError> {
+    let path = path.as_ref();
+    let format = FileFormat::from_path(path)
+        .ok_or_else(|| CatalogFileError::UnknownFormat(path.to_path_buf()))?;
+    let text = std::fs::read_to_string(path).map_err(|source| CatalogFileError::Io {
+        file: path.to_path_buf(),
+        source,
+    })?;
+    parse(&text, format).map_err(|e| match e {
+        CatalogFileError::Invalid { problems, .. } => CatalogFileError::Invalid {
+            file: Some(path.to_path_buf()),
+            problems,
+        },
+        other => other,
+    })
+}
+
+/// Parse and check a catalog document. The result uses `DuplicatePolicy::Reject`,
+/// like the SQLite schema.
+pub fn parse(text: &str, format: FileFormat) -> Result<Catalog, CatalogFileError> {
+    let doc = deserialize(text, format).map_err(|p| CatalogFileError::Invalid {
+        file: None,
+        problems: vec![p],
+    })?;
+    let problems = check(&doc);
+    if !problems.is_empty() {
+        return Err(CatalogFileError::Invalid {
+            file: None,
+            problems,
+        });
+    }
+    let services = doc.services.into_iter().map(|s| {
+        Service {
+            id: ServiceId(s.id),
+            name: s.name,
+            accepted_payments: Vec::new(),
+            products: s
+                .products
+                .into_iter()
+                .map(|p| {
+                    let product = Product {
+                        id: ProductId(p.id),
+                        name: p.name,
+                        price_cents: p.price_cents,
+                        price_changes: Vec::new(),
+                    };
+                    p.prices.iter().fold(product, |product, c| {
+                        let from = parse_from(&c.from).expect("check parses every date");
+                        product.with_price_change(from, c.price_cents)
+                    })
+                })
+                .collect(),
+        }
+        .accepting(&s.accepts)
+    });
+    Ok(Catalog::from_services(DuplicatePolicy::Reject, services)
+        .expect("check rejects duplicate product ids"))
+}
+
+fn deserialize(text: &str, format: FileFormat) -> Result<CatalogDoc, Problem> {
+    fn path_of(path: &serde_path_to_error::Path) -> String {
+        match path.iter().next() {
+            None => String::new(),
+            Some(_) => path.to_string(),
+        }
+    }
+    match format {
+        FileFormat::Toml => serde_path_to_error::deserialize(toml::Deserializer::new(text))
+            .map_err(|e| {
+                let path = path_of(e.path());
+                let inner = e.into_inner();
+                let (line, column) = inner
+                    .span()
+                    .map(|span| line_column(text, span.start))
+                    .unzip();
+                Problem {
+                    path,
+                    line,
+                    column,
+                    message: inner.message().trim_end().to_string(),
+                }
+            }),
+        FileFormat::Json => {
+            let mut de = serde_json::Deserializer::from_str(text);
+            serde_path_to_error::deserialize(&mut de).map_err(|e| {
+                let path = path_of(e.path());
+                let inner = e.into_inner();
+                let (line, column) = (inner.line(), inner.column());
+                Problem {
+                    path,
+                    line: Some(line),
+                    column: Some(column),
+                    message: strip_location(&inner.to_string(), line, column),
+                }
+            })
+        }
+        FileFormat::Yaml => {
+            serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(text)).map_err(
+                |e| {
+                    let path = path_of(e.path());
+                    let inner = e.into_inner();
+                    let location = inner.location();
+                    let mut message = inner.to_string();
+                    // serde_yaml puts its own path in front of some messages
+                    if let Some(rest) = message.strip_prefix(&format!("{}: ", path)) {
+                        message = rest.to_string();
+                    }
+                    if let Some(l) = &location {
+                        message = strip_location(&message, l.line(), l.column());
+                    }
+                    Problem {
+                        path,
+                        line: location.as_ref().map(|l| l.line()),
+                        column: location.as_ref().map(|l| l.column()),
+                        message,
+                    }
+                },
+            )
+        }
+    }
+}
+
+fn strip_location(message: &str, line: usize, column: usize) -> String {
+    let suffix = format!(" at line {} column {}", line, column);
+    message.strip_suffix(&suffix).unwrap_or(message).to_string()
+}
+
+/// 1-based line and column of byte `offset` in `text`.
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    (line, column)
+}
+
+/// Schema rules serde cannot express; every violation, in document order.
+fn check(doc: &CatalogDoc) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut report = |path: String, message: String| {
+        problems.push(Problem {
+            path,
+            line: None,
+            column: None,
+            message,
+        })
+    };
+    let mut services: HashMap<&str, String> = HashMap::new();
+    let mut products: HashMap<&str, String> = HashMap::new();
+    for (i, s) in doc.services.iter().enumerate() {
+        let at = format!("services[{}]", i);
+        if let Some(m) = id_problem(&s.id) {
+            report(format!("{}.id", at), m);
+        } else if let Some(first) = services.get(s.id.as_str()) {
+            report(
+                format!("{}.id", at),
+                format!("service {} is already defined at {}", s.id, first),
+            );
+        } else {
+            services.insert(&s.id, at.clone());
+        }
+        if s.name.trim().is_empty() {
+            report(format!("{}.name", at), "must not be empty".to_string());
+        }
+        for (j, p) in s.products.iter().enumerate() {
+            let at = format!("{}.products[{}]", at, j);
+            if let Some(m) = id_problem(&p.id) {
+                report(format!("{}.id", at), m);
+            } else if let Some(first) = products.get(p.id.as_str()) {
+                // products are keyed by id alone, also across services
+                report(
+                    format!("{}.id", at),
+                    format!("product {} is already defined at {}", p.id, first),
+                );
+            } else {
+                products.insert(&p.id, at.clone());
+            }
+            if p.name.trim().is_empty() {
+                report(format!("{}.name", at), "must not be empty".to_string());
+            }
+            // stored as a signed 64-bit integer
+            if p.price_cents > i64::MAX as u64 {
+                report(
+                    format!("{}.price_cents", at),
+                    format!("must be at most {}", i64::MAX),
+                );
+            }
+            let mut dates: HashMap<DateTime<Utc>, String> = HashMap::new();
+            for (k, c) in p.prices.iter().enumerate() {
+                let at = format!("{}.prices[{}]", at, k);
+                match parse_from(&c.from) {
+                    None => report(
+                        format!("{}.from", at),
+                        format!("expected RFC 3339 or YYYY-MM-DD, got {:?}", c.from),
+                    ),
+                    Some(from) => match dates.get(&from) {
+                        Some(first) => report(
+                            format!("{}.from", at),
+                            format!("a price from {} is already defined at {}", c.from, first),
+                        ),
+                        None => {
+                            dates.insert(from, at.clone());
+                        }
+                    },
+                }
+                if c.price_cents > i64::MAX as u64 {
+                    report(
+                        format!("{}.price_cents", at),
+                        format!("must be at most {}", i64::MAX),
+                    );
+                }
+            }
+        }
+    }
+    problems
+}
+
+fn id_problem(id: &str) -> Option<String> {
+    if id.is_empty() {
+        Some("must not be empty".to_string())
+    } else if id.chars().any(char::is_whitespace) {
+        Some(format!("{:?} must not contain whitespace", id))
+    } else {
+        None
+    }
+}
+
+/// One difference between a stored catalog and a catalog file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum CatalogChange {
+    AddService {
+        service_id: ServiceId,
+        name: String,
+    },
+    RenameService {
+        service_id: ServiceId,
+        from: String,
+        to: String,
+    },
+    /// The payment kinds the service accepts change; empty is every kind.
+    SetAcceptedPayments {
+        service_id: ServiceId,
+        from: Vec<PaymentKind>,
+        to: Vec<PaymentKind>,
+    },
+    AddProduct {
+        service_id: ServiceId,
+        product: Product,
+    },
+    UpdateProduct {
+        service_id: ServiceId,
+        before: Product,
+        after: Product,
+    },
+    /// Stored under `from`, listed under `service_id` in the file.
+    MoveProduct {
+        service_id: ServiceId,
+        from: ServiceId,
+        before: Product,
+        after: Product,
+    },
+    /// Stored but missing from the file; sync keeps it. `product_id` is
+    /// `None` for a whole service.
+    Unlisted {
+        service_id: ServiceId,
+        product_id: Option<ProductId>,
+    },
+}
+
+impl CatalogChange {
+    /// Whether sync writes anything for this change.
+    pub fn is_write(&self) -> bool {
+        !matches!(self, CatalogChange::Unlisted { .. })
+    }
+}
+
+impl fmt::Display for CatalogChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CatalogChange::AddService { service_id, name } => {
+                write!(f, "+ service {} {:?}", service_id.0, name)
+            }
+            CatalogChange::RenameService {
+                service_id,
+                from,
+                to,
+            } => write!(f, "~ service {} name {:?} -> {:?}", service_id.0, from, to),
+            CatalogChange::SetAcceptedPayments {
+                service_id,
+                from,
+                to,
+            } => write!(
+                f,
+                "~ service {} accepts {} -> {}",
+                service_id.0,
+                kinds(from),
+                kinds(to)
+            ),
+            CatalogChange::AddProduct {
+                service_id,
+                product,
+            } => write!(
+                f,
+                "+ product {}/{} {:?} {}¢",
+                service_id.0, product.id.0, product.name, product.price_cents
+            ),
+            CatalogChange::UpdateProduct {
+                service_id,
+                before,
+                after,
+            } => write!(
+                f,
+                "~ product {}/{} {}",
+                service_id.0,
+                after.id.0,
+                product_changes(before, after)
+            ),
+            CatalogChange::MoveProduct {
+                service_id,
+                from,
+                before,
+                after,
+            } => {
+                write!(
+                    f,
+                    "> product {} from service {} to {}",
+                    after.id.0, from.0, service_id.0
+                )?;
+                match product_changes(before, after) {
+                    c if c.is_empty() => Ok(()),
+                    c => write!(f, ", {}", c),
+                }
+            }
+            CatalogChange::Unlisted {
+                service_id,
+                product_id: None,
+            } => write!(f, "= service {} is not in the file, kept", service_id.0),
+            CatalogChange::Unlisted {
+                service_id,
+                product_id: Some(p),
+            } => write!(
+                f,
+                "= product {}/{} is not in the file, kept",
+                service_id.0, p.0
+            ),
+        }
+    }
+}
+
+fn kinds(kinds: &[PaymentKind]) -> String {
+    match kinds {
+        [] => "any".to_string(),
+        kinds => kinds
+            .iter()
+            .map(|k| k.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+}
+
+fn product_changes(before: &Product, after: &Product) -> String {
+    let mut parts = Vec::new();
+    if before.name != after.name {
+        parts.push(format!("name {:?} -> {:?}", before.name, after.name));
+    }
+    if before.price_cents != after.price_cents {
+        parts.push(format!(
+            "price {}¢ -> {}¢",
+            before.price_cents, after.price_cents
+        ));
+    }
+    for c in &after.price_changes {
+        let old = before
+            .price_changes
+            .iter()
+            .find(|o| o.effective_from == c.effective_from);
+        match old {
+            Some(o) if o.price_cents == c.price_cents => {}
+            Some(o) => parts.push(format!(
+                "price from {} {}¢ -> {}¢",
+                c.effective_from.to_rfc3339(),
+                o.price_cents,
+                c.price_cents
+            )),
+            None => parts.push(format!(
+                "+ price from {} {}¢",
+                c.effective_from.to_rfc3339(),
+                c.price_cents
+            )),
+        }
+    }
+    for o in &before.price_changes {
+        if !after
+            .price_changes
+            .iter()
+            .any(|c| c.effective_from == o.effective_from)
+        {
+            parts.push(format!("- price from {}", o.effective_from.to_rfc3339()));
+        }
+    }
+    parts.join(", ")
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CatalogDiff {
+    /// By service id, products in file order.
+    pub changes: Vec<CatalogChange>,
+    /// The services `sync` saves, whole: the file's products plus the stored
+    /// ones it does not list.
+    pub writes: Vec<Service>,
+}
+
+impl CatalogDiff {
+    /// True if sync would not write anything.
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+/// What syncing `desired` into a store holding `stored` would change.
+pub fn diff(stored: &Catalog, desired: &Catalog) -> CatalogDiff {
+    // products the file lists under another service than the stored one
+    let moved: HashSet<(&ServiceId, &ProductId)> = desired
+        .services()
+        .values()
+        .flat_map(|d| d.products.iter().map(move |p| (&d.id, &p.id)))
+        .filter(|(sid, pid)| stored.get_product(sid, pid).is_none())
+        .filter_map(|(_, pid)| Some((stored.product_services(pid).first()?, pid)))
+        .collect();
+
+    let ids: BTreeSet<&ServiceId> = stored
+        .services()
+        .keys()
+        .chain(desired.services().keys())
+        .collect();
+    let mut diff = CatalogDiff::default();
+    let mut dirty: BTreeSet<&ServiceId> = BTreeSet::new();
+    for id in ids {
+        let old = stored.services().get(id);
+        let Some(new) = desired.services().get(id) else {
+            diff.changes.push(CatalogChange::Unlisted {
+                service_id: id.clone(),
+                product_id: None,
+            });
+            if moved.iter().any(|(from, _)| *from == id) {
+                dirty.insert(id);
+            }
+            continue;
+        };
+        let before = diff.changes.len();
+        match old {
+            None => diff.changes.push(CatalogChange::AddService {
+                service_id: id.clone(),
+                name: new.name.clone(),
+            }),
+            Some(old) if old.name != new.name => diff.changes.push(CatalogChange::RenameService {
+                service_id: id.clone(),
+                from: old.name.clone(),
+                to: new.name.clone(),
+            }),
+            Some(_) => {}
+        }
+        let accepted = |s: &Service| -> BTreeSet<&str> {
+            s.accepted_payments.iter().map(|k| k.as_str()).collect()
+        };
+        if let Some(old) = old.filter(|old| accepted(old) != accepted(new)) {
+            diff.changes.push(CatalogChange::SetAcceptedPayments {
+                service_id: id.clone(),
+                from: old.accepted_payments.clone(),
+                to: new.accepted_payments.clone(),
+            });
+        }
+        for p in &new.products {
+            let change = match stored.get_product(id, &p.id) {
+                Some(current) if current == p => continue,
+                Some(current) => CatalogChange::UpdateProduct {
+                    service_id: id.clone(),
+                    before: current.clone(),
+                    after: p.clone(),
+                },
+                None => match stored.product_services(&p.id).first() {
+                    Some(from) => CatalogChange::MoveProduct {
+                        service_id: id.clone(),
+                        from: from.clone(),
+                        before: stored
+                            .get_product(from, &p.id)
+                            .expect("indexed product")
+                            .clone(),
+                        after: p.clone(),
+                    },
+                    None => CatalogChange::AddProduct {
+                        service_id: id.clone(),
+                        product: p.clone(),
+                    },
+                },
+            };
+            diff.changes.push(change);
+        }
+        if diff.changes.len() > before || moved.iter().any(|(from, _)| *from == id) {
+            dirty.insert(id);
+        }
+        for p in old.map(|s| s.products.as_slice()).unwrap_or_default() {
+            if !new.products.iter().any(|q| q.id == p.id) && !moved.contains(&(id, &p.id)) {
+                diff.changes.push(CatalogChange::Unlisted {
+                    service_id: id.clone(),
+                    product_id: Some(p.id.clone()),
+                });
+            }
+        }
+    }
+
+    diff.writes = dirty
+        .into_iter()
+        .map(|id| {
+            let old = stored.services().get(id);
+            let new = desired.services().get(id);
+            let name = new.or(old).map(|s| s.name.clone()).unwrap_or_default();
+            let accepted_payments = new
+                .or(old)
+                .map(|s| s.accepted_payments.clone())
+                .unwrap_or_default();
+            let mut products = new.map(|s| s.products.clone()).unwrap_or_default();
+            let kept = old
+                .map(|s| s.products.as_slice())
+                .unwrap_or_default()
+                .iter()
+                .filter(|p| !products.iter().any(|q| q.id == p.id))
+                .filter(|p| !moved.contains(&(id, &p.id)))
+                .cloned()
+                .collect::<Vec<_>>();
+            products.extend(kept);
+            Service {
+                id: id.clone(),
+                name,
+                products,
+                accepted_payments,
+            }
+        })
+        .collect();
+    diff
+}
+
+/// Compare `desired` with the catalog stored in `repo` and, unless `dry_run`,
+/// save every service that changes, all in one `save_services` call: a sync
+/// that fails stores nothing.
+///
+/// A `MoveProduct` whose old service and product stored usages still name
+/// fails the whole sync, dry run included, with one `Problem` per move.
+pub async fn sync(
+    repo: &dyn Repository,
+    desired: &Catalog,
+    dry_run: bool,
+) -> Result<CatalogDiff, CatalogFileError> {
+    let stored = validation::load_catalog(repo).await?;
+    let diff = diff(&stored, desired);
+    let mut problems = Vec::new();
+    for change in &diff.changes {
+        let CatalogChange::MoveProduct {
+            service_id,
+            from,
+            after,
+            ..
+        } = change
+        else {
+            continue;
+        };
+        let query = UsageQuery::new().service(from).product(&after.id);
+        let used = repo.aggregate_usages(&query).await?.count;
+        if used > 0 {
+            problems.push(Problem {
+                path: String::new(),
+                line: None,
+                column: None,
+                message: format!(
+                    "product {} cannot move from service {} to {}: {} stored usages use it",
+                    after.id.0, from.0, service_id.0, used
+                ),
+            });
+        }
+    }
+    if !problems.is_empty() {
+        return Err(CatalogFileError::Invalid {
+            file: None,
+            problems,
+        });
+    }
+    if dry_run {
+        return Ok(diff);
+    }
+    // a product may only be stored under one service: first save the
+    // services products move out of without those they gain, so that moves
+    // (swaps included) never meet the product still under its old service
+    let moved_in: HashSet<(&ServiceId, &ProductId)> = diff
+        .changes
+        .iter()
+        .filter_map(|c| match c {
+            CatalogChange::MoveProduct {
+                service_id, after, ..
+            } => Some((service_id, &after.id)),
+            _ => None,
+        })
+        .collect();
+    let sources: HashSet<&ServiceId> = diff
+        .changes
+        .iter()
+        .filter_map(|c| match c {
+            CatalogChange::MoveProduct { from, .. } => Some(from),
+            _ => None,
+        })
+        .collect();
+    let detached = diff
+        .writes
+        .iter()
+        .filter(|s| sources.contains(&s.id))
+        .map(|service| Service {
+            products: service
+                .products
+                .iter()
+                .filter(|p| !moved_in.contains(&(&service.id, &p.id)))
+                .cloned()
+                .collect(),
+            ..service.clone()
+        });
+    let writes: Vec<Service> = detached.chain(diff.writes.iter().cloned()).collect();
+    repo.save_services(&writes).await?;
+    Ok(diff)
+}
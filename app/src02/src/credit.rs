@@ -0,0 +1,441 @@
+//! Correcting billed usages: credit notes, refunds and credit balances.
+//!
+//! A `CreditNote` reverses all or part of one stored usage, priced like
+//! every aggregate at the catalog price in effect when it occurred. It is
+//! settled either as a refund to the method the usage was paid with
+//! (`Settlement::Refund`), or as credit for whoever the usage is billed to
+//! (`Settlement::Credit`): its account, otherwise its user. The notes of one
+//! usage never add up to more than its price; `Repository::issue_credit_note`
+//! checks that in the transaction that stores the note.
+//!
+//! A payer's credit balance is what its credit notes added minus what
+//! invoices took. `invoice` lists a payer's usages for a period and takes as
+//! much of the balance as the invoice total allows; each payer and period is
+//! charged once (`Repository::apply_credit`), so invoicing the same period
+//! again gives the same total.
+
+use crate::account::AccountId;
+use crate::models::{PaymentMethod, ProductId, ServiceId, ServiceUsage, UserId};
+use crate::policy::{PaymentContext, PaymentPolicy};
+use crate::query::{Cursor, UsageQuery, UsageRecord, MAX_PAGE_SIZE};
+use crate::reporting::ReportPeriod;
+use crate::repository::{Repository, RepositoryError};
+use crate::validation::{load_catalog, load_references};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct CreditNoteId(pub String);
+impl From<&str> for CreditNoteId {
+    fn from(s: &str) -> Self {
+        CreditNoteId(s.to_string())
+    }
+}
+
+impl CreditNoteId {
+    /// A fresh, random id.
+    pub fn generate() -> Self {
+        CreditNoteId(format!("cn-{}", uuid::Uuid::new_v4().simple()))
+    }
+}
+
+/// Whoever a usage is billed to, and so holds the credit of its notes.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "id", rename_all = "snake_case")]
+pub enum Payer {
+    User(UserId),
+    Account(AccountId),
+}
+
+impl Payer {
+    /// The account `usage` is billed to, otherwise its user.
+    pub fn of(usage: &ServiceUsage) -> Payer {
+        match &usage.account_id {
+            Some(account_id) => Payer::Account(account_id.clone()),
+            None => Payer::User(usage.user_id.clone()),
+        }
+    }
+
+    /// `"user"` or `"account"`, as stored in the database.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Payer::User(_) => "user",
+            Payer::Account(_) => "account",
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            Payer::User(id) => &id.0,
+            Payer::Account(id) => &id.0,
+        }
+    }
+
+    pub fn parse(kind: &str, id: &str) -> Option<Payer> {
+        match kind {
+            "user" => Some(Payer::User(UserId(id.to_string()))),
+            "account" => Some(Payer::Account(AccountId(id.to_string()))),
+            _ => None,
+        }
+    }
+
+    /// Whether `usage` is billed to this payer. A user pays only the usages
+    /// not billed to an account.
+    pub fn pays_for(&self, usage: &ServiceUsage) -> bool {
+        Payer::of(usage) == *self
+    }
+}
+
+impl fmt::Display for Payer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.kind(), self.id())
+    }
+}
+
+/// How a credit note is paid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Settlement {
+    /// Money back to `CreditNote::refund_to`.
+    Refund,
+    /// Added to the payer's credit balance for later invoices.
+    Credit,
+}
+
+impl Settlement {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Settlement::Refund => "refund",
+            Settlement::Credit => "credit",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Settlement> {
+        match s {
+            "refund" => Some(Settlement::Refund),
+            "credit" => Some(Settlement::Credit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreditNote {
+    pub id: CreditNoteId,
+    /// The usage reversed, by its `UsageRecord::id`.
+    pub usage_id: i64,
+    pub payer: Payer,
+    /// The usage's price when the note was issued; set by
+    /// `Repository::issue_credit_note` from the stored usage and catalog.
+    pub usage_amount_cents: u64,
+    pub amount_cents: u64,
+    pub reason: String,
+    pub settlement: Settlement,
+    /// Where a refund goes; `None` for credit, and once the user is erased.
+    pub refund_to: Option<PaymentMethod>,
+    pub issued_at: DateTime<Utc>,
+}
+
+/// Outcome of `Repository::issue_credit_note`.
+#[derive(Debug, Clone)]
+pub enum Issued {
+    /// The note was stored.
+    Issued(CreditNote),
+    /// The usage's notes would add up to more than its price; nothing was
+    /// stored. `remaining_cents` may still be credited.
+    Exceeds { remaining_cents: u64 },
+    /// No usage is stored under the note's `usage_id`.
+    UnknownUsage,
+    /// The usage's product is no longer in the catalog, so it has no price.
+    UnknownPrice,
+}
+
+/// A payer's credit balance charged to one invoice period.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CreditApplication {
+    pub payer: Payer,
+    pub period: ReportPeriod,
+    pub amount_cents: u64,
+    pub applied_at: DateTime<Utc>,
+}
+
+/// `payer`'s balance given every note and application: credit notes settled
+/// as credit, minus what was applied.
+pub fn balance(notes: &[CreditNote], applications: &[CreditApplication], payer: &Payer) -> u64 {
+    let credited: u64 = notes
+        .iter()
+        .filter(|n| &n.payer == payer && n.settlement == Settlement::Credit)
+        .map(|n| n.amount_cents)
+        .sum();
+    let applied: u64 = applications
+        .iter()
+        .filter(|a| &a.payer == payer)
+        .map(|a| a.amount_cents)
+        .sum();
+    credited.saturating_sub(applied)
+}
+
+/// How much of a usage priced at `usage_amount_cents` is not yet covered by
+/// the notes in `notes` for `usage_id`.
+pub fn remaining(notes: &[CreditNote], usage_id: i64, usage_amount_cents: u64) -> u64 {
+    let credited: u64 = notes
+        .iter()
+        .filter(|n| n.usage_id == usage_id)
+        .map(|n| n.amount_cents)
+        .sum();
+    usage_amount_cents.saturating_sub(credited)
+}
+
+#[derive(Debug)]
+pub enum CreditError {
+    UnknownUsage {
+        usage_id: i64,
+    },
+    /// The usage's product is no longer in the catalog, so it has no price.
+    UnknownPrice {
+        usage_id: i64,
+    },
+    ZeroAmount,
+    /// The usage's notes would add up to more than its price.
+    ExceedsUsage {
+        usage_id: i64,
+        amount_cents: u64,
+        remaining_cents: u64,
+    },
+    /// A refund was asked for, but neither the usage nor its payer has a
+    /// payment method to send it to.
+    NoRefundMethod {
+        usage_id: i64,
+    },
+    Repository(RepositoryError),
+}
+
+impl fmt::Display for CreditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CreditError::UnknownUsage { usage_id } => write!(f, "unknown usage {}", usage_id),
+            CreditError::UnknownPrice { usage_id } => {
+                write!(f, "usage {} has no price in the catalog", usage_id)
+            }
+            CreditError::ZeroAmount => write!(f, "a credit note must be for more than 0¢"),
+            CreditError::ExceedsUsage {
+                usage_id,
+                amount_cents,
+                remaining_cents,
+            } => write!(
+                f,
+                "credit of {}¢ exceeds what is left of usage {} ({}¢)",
+                amount_cents, usage_id, remaining_cents
+            ),
+            CreditError::NoRefundMethod { usage_id } => {
+                write!(f, "no payment method to refund usage {} to", usage_id)
+            }
+            CreditError::Repository(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CreditError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CreditError::Repository(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<RepositoryError> for CreditError {
+    fn from(e: RepositoryError) -> Self {
+        CreditError::Repository(e)
+    }
+}
+
+/// The stored usage with id `usage_id`.
+pub async fn find_usage(
+    repo: &dyn Repository,
+    usage_id: i64,
+) -> Result<Option<UsageRecord>, RepositoryError> {
+    // ids only grow; sled's start at 0
+    let query = UsageQuery::new()
+        .after(Cursor::after_id(usage_id.saturating_sub(1)))
+        .limit(1);
+    Ok(repo
+        .query_usages(&query)
+        .await?
+        .items
+        .into_iter()
+        .find(|r| r.id == usage_id))
+}
+
+/// Issue a credit note for `usage_id`: `amount_cents` of it, or all that no
+/// earlier note covers. A refund goes to the method the usage was paid with,
+/// or, if it names none, to the one the default `PaymentPolicy` resolves for
+/// it now.
+pub async fn issue(
+    repo: &dyn Repository,
+    usage_id: i64,
+    amount_cents: Option<u64>,
+    reason: &str,
+    settlement: Settlement,
+) -> Result<CreditNote, CreditError> {
+    if amount_cents == Some(0) {
+        return Err(CreditError::ZeroAmount);
+    }
+    let Some(record) = find_usage(repo, usage_id).await? else {
+        return Err(CreditError::UnknownUsage { usage_id });
+    };
+    let usage = record.usage;
+    let refs = load_references(repo).await?;
+    let usage_amount_cents = refs
+        .catalog
+        .usage_price(&usage)
+        .ok_or(CreditError::UnknownPrice { usage_id })?;
+    let amount_cents = match amount_cents {
+        Some(amount) => amount,
+        None => match remaining(
+            &repo.get_credit_notes().await?,
+            usage_id,
+            usage_amount_cents,
+        ) {
+            0 => {
+                return Err(CreditError::ExceedsUsage {
+                    usage_id,
+                    amount_cents: 0,
+                    remaining_cents: 0,
+                })
+            }
+            left => left,
+        },
+    };
+    let refund_to = match settlement {
+        Settlement::Credit => None,
+        Settlement::Refund => {
+            let resolved = || {
+                let mut ctx = PaymentContext::new();
+                if let Some(user) = refs.users.get(&usage.user_id) {
+                    ctx = ctx.user(user);
+                }
+                if let Some(service) = refs.catalog.services().get(&usage.service_id) {
+                    ctx = ctx.service(service);
+                }
+                if let Some(account) = usage.account_id.as_ref().and_then(|a| refs.accounts.get(a))
+                {
+                    ctx = ctx.account(account);
+                }
+                PaymentPolicy::default().resolve(&ctx).payment
+            };
+            let pm = usage.payment_used.clone().or_else(resolved);
+            Some(pm.ok_or(CreditError::NoRefundMethod { usage_id })?)
+        }
+    };
+    let note = CreditNote {
+        id: CreditNoteId::generate(),
+        usage_id,
+        payer: Payer::of(&usage),
+        usage_amount_cents,
+        amount_cents,
+        reason: reason.to_string(),
+        settlement,
+        refund_to,
+        issued_at: Utc::now(),
+    };
+    match repo.issue_credit_note(&note).await? {
+        Issued::Issued(note) => Ok(note),
+        Issued::Exceeds { remaining_cents } => Err(CreditError::ExceedsUsage {
+            usage_id,
+            amount_cents,
+            remaining_cents,
+        }),
+        Issued::UnknownUsage => Err(CreditError::UnknownUsage { usage_id }),
+        Issued::UnknownPrice => Err(CreditError::UnknownPrice { usage_id }),
+    }
+}
+
+/// `payer`'s credit balance in `repo`.
+pub async fn credit_balance(repo: &dyn Repository, payer: &Payer) -> Result<u64, RepositoryError> {
+    Ok(balance(
+        &repo.get_credit_notes().await?,
+        &repo.get_credit_applications().await?,
+        payer,
+    ))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InvoiceLine {
+    pub usage_id: i64,
+    pub user_id: UserId,
+    pub service_id: ServiceId,
+    pub product_id: ProductId,
+    pub occurred_at: DateTime<Utc>,
+    /// 0 for a usage whose product is no longer in the catalog.
+    pub amount_cents: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Invoice {
+    pub payer: Payer,
+    pub period: ReportPeriod,
+    /// In usage order.
+    pub lines: Vec<InvoiceLine>,
+    pub subtotal_cents: u64,
+    /// Taken from the payer's credit balance.
+    pub credit_applied_cents: u64,
+    pub total_cents: u64,
+}
+
+/// `payer`'s invoice for `period`, with as much of its credit balance as
+/// the subtotal allows taken off. The balance is charged the first time a
+/// period is invoiced; later calls show the same amount.
+pub async fn invoice(
+    repo: &dyn Repository,
+    payer: &Payer,
+    period: ReportPeriod,
+) -> Result<Invoice, RepositoryError> {
+    let catalog = load_catalog(repo).await?;
+    let mut query = match payer {
+        Payer::User(user_id) => period.query().user(user_id),
+        Payer::Account(account_id) => period.query().account(account_id),
+    }
+    .limit(MAX_PAGE_SIZE);
+    let mut lines = Vec::new();
+    loop {
+        let page = repo.query_usages(&query).await?;
+        for record in page.items {
+            if !payer.pays_for(&record.usage) {
+                continue;
+            }
+            let u = &record.usage;
+            lines.push(InvoiceLine {
+                usage_id: record.id,
+                user_id: u.user_id.clone(),
+                service_id: u.service_id.clone(),
+                product_id: u.product_id.clone(),
+                occurred_at: u.occurred_at,
+                amount_cents: catalog.usage_price(u).unwrap_or(0),
+            });
+        }
+        match page.next_cursor {
+            Some(c) => query = query.after(c),
+            None => break,
+        }
+    }
+    let subtotal_cents = lines.iter().map(|l| l.amount_cents).sum();
+    let applied = repo
+        .apply_credit(&CreditApplication {
+            payer: payer.clone(),
+            period,
+            amount_cents: subtotal_cents,
+            applied_at: Utc::now(),
+        })
+        .await?;
+    let credit_applied_cents = applied.amount_cents.min(subtotal_cents);
+    Ok(Invoice {
+        payer: payer.clone(),
+        period,
+        lines,
+        subtotal_cents,
+        credit_applied_cents,
+        total_cents: subtotal_cents - credit_applied_cents,
+    })
+}
@@ -0,0 +1,557 @@
+//! Dunning: chasing a charge that failed.
+//!
+//! `open` records a failed charge as a `DunningCase`, which is retried on its
+//! `DunningSchedule`; each delay counts from the attempt before. When the
+//! last retry fails the case is suspended, and the user may not use the
+//! service until it is paid (`check_access`); paying reinstates them. Every
+//! step yields `DunningEvent`s to notify the user with, stored together with
+//! the case (`Repository::save_dunning_case`).
+//!
+//! Nothing here reads the system clock: callers pass a `Clock`, and `run`
+//! retries the cases due by it through a `Gateway`.
+
+use crate::clock::Clock;
+use crate::models::{PaymentMethod, ServiceId, ServiceUsage, UserId};
+use crate::repository::{Repository, RepositoryError};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Hours before each retry when a case is opened without a schedule: a day,
+/// three days, then a week after the attempt before.
+pub const DEFAULT_RETRY_HOURS: [u32; 3] = [24, 72, 168];
+
+/// Minutes a case claimed by `run` is not due, so that no other run charges
+/// it meanwhile; a run that never records the charge leaves it due again after.
+pub const CLAIM_MINUTES: u32 = 15;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct DunningCaseId(pub String);
+impl From<&str> for DunningCaseId {
+    fn from(s: &str) -> Self {
+        DunningCaseId(s.to_string())
+    }
+}
+
+impl DunningCaseId {
+    /// A fresh, random id.
+    pub fn generate() -> Self {
+        DunningCaseId(format!("dc-{}", uuid::Uuid::new_v4().simple()))
+    }
+}
+
+/// When a failed charge is retried.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DunningSchedule {
+    /// Hours before each retry, counted from the attempt before it. Empty
+    /// suspends at the first failure.
+    pub retry_after_hours: Vec<u32>,
+}
+
+impl Default for DunningSchedule {
+    fn default() -> Self {
+        DunningSchedule::new(&DEFAULT_RETRY_HOURS)
+    }
+}
+
+impl DunningSchedule {
+    pub fn new(retry_after_hours: &[u32]) -> Self {
+        DunningSchedule {
+            retry_after_hours: retry_after_hours.to_vec(),
+        }
+    }
+
+    /// How long to wait after `attempts` failed charges; `None` once the
+    /// retries are used up.
+    pub fn delay_after(&self, attempts: u32) -> Option<Duration> {
+        let index = usize::try_from(attempts).ok()?.checked_sub(1)?;
+        let hours = *self.retry_after_hours.get(index)?;
+        Some(Duration::hours(hours.into()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DunningState {
+    /// Waiting for the next retry.
+    Retrying,
+    /// The retries failed; the user may not use the service.
+    Suspended,
+    /// Paid.
+    Settled,
+}
+
+impl DunningState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DunningState::Retrying => "retrying",
+            DunningState::Suspended => "suspended",
+            DunningState::Settled => "settled",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<DunningState> {
+        match s {
+            "retrying" => Some(DunningState::Retrying),
+            "suspended" => Some(DunningState::Suspended),
+            "settled" => Some(DunningState::Settled),
+            _ => None,
+        }
+    }
+}
+
+/// What a `DunningEvent` tells the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// The original charge failed.
+    ChargeFailed,
+    /// A retry failed.
+    RetryFailed,
+    /// No retries are left; access to the service is suspended.
+    Suspended,
+    /// A retry succeeded before suspension.
+    Recovered,
+    /// A suspended case was paid; access is restored.
+    Reinstated,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::ChargeFailed => "charge_failed",
+            Stage::RetryFailed => "retry_failed",
+            Stage::Suspended => "suspended",
+            Stage::Recovered => "recovered",
+            Stage::Reinstated => "reinstated",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Stage> {
+        match s {
+            "charge_failed" => Some(Stage::ChargeFailed),
+            "retry_failed" => Some(Stage::RetryFailed),
+            "suspended" => Some(Stage::Suspended),
+            "recovered" => Some(Stage::Recovered),
+            "reinstated" => Some(Stage::Reinstated),
+            _ => None,
+        }
+    }
+}
+
+/// One step of a case, to notify the user of.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DunningEvent {
+    pub case_id: DunningCaseId,
+    pub stage: Stage,
+    /// Charges tried so far, the original one included.
+    pub attempt: u32,
+    pub at: DateTime<Utc>,
+    /// When the next retry is due, after a failure that leaves one.
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    /// Why the charge failed, for failures.
+    pub reason: Option<String>,
+}
+
+impl fmt::Display for DunningEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "dunning {} {} at attempt {}",
+            self.case_id.0,
+            self.stage.as_str(),
+            self.attempt
+        )?;
+        if let Some(reason) = &self.reason {
+            write!(f, ": {}", reason)?;
+        }
+        if let Some(next) = self.next_attempt_at {
+            write!(f, ", next retry {}", next.to_rfc3339())?;
+        }
+        Ok(())
+    }
+}
+
+/// A charge that failed, to open a case for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailedCharge {
+    pub user_id: UserId,
+    pub service_id: ServiceId,
+    pub amount_cents: u64,
+    /// The method charged.
+    #[serde(default)]
+    pub payment: Option<PaymentMethod>,
+    pub reason: String,
+}
+
+/// How one retry went.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Attempt {
+    Paid,
+    Failed { reason: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DunningCase {
+    pub id: DunningCaseId,
+    pub user_id: UserId,
+    pub service_id: ServiceId,
+    pub amount_cents: u64,
+    /// The method charged; `None` if unknown, and once the user is erased.
+    pub payment: Option<PaymentMethod>,
+    /// Why the last failed attempt failed.
+    pub reason: String,
+    pub schedule: DunningSchedule,
+    pub state: DunningState,
+    /// Charges tried, the original one included.
+    pub attempts: u32,
+    pub opened_at: DateTime<Utc>,
+    /// When the next retry is due; `None` unless retrying.
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub updated_at: DateTime<Utc>,
+    /// 0 when opened, one more after every change; see
+    /// `Repository::save_dunning_case`.
+    pub revision: u32,
+}
+
+/// A case after a change, with the events the change fired.
+#[derive(Debug, Clone, Serialize)]
+pub struct Transition {
+    pub case: DunningCase,
+    pub events: Vec<DunningEvent>,
+}
+
+impl DunningCase {
+    /// A case for `charge`, which failed at `now`, retried on `schedule`.
+    pub fn open(
+        charge: &FailedCharge,
+        schedule: DunningSchedule,
+        now: DateTime<Utc>,
+    ) -> Transition {
+        let case = DunningCase {
+            id: DunningCaseId::generate(),
+            user_id: charge.user_id.clone(),
+            service_id: charge.service_id.clone(),
+            amount_cents: charge.amount_cents,
+            payment: charge.payment.clone(),
+            reason: String::new(),
+            schedule,
+            state: DunningState::Retrying,
+            attempts: 1,
+            opened_at: now,
+            next_attempt_at: None,
+            updated_at: now,
+            revision: 0,
+        };
+        case.failed(&charge.reason, Stage::ChargeFailed, now)
+    }
+
+    /// The case after `attempt` at `now`. A failure is only recorded while
+    /// retrying; a payment is recorded until the case is settled.
+    pub fn record(
+        &self,
+        attempt: &Attempt,
+        now: DateTime<Utc>,
+    ) -> Result<Transition, DunningError> {
+        let next = DunningCase {
+            attempts: self.attempts + 1,
+            updated_at: now,
+            revision: self.revision + 1,
+            ..self.clone()
+        };
+        match (self.state, attempt) {
+            (DunningState::Settled, _) => Err(DunningError::AlreadySettled {
+                case_id: self.id.clone(),
+            }),
+            (DunningState::Retrying, Attempt::Failed { reason }) => {
+                Ok(next.failed(reason, Stage::RetryFailed, now))
+            }
+            (state, Attempt::Failed { .. }) => Err(DunningError::NotRetrying {
+                case_id: self.id.clone(),
+                state,
+            }),
+            (state, Attempt::Paid) => {
+                let stage = match state {
+                    DunningState::Suspended => Stage::Reinstated,
+                    _ => Stage::Recovered,
+                };
+                let case = DunningCase {
+                    state: DunningState::Settled,
+                    next_attempt_at: None,
+                    ..next
+                };
+                let event = case.event(stage, now, None);
+                Ok(Transition {
+                    case,
+                    events: vec![event],
+                })
+            }
+        }
+    }
+
+    /// The case claimed at `now` by whoever is about to charge it: not due
+    /// again for `CLAIM_MINUTES`. Stored like any change, so only one
+    /// claimant of a revision succeeds.
+    pub fn claim(&self, now: DateTime<Utc>) -> DunningCase {
+        DunningCase {
+            next_attempt_at: Some(now + Duration::minutes(CLAIM_MINUTES.into())),
+            updated_at: now,
+            revision: self.revision + 1,
+            ..self.clone()
+        }
+    }
+
+    /// The case after its latest attempt failed: the next retry scheduled,
+    /// or suspended if none is left.
+    fn failed(self, reason: &str, stage: Stage, now: DateTime<Utc>) -> Transition {
+        let next_attempt_at = self.schedule.delay_after(self.attempts).map(|d| now + d);
+        let case = DunningCase {
+            reason: reason.to_string(),
+            state: match next_attempt_at {
+                Some(_) => DunningState::Retrying,
+                None => DunningState::Suspended,
+            },
+            next_attempt_at,
+            ..self
+        };
+        let mut events = vec![case.event(stage, now, Some(reason))];
+        if case.state == DunningState::Suspended {
+            events.push(case.event(Stage::Suspended, now, None));
+        }
+        Transition { case, events }
+    }
+
+    fn event(&self, stage: Stage, at: DateTime<Utc>, reason: Option<&str>) -> DunningEvent {
+        DunningEvent {
+            case_id: self.id.clone(),
+            stage,
+            attempt: self.attempts,
+            at,
+            next_attempt_at: self.next_attempt_at,
+            reason: reason.map(str::to_string),
+        }
+    }
+
+    /// Whether a retry is due at `now`.
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.state == DunningState::Retrying && self.next_attempt_at.is_some_and(|t| t <= now)
+    }
+
+    /// Whether this case keeps `user_id` from using `service_id`.
+    pub fn suspends(&self, user_id: &UserId, service_id: &ServiceId) -> bool {
+        self.state == DunningState::Suspended
+            && &self.user_id == user_id
+            && &self.service_id == service_id
+    }
+}
+
+#[derive(Debug)]
+pub enum DunningError {
+    UnknownCase {
+        case_id: DunningCaseId,
+    },
+    UnknownUser {
+        user_id: UserId,
+    },
+    UnknownService {
+        service_id: ServiceId,
+    },
+    /// A failed retry for a case that is not waiting for one.
+    NotRetrying {
+        case_id: DunningCaseId,
+        state: DunningState,
+    },
+    AlreadySettled {
+        case_id: DunningCaseId,
+    },
+    /// The case changed since it was read; read it again and retry.
+    Conflict {
+        case_id: DunningCaseId,
+    },
+    /// The user's access to the service is suspended until the case is paid.
+    Suspended {
+        case_id: DunningCaseId,
+        user_id: UserId,
+        service_id: ServiceId,
+    },
+    Repository(RepositoryError),
+}
+
+impl fmt::Display for DunningError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DunningError::UnknownCase { case_id } => {
+                write!(f, "unknown dunning case {}", case_id.0)
+            }
+            DunningError::UnknownUser { user_id } => write!(f, "unknown user {}", user_id.0),
+            DunningError::UnknownService { service_id } => {
+                write!(f, "unknown service {}", service_id.0)
+            }
+            DunningError::NotRetrying { case_id, state } => write!(
+                f,
+                "dunning case {} is {}, not waiting for a retry",
+                case_id.0,
+                state.as_str()
+            ),
+            DunningError::AlreadySettled { case_id } => {
+                write!(f, "dunning case {} is already settled", case_id.0)
+            }
+            DunningError::Conflict { case_id } => {
+                write!(f, "dunning case {} was changed concurrently", case_id.0)
+            }
+            DunningError::Suspended {
+                case_id,
+                user_id,
+                service_id,
+            } => write!(
+                f,
+                "{} is suspended from service {} until dunning case {} is paid",
+                user_id.0, service_id.0, case_id.0
+            ),
+            DunningError::Repository(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DunningError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DunningError::Repository(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<RepositoryError> for DunningError {
+    fn from(e: RepositoryError) -> Self {
+        DunningError::Repository(e)
+    }
+}
+
+/// Charges a case again; the payment provider's side of `run`.
+#[async_trait]
+pub trait Gateway: Send + Sync {
+    /// Try to collect `case.amount_cents` with `case.payment`.
+    async fn charge(&self, case: &DunningCase) -> Attempt;
+}
+
+/// Open a case for `charge`, which failed just now, and store it with its
+/// events.
+pub async fn open(
+    repo: &dyn Repository,
+    clock: &dyn Clock,
+    charge: &FailedCharge,
+    schedule: DunningSchedule,
+) -> Result<Transition, DunningError> {
+    if !repo
+        .get_users()
+        .await?
+        .iter()
+        .any(|u| u.id == charge.user_id)
+    {
+        return Err(DunningError::UnknownUser {
+            user_id: charge.user_id.clone(),
+        });
+    }
+    if !repo
+        .get_services()
+        .await?
+        .iter()
+        .any(|s| s.id == charge.service_id)
+    {
+        return Err(DunningError::UnknownService {
+            service_id: charge.service_id.clone(),
+        });
+    }
+    let opened = DunningCase::open(charge, schedule, clock.now());
+    save(repo, opened).await
+}
+
+/// The case with id `case_id`.
+pub async fn find_case(
+    repo: &dyn Repository,
+    case_id: &DunningCaseId,
+) -> Result<DunningCase, DunningError> {
+    repo.get_dunning_cases()
+        .await?
+        .into_iter()
+        .find(|c| &c.id == case_id)
+        .ok_or_else(|| DunningError::UnknownCase {
+            case_id: case_id.clone(),
+        })
+}
+
+/// Record how a retry of `case_id` went, or that it was paid another way.
+pub async fn record_attempt(
+    repo: &dyn Repository,
+    clock: &dyn Clock,
+    case_id: &DunningCaseId,
+    attempt: &Attempt,
+) -> Result<Transition, DunningError> {
+    let case = find_case(repo, case_id).await?;
+    save(repo, case.record(attempt, clock.now())?).await
+}
+
+async fn save(repo: &dyn Repository, t: Transition) -> Result<Transition, DunningError> {
+    if !repo.save_dunning_case(&t.case, &t.events).await? {
+        return Err(DunningError::Conflict { case_id: t.case.id });
+    }
+    Ok(t)
+}
+
+/// Retry every case due at `clock.now()` through `gateway`, oldest first,
+/// and return the events fired. Each case is claimed (`DunningCase::claim`)
+/// before it is charged; one claimed or changed by someone else meanwhile
+/// is left to them. A failed retry that loses to another change is dropped,
+/// but a payment that cannot be recorded is a `Conflict`: the money was
+/// taken.
+pub async fn run(
+    repo: &dyn Repository,
+    clock: &dyn Clock,
+    gateway: &dyn Gateway,
+) -> Result<Vec<DunningEvent>, DunningError> {
+    let due: Vec<DunningCase> = repo
+        .get_dunning_cases()
+        .await?
+        .into_iter()
+        .filter(|c| c.is_due(clock.now()))
+        .collect();
+    let mut events = Vec::new();
+    for case in due {
+        let claimed = case.claim(clock.now());
+        if !repo.save_dunning_case(&claimed, &[]).await? {
+            continue;
+        }
+        let attempt = gateway.charge(&claimed).await;
+        match save(repo, claimed.record(&attempt, clock.now())?).await {
+            Ok(t) => events.extend(t.events),
+            Err(DunningError::Conflict { .. }) if attempt != Attempt::Paid => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(events)
+}
+
+/// The case keeping `user_id` from using `service_id`, if any.
+pub fn suspension<'a>(
+    cases: &'a [DunningCase],
+    user_id: &UserId,
+    service_id: &ServiceId,
+) -> Option<&'a DunningCase> {
+    cases.iter().find(|c| c.suspends(user_id, service_id))
+}
+
+/// `Suspended` if the user of `usage` may not use its service.
+pub async fn check_access(repo: &dyn Repository, usage: &ServiceUsage) -> Result<(), DunningError> {
+    let cases = repo.get_dunning_cases().await?;
+    match suspension(&cases, &usage.user_id, &usage.service_id) {
+        Some(case) => Err(DunningError::Suspended {
+            case_id: case.id.clone(),
+            user_id: usage.user_id.clone(),
+            service_id: usage.service_id.clone(),
+        }),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,56 @@
+//! Idempotent usage recording.
+//!
+//! A client that may retry a write tags the usage with an `IdempotencyKey`
+//! (`ServiceUsage::with_idempotency_key`). Recording a usage whose key is
+//! already stored returns the stored record (`Recorded::Replayed`) instead
+//! of writing it again. A key is remembered for a retention window from when
+//! it was first recorded; after that it may be used for a new usage, which
+//! takes the key over.
+
+use crate::query::UsageRecord;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+/// How long a key is remembered when the caller does not say otherwise.
+pub const DEFAULT_RETENTION: Duration = Duration::hours(24);
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct IdempotencyKey(pub String);
+impl From<&str> for IdempotencyKey {
+    fn from(s: &str) -> Self {
+        IdempotencyKey(s.to_string())
+    }
+}
+
+/// Outcome of `Repository::record_usage`.
+#[derive(Debug, Clone)]
+pub enum Recorded {
+    /// The usage was written.
+    New(UsageRecord),
+    /// A usage with the same live key was already stored; nothing was
+    /// written and this is the stored record.
+    Replayed(UsageRecord),
+}
+
+impl Recorded {
+    pub fn record(&self) -> &UsageRecord {
+        match self {
+            Recorded::New(r) | Recorded::Replayed(r) => r,
+        }
+    }
+
+    pub fn into_record(self) -> UsageRecord {
+        match self {
+            Recorded::New(r) | Recorded::Replayed(r) => r,
+        }
+    }
+
+    pub fn is_replay(&self) -> bool {
+        matches!(self, Recorded::Replayed(_))
+    }
+}
+
+/// Whether a key first recorded at `recorded_at` is still remembered at `now`.
+pub fn is_live(recorded_at: DateTime<Utc>, now: DateTime<Utc>, retention: Duration) -> bool {
+    now < recorded_at + retention
+}
@@ -0,0 +1,189 @@
+//! Buffered usage ingestion for high write rates.
+//!
+//! `UsageIngestor` owns a background task that collects usages in memory and
+//! writes them with `persistence::save_usages` (one transaction per batch)
+//! whenever `max_batch` usages are buffered or `flush_interval` elapses.
+//! The channel in front of the task is bounded, so producers wait instead of
+//! growing memory without limit when the database falls behind.
+
+use crate::models::ServiceUsage;
+use crate::persistence::{self, PersistenceError};
+use sqlx::SqlitePool;
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+#[derive(Debug, Clone, Copy)]
+pub struct IngestConfig {
+    /// Flush as soon as this many usages are buffered.
+    pub max_batch: usize,
+    /// Flush a non-empty buffer at least this often.
+    pub flush_interval: Duration,
+    /// Usages that may wait in the channel before `record` blocks.
+    pub queue_capacity: usize,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        IngestConfig {
+            max_batch: 1000,
+            flush_interval: Duration::from_millis(200),
+            queue_capacity: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IngestStats {
+    pub usages_written: usize,
+    pub batches: usize,
+}
+
+#[derive(Debug)]
+pub enum IngestError {
+    /// A batch failed to write. The ingestor stops; `unflushed` holds every
+    /// usage that was accepted but not persisted, so the caller can retry.
+    Flush {
+        source: PersistenceError,
+        unflushed: Vec<ServiceUsage>,
+    },
+    /// The ingestor already stopped (after a failed flush or shutdown).
+    Closed,
+}
+
+impl fmt::Display for IngestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IngestError::Flush { source, unflushed } => write!(
+                f,
+                "usage batch flush failed ({} usages not persisted): {}",
+                unflushed.len(),
+                source
+            ),
+            IngestError::Closed => write!(f, "usage ingestor is closed"),
+        }
+    }
+}
+
+impl std::error::Error for IngestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IngestError::Flush { source, .. } => Some(source),
+            IngestError::Closed => None,
+        }
+    }
+}
+
+enum Command {
+    Record(Box<ServiceUsage>),
+    Flush(oneshot::Sender<()>),
+}
+
+pub struct UsageIngestor {
+    tx: mpsc::Sender<Command>,
+    task: JoinHandle<Result<IngestStats, IngestError>>,
+}
+
+impl UsageIngestor {
+    /// Start the background writer. Must be called inside a Tokio runtime.
+    pub fn spawn(pool: SqlitePool, config: IngestConfig) -> Self {
+        let (tx, rx) = mpsc::channel(config.queue_capacity.max(1));
+        let task = tokio::spawn(run(pool, config, rx));
+        UsageIngestor { tx, task }
+    }
+
+    /// Queue one usage; waits while the queue is full.
+    pub async fn record(&self, usage: ServiceUsage) -> Result<(), IngestError> {
+        self.tx
+            .send(Command::Record(Box::new(usage)))
+            .await
+            .map_err(|_| IngestError::Closed)
+    }
+
+    /// Write everything queued so far and wait until it is committed.
+    pub async fn flush(&self) -> Result<(), IngestError> {
+        let (ack, done) = oneshot::channel();
+        self.tx
+            .send(Command::Flush(ack))
+            .await
+            .map_err(|_| IngestError::Closed)?;
+        done.await.map_err(|_| IngestError::Closed)
+    }
+
+    /// Flush the remaining usages and stop the writer.
+    pub async fn shutdown(self) -> Result<IngestStats, IngestError> {
+        drop(self.tx);
+        self.task.await.unwrap_or(Err(IngestError::Closed))
+    }
+}
+
+async fn run(
+    pool: SqlitePool,
+    config: IngestConfig,
+    mut rx: mpsc::Receiver<Command>,
+) -> Result<IngestStats, IngestError> {
+    let max_batch = config.max_batch.max(1);
+    let mut buffer: Vec<ServiceUsage> = Vec::with_capacity(max_batch);
+    let mut stats = IngestStats::default();
+    // the first tick of a plain `interval` fires immediately; start one period out
+    let mut ticker = tokio::time::interval_at(
+        tokio::time::Instant::now() + config.flush_interval,
+        config.flush_interval,
+    );
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            cmd = rx.recv() => match cmd {
+                Some(Command::Record(usage)) => {
+                    buffer.push(*usage);
+                    if buffer.len() >= max_batch {
+                        flush(&pool, &mut buffer, &mut stats, &mut rx).await?;
+                    }
+                }
+                Some(Command::Flush(ack)) => {
+                    flush(&pool, &mut buffer, &mut stats, &mut rx).await?;
+                    let _ = ack.send(());
+                }
+                None => {
+                    flush(&pool, &mut buffer, &mut stats, &mut rx).await?;
+                    return Ok(stats);
+                }
+            },
+            _ = ticker.tick() => {
+                flush(&pool, &mut buffer, &mut stats, &mut rx).await?;
+            }
+        }
+    }
+}
+
+async fn flush(
+    pool: &SqlitePool,
+    buffer: &mut Vec<ServiceUsage>,
+    stats: &mut IngestStats,
+    rx: &mut mpsc::Receiver<Command>,
+) -> Result<(), IngestError> {
+    if buffer.is_empty() {
+        return Ok(());
+    }
+    match persistence::save_usages(pool, buffer).await {
+        Ok(n) => {
+            stats.usages_written += n;
+            stats.batches += 1;
+            buffer.clear();
+            Ok(())
+        }
+        Err(source) => {
+            // hand back the failed batch plus whatever is still queued
+            rx.close();
+            let mut unflushed = std::mem::take(buffer);
+            while let Ok(cmd) = rx.try_recv() {
+                if let Command::Record(u) = cmd {
+                    unflushed.push(*u);
+                }
+            }
+            Err(IngestError::Flush { source, unflushed })
+        }
+    }
+}
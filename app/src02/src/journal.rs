@@ -0,0 +1,424 @@
+//! Durable, append-only journal of usage events.
+//!
+//! File layout: an 8-byte header, then one record per event:
+//!
+//! ```text
+//! [len: u32 LE][crc32(payload): u32 LE][payload: JSON JournalEntry, len bytes]
+//! ```
+//!
+//! A crash can leave the last record incomplete or with a bad checksum (a torn
+//! write). `Journal::open` cuts such a tail off and reports it in `Recovery`;
+//! a bad record followed by more data is real corruption and an error.
+//!
+//! `snapshot` writes the whole `UsageLog` to `<path>.snapshot` (atomically,
+//! via rename) and then empties the journal. Entries carry a sequence number,
+//! so a crash between those two steps never replays an event twice.
+
+use crate::models::ServiceUsage;
+use crate::usage::UsageLog;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const JOURNAL_MAGIC: &[u8; 8] = b"src02j1\n";
+const SNAPSHOT_MAGIC: &[u8; 8] = b"src02s1\n";
+/// Length and checksum in front of every payload.
+const RECORD_HEADER: usize = 8;
+/// Larger lengths can only come from a damaged header.
+const MAX_RECORD: u32 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum UsageEvent {
+    Recorded(ServiceUsage),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub event: UsageEvent,
+}
+
+/// When appended records are forced to disk with fsync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    /// After every append: nothing acknowledged is ever lost.
+    #[default]
+    Always,
+    /// After every `n` appends; a crash loses at most the last `n - 1`.
+    EveryN(u32),
+    /// Only on `Journal::sync`, `snapshot` and drop.
+    Manual,
+}
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    Encoding(serde_json::Error),
+    /// A file that does not start with the expected header.
+    BadHeader(PathBuf),
+    /// A damaged record that is not the last one; the journal is not truncated.
+    Corrupt {
+        offset: u64,
+        reason: String,
+    },
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Io(e) => write!(f, "journal I/O error: {}", e),
+            JournalError::Encoding(e) => write!(f, "journal encoding error: {}", e),
+            JournalError::BadHeader(path) => {
+                write!(f, "{} is not a usage journal", path.display())
+            }
+            JournalError::Corrupt { offset, reason } => {
+                write!(f, "corrupt journal record at byte {}: {}", offset, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for JournalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JournalError::Io(e) => Some(e),
+            JournalError::Encoding(e) => Some(e),
+            JournalError::BadHeader(_) | JournalError::Corrupt { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for JournalError {
+    fn from(e: io::Error) -> Self {
+        JournalError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for JournalError {
+    fn from(e: serde_json::Error) -> Self {
+        JournalError::Encoding(e)
+    }
+}
+
+/// What `open` found on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Recovery {
+    /// Events restored from the snapshot.
+    pub from_snapshot: u64,
+    /// Events replayed from the journal on top of the snapshot.
+    pub replayed: u64,
+    /// Bytes of a torn last record that were cut off.
+    pub truncated_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    last_seq: u64,
+    usages: Vec<ServiceUsage>,
+}
+
+/// An open journal plus the `UsageLog` it describes.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    policy: FsyncPolicy,
+    log: UsageLog,
+    next_seq: u64,
+    unsynced: u32,
+    recovery: Recovery,
+}
+
+impl Journal {
+    /// Open (or create) the journal at `path`, replay it and cut off a torn tail.
+    pub fn open(path: impl AsRef<Path>, policy: FsyncPolicy) -> Result<Self, JournalError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(JOURNAL_MAGIC)?;
+            file.sync_all()?;
+        }
+
+        let (snapshot_seq, mut log) = read_snapshot(&snapshot_path(&path))?;
+        let mut recovery = Recovery {
+            from_snapshot: log.usages_len() as u64,
+            ..Recovery::default()
+        };
+        let scan = scan(&path, &mut file)?;
+        let mut last_seq = snapshot_seq;
+        for entry in scan.entries {
+            last_seq = last_seq.max(entry.seq);
+            // already folded into the snapshot
+            if entry.seq <= snapshot_seq {
+                continue;
+            }
+            match entry.event {
+                UsageEvent::Recorded(u) => log.push(u),
+            }
+            recovery.replayed += 1;
+        }
+        if scan.torn_bytes > 0 {
+            file.set_len(scan.valid_len)?;
+            file.sync_all()?;
+            recovery.truncated_bytes = scan.torn_bytes;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(Journal {
+            path,
+            file,
+            policy,
+            log,
+            next_seq: last_seq + 1,
+            unsynced: 0,
+            recovery,
+        })
+    }
+
+    /// Read the state at `path` without opening it for writing or repairing it.
+    pub fn replay(path: impl AsRef<Path>) -> Result<UsageLog, JournalError> {
+        let path = path.as_ref();
+        let (snapshot_seq, mut log) = read_snapshot(&snapshot_path(path))?;
+        let mut file = File::open(path)?;
+        for entry in scan(path, &mut file)?.entries {
+            if entry.seq > snapshot_seq {
+                match entry.event {
+                    UsageEvent::Recorded(u) => log.push(u),
+                }
+            }
+        }
+        Ok(log)
+    }
+
+    pub fn recovery(&self) -> Recovery {
+        self.recovery
+    }
+
+    pub fn log(&self) -> &UsageLog {
+        &self.log
+    }
+
+    /// Durably record one usage (subject to the fsync policy); returns its sequence number.
+    pub fn append(&mut self, usage: ServiceUsage) -> Result<u64, JournalError> {
+        self.append_all(vec![usage])
+    }
+
+    /// Record several usages with a single write and at most one fsync.
+    /// Returns the sequence number of the last one.
+    pub fn append_all(&mut self, usages: Vec<ServiceUsage>) -> Result<u64, JournalError> {
+        let mut buf = Vec::new();
+        let mut seq = self.next_seq;
+        for usage in &usages {
+            let entry = JournalEntry {
+                seq,
+                event: UsageEvent::Recorded(usage.clone()),
+            };
+            encode_record(&mut buf, &serde_json::to_vec(&entry)?);
+            seq += 1;
+        }
+        let start = self.file.stream_position()?;
+        let unsynced = self.unsynced;
+        if let Err(e) = self.write_records(&buf, usages.len() as u32) {
+            // nothing of a failed append may be replayed on the next open
+            self.unsynced = unsynced;
+            self.file.set_len(start)?;
+            self.file.seek(SeekFrom::End(0))?;
+            return Err(e);
+        }
+        self.next_seq = seq;
+        for usage in usages {
+            self.log.push(usage);
+        }
+        Ok(seq - 1)
+    }
+
+    /// Write encoded records and fsync as the policy asks.
+    fn write_records(&mut self, buf: &[u8], records: u32) -> Result<(), JournalError> {
+        self.file.write_all(buf)?;
+        self.unsynced += records;
+        let due = match self.policy {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryN(n) => self.unsynced >= n.max(1),
+            FsyncPolicy::Manual => false,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Force every appended record to disk.
+    pub fn sync(&mut self) -> Result<(), JournalError> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Write the current state to the snapshot file and empty the journal.
+    pub fn snapshot(&mut self) -> Result<(), JournalError> {
+        self.sync()?;
+        let snapshot = Snapshot {
+            last_seq: self.next_seq - 1,
+            usages: self.log.usages.clone(),
+        };
+        let payload = serde_json::to_vec(&snapshot)?;
+        let mut buf = SNAPSHOT_MAGIC.to_vec();
+        encode_record(&mut buf, &payload);
+
+        let target = snapshot_path(&self.path);
+        let tmp = target.with_extension("snapshot.tmp");
+        let mut out = File::create(&tmp)?;
+        out.write_all(&buf)?;
+        out.sync_all()?;
+        fs::rename(&tmp, &target)?;
+        sync_parent(&target)?;
+
+        // from here on a crash leaves entries the snapshot already covers;
+        // their sequence numbers make replay skip them
+        self.file.set_len(JOURNAL_MAGIC.len() as u64)?;
+        self.file.sync_all()?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        if self.unsynced > 0 {
+            let _ = self.file.sync_data();
+        }
+    }
+}
+
+fn snapshot_path(journal: &Path) -> PathBuf {
+    let mut name = journal.as_os_str().to_owned();
+    name.push(".snapshot");
+    PathBuf::from(name)
+}
+
+fn encode_record(buf: &mut Vec<u8>, payload: &[u8]) {
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+}
+
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+struct Scan {
+    entries: Vec<JournalEntry>,
+    /// Length of the file up to the end of the last good record.
+    valid_len: u64,
+    /// Bytes after `valid_len` belonging to a torn last record.
+    torn_bytes: u64,
+}
+
+fn scan(path: &Path, file: &mut File) -> Result<Scan, JournalError> {
+    let mut data = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut data)?;
+    if !data.starts_with(JOURNAL_MAGIC) {
+        return Err(JournalError::BadHeader(path.to_path_buf()));
+    }
+
+    let mut entries = Vec::new();
+    let mut at = JOURNAL_MAGIC.len();
+    while at < data.len() {
+        let torn = || Scan {
+            entries: Vec::new(),
+            valid_len: at as u64,
+            torn_bytes: (data.len() - at) as u64,
+        };
+        let rest = &data[at..];
+        if rest.len() < RECORD_HEADER {
+            return Ok(Scan { entries, ..torn() });
+        }
+        let len = u32::from_le_bytes(rest[0..4].try_into().expect("4 bytes"));
+        let crc = u32::from_le_bytes(rest[4..8].try_into().expect("4 bytes"));
+        if len > MAX_RECORD {
+            // no writer produces this length; a header with nothing after it
+            // is a torn final write, anything else is a damaged header
+            if rest.len() == RECORD_HEADER {
+                return Ok(Scan { entries, ..torn() });
+            }
+            return Err(JournalError::Corrupt {
+                offset: at as u64,
+                reason: format!("record length {} exceeds {}", len, MAX_RECORD),
+            });
+        }
+        let end = RECORD_HEADER + len as usize;
+        if end > rest.len() {
+            // a length past the end of the file: the write never completed
+            return Ok(Scan { entries, ..torn() });
+        }
+        let payload = &rest[RECORD_HEADER..end];
+        let last = end == rest.len();
+        let decoded = if crc32fast::hash(payload) != crc {
+            Err("checksum mismatch".to_string())
+        } else {
+            serde_json::from_slice::<JournalEntry>(payload).map_err(|e| e.to_string())
+        };
+        match decoded {
+            Ok(entry) => entries.push(entry),
+            Err(_) if last => return Ok(Scan { entries, ..torn() }),
+            Err(reason) => {
+                return Err(JournalError::Corrupt {
+                    offset: at as u64,
+                    reason,
+                })
+            }
+        }
+        at += end;
+    }
+    Ok(Scan {
+        entries,
+        valid_len: data.len() as u64,
+        torn_bytes: 0,
+    })
+}
+
+/// `(last_seq, log)` from the snapshot file; `(0, empty)` if there is none.
+fn read_snapshot(path: &Path) -> Result<(u64, UsageLog), JournalError> {
+    let data = match fs::read(path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, UsageLog::default())),
+        Err(e) => return Err(e.into()),
+    };
+    let corrupt = |reason: &str| JournalError::Corrupt {
+        offset: 0,
+        reason: format!("snapshot {}: {}", path.display(), reason),
+    };
+    let body = data
+        .strip_prefix(SNAPSHOT_MAGIC.as_slice())
+        .ok_or_else(|| JournalError::BadHeader(path.to_path_buf()))?;
+    if body.len() < RECORD_HEADER {
+        return Err(corrupt("truncated"));
+    }
+    let len = u32::from_le_bytes(body[0..4].try_into().expect("4 bytes")) as usize;
+    let crc = u32::from_le_bytes(body[4..8].try_into().expect("4 bytes"));
+    let payload = body
+        .get(RECORD_HEADER..RECORD_HEADER + len)
+        .ok_or_else(|| corrupt("truncated"))?;
+    // snapshots are renamed into place whole, so any damage is real corruption
+    if crc32fast::hash(payload) != crc {
+        return Err(corrupt("checksum mismatch"));
+    }
+    let snapshot: Snapshot = serde_json::from_slice(payload)?;
+    Ok((snapshot.last_seq, UsageLog::from_vec(snapshot.usages)))
+}
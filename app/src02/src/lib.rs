@@ -1,18 +1,47 @@
+pub mod account;
+pub mod api;
+pub mod budget;
 pub mod catalog;
+pub mod catalog_file;
+pub mod clock;
+pub mod credit;
+pub mod dunning;
+pub mod idempotency;
+pub mod ingest;
+pub mod journal;
+pub mod migrations;
 pub mod models;
+pub mod payment;
 pub mod persistence;
+pub mod policy;
+pub mod privacy;
+pub mod profile;
+pub mod query;
+pub mod reporting;
+pub mod repository;
+pub mod shared_catalog;
 pub mod usage;
+pub mod validation;
+pub mod vault;
+pub mod wallet;
 
+pub use account::*;
 pub use catalog::*;
 pub use models::*;
+pub use payment::*;
 pub use persistence::*;
+pub use query::*;
 pub use usage::*;
+pub use vault::*;
+pub use wallet::*;
 
 // high level convenience: run a small demo (async)
 pub async fn run_demo() {
     use crate::catalog::Catalog;
     use crate::models::*;
     use crate::usage::{resolve_payment_for_usage, UsageLog};
+    use crate::vault::{CardExpiry, CardVault};
+    use chrono::{Datelike, Utc};
 
     let p1 = Product::new("p-1", "Email Support", 500);
     let p2 = Product::new("p-2", "Premium Analytics", 1500);
@@ -25,11 +54,17 @@ pub async fn run_demo() {
     catalog = catalog.with_service(svc);
     catalog = catalog.with_service(svc2);
 
-    let alice = User::new(
-        "u-alice",
-        "Alice",
-        Some(PaymentMethod::card("**** **** **** 4242", "Alice")),
-    );
+    // a card that stays valid whenever the demo runs
+    let expiry_year = Utc::now().year() + 5;
+    let mut vault = CardVault::new();
+    let alice_card = vault
+        .tokenize(
+            "4242 4242 4242 4242",
+            CardExpiry::new(12, expiry_year).expect("valid expiry"),
+            "Alice",
+        )
+        .expect("valid test card");
+    let alice = User::new("u-alice", "Alice", Some(PaymentMethod::card(&alice_card)));
     let bob = User::new("u-bob", "Bob", None);
 
     let usage1 = ServiceUsage::new(&alice.id, &"s-1".into(), &"p-2".into(), None);
@@ -68,12 +103,13 @@ pub async fn run_demo() {
         );
     }
 
-    // demonstrate pure-style addition (returns new log)
+    // demonstrate functional-style addition (consumes the log, returns the new one)
     let new_usage = ServiceUsage::new(&bob.id, &"s-2".into(), &"p-3".into(), None);
-    let log2 = log.add_usage(new_usage);
+    let before = log.usages_len();
+    let log = log.add_usage(new_usage);
     println!(
         "\nOriginal log length: {}, New log length: {}",
-        log.usages_len(),
-        log2.usages_len()
+        before,
+        log.usages_len()
     );
 }
@@ -0,0 +1,582 @@
+//! Numbered schema migrations for the SQLite database.
+//!
+//! The applied version is tracked in `schema_version`. Migrations are only
+//! ever appended to `MIGRATIONS`; an applied migration must never be edited,
+//! add a new one instead. Migration 1 uses `IF NOT EXISTS` so databases created
+//! by the old `init_db` are adopted as version 1 without changes.
+
+use chrono::Utc;
+use sqlx::{Row, SqlitePool};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub statements: &'static [&'static str],
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        statements: &[
+            r#"CREATE TABLE IF NOT EXISTS users (
+                id TEXT PRIMARY KEY,
+                display_name TEXT NOT NULL,
+                default_payment TEXT NULL
+            );"#,
+            r#"CREATE TABLE IF NOT EXISTS services (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL
+            );"#,
+            r#"CREATE TABLE IF NOT EXISTS products (
+                id TEXT PRIMARY KEY,
+                service_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                price_cents INTEGER NOT NULL,
+                FOREIGN KEY(service_id) REFERENCES services(id)
+            );"#,
+            r#"CREATE TABLE IF NOT EXISTS usages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id TEXT NOT NULL,
+                service_id TEXT NOT NULL,
+                product_id TEXT NOT NULL,
+                payment_used TEXT NULL
+            );"#,
+            // vaulted cards: token plus masked display data only, never the PAN
+            r#"CREATE TABLE IF NOT EXISTS cards (
+                token TEXT PRIMARY KEY,
+                brand TEXT NOT NULL,
+                last4 TEXT NOT NULL,
+                exp_month INTEGER NOT NULL,
+                exp_year INTEGER NOT NULL,
+                holder TEXT NOT NULL
+            );"#,
+            r#"CREATE TABLE IF NOT EXISTS wallets (
+                id TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                balance_cents INTEGER NOT NULL
+            );"#,
+        ],
+    },
+    Migration {
+        version: 2,
+        name: "usage_timestamps",
+        statements: &[
+            // unix epoch milliseconds; rows recorded before this migration get
+            // the migration time as their best known upper bound
+            "ALTER TABLE usages ADD COLUMN occurred_at INTEGER NOT NULL DEFAULT 0;",
+            "UPDATE usages SET occurred_at = CAST(strftime('%s', 'now') AS INTEGER) * 1000 WHERE occurred_at = 0;",
+        ],
+    },
+    Migration {
+        version: 3,
+        name: "lookup_indexes",
+        statements: &[
+            "CREATE INDEX IF NOT EXISTS idx_usages_user_id ON usages(user_id);",
+            "CREATE INDEX IF NOT EXISTS idx_products_service_id ON products(service_id);",
+        ],
+    },
+    Migration {
+        version: 4,
+        name: "usage_payment_kind",
+        statements: &[
+            // `PaymentKind::as_str` of `payment_used`, so queries can filter
+            // on it without decoding JSON
+            "ALTER TABLE usages ADD COLUMN payment_kind TEXT NULL;",
+            r#"UPDATE usages SET payment_kind =
+                CASE (SELECT key FROM json_each(payment_used) LIMIT 1)
+                    WHEN 'Card' THEN 'card'
+                    WHEN 'Paypal' THEN 'paypal'
+                    WHEN 'SepaDebit' THEN 'sepa_debit'
+                    WHEN 'BankTransfer' THEN 'bank_transfer'
+                    WHEN 'Prepaid' THEN 'prepaid'
+                END
+                WHERE payment_used IS NOT NULL AND json_valid(payment_used);"#,
+            "CREATE INDEX IF NOT EXISTS idx_usages_occurred_at ON usages(occurred_at);",
+        ],
+    },
+    Migration {
+        version: 5,
+        name: "usage_references",
+        statements: &[
+            // triggers instead of FOREIGN KEYs: adding those means rebuilding
+            // `usages`, which would fail on rows that already violate them.
+            // Existing rows are left alone; `validation::audit` lists them.
+            r#"CREATE TRIGGER IF NOT EXISTS usages_references_insert
+                BEFORE INSERT ON usages
+                BEGIN
+                    SELECT RAISE(ABORT, 'usage references unknown user')
+                        WHERE NOT EXISTS (SELECT 1 FROM users WHERE id = NEW.user_id);
+                    SELECT RAISE(ABORT, 'usage references unknown service')
+                        WHERE NOT EXISTS (SELECT 1 FROM services WHERE id = NEW.service_id);
+                    SELECT RAISE(ABORT, 'usage references a product its service does not offer')
+                        WHERE NOT EXISTS (SELECT 1 FROM products
+                            WHERE id = NEW.product_id AND service_id = NEW.service_id);
+                END;"#,
+            r#"CREATE TRIGGER IF NOT EXISTS usages_references_update
+                BEFORE UPDATE OF user_id, service_id, product_id ON usages
+                BEGIN
+                    SELECT RAISE(ABORT, 'usage references unknown user')
+                        WHERE NOT EXISTS (SELECT 1 FROM users WHERE id = NEW.user_id);
+                    SELECT RAISE(ABORT, 'usage references unknown service')
+                        WHERE NOT EXISTS (SELECT 1 FROM services WHERE id = NEW.service_id);
+                    SELECT RAISE(ABORT, 'usage references a product its service does not offer')
+                        WHERE NOT EXISTS (SELECT 1 FROM products
+                            WHERE id = NEW.product_id AND service_id = NEW.service_id);
+                END;"#,
+        ],
+    },
+    Migration {
+        version: 6,
+        name: "product_prices",
+        statements: &[
+            // effective-dated prices; `products.price_cents` is the price
+            // before the first change. Times are Unix milliseconds, like
+            // `usages.occurred_at`.
+            r#"CREATE TABLE IF NOT EXISTS product_prices (
+                product_id TEXT NOT NULL,
+                effective_from INTEGER NOT NULL,
+                price_cents INTEGER NOT NULL,
+                PRIMARY KEY (product_id, effective_from)
+            );"#,
+        ],
+    },
+    Migration {
+        version: 7,
+        name: "accounts",
+        statements: &[
+            r#"CREATE TABLE IF NOT EXISTS accounts (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL
+            );"#,
+            // `position` keeps the join order that `Account::members` has
+            r#"CREATE TABLE IF NOT EXISTS account_members (
+                account_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                role TEXT NOT NULL CHECK (role IN ('owner', 'billing_admin', 'member')),
+                position INTEGER NOT NULL,
+                PRIMARY KEY (account_id, user_id),
+                FOREIGN KEY(account_id) REFERENCES accounts(id)
+            );"#,
+            // position 0 is the account's default payment method
+            r#"CREATE TABLE IF NOT EXISTS account_payment_methods (
+                account_id TEXT NOT NULL,
+                position INTEGER NOT NULL,
+                payment TEXT NOT NULL,
+                PRIMARY KEY (account_id, position),
+                FOREIGN KEY(account_id) REFERENCES accounts(id)
+            );"#,
+            // NULL bills the user, as every usage before this migration did
+            "ALTER TABLE usages ADD COLUMN account_id TEXT NULL;",
+            "CREATE INDEX IF NOT EXISTS idx_usages_account_id ON usages(account_id);",
+            r#"CREATE TRIGGER IF NOT EXISTS usages_account_insert
+                BEFORE INSERT ON usages
+                WHEN NEW.account_id IS NOT NULL
+                BEGIN
+                    SELECT RAISE(ABORT, 'usage references unknown account')
+                        WHERE NOT EXISTS (SELECT 1 FROM accounts WHERE id = NEW.account_id);
+                    SELECT RAISE(ABORT, 'usage references an account its user is not a member of')
+                        WHERE NOT EXISTS (SELECT 1 FROM account_members
+                            WHERE account_id = NEW.account_id AND user_id = NEW.user_id);
+                END;"#,
+            r#"CREATE TRIGGER IF NOT EXISTS usages_account_update
+                BEFORE UPDATE OF user_id, account_id ON usages
+                WHEN NEW.account_id IS NOT NULL
+                BEGIN
+                    SELECT RAISE(ABORT, 'usage references unknown account')
+                        WHERE NOT EXISTS (SELECT 1 FROM accounts WHERE id = NEW.account_id);
+                    SELECT RAISE(ABORT, 'usage references an account its user is not a member of')
+                        WHERE NOT EXISTS (SELECT 1 FROM account_members
+                            WHERE account_id = NEW.account_id AND user_id = NEW.user_id);
+                END;"#,
+        ],
+    },
+    Migration {
+        version: 8,
+        name: "budgets",
+        statements: &[
+            // `thresholds` is a JSON array of percentages, e.g. `[50,80,100]`
+            r#"CREATE TABLE IF NOT EXISTS budgets (
+                id TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                service_id TEXT NULL,
+                period TEXT NOT NULL CHECK (period IN ('daily', 'weekly', 'monthly')),
+                limit_cents INTEGER NOT NULL,
+                enforcement TEXT NOT NULL CHECK (enforcement IN ('alert', 'hard_limit')),
+                thresholds TEXT NOT NULL
+            );"#,
+            "CREATE INDEX IF NOT EXISTS idx_budgets_user_id ON budgets(user_id);",
+        ],
+    },
+    Migration {
+        version: 9,
+        name: "idempotency_keys",
+        statements: &[
+            // an expired key is set back to NULL when a new usage takes it over
+            "ALTER TABLE usages ADD COLUMN idempotency_key TEXT NULL;",
+            "ALTER TABLE usages ADD COLUMN idempotency_key_at INTEGER NULL;",
+            r#"CREATE UNIQUE INDEX IF NOT EXISTS idx_usages_idempotency_key
+                ON usages(idempotency_key) WHERE idempotency_key IS NOT NULL;"#,
+        ],
+    },
+    Migration {
+        version: 10,
+        name: "privacy_audit",
+        statements: &[
+            // exports and erasures; `at` is Unix milliseconds
+            r#"CREATE TABLE IF NOT EXISTS privacy_audit (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                action TEXT NOT NULL CHECK (action IN ('export', 'erasure')),
+                subject TEXT NOT NULL,
+                at INTEGER NOT NULL,
+                usages INTEGER NOT NULL
+            );"#,
+            "CREATE INDEX IF NOT EXISTS idx_privacy_audit_subject ON privacy_audit(subject);",
+        ],
+    },
+    Migration {
+        version: 11,
+        name: "service_payment_kinds",
+        statements: &[
+            // a service without rows accepts every kind; `kind` is
+            // `PaymentKind::as_str`, rows keep the service's order by rowid
+            r#"CREATE TABLE IF NOT EXISTS service_payment_kinds (
+                service_id TEXT NOT NULL,
+                kind TEXT NOT NULL CHECK (kind IN ('card', 'paypal', 'sepa_debit', 'bank_transfer', 'prepaid')),
+                PRIMARY KEY (service_id, kind),
+                FOREIGN KEY(service_id) REFERENCES services(id)
+            );"#,
+        ],
+    },
+    Migration {
+        version: 12,
+        name: "credit_notes",
+        statements: &[
+            // `refund_to` is a JSON `PaymentMethod`; times are Unix
+            // milliseconds; rows keep the order notes were issued in by rowid
+            r#"CREATE TABLE IF NOT EXISTS credit_notes (
+                id TEXT PRIMARY KEY,
+                usage_id INTEGER NOT NULL,
+                payer_kind TEXT NOT NULL CHECK (payer_kind IN ('user', 'account')),
+                payer_id TEXT NOT NULL,
+                usage_amount_cents INTEGER NOT NULL,
+                amount_cents INTEGER NOT NULL CHECK (amount_cents > 0),
+                reason TEXT NOT NULL,
+                settlement TEXT NOT NULL CHECK (settlement IN ('refund', 'credit')),
+                refund_to TEXT NULL,
+                issued_at INTEGER NOT NULL,
+                FOREIGN KEY(usage_id) REFERENCES usages(id)
+            );"#,
+            "CREATE INDEX IF NOT EXISTS idx_credit_notes_usage_id ON credit_notes(usage_id);",
+            "CREATE INDEX IF NOT EXISTS idx_credit_notes_payer ON credit_notes(payer_kind, payer_id);",
+            // one row per payer and invoice period
+            r#"CREATE TABLE IF NOT EXISTS credit_applications (
+                payer_kind TEXT NOT NULL CHECK (payer_kind IN ('user', 'account')),
+                payer_id TEXT NOT NULL,
+                period_from INTEGER NOT NULL,
+                period_until INTEGER NOT NULL,
+                amount_cents INTEGER NOT NULL,
+                applied_at INTEGER NOT NULL,
+                PRIMARY KEY (payer_kind, payer_id, period_from, period_until)
+            );"#,
+        ],
+    },
+    Migration {
+        version: 13,
+        name: "dunning",
+        statements: &[
+            // `payment` is a JSON `PaymentMethod`, `schedule` a JSON array of
+            // hours; times are Unix milliseconds
+            r#"CREATE TABLE IF NOT EXISTS dunning_cases (
+                id TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                service_id TEXT NOT NULL,
+                amount_cents INTEGER NOT NULL CHECK (amount_cents >= 0),
+                payment TEXT NULL,
+                reason TEXT NOT NULL,
+                schedule TEXT NOT NULL,
+                state TEXT NOT NULL CHECK (state IN ('retrying', 'suspended', 'settled')),
+                attempts INTEGER NOT NULL,
+                opened_at INTEGER NOT NULL,
+                next_attempt_at INTEGER NULL,
+                updated_at INTEGER NOT NULL,
+                revision INTEGER NOT NULL
+            );"#,
+            "CREATE INDEX IF NOT EXISTS idx_dunning_cases_user_service ON dunning_cases(user_id, service_id);",
+            r#"CREATE TABLE IF NOT EXISTS dunning_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                case_id TEXT NOT NULL,
+                stage TEXT NOT NULL,
+                attempt INTEGER NOT NULL,
+                at INTEGER NOT NULL,
+                next_attempt_at INTEGER NULL,
+                reason TEXT NULL,
+                FOREIGN KEY(case_id) REFERENCES dunning_cases(id)
+            );"#,
+        ],
+    },
+    Migration {
+        version: 14,
+        name: "user_profiles",
+        statements: &[
+            "ALTER TABLE users ADD COLUMN email TEXT NULL;",
+            "ALTER TABLE users ADD COLUMN locale TEXT NULL;",
+            "ALTER TABLE users ADD COLUMN timezone TEXT NULL;",
+            // NULLs never collide: users without an email are not limited
+            "CREATE UNIQUE INDEX IF NOT EXISTS users_email ON users(email);",
+            // saved methods after the default, which stays in
+            // users.default_payment as position 0
+            r#"CREATE TABLE IF NOT EXISTS user_payment_methods (
+                user_id TEXT NOT NULL,
+                position INTEGER NOT NULL CHECK (position >= 1),
+                payment TEXT NOT NULL,
+                PRIMARY KEY (user_id, position),
+                FOREIGN KEY(user_id) REFERENCES users(id)
+            );"#,
+            r#"CREATE TABLE IF NOT EXISTS user_billing_addresses (
+                user_id TEXT NOT NULL,
+                position INTEGER NOT NULL,
+                label TEXT NULL,
+                line1 TEXT NOT NULL,
+                line2 TEXT NULL,
+                city TEXT NOT NULL,
+                postal_code TEXT NOT NULL,
+                region TEXT NULL,
+                country TEXT NOT NULL CHECK (length(country) = 2),
+                PRIMARY KEY (user_id, position),
+                FOREIGN KEY(user_id) REFERENCES users(id)
+            );"#,
+        ],
+    },
+    Migration {
+        version: 15,
+        name: "profile_revisions",
+        statements: &["ALTER TABLE users ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;"],
+    },
+    Migration {
+        version: 16,
+        name: "card_tokens",
+        statements: &[
+            // payment methods embedded whole cards until now: vault them in
+            // `cards`, then keep only `{"Card": token}` in the JSON columns
+            r#"INSERT OR IGNORE INTO cards (token, brand, last4, exp_month, exp_year, holder)
+                SELECT json_extract(p, '$.Card.token'), json_extract(p, '$.Card.brand'),
+                    json_extract(p, '$.Card.last4'), json_extract(p, '$.Card.expiry.month'),
+                    json_extract(p, '$.Card.expiry.year'), json_extract(p, '$.Card.holder')
+                FROM (SELECT default_payment AS p FROM users
+                    UNION ALL SELECT payment FROM user_payment_methods
+                    UNION ALL SELECT payment FROM account_payment_methods
+                    UNION ALL SELECT payment_used FROM usages
+                    UNION ALL SELECT refund_to FROM credit_notes
+                    UNION ALL SELECT payment FROM dunning_cases)
+                WHERE CASE WHEN json_valid(p) THEN json_type(p, '$.Card') END = 'object';"#,
+            r#"UPDATE users SET default_payment =
+                json_object('Card', json_extract(default_payment, '$.Card.token'))
+                WHERE CASE WHEN json_valid(default_payment)
+                    THEN json_type(default_payment, '$.Card') END = 'object';"#,
+            r#"UPDATE user_payment_methods SET payment =
+                json_object('Card', json_extract(payment, '$.Card.token'))
+                WHERE CASE WHEN json_valid(payment)
+                    THEN json_type(payment, '$.Card') END = 'object';"#,
+            r#"UPDATE account_payment_methods SET payment =
+                json_object('Card', json_extract(payment, '$.Card.token'))
+                WHERE CASE WHEN json_valid(payment)
+                    THEN json_type(payment, '$.Card') END = 'object';"#,
+            r#"UPDATE usages SET payment_used =
+                json_object('Card', json_extract(payment_used, '$.Card.token'))
+                WHERE CASE WHEN json_valid(payment_used)
+                    THEN json_type(payment_used, '$.Card') END = 'object';"#,
+            r#"UPDATE credit_notes SET refund_to =
+                json_object('Card', json_extract(refund_to, '$.Card.token'))
+                WHERE CASE WHEN json_valid(refund_to)
+                    THEN json_type(refund_to, '$.Card') END = 'object';"#,
+            r#"UPDATE dunning_cases SET payment =
+                json_object('Card', json_extract(payment, '$.Card.token'))
+                WHERE CASE WHEN json_valid(payment)
+                    THEN json_type(payment, '$.Card') END = 'object';"#,
+            // new rows may only name vaulted cards; like migration 5, rows
+            // already stored are left alone
+            r#"CREATE TRIGGER IF NOT EXISTS users_card_insert
+                BEFORE INSERT ON users
+                WHEN CASE WHEN json_valid(NEW.default_payment)
+                    THEN json_extract(NEW.default_payment, '$.Card') END IS NOT NULL
+                BEGIN
+                    SELECT RAISE(ABORT, 'payment method references unknown card')
+                        WHERE NOT EXISTS (SELECT 1 FROM cards
+                            WHERE token = json_extract(NEW.default_payment, '$.Card'));
+                END;"#,
+            r#"CREATE TRIGGER IF NOT EXISTS users_card_update
+                BEFORE UPDATE OF default_payment ON users
+                WHEN CASE WHEN json_valid(NEW.default_payment)
+                    THEN json_extract(NEW.default_payment, '$.Card') END IS NOT NULL
+                BEGIN
+                    SELECT RAISE(ABORT, 'payment method references unknown card')
+                        WHERE NOT EXISTS (SELECT 1 FROM cards
+                            WHERE token = json_extract(NEW.default_payment, '$.Card'));
+                END;"#,
+            r#"CREATE TRIGGER IF NOT EXISTS user_payment_methods_card_insert
+                BEFORE INSERT ON user_payment_methods
+                WHEN CASE WHEN json_valid(NEW.payment)
+                    THEN json_extract(NEW.payment, '$.Card') END IS NOT NULL
+                BEGIN
+                    SELECT RAISE(ABORT, 'payment method references unknown card')
+                        WHERE NOT EXISTS (SELECT 1 FROM cards
+                            WHERE token = json_extract(NEW.payment, '$.Card'));
+                END;"#,
+            r#"CREATE TRIGGER IF NOT EXISTS account_payment_methods_card_insert
+                BEFORE INSERT ON account_payment_methods
+                WHEN CASE WHEN json_valid(NEW.payment)
+                    THEN json_extract(NEW.payment, '$.Card') END IS NOT NULL
+                BEGIN
+                    SELECT RAISE(ABORT, 'payment method references unknown card')
+                        WHERE NOT EXISTS (SELECT 1 FROM cards
+                            WHERE token = json_extract(NEW.payment, '$.Card'));
+                END;"#,
+            r#"CREATE TRIGGER IF NOT EXISTS usages_card_insert
+                BEFORE INSERT ON usages
+                WHEN CASE WHEN json_valid(NEW.payment_used)
+                    THEN json_extract(NEW.payment_used, '$.Card') END IS NOT NULL
+                BEGIN
+                    SELECT RAISE(ABORT, 'payment method references unknown card')
+                        WHERE NOT EXISTS (SELECT 1 FROM cards
+                            WHERE token = json_extract(NEW.payment_used, '$.Card'));
+                END;"#,
+        ],
+    },
+];
+
+/// Highest schema version this binary knows about.
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Database(sqlx::Error),
+    /// The database was migrated by a newer binary; refusing to touch it.
+    DatabaseNewer {
+        database: i64,
+        binary: i64,
+    },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Database(e) => write!(f, "migration failed: {}", e),
+            MigrationError::DatabaseNewer { database, binary } => write!(
+                f,
+                "database schema version {} is newer than this binary supports ({})",
+                database, binary
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MigrationError::Database(e) => Some(e),
+            MigrationError::DatabaseNewer { .. } => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for MigrationError {
+    fn from(e: sqlx::Error) -> Self {
+        MigrationError::Database(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MigrateOptions {
+    /// Report what would be applied without changing the database.
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub from_version: i64,
+    pub to_version: i64,
+    /// `(version, name)` of every migration applied (or pending, in a dry run).
+    pub applied: Vec<(i64, &'static str)>,
+    pub dry_run: bool,
+}
+
+impl MigrationReport {
+    pub fn is_up_to_date(&self) -> bool {
+        self.applied.is_empty()
+    }
+}
+
+/// Schema version recorded in the database, 0 if it was never migrated.
+pub async fn current_version(pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+    let exists: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+    )
+    .fetch_one(pool)
+    .await?;
+    if exists == 0 {
+        return Ok(0);
+    }
+    let row = sqlx::query("SELECT COALESCE(MAX(version), 0) AS v FROM schema_version")
+        .fetch_one(pool)
+        .await?;
+    Ok(row.get("v"))
+}
+
+/// Apply every migration newer than the database, each in its own transaction.
+pub async fn migrate(
+    pool: &SqlitePool,
+    options: MigrateOptions,
+) -> Result<MigrationReport, MigrationError> {
+    let from_version = current_version(pool).await?;
+    let binary = latest_version();
+    if from_version > binary {
+        return Err(MigrationError::DatabaseNewer {
+            database: from_version,
+            binary,
+        });
+    }
+
+    let pending: Vec<&Migration> = MIGRATIONS
+        .iter()
+        .filter(|m| m.version > from_version)
+        .collect();
+    let applied = pending.iter().map(|m| (m.version, m.name)).collect();
+
+    if !options.dry_run {
+        sqlx::query(
+            r#"CREATE TABLE IF NOT EXISTS schema_version (
+                version INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at TEXT NOT NULL
+            );"#,
+        )
+        .execute(pool)
+        .await?;
+
+        for m in &pending {
+            let mut tx = pool.begin().await?;
+            for stmt in m.statements {
+                sqlx::query(stmt).execute(&mut *tx).await?;
+            }
+            sqlx::query("INSERT INTO schema_version (version, name, applied_at) VALUES (?, ?, ?)")
+                .bind(m.version)
+                .bind(m.name)
+                .bind(Utc::now().to_rfc3339())
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        }
+    }
+
+    Ok(MigrationReport {
+        from_version,
+        to_version: binary,
+        applied,
+        dry_run: options.dry_run,
+    })
+}
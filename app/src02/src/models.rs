@@ -1,7 +1,14 @@
+use crate::account::AccountId;
+use crate::idempotency::IdempotencyKey;
+use crate::payment::{Iban, PaymentKind, PaymentMethodError};
+use crate::profile::{BillingAddress, Email, Locale, ProfileError, Timezone};
+use crate::vault::{CardToken, StoredCard};
+use crate::wallet::WalletId;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct UserId(pub String);
 
 impl From<&str> for UserId {
@@ -10,7 +17,7 @@ impl From<&str> for UserId {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct ServiceId(pub String);
 impl From<&str> for ServiceId {
     fn from(s: &str) -> Self {
@@ -18,7 +25,7 @@ impl From<&str> for ServiceId {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct ProductId(pub String);
 impl From<&str> for ProductId {
     fn from(s: &str) -> Self {
@@ -37,49 +44,265 @@ impl User {
         User {
             id: UserId(id.to_string()),
             profile: Profile {
-                display_name: display_name.to_string(),
-                default_payment,
+                payment_methods: default_payment.into_iter().collect(),
+                ..Profile::new(display_name)
             },
         }
     }
+
+    /// Same user, reachable at `email`.
+    pub fn with_email(mut self, email: Email) -> Self {
+        self.profile.email = Some(email);
+        self
+    }
 }
 
+/// See `profile` for the checks on each part.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "StoredProfile")]
 pub struct Profile {
     pub display_name: String,
-    pub default_payment: Option<PaymentMethod>,
+    /// Unique among users.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<Email>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub billing_addresses: Vec<BillingAddress>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locale: Option<Locale>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<Timezone>,
+    /// Saved methods in order of preference; the first one is the default.
+    pub payment_methods: Vec<PaymentMethod>,
+    /// 0 for a new user, one more after every change; see
+    /// `Repository::update_profile`.
+    pub revision: u32,
+}
+
+impl Profile {
+    pub fn new(display_name: &str) -> Self {
+        Profile {
+            display_name: display_name.to_string(),
+            email: None,
+            billing_addresses: Vec::new(),
+            locale: None,
+            timezone: None,
+            payment_methods: Vec::new(),
+            revision: 0,
+        }
+    }
+
+    pub fn default_payment(&self) -> Option<&PaymentMethod> {
+        self.payment_methods.first()
+    }
+
+    /// Save a payment method; `as_default` puts it first.
+    pub fn add_payment_method(&self, method: PaymentMethod, as_default: bool) -> Profile {
+        let mut next = self.clone();
+        if as_default {
+            next.payment_methods.insert(0, method);
+        } else {
+            next.payment_methods.push(method);
+        }
+        next
+    }
+
+    /// Forget a saved method. Removing the default makes the next one the
+    /// default.
+    pub fn remove_payment_method(&self, index: usize) -> Result<Profile, ProfileError> {
+        if index >= self.payment_methods.len() {
+            return Err(ProfileError::NoSuchPaymentMethod(index));
+        }
+        let mut next = self.clone();
+        next.payment_methods.remove(index);
+        Ok(next)
+    }
+
+    /// Make the saved method at `index` the default; the others keep their
+    /// order.
+    pub fn set_default_payment(&self, index: usize) -> Result<Profile, ProfileError> {
+        if index >= self.payment_methods.len() {
+            return Err(ProfileError::NoSuchPaymentMethod(index));
+        }
+        let mut next = self.clone();
+        let method = next.payment_methods.remove(index);
+        next.payment_methods.insert(0, method);
+        Ok(next)
+    }
+
+    pub fn add_address(&self, address: BillingAddress) -> Profile {
+        let mut next = self.clone();
+        next.billing_addresses.push(address);
+        next
+    }
+
+    pub fn remove_address(&self, index: usize) -> Result<Profile, ProfileError> {
+        if index >= self.billing_addresses.len() {
+            return Err(ProfileError::NoSuchAddress(index));
+        }
+        let mut next = self.clone();
+        next.billing_addresses.remove(index);
+        Ok(next)
+    }
+}
+
+/// A `Profile` as read, including those written before it held several
+/// payment methods.
+#[derive(Deserialize)]
+struct StoredProfile {
+    display_name: String,
+    #[serde(default)]
+    email: Option<Email>,
+    #[serde(default)]
+    billing_addresses: Vec<BillingAddress>,
+    #[serde(default)]
+    locale: Option<Locale>,
+    #[serde(default)]
+    timezone: Option<Timezone>,
+    #[serde(default)]
+    payment_methods: Vec<PaymentMethod>,
+    /// The single method of older profiles; ignored next to
+    /// `payment_methods`.
+    #[serde(default)]
+    default_payment: Option<PaymentMethod>,
+    #[serde(default)]
+    revision: u32,
+}
+
+impl From<StoredProfile> for Profile {
+    fn from(p: StoredProfile) -> Self {
+        let payment_methods = if p.payment_methods.is_empty() {
+            p.default_payment.into_iter().collect()
+        } else {
+            p.payment_methods
+        };
+        Profile {
+            display_name: p.display_name,
+            email: p.email,
+            billing_addresses: p.billing_addresses,
+            locale: p.locale,
+            timezone: p.timezone,
+            payment_methods,
+            revision: p.revision,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PaymentMethod {
-    Card {
-        card_number_masked: String,
-        holder: String,
-    },
+    /// A vaulted card, by token; its display data stays in the card store.
+    Card(#[serde(deserialize_with = "crate::vault::deserialize_card_reference")] CardToken),
     Paypal {
         account: String,
     },
+    /// SEPA direct debit against a validated IBAN under a signed mandate.
+    SepaDebit {
+        iban: Iban,
+        holder: String,
+        mandate_reference: String,
+    },
+    /// Invoiced customers paying by bank transfer within `terms_days`.
+    BankTransfer {
+        reference: String,
+        terms_days: u32,
+    },
+    /// Debit from a prepaid balance wallet.
+    Prepaid {
+        wallet_id: WalletId,
+    },
 }
 
 impl PaymentMethod {
-    pub fn card(mask: &str, holder: &str) -> Self {
-        PaymentMethod::Card {
-            card_number_masked: mask.to_string(),
-            holder: holder.to_string(),
-        }
+    pub fn card(card: &StoredCard) -> Self {
+        PaymentMethod::Card(card.token().clone())
     }
     pub fn paypal(account: &str) -> Self {
         PaymentMethod::Paypal {
             account: account.to_string(),
         }
     }
+    pub fn sepa_debit(
+        iban: &str,
+        holder: &str,
+        mandate_reference: &str,
+    ) -> Result<Self, PaymentMethodError> {
+        let iban = Iban::parse(iban)?;
+        if holder.trim().is_empty() {
+            return Err(PaymentMethodError::EmptyHolder);
+        }
+        if mandate_reference.trim().is_empty() {
+            return Err(PaymentMethodError::EmptyMandateReference);
+        }
+        Ok(PaymentMethod::SepaDebit {
+            iban,
+            holder: holder.to_string(),
+            mandate_reference: mandate_reference.to_string(),
+        })
+    }
+    pub fn bank_transfer(reference: &str, terms_days: u32) -> Result<Self, PaymentMethodError> {
+        if reference.trim().is_empty() {
+            return Err(PaymentMethodError::EmptyReference);
+        }
+        if !(1..=120).contains(&terms_days) {
+            return Err(PaymentMethodError::InvalidTerms(terms_days));
+        }
+        Ok(PaymentMethod::BankTransfer {
+            reference: reference.to_string(),
+            terms_days,
+        })
+    }
+    pub fn prepaid(wallet_id: &WalletId) -> Self {
+        PaymentMethod::Prepaid {
+            wallet_id: wallet_id.clone(),
+        }
+    }
+
+    /// One-line description that shows no more than masked account data,
+    /// e.g. `card tok_...` or `paypal a***@example.com`.
+    pub fn masked(&self) -> String {
+        match self {
+            PaymentMethod::Card(token) => format!("card {}", token),
+            PaymentMethod::Paypal { account } => {
+                let (local, domain) = account.split_once('@').unwrap_or((account, ""));
+                let first: String = local.chars().take(1).collect();
+                format!("paypal {}***@{}", first, domain)
+            }
+            PaymentMethod::SepaDebit { iban, .. } => format!("sepa {}", iban.masked()),
+            PaymentMethod::BankTransfer {
+                reference,
+                terms_days,
+            } => format!("bank transfer {} ({} days)", reference, terms_days),
+            PaymentMethod::Prepaid { wallet_id } => format!("prepaid {}", wallet_id.0),
+        }
+    }
+
+    pub fn kind(&self) -> PaymentKind {
+        match self {
+            PaymentMethod::Card(_) => PaymentKind::Card,
+            PaymentMethod::Paypal { .. } => PaymentKind::Paypal,
+            PaymentMethod::SepaDebit { .. } => PaymentKind::SepaDebit,
+            PaymentMethod::BankTransfer { .. } => PaymentKind::BankTransfer,
+            PaymentMethod::Prepaid { .. } => PaymentKind::Prepaid,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A product costs `price_cents` from `effective_from` until the next change.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PriceChange {
+    pub effective_from: DateTime<Utc>,
+    pub price_cents: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Product {
     pub id: ProductId,
     pub name: String,
+    /// Base price, in effect before the first of `price_changes`.
     pub price_cents: u64,
+    /// Past and scheduled prices, sorted by `effective_from`; use
+    /// `with_price_change` to keep them sorted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub price_changes: Vec<PriceChange>,
 }
 
 impl Product {
@@ -88,15 +311,52 @@ impl Product {
             id: ProductId(id.to_string()),
             name: name.to_string(),
             price_cents,
+            price_changes: Vec::new(),
+        }
+    }
+
+    /// Same product, costing `price_cents` from `effective_from` on. A change
+    /// at the same instant is replaced.
+    pub fn with_price_change(mut self, effective_from: DateTime<Utc>, price_cents: u64) -> Self {
+        let at = self
+            .price_changes
+            .partition_point(|c| c.effective_from < effective_from);
+        let change = PriceChange {
+            effective_from,
+            price_cents,
+        };
+        match self.price_changes.get_mut(at) {
+            Some(c) if c.effective_from == effective_from => *c = change,
+            _ => self.price_changes.insert(at, change),
         }
+        self
+    }
+
+    /// The price in effect at `at`.
+    pub fn price_at(&self, at: DateTime<Utc>) -> u64 {
+        let n = self
+            .price_changes
+            .partition_point(|c| c.effective_from <= at);
+        match n {
+            0 => self.price_cents,
+            n => self.price_changes[n - 1].price_cents,
+        }
+    }
+
+    pub fn current_price(&self) -> u64 {
+        self.price_at(Utc::now())
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Service {
     pub id: ServiceId,
     pub name: String,
     pub products: Vec<Product>,
+    /// Payment kinds this service may be paid with; empty accepts every kind.
+    /// See `policy`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accepted_payments: Vec<PaymentKind>,
 }
 
 impl Service {
@@ -105,8 +365,28 @@ impl Service {
             id: ServiceId(id.to_string()),
             name: name.to_string(),
             products,
+            accepted_payments: Vec::new(),
         }
     }
+
+    /// Same service, accepting only `kinds` (every kind if empty). Repeated
+    /// kinds are dropped.
+    pub fn accepting(self, kinds: &[PaymentKind]) -> Self {
+        let mut accepted_payments: Vec<PaymentKind> = Vec::new();
+        for k in kinds {
+            if !accepted_payments.contains(k) {
+                accepted_payments.push(*k);
+            }
+        }
+        Service {
+            accepted_payments,
+            ..self
+        }
+    }
+
+    pub fn accepts(&self, kind: PaymentKind) -> bool {
+        self.accepted_payments.is_empty() || self.accepted_payments.contains(&kind)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -115,6 +395,13 @@ pub struct ServiceUsage {
     pub service_id: ServiceId,
     pub product_id: ProductId,
     pub payment_used: Option<PaymentMethod>,
+    pub occurred_at: DateTime<Utc>,
+    /// The account billed for this usage; `None` bills the user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<AccountId>,
+    /// Set by clients that may retry the write; see `idempotency`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<IdempotencyKey>,
 }
 
 impl ServiceUsage {
@@ -129,9 +416,38 @@ impl ServiceUsage {
             service_id: service_id.clone(),
             product_id: product_id.clone(),
             payment_used,
+            occurred_at: Utc::now(),
+            account_id: None,
+            idempotency_key: None,
+        }
+    }
+
+    /// Same usage, billed to `account_id` instead of the user.
+    pub fn for_account(self, account_id: &AccountId) -> Self {
+        ServiceUsage {
+            account_id: Some(account_id.clone()),
+            ..self
+        }
+    }
+
+    /// Same usage, recorded at most once per `key` (within the retention
+    /// window).
+    pub fn with_idempotency_key(self, key: &str) -> Self {
+        ServiceUsage {
+            idempotency_key: Some(IdempotencyKey(key.to_string())),
+            ..self
+        }
+    }
+
+    /// Same usage, recorded as having happened at `occurred_at`.
+    pub fn at(self, occurred_at: DateTime<Utc>) -> Self {
+        ServiceUsage {
+            occurred_at,
+            ..self
         }
     }
 }
 
 // small helper types for collections
 pub type ServiceMap = HashMap<ServiceId, Service>;
+pub type UserMap = HashMap<UserId, User>;
@@ -0,0 +1,234 @@
+//! Validated building blocks for non-card payment methods: IBANs for SEPA
+//! direct debit and bank transfer references for invoiced customers.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaymentMethodError {
+    /// IBAN contains characters other than A-Z, 0-9 and spaces.
+    IbanInvalidCharacters,
+    /// Country code unknown or IBAN length wrong for that country.
+    IbanInvalidLength {
+        country: String,
+        len: usize,
+    },
+    /// ISO 13616 mod-97 check failed.
+    IbanChecksumMismatch,
+    /// SEPA direct debit is not available for this IBAN country.
+    IbanNotSepa(String),
+    EmptyHolder,
+    EmptyMandateReference,
+    EmptyReference,
+    /// Payment terms must be between 1 and 120 days.
+    InvalidTerms(u32),
+}
+
+impl fmt::Display for PaymentMethodError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentMethodError::IbanInvalidCharacters => {
+                write!(f, "IBAN may only contain letters and digits")
+            }
+            PaymentMethodError::IbanInvalidLength { country, len } => {
+                write!(
+                    f,
+                    "IBAN length {} is not valid for country {:?}",
+                    len, country
+                )
+            }
+            PaymentMethodError::IbanChecksumMismatch => write!(f, "IBAN checksum mismatch"),
+            PaymentMethodError::IbanNotSepa(c) => {
+                write!(f, "country {} is not part of the SEPA scheme", c)
+            }
+            PaymentMethodError::EmptyHolder => write!(f, "account holder must not be empty"),
+            PaymentMethodError::EmptyMandateReference => {
+                write!(f, "SEPA mandate reference must not be empty")
+            }
+            PaymentMethodError::EmptyReference => {
+                write!(f, "bank transfer reference must not be empty")
+            }
+            PaymentMethodError::InvalidTerms(d) => {
+                write!(f, "payment terms of {} days are out of range", d)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PaymentMethodError {}
+
+/// IBAN lengths for the SEPA countries we accept.
+const SEPA_IBAN_LENGTHS: &[(&str, usize)] = &[
+    ("AD", 24),
+    ("AT", 20),
+    ("BE", 16),
+    ("BG", 22),
+    ("CH", 21),
+    ("CY", 28),
+    ("CZ", 24),
+    ("DE", 22),
+    ("DK", 18),
+    ("EE", 20),
+    ("ES", 24),
+    ("FI", 18),
+    ("FR", 27),
+    ("GB", 22),
+    ("GR", 27),
+    ("HR", 21),
+    ("HU", 28),
+    ("IE", 22),
+    ("IS", 26),
+    ("IT", 27),
+    ("LI", 21),
+    ("LT", 20),
+    ("LU", 20),
+    ("LV", 21),
+    ("MC", 27),
+    ("MT", 31),
+    ("NL", 18),
+    ("NO", 15),
+    ("PL", 28),
+    ("PT", 25),
+    ("RO", 24),
+    ("SE", 24),
+    ("SI", 19),
+    ("SK", 24),
+    ("SM", 27),
+    ("VA", 22),
+];
+
+/// A checksum-validated IBAN in electronic format (upper case, no spaces).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Iban(String);
+
+impl Iban {
+    pub fn parse(input: &str) -> Result<Self, PaymentMethodError> {
+        let compact: String = input
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        if compact.len() < 5 || !compact.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(PaymentMethodError::IbanInvalidCharacters);
+        }
+        let country = &compact[..2];
+        if !country.chars().all(|c| c.is_ascii_uppercase())
+            || !compact[2..4].chars().all(|c| c.is_ascii_digit())
+        {
+            return Err(PaymentMethodError::IbanInvalidCharacters);
+        }
+        let expected = SEPA_IBAN_LENGTHS
+            .iter()
+            .find(|(c, _)| *c == country)
+            .map(|(_, len)| *len)
+            .ok_or_else(|| PaymentMethodError::IbanNotSepa(country.to_string()))?;
+        if compact.len() != expected {
+            return Err(PaymentMethodError::IbanInvalidLength {
+                country: country.to_string(),
+                len: compact.len(),
+            });
+        }
+        if iban_mod97(&compact) != 1 {
+            return Err(PaymentMethodError::IbanChecksumMismatch);
+        }
+        Ok(Iban(compact))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn country(&self) -> &str {
+        &self.0[..2]
+    }
+
+    /// Display form that hides all but the country and last four characters.
+    pub fn masked(&self) -> String {
+        format!("{}** **** {}", self.country(), &self.0[self.0.len() - 4..])
+    }
+}
+
+/// Move the first four characters to the end, map letters to 10..35 and
+/// reduce the resulting number modulo 97 digit by digit.
+fn iban_mod97(compact: &str) -> u32 {
+    let rearranged = compact[4..].chars().chain(compact[..4].chars());
+    let mut rem: u32 = 0;
+    for c in rearranged {
+        let v = c.to_digit(36).unwrap_or(0);
+        rem = if v >= 10 {
+            (rem * 100 + v) % 97
+        } else {
+            (rem * 10 + v) % 97
+        };
+    }
+    rem
+}
+
+impl TryFrom<String> for Iban {
+    type Error = PaymentMethodError;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Iban::parse(&s)
+    }
+}
+
+impl From<Iban> for String {
+    fn from(i: Iban) -> Self {
+        i.0
+    }
+}
+
+impl fmt::Display for Iban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.masked())
+    }
+}
+
+/// The kind of a `PaymentMethod`, without its data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentKind {
+    Card,
+    Paypal,
+    SepaDebit,
+    BankTransfer,
+    Prepaid,
+}
+
+impl PaymentKind {
+    /// Every kind, in declaration order.
+    pub const ALL: [PaymentKind; 5] = [
+        PaymentKind::Card,
+        PaymentKind::Paypal,
+        PaymentKind::SepaDebit,
+        PaymentKind::BankTransfer,
+        PaymentKind::Prepaid,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PaymentKind::Card => "card",
+            PaymentKind::Paypal => "paypal",
+            PaymentKind::SepaDebit => "sepa_debit",
+            PaymentKind::BankTransfer => "bank_transfer",
+            PaymentKind::Prepaid => "prepaid",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<PaymentKind> {
+        match s {
+            "card" => Some(PaymentKind::Card),
+            "paypal" => Some(PaymentKind::Paypal),
+            "sepa_debit" => Some(PaymentKind::SepaDebit),
+            "bank_transfer" => Some(PaymentKind::BankTransfer),
+            "prepaid" => Some(PaymentKind::Prepaid),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PaymentKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
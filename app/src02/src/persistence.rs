@@ -1,180 +1,2036 @@
+use crate::account::{Account, AccountId, Membership, Role};
+use crate::budget::{BillingPeriod, Budget, BudgetId, Enforcement};
+use crate::credit::{CreditApplication, CreditNote, CreditNoteId, Issued, Payer, Settlement};
+use crate::dunning::{
+    DunningCase, DunningCaseId, DunningEvent, DunningSchedule, DunningState, Stage,
+};
+use crate::idempotency::{IdempotencyKey, Recorded, DEFAULT_RETENTION};
+use crate::migrations::{migrate, MigrateOptions, MigrationError};
 use crate::models::{
-    PaymentMethod, Product, ProductId, Service, ServiceId, ServiceUsage, User, UserId,
+    PaymentMethod, PriceChange, Product, ProductId, Profile, Service, ServiceId, ServiceUsage,
+    User, UserId,
+};
+use crate::payment::PaymentKind;
+use crate::privacy::{
+    self, AuditRecord, ErasureReport, ExportedMembership, PrivacyAction, UserExport,
 };
+use crate::profile::{BillingAddress, Email, Locale, ProfileUpdate, Timezone};
+use crate::query::{Cursor, Page, UsageAggregate, UsageQuery, UsageRecord};
+use crate::reporting::ReportPeriod;
+use crate::vault::{CardBrand, CardExpiry, CardToken, StoredCard};
+use crate::wallet::{PrepaidWallet, WalletId};
+use chrono::{DateTime, Duration, Utc};
 use serde_json;
-use sqlx::{Row, SqlitePool};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteRow};
+use sqlx::{QueryBuilder, Row, Sqlite, SqliteConnection, SqlitePool};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
 
-pub async fn init_db(pool: &SqlitePool) -> Result<(), sqlx::Error> {
-    // create tables
-    sqlx::query(
-        r#"CREATE TABLE IF NOT EXISTS users (
-            id TEXT PRIMARY KEY,
-            display_name TEXT NOT NULL,
-            default_payment TEXT NULL
-        );"#,
-    )
-    .execute(pool)
-    .await?;
+/// A row that could not be turned back into a domain value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptRow {
+    pub table: &'static str,
+    /// Primary key of the offending row (as text).
+    pub row_id: String,
+    pub column: &'static str,
+    pub reason: String,
+}
 
-    sqlx::query(
-        r#"CREATE TABLE IF NOT EXISTS services (
-            id TEXT PRIMARY KEY,
-            name TEXT NOT NULL
-        );"#,
-    )
-    .execute(pool)
-    .await?;
+impl fmt::Display for CorruptRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "corrupt {}.{} in row {}: {}",
+            self.table, self.column, self.row_id, self.reason
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum PersistenceError {
+    Database(sqlx::Error),
+    Migration(MigrationError),
+    /// A value could not be encoded before writing it.
+    Encoding {
+        table: &'static str,
+        row_id: String,
+        source: serde_json::Error,
+    },
+    CorruptRow(CorruptRow),
+    /// The database refused a write that breaks one of its constraints.
+    Constraint {
+        table: &'static str,
+        reason: String,
+    },
+    /// Another user already has this email.
+    EmailTaken(Email),
+    /// Another service already offers this product.
+    DuplicateProduct {
+        product_id: ProductId,
+        existing: ServiceId,
+    },
+}
+
+impl fmt::Display for PersistenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistenceError::Database(e) => write!(f, "database error: {}", e),
+            PersistenceError::Migration(e) => write!(f, "{}", e),
+            PersistenceError::Encoding {
+                table,
+                row_id,
+                source,
+            } => write!(f, "cannot encode {} row {}: {}", table, row_id, source),
+            PersistenceError::CorruptRow(c) => write!(f, "{}", c),
+            PersistenceError::Constraint { table, reason } => {
+                write!(f, "{} constraint violated: {}", table, reason)
+            }
+            PersistenceError::EmailTaken(email) => {
+                write!(f, "email {} is already used by another user", email)
+            }
+            PersistenceError::DuplicateProduct {
+                product_id,
+                existing,
+            } => write!(
+                f,
+                "product {} is already offered by service {}",
+                product_id.0, existing.0
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PersistenceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PersistenceError::Database(e) => Some(e),
+            PersistenceError::Migration(e) => Some(e),
+            PersistenceError::Encoding { source, .. } => Some(source),
+            PersistenceError::CorruptRow(_)
+            | PersistenceError::Constraint { .. }
+            | PersistenceError::EmailTaken(_)
+            | PersistenceError::DuplicateProduct { .. } => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for PersistenceError {
+    fn from(e: sqlx::Error) -> Self {
+        PersistenceError::Database(e)
+    }
+}
 
+impl From<MigrationError> for PersistenceError {
+    fn from(e: MigrationError) -> Self {
+        PersistenceError::Migration(e)
+    }
+}
+
+/// How reads treat rows that cannot be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadMode {
+    /// Fail on the first corrupt row.
+    #[default]
+    Strict,
+    /// Skip corrupt rows and report each of them in `ReadOutcome::skipped`.
+    Lenient,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadOutcome<T> {
+    pub items: Vec<T>,
+    pub skipped: Vec<CorruptRow>,
+}
+
+impl<T> ReadOutcome<T> {
+    fn collect(
+        mode: ReadMode,
+        decoded: impl IntoIterator<Item = Result<T, CorruptRow>>,
+    ) -> Result<Self, PersistenceError> {
+        let mut out = ReadOutcome {
+            items: Vec::new(),
+            skipped: Vec::new(),
+        };
+        for d in decoded {
+            match (d, mode) {
+                (Ok(item), _) => out.items.push(item),
+                (Err(c), ReadMode::Strict) => return Err(PersistenceError::CorruptRow(c)),
+                (Err(c), ReadMode::Lenient) => out.skipped.push(c),
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Column accessors for one row, producing `CorruptRow` with the row's id.
+struct RowReader<'r> {
+    row: &'r SqliteRow,
+    table: &'static str,
+    row_id: String,
+}
+
+impl<'r> RowReader<'r> {
+    fn new(row: &'r SqliteRow, table: &'static str, id_column: &'static str) -> Self {
+        let row_id = row
+            .try_get::<String, _>(id_column)
+            .or_else(|_| row.try_get::<i64, _>(id_column).map(|i| i.to_string()))
+            .unwrap_or_else(|_| "?".to_string());
+        RowReader { row, table, row_id }
+    }
+
+    fn corrupt(&self, column: &'static str, reason: impl fmt::Display) -> CorruptRow {
+        CorruptRow {
+            table: self.table,
+            row_id: self.row_id.clone(),
+            column,
+            reason: reason.to_string(),
+        }
+    }
+
+    fn get<T>(&self, column: &'static str) -> Result<T, CorruptRow>
+    where
+        T: for<'a> sqlx::Decode<'a, sqlx::Sqlite> + sqlx::Type<sqlx::Sqlite>,
+    {
+        self.row
+            .try_get(column)
+            .map_err(|e| self.corrupt(column, e))
+    }
+
+    fn non_negative(&self, column: &'static str) -> Result<u64, CorruptRow> {
+        let v: i64 = self.get(column)?;
+        u64::try_from(v).map_err(|_| self.corrupt(column, format!("negative value {}", v)))
+    }
+
+    fn payment(&self, column: &'static str) -> Result<Option<PaymentMethod>, CorruptRow> {
+        let raw: Option<String> = self.get(column)?;
+        raw.map(|s| serde_json::from_str(&s).map_err(|e| self.corrupt(column, e)))
+            .transpose()
+    }
+
+    fn timestamp_ms(&self, column: &'static str) -> Result<DateTime<Utc>, CorruptRow> {
+        let ms: i64 = self.get(column)?;
+        DateTime::from_timestamp_millis(ms)
+            .ok_or_else(|| self.corrupt(column, format!("timestamp {} out of range", ms)))
+    }
+
+    fn optional_timestamp_ms(
+        &self,
+        column: &'static str,
+    ) -> Result<Option<DateTime<Utc>>, CorruptRow> {
+        match self.get::<Option<i64>>(column)? {
+            Some(_) => self.timestamp_ms(column).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+fn encode_payment(
+    table: &'static str,
+    row_id: &str,
+    pm: Option<&PaymentMethod>,
+) -> Result<Option<String>, PersistenceError> {
+    pm.map(|pm| {
+        serde_json::to_string(pm).map_err(|source| PersistenceError::Encoding {
+            table,
+            row_id: row_id.to_string(),
+            source,
+        })
+    })
+    .transpose()
+}
+
+/// Open a pool for `url`, creating the database file if it does not exist.
+pub async fn connect(url: &str) -> Result<SqlitePool, sqlx::Error> {
+    let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+    SqlitePool::connect_with(options).await
+}
+
+/// Bring the schema up to date; see `migrations::migrate`.
+pub async fn init_db(pool: &SqlitePool) -> Result<(), PersistenceError> {
+    migrate(pool, MigrateOptions::default()).await?;
+    Ok(())
+}
+
+/// Insert or replace a vaulted card; payment methods may name it from then on.
+pub async fn save_card(pool: &SqlitePool, card: &StoredCard) -> Result<(), PersistenceError> {
     sqlx::query(
-        r#"CREATE TABLE IF NOT EXISTS products (
-            id TEXT PRIMARY KEY,
-            service_id TEXT NOT NULL,
-            name TEXT NOT NULL,
-            price_cents INTEGER NOT NULL,
-            FOREIGN KEY(service_id) REFERENCES services(id)
-        );"#,
+        "INSERT OR REPLACE INTO cards (token, brand, last4, exp_month, exp_year, holder) VALUES (?, ?, ?, ?, ?, ?)",
     )
+    .bind(card.token().as_str())
+    .bind(card.brand().as_str())
+    .bind(card.last4())
+    .bind(card.expiry().month() as i64)
+    .bind(card.expiry().year() as i64)
+    .bind(card.holder())
     .execute(pool)
     .await?;
+    Ok(())
+}
 
-    sqlx::query(
-        r#"CREATE TABLE IF NOT EXISTS usages (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            user_id TEXT NOT NULL,
-            service_id TEXT NOT NULL,
-            product_id TEXT NOT NULL,
-            payment_used TEXT NULL
-        );"#,
+pub async fn get_card(
+    pool: &SqlitePool,
+    token: &CardToken,
+) -> Result<Option<StoredCard>, PersistenceError> {
+    let row = sqlx::query(
+        "SELECT token, brand, last4, exp_month, exp_year, holder FROM cards WHERE token = ?",
     )
-    .execute(pool)
+    .bind(token.as_str())
+    .fetch_optional(pool)
     .await?;
+    let Some(r) = row else {
+        return Ok(None);
+    };
+    Ok(Some(decode_card(&r).map_err(PersistenceError::CorruptRow)?))
+}
+
+fn decode_card(row: &SqliteRow) -> Result<StoredCard, CorruptRow> {
+    let r = RowReader::new(row, "cards", "token");
+    let token = CardToken::parse(r.get::<String>("token")?).map_err(|e| r.corrupt("token", e))?;
+    let brand: String = r.get("brand")?;
+    let brand = CardBrand::parse(&brand)
+        .ok_or_else(|| r.corrupt("brand", format!("unknown brand {:?}", brand)))?;
+    let exp_month: i64 = r.get("exp_month")?;
+    let exp_year: i64 = r.get("exp_year")?;
+    let expiry = CardExpiry::new(exp_month as u32, exp_year as i32)
+        .map_err(|e| r.corrupt("exp_month", e))?;
+    let last4: String = r.get("last4")?;
+    let holder: String = r.get("holder")?;
+    StoredCard::from_parts(token, brand, &last4, expiry, &holder).map_err(|e| r.corrupt("last4", e))
+}
+
+/// Message of the card triggers (migration 16) refusing a payment method
+/// whose card is not in `cards`.
+pub(crate) const CARD_REFERENCE_ERROR: &str = "payment method references unknown card";
+
+/// `Constraint` for a write to `table` refused by a card trigger.
+fn card_write_error(table: &'static str, e: sqlx::Error) -> PersistenceError {
+    match &e {
+        sqlx::Error::Database(db) if db.message() == CARD_REFERENCE_ERROR => {
+            PersistenceError::Constraint {
+                table,
+                reason: CARD_REFERENCE_ERROR.to_string(),
+            }
+        }
+        _ => PersistenceError::Database(e),
+    }
+}
 
+/// Insert or replace a user with their saved payment methods and billing
+/// addresses in one transaction; `EmailTaken` if another user has the email.
+/// A new user starts at revision 0 and every save moves it on by one,
+/// whatever `user.profile.revision` says.
+pub async fn save_user(pool: &SqlitePool, user: &User) -> Result<(), PersistenceError> {
+    let mut tx = pool.begin().await?;
+    write_user(&mut tx, user).await?;
+    tx.commit().await?;
     Ok(())
 }
 
-pub async fn save_user(pool: &SqlitePool, user: &User) -> Result<(), sqlx::Error> {
-    let payment_json = user.profile.default_payment.as_ref()
-        .map(|pm| serde_json::to_string(pm).unwrap());
+/// Replace the profile of an existing user if the stored one is at the
+/// revision before `profile.revision`.
+pub async fn update_profile(
+    pool: &SqlitePool,
+    user_id: &UserId,
+    profile: &Profile,
+) -> Result<ProfileUpdate, PersistenceError> {
+    let mut tx = pool.begin().await?;
+    // a write first, so a concurrent update of the user waits for this
+    // transaction instead of reading the revision it replaces
+    // (`write_user` then moves the revision on)
+    let claimed = sqlx::query("UPDATE users SET revision = revision WHERE id = ? AND revision = ?")
+        .bind(&user_id.0)
+        .bind(i64::from(profile.revision) - 1)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if claimed == 0 {
+        let exists = sqlx::query("SELECT 1 FROM users WHERE id = ?")
+            .bind(&user_id.0)
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some();
+        return Ok(if exists {
+            ProfileUpdate::Stale
+        } else {
+            ProfileUpdate::UnknownUser
+        });
+    }
+    let user = User {
+        id: user_id.clone(),
+        profile: profile.clone(),
+    };
+    write_user(&mut tx, &user).await?;
+    tx.commit().await?;
+    Ok(ProfileUpdate::Updated(user))
+}
+
+async fn write_user(conn: &mut SqliteConnection, user: &User) -> Result<(), PersistenceError> {
+    let profile = &user.profile;
+    let mut payments = profile
+        .payment_methods
+        .iter()
+        .map(|pm| encode_payment("users", &user.id.0, Some(pm)))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .flatten();
+    if let Some(email) = &profile.email {
+        let holder = sqlx::query("SELECT id FROM users WHERE email = ? AND id <> ?")
+            .bind(email.as_str())
+            .bind(&user.id.0)
+            .fetch_optional(&mut *conn)
+            .await?;
+        if holder.is_some() {
+            return Err(PersistenceError::EmailTaken(email.clone()));
+        }
+    }
+    // an upsert, not INSERT OR REPLACE: replacing would delete whichever
+    // row a unique column collides with
     sqlx::query(
-        "INSERT OR REPLACE INTO users (id, display_name, default_payment) VALUES (?, ?, ?)",
+        "INSERT INTO users (id, display_name, default_payment, email, locale, timezone, revision) \
+         VALUES (?, ?, ?, ?, ?, ?, 0) ON CONFLICT(id) DO UPDATE SET \
+         display_name = excluded.display_name, default_payment = excluded.default_payment, \
+         email = excluded.email, locale = excluded.locale, timezone = excluded.timezone, \
+         revision = users.revision + 1",
     )
     .bind(&user.id.0)
-    .bind(&user.profile.display_name)
-    .bind(payment_json)
-    .execute(pool)
-    .await?;
+    .bind(&profile.display_name)
+    .bind(payments.next())
+    .bind(profile.email.as_ref().map(Email::as_str))
+    .bind(profile.locale.as_ref().map(Locale::as_str))
+    .bind(profile.timezone.as_ref().map(Timezone::as_str))
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| match (&e, &profile.email) {
+        // another writer took the email since the check above
+        (sqlx::Error::Database(db), Some(email)) if db.message().contains("users.email") => {
+            PersistenceError::EmailTaken(email.clone())
+        }
+        _ => card_write_error("users", e),
+    })?;
+
+    delete_user_details(&mut *conn, &user.id).await?;
+    for (position, payment) in (1i64..).zip(payments) {
+        sqlx::query(
+            "INSERT INTO user_payment_methods (user_id, position, payment) VALUES (?, ?, ?)",
+        )
+        .bind(&user.id.0)
+        .bind(position)
+        .bind(payment)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| card_write_error("users", e))?;
+    }
+    for (position, a) in (0i64..).zip(&profile.billing_addresses) {
+        sqlx::query(
+            "INSERT INTO user_billing_addresses \
+             (user_id, position, label, line1, line2, city, postal_code, region, country) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&user.id.0)
+        .bind(position)
+        .bind(&a.label)
+        .bind(&a.line1)
+        .bind(&a.line2)
+        .bind(&a.city)
+        .bind(&a.postal_code)
+        .bind(&a.region)
+        .bind(&a.country)
+        .execute(&mut *conn)
+        .await?;
+    }
     Ok(())
 }
 
-pub async fn save_service(pool: &SqlitePool, service: &Service) -> Result<(), sqlx::Error> {
+/// Drop `user_id`'s saved payment methods (but the default) and billing
+/// addresses.
+async fn delete_user_details(
+    conn: &mut SqliteConnection,
+    user_id: &UserId,
+) -> Result<(), PersistenceError> {
+    for table in ["user_payment_methods", "user_billing_addresses"] {
+        sqlx::query(&format!("DELETE FROM {} WHERE user_id = ?", table))
+            .bind(&user_id.0)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Insert or replace a service and all of its products in one transaction.
+pub async fn save_service(pool: &SqlitePool, service: &Service) -> Result<(), PersistenceError> {
+    save_services(pool, std::slice::from_ref(service)).await
+}
+
+/// `save_service` for each of `services`, in order, in one transaction.
+pub async fn save_services(
+    pool: &SqlitePool,
+    services: &[Service],
+) -> Result<(), PersistenceError> {
+    let mut tx = pool.begin().await?;
+    for service in services {
+        write_service(&mut tx, service).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn write_service(
+    conn: &mut SqliteConnection,
+    service: &Service,
+) -> Result<(), PersistenceError> {
     sqlx::query("INSERT OR REPLACE INTO services (id, name) VALUES (?, ?)")
         .bind(&service.id.0)
         .bind(&service.name)
-        .execute(pool)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("DELETE FROM service_payment_kinds WHERE service_id = ?")
+        .bind(&service.id.0)
+        .execute(&mut *conn)
+        .await?;
+    for kind in &service.accepted_payments {
+        sqlx::query("INSERT INTO service_payment_kinds (service_id, kind) VALUES (?, ?)")
+            .bind(&service.id.0)
+            .bind(kind.as_str())
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    // the service is replaced whole: products it no longer lists go
+    let stored: Vec<String> = sqlx::query_scalar("SELECT id FROM products WHERE service_id = ?")
+        .bind(&service.id.0)
+        .fetch_all(&mut *conn)
         .await?;
+    for id in stored
+        .iter()
+        .filter(|id| !service.products.iter().any(|p| p.id.0 == **id))
+    {
+        for sql in [
+            "DELETE FROM product_prices WHERE product_id = ?",
+            "DELETE FROM products WHERE id = ?",
+        ] {
+            sqlx::query(sql).bind(id).execute(&mut *conn).await?;
+        }
+    }
 
-    for p in &service.products {
+    for (i, p) in service.products.iter().enumerate() {
+        // products are keyed by id alone: one service per product
+        let existing = if service.products[..i].iter().any(|q| q.id == p.id) {
+            Some(service.id.0.clone())
+        } else {
+            sqlx::query_scalar("SELECT service_id FROM products WHERE id = ? AND service_id <> ?")
+                .bind(&p.id.0)
+                .bind(&service.id.0)
+                .fetch_optional(&mut *conn)
+                .await?
+        };
+        if let Some(existing) = existing {
+            return Err(PersistenceError::DuplicateProduct {
+                product_id: p.id.clone(),
+                existing: ServiceId(existing),
+            });
+        }
         sqlx::query("INSERT OR REPLACE INTO products (id, service_id, name, price_cents) VALUES (?, ?, ?, ?)")
             .bind(&p.id.0)
             .bind(&service.id.0)
             .bind(&p.name)
             .bind(p.price_cents as i64)
-            .execute(pool)
+            .execute(&mut *conn)
             .await?;
+        // the product's whole price history is replaced, like its row
+        sqlx::query("DELETE FROM product_prices WHERE product_id = ?")
+            .bind(&p.id.0)
+            .execute(&mut *conn)
+            .await?;
+        for c in &p.price_changes {
+            sqlx::query(
+                "INSERT INTO product_prices (product_id, effective_from, price_cents) VALUES (?, ?, ?)",
+            )
+            .bind(&p.id.0)
+            .bind(c.effective_from.timestamp_millis())
+            .bind(c.price_cents as i64)
+            .execute(&mut *conn)
+            .await?;
+        }
     }
     Ok(())
 }
 
-pub async fn save_usage(pool: &SqlitePool, usage: &ServiceUsage) -> Result<(), sqlx::Error> {
-    let payment_json = usage
-        .payment_used
-        .as_ref()
-        .map(|pm| serde_json::to_string(pm).unwrap());
-    sqlx::query(
-        "INSERT INTO usages (user_id, service_id, product_id, payment_used) VALUES (?, ?, ?, ?)",
-    )
+/// Store a usage. A usage whose idempotency key is already stored (within
+/// `DEFAULT_RETENTION`) is not written again; see `record_usage`.
+pub async fn save_usage(pool: &SqlitePool, usage: &ServiceUsage) -> Result<(), PersistenceError> {
+    record_usage(pool, usage, DEFAULT_RETENTION).await?;
+    Ok(())
+}
+
+/// Columns of `decode_usage`, for `SELECT`s over `usages u`.
+const USAGE_COLUMNS: &str = "u.id, u.user_id, u.service_id, u.product_id, u.payment_used, \
+     u.occurred_at, u.account_id, u.idempotency_key";
+
+const INSERT_USAGE_SQL: &str =
+    "INSERT INTO usages (user_id, service_id, product_id, payment_used, \
+     payment_kind, occurred_at, account_id, idempotency_key, idempotency_key_at) ";
+
+/// Store a usage and return it with its id, or, when a usage with the same
+/// idempotency key was recorded less than `retention` ago, return that one
+/// without writing anything. An older holder of the key loses it.
+pub async fn record_usage(
+    pool: &SqlitePool,
+    usage: &ServiceUsage,
+    retention: Duration,
+) -> Result<Recorded, PersistenceError> {
+    // the row has no id before the insert; identify it by its user instead
+    let row_id = format!("new usage of {}", usage.user_id.0);
+    let payment_json = encode_payment("usages", &row_id, usage.payment_used.as_ref())?;
+    let now = Utc::now();
+    let mut tx = pool.begin().await?;
+    if let Some(key) = &usage.idempotency_key {
+        release_expired_key(&mut tx, key, now - retention).await?;
+        if let Some(stored) = usage_with_key(&mut tx, key).await? {
+            return Ok(Recorded::Replayed(stored));
+        }
+    }
+    let inserted = sqlx::query(&format!(
+        "{}VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        INSERT_USAGE_SQL
+    ))
     .bind(&usage.user_id.0)
     .bind(&usage.service_id.0)
     .bind(&usage.product_id.0)
     .bind(payment_json)
-    .execute(pool)
+    .bind(payment_kind(usage))
+    .bind(usage.occurred_at.timestamp_millis())
+    .bind(usage.account_id.as_ref().map(|a| a.0.as_str()))
+    .bind(usage.idempotency_key.as_ref().map(|k| k.0.as_str()))
+    .bind(
+        usage
+            .idempotency_key
+            .as_ref()
+            .map(|_| now.timestamp_millis()),
+    )
+    .execute(&mut *tx)
+    .await;
+    match (inserted, &usage.idempotency_key) {
+        (Ok(done), _) => {
+            tx.commit().await?;
+            Ok(Recorded::New(UsageRecord {
+                id: done.last_insert_rowid(),
+                usage: usage.clone(),
+            }))
+        }
+        // a concurrent writer stored the same key first
+        (Err(sqlx::Error::Database(db)), Some(key)) if db.is_unique_violation() => {
+            drop(tx);
+            let mut conn = pool.acquire().await?;
+            match usage_with_key(&mut conn, key).await? {
+                Some(stored) => Ok(Recorded::Replayed(stored)),
+                None => Err(PersistenceError::Database(sqlx::Error::Database(db))),
+            }
+        }
+        (Err(e), _) => Err(usage_write_error(e)),
+    }
+}
+
+/// The usage recorded with `key` less than `retention` ago, if any.
+pub async fn find_idempotent_usage(
+    pool: &SqlitePool,
+    key: &IdempotencyKey,
+    retention: Duration,
+) -> Result<Option<UsageRecord>, PersistenceError> {
+    let cutoff = (Utc::now() - retention).timestamp_millis();
+    let row = sqlx::query(&format!(
+        "SELECT {} FROM usages u WHERE u.idempotency_key = ? AND u.idempotency_key_at > ?",
+        USAGE_COLUMNS
+    ))
+    .bind(&key.0)
+    .bind(cutoff)
+    .fetch_optional(pool)
+    .await?;
+    row.as_ref()
+        .map(decode_usage_record)
+        .transpose()
+        .map_err(PersistenceError::CorruptRow)
+}
+
+/// Clear `key` from the usage holding it if it was recorded at or before
+/// `cutoff`, so a new usage may take it.
+async fn release_expired_key(
+    conn: &mut SqliteConnection,
+    key: &IdempotencyKey,
+    cutoff: DateTime<Utc>,
+) -> Result<(), PersistenceError> {
+    sqlx::query(
+        "UPDATE usages SET idempotency_key = NULL, idempotency_key_at = NULL \
+         WHERE idempotency_key = ? AND idempotency_key_at <= ?",
+    )
+    .bind(&key.0)
+    .bind(cutoff.timestamp_millis())
+    .execute(conn)
     .await?;
     Ok(())
 }
 
-pub async fn get_users(pool: &SqlitePool) -> Result<Vec<User>, sqlx::Error> {
-    let rows = sqlx::query("SELECT id, display_name, default_payment FROM users")
+async fn usage_with_key(
+    conn: &mut SqliteConnection,
+    key: &IdempotencyKey,
+) -> Result<Option<UsageRecord>, PersistenceError> {
+    let row = sqlx::query(&format!(
+        "SELECT {} FROM usages u WHERE u.idempotency_key = ?",
+        USAGE_COLUMNS
+    ))
+    .bind(&key.0)
+    .fetch_optional(conn)
+    .await?;
+    row.as_ref()
+        .map(decode_usage_record)
+        .transpose()
+        .map_err(PersistenceError::CorruptRow)
+}
+
+/// Start of the messages raised by the `usages` reference triggers
+/// (migrations 5 and 7).
+const USAGE_REFERENCE_ERROR: &str = "usage references";
+
+fn usage_write_error(e: sqlx::Error) -> PersistenceError {
+    match &e {
+        sqlx::Error::Database(db) if db.message().starts_with(USAGE_REFERENCE_ERROR) => {
+            PersistenceError::Constraint {
+                table: "usages",
+                reason: db.message().to_string(),
+            }
+        }
+        _ => card_write_error("usages", e),
+    }
+}
+
+fn payment_kind(usage: &ServiceUsage) -> Option<&'static str> {
+    usage.payment_used.as_ref().map(|pm| pm.kind().as_str())
+}
+
+/// Rows per multi-row INSERT; 9 bound parameters each keeps us well below
+/// SQLite's host parameter limit.
+const USAGE_INSERT_CHUNK: usize = 500;
+
+/// Insert many usages atomically: either all rows are written or none.
+/// Usages whose idempotency key is already stored (within
+/// `DEFAULT_RETENTION`) or repeats an earlier one in `usages` are skipped.
+/// Returns the number of rows inserted.
+pub async fn save_usages(
+    pool: &SqlitePool,
+    usages: &[ServiceUsage],
+) -> Result<usize, PersistenceError> {
+    if usages.is_empty() {
+        return Ok(0);
+    }
+    // encode everything up front so an encoding error aborts before any write
+    let payments = usages
+        .iter()
+        .map(|u| {
+            let row_id = format!("new usage of {}", u.user_id.0);
+            encode_payment("usages", &row_id, u.payment_used.as_ref())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let now = Utc::now();
+    let mut tx = pool.begin().await?;
+    let mut keys = HashSet::new();
+    let mut fresh = Vec::with_capacity(usages.len());
+    for (u, pm) in usages.iter().zip(payments) {
+        if let Some(key) = &u.idempotency_key {
+            if !keys.insert(key) {
+                continue;
+            }
+            release_expired_key(&mut tx, key, now - DEFAULT_RETENTION).await?;
+            if usage_with_key(&mut tx, key).await?.is_some() {
+                continue;
+            }
+        }
+        fresh.push((u, pm));
+    }
+    for chunk in fresh.chunks(USAGE_INSERT_CHUNK) {
+        let mut qb = QueryBuilder::<Sqlite>::new(INSERT_USAGE_SQL);
+        qb.push_values(chunk, |mut b, (u, pm)| {
+            b.push_bind(&u.user_id.0)
+                .push_bind(&u.service_id.0)
+                .push_bind(&u.product_id.0)
+                .push_bind(pm.clone())
+                .push_bind(payment_kind(u))
+                .push_bind(u.occurred_at.timestamp_millis())
+                .push_bind(u.account_id.as_ref().map(|a| a.0.as_str()))
+                .push_bind(u.idempotency_key.as_ref().map(|k| k.0.as_str()))
+                .push_bind(u.idempotency_key.as_ref().map(|_| now.timestamp_millis()));
+        });
+        qb.build()
+            .execute(&mut *tx)
+            .await
+            .map_err(usage_write_error)?;
+    }
+    tx.commit().await?;
+    Ok(fresh.len())
+}
+
+/// All users; fails on the first corrupt row (see `get_users_with`).
+pub async fn get_users(pool: &SqlitePool) -> Result<Vec<User>, PersistenceError> {
+    Ok(get_users_with(pool, ReadMode::Strict).await?.items)
+}
+
+pub async fn get_users_with(
+    pool: &SqlitePool,
+    mode: ReadMode,
+) -> Result<ReadOutcome<User>, PersistenceError> {
+    let rows = sqlx::query(&format!("SELECT {} FROM users", USER_COLUMNS))
         .fetch_all(pool)
         .await?;
-    let mut out = Vec::new();
-    for r in rows {
-        let id: String = r.get("id");
-        let display_name: String = r.get("display_name");
-        let default_payment: Option<String> = r.get("default_payment");
-        let payment = match default_payment {
-            Some(s) => serde_json::from_str::<PaymentMethod>(&s).ok(),
-            None => None,
-        };
-        let u = User::new(&id, &display_name, payment);
-        out.push(u);
+    let mut outcome = ReadOutcome::collect(mode, rows.iter().map(decode_user))?;
+    let mut conn = pool.acquire().await?;
+    let details = read_user_details(&mut conn, None).await?;
+    // a corrupt method or address drops its user like a corrupt user row
+    let mut users = Vec::with_capacity(outcome.items.len());
+    for user in outcome.items {
+        match attach_user_details(user, &details) {
+            Ok(user) => users.push(user),
+            Err(c) if mode == ReadMode::Lenient => outcome.skipped.push(c),
+            Err(c) => return Err(PersistenceError::CorruptRow(c)),
+        }
     }
-    Ok(out)
+    outcome.items = users;
+    Ok(outcome)
+}
+
+/// One user with their saved payment methods and billing addresses.
+pub async fn get_user(
+    pool: &SqlitePool,
+    user_id: &UserId,
+) -> Result<Option<User>, PersistenceError> {
+    read_user(&mut *pool.acquire().await?, user_id).await
+}
+
+async fn read_user(
+    conn: &mut SqliteConnection,
+    user_id: &UserId,
+) -> Result<Option<User>, PersistenceError> {
+    let row = sqlx::query(&format!("SELECT {} FROM users WHERE id = ?", USER_COLUMNS))
+        .bind(&user_id.0)
+        .fetch_optional(&mut *conn)
+        .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let user = decode_user(&row).map_err(PersistenceError::CorruptRow)?;
+    let details = read_user_details(conn, Some(user_id)).await?;
+    attach_user_details(user, &details)
+        .map(Some)
+        .map_err(PersistenceError::CorruptRow)
+}
+
+const USER_COLUMNS: &str = "id, display_name, default_payment, email, locale, timezone, revision";
+
+fn decode_user(row: &SqliteRow) -> Result<User, CorruptRow> {
+    let r = RowReader::new(row, "users", "id");
+    let id: String = r.get("id")?;
+    let display_name: String = r.get("display_name")?;
+    let payment = r.payment("default_payment")?;
+    let mut user = User::new(&id, &display_name, payment);
+    let profile = &mut user.profile;
+    profile.email = r
+        .get::<Option<String>>("email")?
+        .map(|e| Email::parse(&e).map_err(|err| r.corrupt("email", err)))
+        .transpose()?;
+    profile.locale = r
+        .get::<Option<String>>("locale")?
+        .map(|l| Locale::parse(&l).map_err(|err| r.corrupt("locale", err)))
+        .transpose()?;
+    profile.timezone = r
+        .get::<Option<String>>("timezone")?
+        .map(|t| Timezone::parse(&t).map_err(|err| r.corrupt("timezone", err)))
+        .transpose()?;
+    let revision = r.non_negative("revision")?;
+    profile.revision =
+        u32::try_from(revision).map_err(|_| r.corrupt("revision", "out of range"))?;
+    Ok(user)
+}
+
+/// Rows of `user_payment_methods` and `user_billing_addresses`, in order,
+/// decoded or not; see `attach_user_details`.
+struct UserDetails {
+    payments: Vec<(String, Result<PaymentMethod, CorruptRow>)>,
+    addresses: Vec<(String, Result<BillingAddress, CorruptRow>)>,
 }
 
-pub async fn get_services(pool: &SqlitePool) -> Result<Vec<Service>, sqlx::Error> {
-    // fetch services
-    let services_rows = sqlx::query("SELECT id, name FROM services")
+/// The details of `user_id`, or of every user.
+async fn read_user_details(
+    conn: &mut SqliteConnection,
+    user_id: Option<&UserId>,
+) -> Result<UserDetails, PersistenceError> {
+    let filter = match user_id {
+        Some(_) => "WHERE user_id = ?",
+        None => "",
+    };
+    let sql = format!(
+        "SELECT user_id, position, payment FROM user_payment_methods {} \
+         ORDER BY user_id, position",
+        filter
+    );
+    let mut query = sqlx::query(&sql);
+    if let Some(id) = user_id {
+        query = query.bind(&id.0);
+    }
+    let payments = query
+        .fetch_all(&mut *conn)
+        .await?
+        .iter()
+        .map(|row| {
+            let r = RowReader::new(row, "user_payment_methods", "user_id");
+            let decoded = r
+                .payment("payment")
+                .and_then(|pm| pm.ok_or_else(|| r.corrupt("payment", "missing payment method")));
+            (r.row_id.clone(), decoded)
+        })
+        .collect();
+
+    let sql = format!(
+        "SELECT user_id, label, line1, line2, city, postal_code, region, country \
+         FROM user_billing_addresses {} ORDER BY user_id, position",
+        filter
+    );
+    let mut query = sqlx::query(&sql);
+    if let Some(id) = user_id {
+        query = query.bind(&id.0);
+    }
+    let addresses = query
+        .fetch_all(&mut *conn)
+        .await?
+        .iter()
+        .map(|row| {
+            let r = RowReader::new(row, "user_billing_addresses", "user_id");
+            (r.row_id.clone(), decode_address(&r))
+        })
+        .collect();
+    Ok(UserDetails {
+        payments,
+        addresses,
+    })
+}
+
+fn decode_address(r: &RowReader) -> Result<BillingAddress, CorruptRow> {
+    let address = BillingAddress::new(
+        &r.get::<String>("line1")?,
+        &r.get::<String>("city")?,
+        &r.get::<String>("postal_code")?,
+        &r.get::<String>("country")?,
+    )
+    .map_err(|e| r.corrupt("line1", e))?;
+    Ok(BillingAddress {
+        label: r.get("label")?,
+        line2: r.get("line2")?,
+        region: r.get("region")?,
+        ..address
+    })
+}
+
+/// `user` with their rows of `details`; the first corrupt one fails it.
+fn attach_user_details(mut user: User, details: &UserDetails) -> Result<User, CorruptRow> {
+    for (_, pm) in details.payments.iter().filter(|(id, _)| *id == user.id.0) {
+        user.profile.payment_methods.push(pm.clone()?);
+    }
+    for (_, a) in details.addresses.iter().filter(|(id, _)| *id == user.id.0) {
+        user.profile.billing_addresses.push(a.clone()?);
+    }
+    Ok(user)
+}
+
+/// All services with their products; fails on the first corrupt row.
+pub async fn get_services(pool: &SqlitePool) -> Result<Vec<Service>, PersistenceError> {
+    Ok(get_services_with(pool, ReadMode::Strict).await?.items)
+}
+
+/// Services joined with their products in a single query; rows of one service
+/// are adjacent. Public so tests can check its query plan.
+pub const SERVICES_WITH_PRODUCTS_SQL: &str = "SELECT s.id AS service_id, s.name AS service_name, \
+     p.id AS product_id, p.name AS product_name, p.price_cents \
+     FROM services s LEFT JOIN products p ON p.service_id = s.id \
+     ORDER BY s.id, p.rowid";
+
+/// Every product's price changes, in order.
+pub const PRODUCT_PRICES_SQL: &str = "SELECT product_id, effective_from, price_cents \
+     FROM product_prices ORDER BY product_id, effective_from";
+
+/// The payment kinds each service accepts, in the order they were saved.
+pub const SERVICE_PAYMENT_KINDS_SQL: &str =
+    "SELECT service_id, kind FROM service_payment_kinds ORDER BY service_id, rowid";
+
+/// In lenient mode a corrupt product row is skipped, its service is kept; a
+/// corrupt price change or payment kind is skipped, its product or service
+/// is kept.
+pub async fn get_services_with(
+    pool: &SqlitePool,
+    mode: ReadMode,
+) -> Result<ReadOutcome<Service>, PersistenceError> {
+    let rows = sqlx::query(SERVICES_WITH_PRODUCTS_SQL)
         .fetch_all(pool)
         .await?;
-    let mut services = Vec::new();
-    for s in services_rows {
-        let sid: String = s.get("id");
-        let sname: String = s.get("name");
-        let product_rows =
-            sqlx::query("SELECT id, name, price_cents FROM products WHERE service_id = ?")
-                .bind(&sid)
-                .fetch_all(pool)
-                .await?;
-        let products = product_rows
-            .into_iter()
-            .map(|pr| {
-                let pid: String = pr.get("id");
-                let pname: String = pr.get("name");
-                let price: i64 = pr.get("price_cents");
-                Product::new(&pid, &pname, price as u64)
-            })
-            .collect();
-        services.push(Service::new(&sid, &sname, products));
+    let mut skipped = Vec::new();
+    let mut skip = |c: CorruptRow| match mode {
+        ReadMode::Strict => Err(PersistenceError::CorruptRow(c)),
+        ReadMode::Lenient => {
+            skipped.push(c);
+            Ok(())
+        }
+    };
+    let mut services: Vec<Service> = Vec::new();
+    for row in &rows {
+        let r = RowReader::new(row, "services", "service_id");
+        let header = r
+            .get::<String>("service_id")
+            .and_then(|sid| Ok((sid, r.get::<String>("service_name")?)));
+        let (sid, sname) = match header {
+            Ok(h) => h,
+            Err(c) => {
+                skip(c)?;
+                continue;
+            }
+        };
+        if services.last().map(|s| s.id.0 != sid).unwrap_or(true) {
+            services.push(Service::new(&sid, &sname, Vec::new()));
+        }
+        // LEFT JOIN: a service without products yields one row of NULLs
+        let product_id: Option<String> = row.try_get("product_id").unwrap_or(None);
+        if product_id.is_none() {
+            continue;
+        }
+        match decode_joined_product(row) {
+            Ok(p) => services.last_mut().expect("pushed above").products.push(p),
+            Err(c) => skip(c)?,
+        }
+    }
+
+    let mut prices: HashMap<String, Vec<PriceChange>> = HashMap::new();
+    for row in &sqlx::query(PRODUCT_PRICES_SQL).fetch_all(pool).await? {
+        match decode_price_change(row) {
+            Ok((pid, change)) => prices.entry(pid).or_default().push(change),
+            Err(c) => skip(c)?,
+        }
+    }
+    for p in services.iter_mut().flat_map(|s| s.products.iter_mut()) {
+        p.price_changes = prices.get(&p.id.0).cloned().unwrap_or_default();
+    }
+
+    let mut kinds: HashMap<String, Vec<PaymentKind>> = HashMap::new();
+    for row in &sqlx::query(SERVICE_PAYMENT_KINDS_SQL)
+        .fetch_all(pool)
+        .await?
+    {
+        match decode_payment_kind(row) {
+            Ok((sid, kind)) => kinds.entry(sid).or_default().push(kind),
+            Err(c) => skip(c)?,
+        }
     }
-    Ok(services)
+    for s in services.iter_mut() {
+        s.accepted_payments = kinds.remove(&s.id.0).unwrap_or_default();
+    }
+    Ok(ReadOutcome {
+        items: services,
+        skipped,
+    })
+}
+
+fn decode_joined_product(row: &SqliteRow) -> Result<Product, CorruptRow> {
+    let r = RowReader::new(row, "products", "product_id");
+    let pid: String = r.get("product_id")?;
+    let pname: String = r.get("product_name")?;
+    let price = r.non_negative("price_cents")?;
+    Ok(Product::new(&pid, &pname, price))
+}
+
+fn decode_price_change(row: &SqliteRow) -> Result<(String, PriceChange), CorruptRow> {
+    let r = RowReader::new(row, "product_prices", "product_id");
+    let change = PriceChange {
+        effective_from: r.timestamp_ms("effective_from")?,
+        price_cents: r.non_negative("price_cents")?,
+    };
+    Ok((r.get("product_id")?, change))
+}
+
+fn decode_payment_kind(row: &SqliteRow) -> Result<(String, PaymentKind), CorruptRow> {
+    let r = RowReader::new(row, "service_payment_kinds", "service_id");
+    let kind: String = r.get("kind")?;
+    let kind = PaymentKind::parse(&kind)
+        .ok_or_else(|| r.corrupt("kind", format!("unknown payment kind {:?}", kind)))?;
+    Ok((r.get("service_id")?, kind))
 }
 
+/// Public so tests can check its query plan.
+pub const USAGES_FOR_USER_SQL: &str =
+    "SELECT id, user_id, service_id, product_id, payment_used, occurred_at, account_id, \
+     idempotency_key FROM usages WHERE user_id = ? ORDER BY id";
+
+/// Usages of one user; fails on the first corrupt row.
 pub async fn get_usages_for_user(
     pool: &SqlitePool,
     user_id: &str,
-) -> Result<Vec<ServiceUsage>, sqlx::Error> {
+) -> Result<Vec<ServiceUsage>, PersistenceError> {
+    Ok(get_usages_for_user_with(pool, user_id, ReadMode::Strict)
+        .await?
+        .items)
+}
+
+pub async fn get_usages_for_user_with(
+    pool: &SqlitePool,
+    user_id: &str,
+    mode: ReadMode,
+) -> Result<ReadOutcome<ServiceUsage>, PersistenceError> {
+    let rows = sqlx::query(USAGES_FOR_USER_SQL)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+    ReadOutcome::collect(mode, rows.iter().map(decode_usage))
+}
+
+fn decode_usage(row: &SqliteRow) -> Result<ServiceUsage, CorruptRow> {
+    let r = RowReader::new(row, "usages", "id");
+    let uid: String = r.get("user_id")?;
+    let sid: String = r.get("service_id")?;
+    let pid: String = r.get("product_id")?;
+    let payment = r.payment("payment_used")?;
+    let occurred_at = r.timestamp_ms("occurred_at")?;
+    let account_id: Option<String> = r.get("account_id")?;
+    let idempotency_key: Option<String> = r.get("idempotency_key")?;
+    Ok(ServiceUsage {
+        account_id: account_id.map(AccountId),
+        idempotency_key: idempotency_key.map(IdempotencyKey),
+        ..ServiceUsage::new(&UserId(uid), &ServiceId(sid), &ProductId(pid), payment).at(occurred_at)
+    })
+}
+
+fn decode_usage_record(row: &SqliteRow) -> Result<UsageRecord, CorruptRow> {
+    Ok(UsageRecord {
+        id: RowReader::new(row, "usages", "id").get("id")?,
+        usage: decode_usage(row)?,
+    })
+}
+
+/// Append the `WHERE` clause for `q`'s filters (not its cursor) over `usages u`.
+fn push_usage_filters(qb: &mut QueryBuilder<'_, Sqlite>, q: &UsageQuery) {
+    qb.push(" WHERE 1 = 1");
+    if let Some(u) = &q.user_id {
+        qb.push(" AND u.user_id = ").push_bind(u.0.clone());
+    }
+    if let Some(s) = &q.service_id {
+        qb.push(" AND u.service_id = ").push_bind(s.0.clone());
+    }
+    if let Some(p) = &q.product_id {
+        qb.push(" AND u.product_id = ").push_bind(p.0.clone());
+    }
+    if let Some(k) = q.payment_kind {
+        qb.push(" AND u.payment_kind = ").push_bind(k.as_str());
+    }
+    if let Some(a) = &q.account_id {
+        qb.push(" AND u.account_id = ").push_bind(a.0.clone());
+    }
+    if let Some(t) = q.from {
+        qb.push(" AND u.occurred_at >= ")
+            .push_bind(t.timestamp_millis());
+    }
+    if let Some(t) = q.until {
+        qb.push(" AND u.occurred_at < ")
+            .push_bind(t.timestamp_millis());
+    }
+}
+
+/// One page of usages matching `q`, in id order; fails on a corrupt row.
+pub async fn query_usages(
+    pool: &SqlitePool,
+    q: &UsageQuery,
+) -> Result<Page<UsageRecord>, PersistenceError> {
+    let size = q.page_size();
+    let mut qb = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM usages u", USAGE_COLUMNS));
+    push_usage_filters(&mut qb, q);
+    if let Some(c) = q.after {
+        qb.push(" AND u.id > ").push_bind(c.id());
+    }
+    // one extra row tells us whether another page follows
+    qb.push(" ORDER BY u.id LIMIT ")
+        .push_bind((size + 1) as i64);
+    let rows = qb.build().fetch_all(pool).await?;
+
+    let mut items = rows
+        .iter()
+        .map(decode_usage_record)
+        .collect::<Result<Vec<_>, CorruptRow>>()
+        .map_err(PersistenceError::CorruptRow)?;
+    let next_cursor = if items.len() > size {
+        items.truncate(size);
+        items.last().map(|r| Cursor::after_id(r.id))
+    } else {
+        None
+    };
+    Ok(Page { items, next_cursor })
+}
+
+/// The price of usage `u` of product `p` (joined on both ids) at its
+/// `occurred_at`; `NULL` when `p` is.
+const USAGE_PRICE_SQL: &str = "COALESCE( \
+     (SELECT pp.price_cents FROM product_prices pp \
+      WHERE pp.product_id = p.id AND pp.effective_from <= u.occurred_at \
+      ORDER BY pp.effective_from DESC LIMIT 1), \
+     p.price_cents)";
+
+/// Count and total catalog price of every usage matching `q` (cursor and
+/// limit are ignored), each at the price in effect when it occurred. Usages
+/// of products not in the catalog add 0.
+pub async fn aggregate_usages(
+    pool: &SqlitePool,
+    q: &UsageQuery,
+) -> Result<UsageAggregate, PersistenceError> {
+    let mut qb = QueryBuilder::<Sqlite>::new(format!(
+        "SELECT COUNT(*) AS n, COALESCE(SUM({}), 0) AS total FROM usages u \
+         LEFT JOIN products p ON p.id = u.product_id AND p.service_id = u.service_id",
+        USAGE_PRICE_SQL
+    ));
+    push_usage_filters(&mut qb, q);
+    let row = qb.build().fetch_one(pool).await?;
+    let r = RowReader::new(&row, "usages", "n");
+    Ok(UsageAggregate {
+        count: r.non_negative("n").map_err(PersistenceError::CorruptRow)?,
+        total_cents: r
+            .non_negative("total")
+            .map_err(PersistenceError::CorruptRow)?,
+    })
+}
+
+/// Insert or replace an account with its members and payment methods in one
+/// transaction.
+pub async fn save_account(pool: &SqlitePool, account: &Account) -> Result<(), PersistenceError> {
+    let payments = account
+        .payment_methods
+        .iter()
+        .map(|pm| encode_payment("account_payment_methods", &account.id.0, Some(pm)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("INSERT OR REPLACE INTO accounts (id, name) VALUES (?, ?)")
+        .bind(&account.id.0)
+        .bind(&account.name)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM account_members WHERE account_id = ?")
+        .bind(&account.id.0)
+        .execute(&mut *tx)
+        .await?;
+    for (i, m) in account.members.iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO account_members (account_id, user_id, role, position) VALUES (?, ?, ?, ?)",
+        )
+        .bind(&account.id.0)
+        .bind(&m.user_id.0)
+        .bind(m.role.as_str())
+        .bind(i as i64)
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query("DELETE FROM account_payment_methods WHERE account_id = ?")
+        .bind(&account.id.0)
+        .execute(&mut *tx)
+        .await?;
+    for (i, pm) in payments.into_iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO account_payment_methods (account_id, position, payment) VALUES (?, ?, ?)",
+        )
+        .bind(&account.id.0)
+        .bind(i as i64)
+        .bind(pm)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| card_write_error("accounts", e))?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// All accounts with their members and payment methods; fails on the first
+/// corrupt row.
+pub async fn get_accounts(pool: &SqlitePool) -> Result<Vec<Account>, PersistenceError> {
+    Ok(get_accounts_with(pool, ReadMode::Strict).await?.items)
+}
+
+/// In lenient mode a corrupt member or payment method is skipped, its
+/// account is kept.
+pub async fn get_accounts_with(
+    pool: &SqlitePool,
+    mode: ReadMode,
+) -> Result<ReadOutcome<Account>, PersistenceError> {
+    let mut skipped = Vec::new();
+    let mut skip = |c: CorruptRow| match mode {
+        ReadMode::Strict => Err(PersistenceError::CorruptRow(c)),
+        ReadMode::Lenient => {
+            skipped.push(c);
+            Ok(())
+        }
+    };
+    let mut accounts: Vec<Account> = Vec::new();
+    for row in &sqlx::query("SELECT id, name FROM accounts ORDER BY id")
+        .fetch_all(pool)
+        .await?
+    {
+        let r = RowReader::new(row, "accounts", "id");
+        match r
+            .get::<String>("id")
+            .and_then(|id| Ok((id, r.get::<String>("name")?)))
+        {
+            Ok((id, name)) => accounts.push(Account {
+                id: AccountId(id),
+                name,
+                members: Vec::new(),
+                payment_methods: Vec::new(),
+            }),
+            Err(c) => skip(c)?,
+        }
+    }
+
+    let members = sqlx::query(
+        "SELECT account_id, user_id, role FROM account_members ORDER BY account_id, position",
+    )
+    .fetch_all(pool)
+    .await?;
+    for row in &members {
+        match decode_member(row) {
+            Ok((aid, m)) => {
+                if let Some(a) = accounts.iter_mut().find(|a| a.id.0 == aid) {
+                    a.members.push(m);
+                }
+            }
+            Err(c) => skip(c)?,
+        }
+    }
+
+    let payments = sqlx::query(
+        "SELECT account_id, payment FROM account_payment_methods ORDER BY account_id, position",
+    )
+    .fetch_all(pool)
+    .await?;
+    for row in &payments {
+        let r = RowReader::new(row, "account_payment_methods", "account_id");
+        let decoded = r.get::<String>("account_id").and_then(|aid| {
+            let pm = r
+                .payment("payment")?
+                .ok_or_else(|| r.corrupt("payment", "missing payment method"))?;
+            Ok((aid, pm))
+        });
+        match decoded {
+            Ok((aid, pm)) => {
+                if let Some(a) = accounts.iter_mut().find(|a| a.id.0 == aid) {
+                    a.payment_methods.push(pm);
+                }
+            }
+            Err(c) => skip(c)?,
+        }
+    }
+    Ok(ReadOutcome {
+        items: accounts,
+        skipped,
+    })
+}
+
+fn decode_member(row: &SqliteRow) -> Result<(String, Membership), CorruptRow> {
+    let r = RowReader::new(row, "account_members", "account_id");
+    let aid: String = r.get("account_id")?;
+    let uid: String = r.get("user_id")?;
+    let role: String = r.get("role")?;
+    let role =
+        Role::parse(&role).ok_or_else(|| r.corrupt("role", format!("unknown role {:?}", role)))?;
+    Ok((
+        aid,
+        Membership {
+            user_id: UserId(uid),
+            role,
+        },
+    ))
+}
+
+pub async fn save_budget(pool: &SqlitePool, budget: &Budget) -> Result<(), PersistenceError> {
+    let thresholds =
+        serde_json::to_string(&budget.thresholds).map_err(|source| PersistenceError::Encoding {
+            table: "budgets",
+            row_id: budget.id.0.clone(),
+            source,
+        })?;
+    sqlx::query(
+        "INSERT OR REPLACE INTO budgets (id, user_id, service_id, period, limit_cents, enforcement, thresholds) VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&budget.id.0)
+    .bind(&budget.user_id.0)
+    .bind(budget.service_id.as_ref().map(|s| s.0.as_str()))
+    .bind(budget.period.as_str())
+    .bind(budget.limit_cents as i64)
+    .bind(budget.enforcement.as_str())
+    .bind(thresholds)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// All budgets; fails on the first corrupt row.
+pub async fn get_budgets(pool: &SqlitePool) -> Result<Vec<Budget>, PersistenceError> {
+    Ok(get_budgets_with(pool, ReadMode::Strict).await?.items)
+}
+
+pub async fn get_budgets_with(
+    pool: &SqlitePool,
+    mode: ReadMode,
+) -> Result<ReadOutcome<Budget>, PersistenceError> {
     let rows = sqlx::query(
-        "SELECT user_id, service_id, product_id, payment_used FROM usages WHERE user_id = ?",
+        "SELECT id, user_id, service_id, period, limit_cents, enforcement, thresholds FROM budgets ORDER BY id",
     )
-    .bind(user_id)
     .fetch_all(pool)
     .await?;
-    let mut out = Vec::new();
-    for r in rows {
-        let uid: String = r.get("user_id");
-        let sid: String = r.get("service_id");
-        let pid: String = r.get("product_id");
-        let payment_s: Option<String> = r.get("payment_used");
-        let payment = match payment_s {
-            Some(s) => serde_json::from_str::<PaymentMethod>(&s).ok(),
-            None => None,
-        };
-        out.push(ServiceUsage::new(
-            &UserId(uid),
-            &ServiceId(sid),
-            &ProductId(pid),
+    ReadOutcome::collect(mode, rows.iter().map(decode_budget))
+}
+
+fn decode_budget(row: &SqliteRow) -> Result<Budget, CorruptRow> {
+    let r = RowReader::new(row, "budgets", "id");
+    let period: String = r.get("period")?;
+    let period = BillingPeriod::parse(&period)
+        .ok_or_else(|| r.corrupt("period", format!("unknown period {:?}", period)))?;
+    let enforcement: String = r.get("enforcement")?;
+    let enforcement = Enforcement::parse(&enforcement).ok_or_else(|| {
+        r.corrupt(
+            "enforcement",
+            format!("unknown enforcement {:?}", enforcement),
+        )
+    })?;
+    let thresholds: String = r.get("thresholds")?;
+    let thresholds = serde_json::from_str(&thresholds).map_err(|e| r.corrupt("thresholds", e))?;
+    Ok(Budget {
+        id: BudgetId(r.get("id")?),
+        user_id: UserId(r.get("user_id")?),
+        service_id: r.get::<Option<String>>("service_id")?.map(ServiceId),
+        period,
+        limit_cents: r.non_negative("limit_cents")?,
+        enforcement,
+        thresholds,
+    })
+}
+
+pub async fn save_wallet(
+    pool: &SqlitePool,
+    wallet: &PrepaidWallet,
+) -> Result<(), PersistenceError> {
+    sqlx::query("INSERT OR REPLACE INTO wallets (id, user_id, balance_cents) VALUES (?, ?, ?)")
+        .bind(&wallet.id.0)
+        .bind(&wallet.owner.0)
+        .bind(wallet.balance_cents as i64)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_wallet(
+    pool: &SqlitePool,
+    wallet_id: &WalletId,
+) -> Result<Option<PrepaidWallet>, PersistenceError> {
+    let row = sqlx::query("SELECT id, user_id, balance_cents FROM wallets WHERE id = ?")
+        .bind(&wallet_id.0)
+        .fetch_optional(pool)
+        .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let r = RowReader::new(&row, "wallets", "id");
+    let decoded = (|| {
+        Ok(PrepaidWallet {
+            id: WalletId(r.get("id")?),
+            owner: UserId(r.get("user_id")?),
+            balance_cents: r.non_negative("balance_cents")?,
+        })
+    })();
+    decoded.map(Some).map_err(PersistenceError::CorruptRow)
+}
+
+/// Everything held about `user_id`, read in one transaction that also stores
+/// the export's audit record; `None` (and no record) for an unknown user.
+pub async fn export_user(
+    pool: &SqlitePool,
+    user_id: &UserId,
+) -> Result<Option<UserExport>, PersistenceError> {
+    let mut tx = pool.begin().await?;
+    let Some(user) = read_user(&mut tx, user_id).await? else {
+        return Ok(None);
+    };
+
+    let memberships = sqlx::query(
+        "SELECT a.id, a.name, m.role FROM account_members m \
+         JOIN accounts a ON a.id = m.account_id WHERE m.user_id = ? ORDER BY a.id",
+    )
+    .bind(&user_id.0)
+    .fetch_all(&mut *tx)
+    .await?
+    .iter()
+    .map(|row| {
+        let r = RowReader::new(row, "account_members", "id");
+        let role: String = r.get("role")?;
+        Ok(ExportedMembership {
+            account_id: AccountId(r.get("id")?),
+            account_name: r.get("name")?,
+            role: Role::parse(&role)
+                .ok_or_else(|| r.corrupt("role", format!("unknown role {:?}", role)))?,
+        })
+    })
+    .collect::<Result<Vec<_>, CorruptRow>>()
+    .map_err(PersistenceError::CorruptRow)?;
+
+    let budgets = sqlx::query(
+        "SELECT id, user_id, service_id, period, limit_cents, enforcement, thresholds \
+         FROM budgets WHERE user_id = ? ORDER BY id",
+    )
+    .bind(&user_id.0)
+    .fetch_all(&mut *tx)
+    .await?
+    .iter()
+    .map(decode_budget)
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(PersistenceError::CorruptRow)?;
+
+    let usages = sqlx::query(&format!(
+        "SELECT {} FROM usages u WHERE u.user_id = ? ORDER BY u.id",
+        USAGE_COLUMNS
+    ))
+    .bind(&user_id.0)
+    .fetch_all(&mut *tx)
+    .await?
+    .iter()
+    .map(decode_usage_record)
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(PersistenceError::CorruptRow)?;
+
+    let notes = sqlx::query(&format!(
+        "SELECT {} FROM credit_notes WHERE usage_id IN (SELECT id FROM usages WHERE user_id = ?) \
+         ORDER BY rowid",
+        CREDIT_NOTE_COLUMNS
+    ))
+    .bind(&user_id.0)
+    .fetch_all(&mut *tx)
+    .await?
+    .iter()
+    .map(decode_credit_note)
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(PersistenceError::CorruptRow)?;
+
+    let cases = sqlx::query(&format!(
+        "SELECT {} FROM dunning_cases WHERE user_id = ? ORDER BY rowid",
+        DUNNING_CASE_COLUMNS
+    ))
+    .bind(&user_id.0)
+    .fetch_all(&mut *tx)
+    .await?
+    .iter()
+    .map(decode_dunning_case)
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(PersistenceError::CorruptRow)?;
+
+    let export = UserExport::build(&user, memberships, budgets, &usages, Utc::now())
+        .with_credit_notes(&notes)
+        .with_dunning_cases(&cases);
+    insert_audit_record(&mut tx, &export.audit_record()).await?;
+    tx.commit().await?;
+    Ok(Some(export))
+}
+
+/// Pseudonymize `user_id` as `pseudonym` in one transaction (see `privacy`).
+/// Vaulted cards the user paid with are deleted and prepaid wallets move to
+/// the pseudonym with their balance.
+pub async fn erase_user(
+    pool: &SqlitePool,
+    user_id: &UserId,
+    pseudonym: &UserId,
+) -> Result<Option<ErasureReport>, PersistenceError> {
+    let mut tx = pool.begin().await?;
+    let Some(user) = read_user(&mut tx, user_id).await? else {
+        return Ok(None);
+    };
+    let mut payments: Vec<PaymentMethod> = user.profile.payment_methods;
+    for row in &sqlx::query(
+        "SELECT id, payment_used FROM usages WHERE user_id = ? AND payment_used IS NOT NULL",
+    )
+    .bind(&user_id.0)
+    .fetch_all(&mut *tx)
+    .await?
+    {
+        let r = RowReader::new(row, "usages", "id");
+        payments.extend(
+            r.payment("payment_used")
+                .map_err(PersistenceError::CorruptRow)?,
+        );
+    }
+    for token in privacy::card_tokens(&payments) {
+        sqlx::query("DELETE FROM cards WHERE token = ?")
+            .bind(token.as_str())
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    // the pseudonym and its memberships first: the `usages` triggers check
+    // both when `user_id` changes
+    sqlx::query("INSERT INTO users (id, display_name, default_payment) VALUES (?, ?, NULL)")
+        .bind(&pseudonym.0)
+        .bind(privacy::ERASED_DISPLAY_NAME)
+        .execute(&mut *tx)
+        .await?;
+    let memberships = sqlx::query("UPDATE account_members SET user_id = ? WHERE user_id = ?")
+        .bind(&pseudonym.0)
+        .bind(&user_id.0)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    sqlx::query(
+        "UPDATE credit_notes SET refund_to = NULL \
+         WHERE usage_id IN (SELECT id FROM usages WHERE user_id = ?)",
+    )
+    .bind(&user_id.0)
+    .execute(&mut *tx)
+    .await?;
+    for table in ["credit_notes", "credit_applications"] {
+        sqlx::query(&format!(
+            "UPDATE {} SET payer_id = ? WHERE payer_kind = 'user' AND payer_id = ?",
+            table
+        ))
+        .bind(&pseudonym.0)
+        .bind(&user_id.0)
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query(
+        "UPDATE dunning_cases SET user_id = ?, payment = NULL, revision = revision + 1 \
+         WHERE user_id = ?",
+    )
+    .bind(&pseudonym.0)
+    .bind(&user_id.0)
+    .execute(&mut *tx)
+    .await?;
+    let usages = sqlx::query(
+        "UPDATE usages SET user_id = ?, payment_used = NULL, payment_kind = NULL, \
+         idempotency_key = NULL, idempotency_key_at = NULL WHERE user_id = ?",
+    )
+    .bind(&pseudonym.0)
+    .bind(&user_id.0)
+    .execute(&mut *tx)
+    .await
+    .map_err(usage_write_error)?
+    .rows_affected();
+    let budgets_deleted = sqlx::query("DELETE FROM budgets WHERE user_id = ?")
+        .bind(&user_id.0)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    sqlx::query("UPDATE wallets SET user_id = ? WHERE user_id = ?")
+        .bind(&pseudonym.0)
+        .bind(&user_id.0)
+        .execute(&mut *tx)
+        .await?;
+    delete_user_details(&mut tx, user_id).await?;
+    sqlx::query("DELETE FROM users WHERE id = ?")
+        .bind(&user_id.0)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE privacy_audit SET subject = ? WHERE subject = ?")
+        .bind(&pseudonym.0)
+        .bind(&user_id.0)
+        .execute(&mut *tx)
+        .await?;
+
+    let report = ErasureReport {
+        user_id: user_id.clone(),
+        pseudonym: pseudonym.clone(),
+        at: Utc::now(),
+        usages,
+        memberships,
+        budgets_deleted,
+    };
+    insert_audit_record(&mut tx, &report.audit_record()).await?;
+    tx.commit().await?;
+    Ok(Some(report))
+}
+
+async fn insert_audit_record(
+    conn: &mut SqliteConnection,
+    record: &AuditRecord,
+) -> Result<(), PersistenceError> {
+    sqlx::query("INSERT INTO privacy_audit (action, subject, at, usages) VALUES (?, ?, ?, ?)")
+        .bind(record.action.as_str())
+        .bind(&record.subject.0)
+        .bind(record.at.timestamp_millis())
+        .bind(record.usages as i64)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Every export and erasure, oldest first.
+pub async fn get_privacy_audit(pool: &SqlitePool) -> Result<Vec<AuditRecord>, PersistenceError> {
+    let rows = sqlx::query("SELECT id, action, subject, at, usages FROM privacy_audit ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+    rows.iter()
+        .map(|row| {
+            let r = RowReader::new(row, "privacy_audit", "id");
+            let action: String = r.get("action")?;
+            Ok(AuditRecord {
+                action: PrivacyAction::parse(&action)
+                    .ok_or_else(|| r.corrupt("action", format!("unknown action {:?}", action)))?,
+                subject: UserId(r.get("subject")?),
+                at: r.timestamp_ms("at")?,
+                usages: r.non_negative("usages")?,
+            })
+        })
+        .collect::<Result<_, CorruptRow>>()
+        .map_err(PersistenceError::CorruptRow)
+}
+
+/// Store `note` unless the credit notes of its usage would then add up to
+/// more than the usage's price, read from the stored usage and its product's
+/// price history (`note.usage_amount_cents` is replaced with it). Pricing,
+/// the check and the insert are one statement, so concurrent notes cannot
+/// both pass it.
+pub async fn issue_credit_note(
+    pool: &SqlitePool,
+    note: &CreditNote,
+) -> Result<Issued, PersistenceError> {
+    let refund_to = encode_payment("credit_notes", &note.id.0, note.refund_to.as_ref())?;
+    let priced = format!(
+        "SELECT {} AS price FROM usages u \
+         LEFT JOIN products p ON p.id = u.product_id AND p.service_id = u.service_id \
+         WHERE u.id = ?",
+        USAGE_PRICE_SQL
+    );
+    // `fetch_all`: the insert is only done once the statement has run to the end
+    let inserted: Vec<i64> = sqlx::query_scalar(&format!(
+        "INSERT INTO credit_notes (id, usage_id, payer_kind, payer_id, usage_amount_cents, \
+         amount_cents, reason, settlement, refund_to, issued_at) \
+         SELECT ?, ?, ?, ?, price, ?, ?, ?, ?, ? FROM ({}) \
+         WHERE (SELECT COALESCE(SUM(amount_cents), 0) FROM credit_notes WHERE usage_id = ?) + ? <= price \
+         RETURNING usage_amount_cents",
+        priced
+    ))
+    .bind(&note.id.0)
+    .bind(note.usage_id)
+    .bind(note.payer.kind())
+    .bind(note.payer.id())
+    .bind(note.amount_cents as i64)
+    .bind(&note.reason)
+    .bind(note.settlement.as_str())
+    .bind(refund_to)
+    .bind(note.issued_at.timestamp_millis())
+    .bind(note.usage_id)
+    .bind(note.usage_id)
+    .bind(note.amount_cents as i64)
+    .fetch_all(pool)
+    .await?;
+    if let Some(&price) = inserted.first() {
+        return Ok(Issued::Issued(CreditNote {
+            usage_amount_cents: price.max(0) as u64,
+            ..note.clone()
+        }));
+    }
+    // nothing stored: say why
+    let price: Option<Option<i64>> = sqlx::query_scalar(&priced)
+        .bind(note.usage_id)
+        .fetch_optional(pool)
+        .await?;
+    let price = match price {
+        None => return Ok(Issued::UnknownUsage),
+        Some(None) => return Ok(Issued::UnknownPrice),
+        Some(Some(price)) => price.max(0) as u64,
+    };
+    let credited: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(SUM(amount_cents), 0) FROM credit_notes WHERE usage_id = ?",
+    )
+    .bind(note.usage_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(Issued::Exceeds {
+        remaining_cents: price.saturating_sub(credited.max(0) as u64),
+    })
+}
+
+const CREDIT_NOTE_COLUMNS: &str = "id, usage_id, payer_kind, payer_id, usage_amount_cents, \
+     amount_cents, reason, settlement, refund_to, issued_at";
+
+/// Every credit note in the order issued; fails on the first corrupt row.
+pub async fn get_credit_notes(pool: &SqlitePool) -> Result<Vec<CreditNote>, PersistenceError> {
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM credit_notes ORDER BY rowid",
+        CREDIT_NOTE_COLUMNS
+    ))
+    .fetch_all(pool)
+    .await?;
+    rows.iter()
+        .map(decode_credit_note)
+        .collect::<Result<_, _>>()
+        .map_err(PersistenceError::CorruptRow)
+}
+
+fn decode_credit_note(row: &SqliteRow) -> Result<CreditNote, CorruptRow> {
+    let r = RowReader::new(row, "credit_notes", "id");
+    let settlement: String = r.get("settlement")?;
+    Ok(CreditNote {
+        id: CreditNoteId(r.get("id")?),
+        usage_id: r.get("usage_id")?,
+        payer: decode_payer(&r)?,
+        usage_amount_cents: r.non_negative("usage_amount_cents")?,
+        amount_cents: r.non_negative("amount_cents")?,
+        reason: r.get("reason")?,
+        settlement: Settlement::parse(&settlement).ok_or_else(|| {
+            r.corrupt("settlement", format!("unknown settlement {:?}", settlement))
+        })?,
+        refund_to: r.payment("refund_to")?,
+        issued_at: r.timestamp_ms("issued_at")?,
+    })
+}
+
+fn decode_payer(r: &RowReader<'_>) -> Result<Payer, CorruptRow> {
+    let kind: String = r.get("payer_kind")?;
+    let id: String = r.get("payer_id")?;
+    Payer::parse(&kind, &id)
+        .ok_or_else(|| r.corrupt("payer_kind", format!("unknown payer kind {:?}", kind)))
+}
+
+/// Charge up to `application.amount_cents` of the payer's credit balance to
+/// its period, unless the period was charged before; returns the stored
+/// application either way. The balance is read and charged in one statement.
+pub async fn apply_credit(
+    pool: &SqlitePool,
+    application: &CreditApplication,
+) -> Result<CreditApplication, PersistenceError> {
+    let (kind, id) = (application.payer.kind(), application.payer.id());
+    let from = application.period.from.timestamp_millis();
+    let until = application.period.until.timestamp_millis();
+    // `WHERE true` keeps `ON CONFLICT` from being parsed as part of the SELECT
+    sqlx::query(
+        "INSERT INTO credit_applications \
+         (payer_kind, payer_id, period_from, period_until, amount_cents, applied_at) \
+         SELECT ?, ?, ?, ?, MIN(?, MAX(0, \
+           (SELECT COALESCE(SUM(amount_cents), 0) FROM credit_notes \
+            WHERE payer_kind = ? AND payer_id = ? AND settlement = 'credit') - \
+           (SELECT COALESCE(SUM(amount_cents), 0) FROM credit_applications \
+            WHERE payer_kind = ? AND payer_id = ?))), ? \
+         WHERE true ON CONFLICT DO NOTHING",
+    )
+    .bind(kind)
+    .bind(id)
+    .bind(from)
+    .bind(until)
+    .bind(application.amount_cents as i64)
+    .bind(kind)
+    .bind(id)
+    .bind(kind)
+    .bind(id)
+    .bind(application.applied_at.timestamp_millis())
+    .execute(pool)
+    .await?;
+    let row = sqlx::query(&format!(
+        "SELECT {} FROM credit_applications \
+         WHERE payer_kind = ? AND payer_id = ? AND period_from = ? AND period_until = ?",
+        CREDIT_APPLICATION_COLUMNS
+    ))
+    .bind(kind)
+    .bind(id)
+    .bind(from)
+    .bind(until)
+    .fetch_one(pool)
+    .await?;
+    decode_credit_application(&row).map_err(PersistenceError::CorruptRow)
+}
+
+const CREDIT_APPLICATION_COLUMNS: &str =
+    "rowid, payer_kind, payer_id, period_from, period_until, amount_cents, applied_at";
+
+/// Every credit application in the order applied; fails on the first
+/// corrupt row.
+pub async fn get_credit_applications(
+    pool: &SqlitePool,
+) -> Result<Vec<CreditApplication>, PersistenceError> {
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM credit_applications ORDER BY rowid",
+        CREDIT_APPLICATION_COLUMNS
+    ))
+    .fetch_all(pool)
+    .await?;
+    rows.iter()
+        .map(decode_credit_application)
+        .collect::<Result<_, _>>()
+        .map_err(PersistenceError::CorruptRow)
+}
+
+fn decode_credit_application(row: &SqliteRow) -> Result<CreditApplication, CorruptRow> {
+    let r = RowReader::new(row, "credit_applications", "rowid");
+    Ok(CreditApplication {
+        payer: decode_payer(&r)?,
+        period: ReportPeriod::new(
+            r.timestamp_ms("period_from")?,
+            r.timestamp_ms("period_until")?,
+        ),
+        amount_cents: r.non_negative("amount_cents")?,
+        applied_at: r.timestamp_ms("applied_at")?,
+    })
+}
+
+/// Store `case` and append `events` in one transaction, if the stored case
+/// is at the revision before `case.revision` (or absent, for revision 0);
+/// returns false and writes nothing otherwise.
+pub async fn save_dunning_case(
+    pool: &SqlitePool,
+    case: &DunningCase,
+    events: &[DunningEvent],
+) -> Result<bool, PersistenceError> {
+    let payment = encode_payment("dunning_cases", &case.id.0, case.payment.as_ref())?;
+    let schedule = serde_json::to_string(&case.schedule.retry_after_hours).map_err(|source| {
+        PersistenceError::Encoding {
+            table: "dunning_cases",
+            row_id: case.id.0.clone(),
+            source,
+        }
+    })?;
+    let mut tx = pool.begin().await?;
+    let written = match case.revision.checked_sub(1) {
+        None => bind_dunning_case(
+            sqlx::query(
+                "INSERT INTO dunning_cases (user_id, service_id, amount_cents, payment, reason, \
+                 schedule, state, attempts, opened_at, next_attempt_at, updated_at, revision, \
+                 id) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT DO NOTHING",
+            ),
+            case,
             payment,
-        ));
+            schedule,
+        ),
+        Some(previous) => bind_dunning_case(
+            sqlx::query(
+                "UPDATE dunning_cases SET user_id = ?, service_id = ?, amount_cents = ?, \
+                 payment = ?, reason = ?, schedule = ?, state = ?, attempts = ?, \
+                 opened_at = ?, next_attempt_at = ?, updated_at = ?, revision = ? \
+                 WHERE id = ? AND revision = ?",
+            ),
+            case,
+            payment,
+            schedule,
+        )
+        .bind(previous as i64),
+    }
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if written == 0 {
+        return Ok(false);
+    }
+    for event in events {
+        sqlx::query(
+            "INSERT INTO dunning_events (case_id, stage, attempt, at, next_attempt_at, reason) \
+             VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&event.case_id.0)
+        .bind(event.stage.as_str())
+        .bind(event.attempt as i64)
+        .bind(event.at.timestamp_millis())
+        .bind(event.next_attempt_at.map(|t| t.timestamp_millis()))
+        .bind(event.reason.as_deref())
+        .execute(&mut *tx)
+        .await?;
     }
-    Ok(out)
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Binds every column of `case`, its id last.
+fn bind_dunning_case<'q>(
+    query: sqlx::query::Query<'q, Sqlite, sqlx::sqlite::SqliteArguments<'q>>,
+    case: &'q DunningCase,
+    payment: Option<String>,
+    schedule: String,
+) -> sqlx::query::Query<'q, Sqlite, sqlx::sqlite::SqliteArguments<'q>> {
+    query
+        .bind(&case.user_id.0)
+        .bind(&case.service_id.0)
+        .bind(case.amount_cents as i64)
+        .bind(payment)
+        .bind(&case.reason)
+        .bind(schedule)
+        .bind(case.state.as_str())
+        .bind(case.attempts as i64)
+        .bind(case.opened_at.timestamp_millis())
+        .bind(case.next_attempt_at.map(|t| t.timestamp_millis()))
+        .bind(case.updated_at.timestamp_millis())
+        .bind(case.revision as i64)
+        .bind(&case.id.0)
+}
+
+const DUNNING_CASE_COLUMNS: &str = "id, user_id, service_id, amount_cents, payment, reason, \
+     schedule, state, attempts, opened_at, next_attempt_at, updated_at, revision";
+
+/// Every dunning case in the order opened; fails on the first corrupt row.
+pub async fn get_dunning_cases(pool: &SqlitePool) -> Result<Vec<DunningCase>, PersistenceError> {
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM dunning_cases ORDER BY rowid",
+        DUNNING_CASE_COLUMNS
+    ))
+    .fetch_all(pool)
+    .await?;
+    rows.iter()
+        .map(decode_dunning_case)
+        .collect::<Result<_, _>>()
+        .map_err(PersistenceError::CorruptRow)
+}
+
+fn decode_dunning_case(row: &SqliteRow) -> Result<DunningCase, CorruptRow> {
+    let r = RowReader::new(row, "dunning_cases", "id");
+    let schedule: String = r.get("schedule")?;
+    let state: String = r.get("state")?;
+    let count = |column| {
+        let n = r.non_negative(column)?;
+        u32::try_from(n).map_err(|_| r.corrupt(column, format!("{} out of range", n)))
+    };
+    Ok(DunningCase {
+        id: DunningCaseId(r.get("id")?),
+        user_id: UserId(r.get("user_id")?),
+        service_id: ServiceId(r.get("service_id")?),
+        amount_cents: r.non_negative("amount_cents")?,
+        payment: r.payment("payment")?,
+        reason: r.get("reason")?,
+        schedule: DunningSchedule {
+            retry_after_hours: serde_json::from_str(&schedule)
+                .map_err(|e| r.corrupt("schedule", e))?,
+        },
+        state: DunningState::parse(&state)
+            .ok_or_else(|| r.corrupt("state", format!("unknown state {:?}", state)))?,
+        attempts: count("attempts")?,
+        opened_at: r.timestamp_ms("opened_at")?,
+        next_attempt_at: r.optional_timestamp_ms("next_attempt_at")?,
+        updated_at: r.timestamp_ms("updated_at")?,
+        revision: count("revision")?,
+    })
+}
+
+/// Every dunning event in the order fired; fails on the first corrupt row.
+pub async fn get_dunning_events(pool: &SqlitePool) -> Result<Vec<DunningEvent>, PersistenceError> {
+    let rows = sqlx::query(
+        "SELECT id, case_id, stage, attempt, at, next_attempt_at, reason \
+         FROM dunning_events ORDER BY id",
+    )
+    .fetch_all(pool)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            let r = RowReader::new(row, "dunning_events", "id");
+            let stage: String = r.get("stage")?;
+            let attempt = r.non_negative("attempt")?;
+            Ok(DunningEvent {
+                case_id: DunningCaseId(r.get("case_id")?),
+                stage: Stage::parse(&stage)
+                    .ok_or_else(|| r.corrupt("stage", format!("unknown stage {:?}", stage)))?,
+                attempt: u32::try_from(attempt)
+                    .map_err(|_| r.corrupt("attempt", format!("{} out of range", attempt)))?,
+                at: r.timestamp_ms("at")?,
+                next_attempt_at: r.optional_timestamp_ms("next_attempt_at")?,
+                reason: r.get("reason")?,
+            })
+        })
+        .collect::<Result<_, CorruptRow>>()
+        .map_err(PersistenceError::CorruptRow)
 }
@@ -0,0 +1,347 @@
+//! Payment resolution: which method pays for a usage, and why.
+//!
+//! A `PaymentPolicy` walks its `chain` of sources and takes the first
+//! candidate method that can pay:
+//! - `Source::Usage` — the method named on the usage itself;
+//! - `Source::Account` — for a usage billed to an account, the account's
+//!   methods in its order of preference;
+//! - `Source::User` — the user's own methods. For a usage billed to an
+//!   account they are only tried with `member_fallback`.
+//!
+//! Within a source, kinds listed in `preference` go first. A candidate is
+//! skipped if the usage's service does not accept its kind
+//! (`Service::accepted_payments`), or, when a charge amount is known, if it
+//! is a prepaid wallet that does not belong to the user or cannot cover the
+//! amount. Every candidate looked at ends up in `Decision::trail` with the
+//! reason it was taken or skipped, for support staff.
+//!
+//! `usage::resolve_payment_for_usage`, `usage::resolve_payment_for_charge`
+//! and `account::resolve_payment_for_account` are the default policy.
+
+use crate::account::Account;
+use crate::models::{PaymentMethod, Service, ServiceId, User, UserId};
+use crate::payment::PaymentKind;
+use crate::wallet::{WalletId, WalletMap};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Where a candidate payment method comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Usage,
+    Account,
+    User,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Usage => "usage",
+            Source::Account => "account",
+            Source::User => "user",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Source> {
+        match s {
+            "usage" => Some(Source::Usage),
+            "account" => Some(Source::Account),
+            "user" => Some(Source::User),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaymentPolicy {
+    /// Sources in the order they are tried; one left out is never used.
+    pub chain: Vec<Source>,
+    /// Within a source, methods of these kinds are tried first, in this
+    /// order; the others follow in their stored order.
+    #[serde(default)]
+    pub preference: Vec<PaymentKind>,
+    /// Whether a usage billed to an account may be paid with the member's
+    /// own methods when none of the account's can pay.
+    #[serde(default)]
+    pub member_fallback: bool,
+}
+
+impl Default for PaymentPolicy {
+    /// The usage's method, then the account's, then (without an account)
+    /// the user's.
+    fn default() -> Self {
+        PaymentPolicy {
+            chain: vec![Source::Usage, Source::Account, Source::User],
+            preference: Vec::new(),
+            member_fallback: false,
+        }
+    }
+}
+
+/// What a payment is resolved for. Everything is optional: without a
+/// service every kind is accepted, without an account the user pays,
+/// without a charge prepaid wallets are not checked.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PaymentContext<'a> {
+    pub user: Option<&'a User>,
+    pub service: Option<&'a Service>,
+    pub account: Option<&'a Account>,
+    pub usage_payment: Option<&'a PaymentMethod>,
+    pub charge: Option<(u64, &'a WalletMap)>,
+}
+
+impl<'a> PaymentContext<'a> {
+    pub fn new() -> Self {
+        PaymentContext::default()
+    }
+
+    pub fn user(mut self, user: &'a User) -> Self {
+        self.user = Some(user);
+        self
+    }
+
+    pub fn service(mut self, service: &'a Service) -> Self {
+        self.service = Some(service);
+        self
+    }
+
+    /// Bill the usage to `account`.
+    pub fn account(mut self, account: &'a Account) -> Self {
+        self.account = Some(account);
+        self
+    }
+
+    /// The method named on the usage, if any.
+    pub fn usage_payment(mut self, payment: Option<&'a PaymentMethod>) -> Self {
+        self.usage_payment = payment;
+        self
+    }
+
+    /// A charge of `amount_cents`; prepaid candidates are looked up in `wallets`.
+    pub fn charge(mut self, amount_cents: u64, wallets: &'a WalletMap) -> Self {
+        self.charge = Some((amount_cents, wallets));
+        self
+    }
+}
+
+/// Why a candidate was taken or skipped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    Chosen,
+    /// The service accepts only `accepted`.
+    NotAccepted {
+        service_id: ServiceId,
+        accepted: Vec<PaymentKind>,
+    },
+    UnknownWallet {
+        wallet_id: WalletId,
+    },
+    WalletNotOwned {
+        wallet_id: WalletId,
+        owner: UserId,
+    },
+    InsufficientBalance {
+        wallet_id: WalletId,
+        balance_cents: u64,
+        amount_cents: u64,
+    },
+    /// The source has no method to offer.
+    Empty,
+    /// The member's own methods are not used for a usage billed to an
+    /// account (see `PaymentPolicy::member_fallback`).
+    MemberMethodsNotUsed,
+}
+
+/// One entry of a `Decision`'s trail.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Step {
+    pub source: Source,
+    /// `PaymentMethod::masked` of the candidate; `None` when the source had
+    /// none.
+    pub payment: Option<String>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)?;
+        if let Some(pm) = &self.payment {
+            write!(f, " {}", pm)?;
+        }
+        write!(f, ": {}", self.outcome)
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Chosen => write!(f, "chosen"),
+            Outcome::NotAccepted {
+                service_id,
+                accepted,
+            } => {
+                let kinds: Vec<&str> = accepted.iter().map(|k| k.as_str()).collect();
+                write!(
+                    f,
+                    "service {} accepts only {}",
+                    service_id.0,
+                    kinds.join(", ")
+                )
+            }
+            Outcome::UnknownWallet { wallet_id } => write!(f, "unknown wallet {}", wallet_id.0),
+            Outcome::WalletNotOwned { wallet_id, owner } => {
+                write!(f, "wallet {} belongs to {}", wallet_id.0, owner.0)
+            }
+            Outcome::InsufficientBalance {
+                wallet_id,
+                balance_cents,
+                amount_cents,
+            } => write!(
+                f,
+                "wallet {} holds {}¢, the charge is {}¢",
+                wallet_id.0, balance_cents, amount_cents
+            ),
+            Outcome::Empty => write!(f, "no payment method"),
+            Outcome::MemberMethodsNotUsed => write!(f, "not used for usages billed to an account"),
+        }
+    }
+}
+
+/// The chosen method, if any, and every candidate looked at on the way.
+#[derive(Debug, Clone, Serialize)]
+pub struct Decision {
+    pub payment: Option<PaymentMethod>,
+    pub source: Option<Source>,
+    pub trail: Vec<Step>,
+}
+
+impl Decision {
+    /// The kinds the service accepts, if a candidate was skipped for its
+    /// kind.
+    pub fn not_accepted(&self) -> Option<(&ServiceId, &[PaymentKind])> {
+        self.trail.iter().find_map(|s| match &s.outcome {
+            Outcome::NotAccepted {
+                service_id,
+                accepted,
+            } => Some((service_id, accepted.as_slice())),
+            _ => None,
+        })
+    }
+
+    /// The trail as lines of text.
+    pub fn reasons(&self) -> Vec<String> {
+        self.trail.iter().map(|s| s.to_string()).collect()
+    }
+}
+
+impl PaymentPolicy {
+    pub fn resolve(&self, ctx: &PaymentContext<'_>) -> Decision {
+        let mut trail = Vec::new();
+        for &source in &self.chain {
+            let candidates = match source {
+                Source::Usage => ctx.usage_payment.into_iter().collect(),
+                Source::Account => match ctx.account {
+                    Some(account) => self.ordered(&account.payment_methods),
+                    None => continue,
+                },
+                Source::User if ctx.account.is_some() && !self.member_fallback => {
+                    trail.push(Step {
+                        source,
+                        payment: None,
+                        outcome: Outcome::MemberMethodsNotUsed,
+                    });
+                    continue;
+                }
+                Source::User => match ctx.user {
+                    Some(user) => self.ordered(user_methods(user)),
+                    None => Vec::new(),
+                },
+            };
+            if candidates.is_empty() {
+                trail.push(Step {
+                    source,
+                    payment: None,
+                    outcome: Outcome::Empty,
+                });
+            }
+            for pm in candidates {
+                let outcome = check(ctx, pm);
+                let chosen = outcome == Outcome::Chosen;
+                trail.push(Step {
+                    source,
+                    payment: Some(pm.masked()),
+                    outcome,
+                });
+                if chosen {
+                    return Decision {
+                        payment: Some(pm.clone()),
+                        source: Some(source),
+                        trail,
+                    };
+                }
+            }
+        }
+        Decision {
+            payment: None,
+            source: None,
+            trail,
+        }
+    }
+
+    /// `methods` with the preferred kinds first; a stable sort, so ties keep
+    /// their stored order.
+    fn ordered<'m>(&self, methods: &'m [PaymentMethod]) -> Vec<&'m PaymentMethod> {
+        let rank = |pm: &PaymentMethod| {
+            self.preference
+                .iter()
+                .position(|k| *k == pm.kind())
+                .unwrap_or(self.preference.len())
+        };
+        let mut ordered: Vec<&PaymentMethod> = methods.iter().collect();
+        ordered.sort_by_key(|pm| rank(pm));
+        ordered
+    }
+}
+
+/// The user's saved methods, default first.
+pub fn user_methods(user: &User) -> &[PaymentMethod] {
+    &user.profile.payment_methods
+}
+
+fn check(ctx: &PaymentContext<'_>, pm: &PaymentMethod) -> Outcome {
+    if let Some(service) = ctx.service {
+        if !service.accepts(pm.kind()) {
+            return Outcome::NotAccepted {
+                service_id: service.id.clone(),
+                accepted: service.accepted_payments.clone(),
+            };
+        }
+    }
+    let (PaymentMethod::Prepaid { wallet_id }, Some((amount_cents, wallets))) = (pm, ctx.charge)
+    else {
+        return Outcome::Chosen;
+    };
+    let wallet_id = wallet_id.clone();
+    match wallets.get(&wallet_id) {
+        None => Outcome::UnknownWallet { wallet_id },
+        Some(w) if ctx.user.map(|u| &u.id) != Some(&w.owner) => Outcome::WalletNotOwned {
+            wallet_id,
+            owner: w.owner.clone(),
+        },
+        Some(w) if !w.can_cover(amount_cents) => Outcome::InsufficientBalance {
+            wallet_id,
+            balance_cents: w.balance_cents,
+            amount_cents,
+        },
+        Some(_) => Outcome::Chosen,
+    }
+}
@@ -0,0 +1,385 @@
+//! Data subject requests: exporting and erasing what we hold about a user.
+//!
+//! `Repository::export_user` bundles the user's profile, payment methods
+//! (masked), account memberships, budgets, usages with their credit notes,
+//! and dunning cases into a `UserExport`.
+//! `Repository::erase_user` pseudonymizes the user: the user row is replaced
+//! by one named `ERASED_DISPLAY_NAME` under a fresh `pseudonym()`, usages and
+//! memberships move to it without their payment details and idempotency
+//! keys, and the user's budgets are deleted. Credit notes for the user's
+//! usages lose their refund method, and the user's credit notes and credit
+//! applications move to the pseudonym, as do their dunning cases, without
+//! the payment method charged. Usage counts and totals per service,
+//! product, account and period are unchanged, so accounting still adds up.
+//! Both run in one transaction and store an `AuditRecord`.
+
+use crate::account::{Account, AccountId, Role};
+use crate::budget::Budget;
+use crate::credit::{CreditApplication, CreditNote, CreditNoteId, Payer, Settlement};
+use crate::dunning::{DunningCase, DunningCaseId, DunningState};
+use crate::models::{PaymentMethod, ProductId, ServiceId, ServiceUsage, User, UserId};
+use crate::profile::{BillingAddress, Email, Locale, Timezone};
+use crate::query::UsageRecord;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Version of the `UserExport` layout, bumped when fields change meaning.
+pub const EXPORT_FORMAT: u32 = 1;
+
+/// Display name of the user left in place of an erased one.
+pub const ERASED_DISPLAY_NAME: &str = "erased user";
+
+/// Start of the ids `pseudonym` hands out.
+pub const PSEUDONYM_PREFIX: &str = "erased-";
+
+/// A fresh, random id for an erased user; it cannot be traced back.
+pub fn pseudonym() -> UserId {
+    UserId(format!(
+        "{}{}",
+        PSEUDONYM_PREFIX,
+        uuid::Uuid::new_v4().simple()
+    ))
+}
+
+/// Whether `user_id` looks like one handed out by `pseudonym`. Usages of
+/// erased users have no payment method left, which validation accepts.
+pub fn is_pseudonym(user_id: &UserId) -> bool {
+    user_id.0.starts_with(PSEUDONYM_PREFIX)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivacyAction {
+    Export,
+    Erasure,
+}
+
+impl PrivacyAction {
+    /// Lowercase name as stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PrivacyAction::Export => "export",
+            PrivacyAction::Erasure => "erasure",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<PrivacyAction> {
+        match s {
+            "export" => Some(PrivacyAction::Export),
+            "erasure" => Some(PrivacyAction::Erasure),
+            _ => None,
+        }
+    }
+}
+
+/// One export or erasure. `subject` is the user's id; erasing a user
+/// replaces it with the pseudonym in every record, so the audit trail does
+/// not link the two.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub action: PrivacyAction,
+    pub subject: UserId,
+    pub at: DateTime<Utc>,
+    /// Usages exported or pseudonymized.
+    pub usages: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedMembership {
+    pub account_id: AccountId,
+    pub account_name: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedUsage {
+    pub id: i64,
+    pub service_id: ServiceId,
+    pub product_id: ProductId,
+    /// `PaymentMethod::masked`.
+    pub payment: Option<String>,
+    pub occurred_at: DateTime<Utc>,
+    pub account_id: Option<AccountId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedCreditNote {
+    pub id: CreditNoteId,
+    pub usage_id: i64,
+    pub amount_cents: u64,
+    pub reason: String,
+    pub settlement: Settlement,
+    /// `PaymentMethod::masked`.
+    pub refund_to: Option<String>,
+    pub issued_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedDunningCase {
+    pub id: DunningCaseId,
+    pub service_id: ServiceId,
+    pub amount_cents: u64,
+    /// `PaymentMethod::masked`.
+    pub payment: Option<String>,
+    pub reason: String,
+    pub state: DunningState,
+    pub attempts: u32,
+    pub opened_at: DateTime<Utc>,
+}
+
+/// Everything held about one user, as handed to them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserExport {
+    pub format: u32,
+    pub exported_at: DateTime<Utc>,
+    pub user_id: UserId,
+    pub display_name: String,
+    #[serde(default)]
+    pub email: Option<Email>,
+    #[serde(default)]
+    pub billing_addresses: Vec<BillingAddress>,
+    #[serde(default)]
+    pub locale: Option<Locale>,
+    #[serde(default)]
+    pub timezone: Option<Timezone>,
+    pub default_payment: Option<String>,
+    /// Every payment method on record for the user, masked: the saved ones
+    /// and those used by their usages, without repeats.
+    pub payment_methods: Vec<String>,
+    pub memberships: Vec<ExportedMembership>,
+    pub budgets: Vec<Budget>,
+    pub usages: Vec<ExportedUsage>,
+    /// Credit notes for `usages`.
+    #[serde(default)]
+    pub credit_notes: Vec<ExportedCreditNote>,
+    #[serde(default)]
+    pub dunning_cases: Vec<ExportedDunningCase>,
+}
+
+impl UserExport {
+    pub fn build(
+        user: &User,
+        memberships: Vec<ExportedMembership>,
+        budgets: Vec<Budget>,
+        usages: &[UsageRecord],
+        at: DateTime<Utc>,
+    ) -> Self {
+        let profile = &user.profile;
+        let default_payment = profile.default_payment().map(|pm| pm.masked());
+        let mut payment_methods: Vec<String> = Vec::new();
+        let saved = profile.payment_methods.iter().map(|pm| pm.masked());
+        let used = usages
+            .iter()
+            .filter_map(|r| r.usage.payment_used.as_ref().map(|pm| pm.masked()));
+        for pm in saved.chain(used) {
+            if !payment_methods.contains(&pm) {
+                payment_methods.push(pm);
+            }
+        }
+        UserExport {
+            format: EXPORT_FORMAT,
+            exported_at: at,
+            user_id: user.id.clone(),
+            display_name: profile.display_name.clone(),
+            email: profile.email.clone(),
+            billing_addresses: profile.billing_addresses.clone(),
+            locale: profile.locale.clone(),
+            timezone: profile.timezone.clone(),
+            default_payment,
+            payment_methods,
+            memberships,
+            budgets,
+            usages: usages
+                .iter()
+                .map(|r| ExportedUsage {
+                    id: r.id,
+                    service_id: r.usage.service_id.clone(),
+                    product_id: r.usage.product_id.clone(),
+                    payment: r.usage.payment_used.as_ref().map(|pm| pm.masked()),
+                    occurred_at: r.usage.occurred_at,
+                    account_id: r.usage.account_id.clone(),
+                })
+                .collect(),
+            credit_notes: Vec::new(),
+            dunning_cases: Vec::new(),
+        }
+    }
+
+    /// The export with those of `notes` that credit its usages.
+    pub fn with_credit_notes(mut self, notes: &[CreditNote]) -> Self {
+        self.credit_notes = notes
+            .iter()
+            .filter(|n| self.usages.iter().any(|u| u.id == n.usage_id))
+            .map(|n| ExportedCreditNote {
+                id: n.id.clone(),
+                usage_id: n.usage_id,
+                amount_cents: n.amount_cents,
+                reason: n.reason.clone(),
+                settlement: n.settlement,
+                refund_to: n.refund_to.as_ref().map(|pm| pm.masked()),
+                issued_at: n.issued_at,
+            })
+            .collect();
+        self
+    }
+
+    /// The export with those of `cases` opened for its user.
+    pub fn with_dunning_cases(mut self, cases: &[DunningCase]) -> Self {
+        self.dunning_cases = cases
+            .iter()
+            .filter(|c| c.user_id == self.user_id)
+            .map(|c| ExportedDunningCase {
+                id: c.id.clone(),
+                service_id: c.service_id.clone(),
+                amount_cents: c.amount_cents,
+                payment: c.payment.as_ref().map(|pm| pm.masked()),
+                reason: c.reason.clone(),
+                state: c.state,
+                attempts: c.attempts,
+                opened_at: c.opened_at,
+            })
+            .collect();
+        self
+    }
+
+    pub fn audit_record(&self) -> AuditRecord {
+        AuditRecord {
+            action: PrivacyAction::Export,
+            subject: self.user_id.clone(),
+            at: self.exported_at,
+            usages: self.usages.len() as u64,
+        }
+    }
+}
+
+/// `user_id`'s memberships in `accounts`.
+pub fn memberships(accounts: &[Account], user_id: &UserId) -> Vec<ExportedMembership> {
+    accounts
+        .iter()
+        .filter_map(|a| {
+            Some(ExportedMembership {
+                account_id: a.id.clone(),
+                account_name: a.name.clone(),
+                role: a.role_of(user_id)?,
+            })
+        })
+        .collect()
+}
+
+/// What `Repository::erase_user` did. Only the caller sees both ids.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErasureReport {
+    pub user_id: UserId,
+    pub pseudonym: UserId,
+    pub at: DateTime<Utc>,
+    pub usages: u64,
+    pub memberships: u64,
+    pub budgets_deleted: u64,
+}
+
+impl ErasureReport {
+    pub fn audit_record(&self) -> AuditRecord {
+        AuditRecord {
+            action: PrivacyAction::Erasure,
+            subject: self.pseudonym.clone(),
+            at: self.at,
+            usages: self.usages,
+        }
+    }
+}
+
+/// The user left in place of an erased one.
+pub fn erased_user(pseudonym: &UserId) -> User {
+    User::new(&pseudonym.0, ERASED_DISPLAY_NAME, None)
+}
+
+/// `usage` moved to `pseudonym`, without its payment method and key.
+pub fn pseudonymize_usage(usage: &ServiceUsage, pseudonym: &UserId) -> ServiceUsage {
+    ServiceUsage {
+        user_id: pseudonym.clone(),
+        payment_used: None,
+        idempotency_key: None,
+        ..usage.clone()
+    }
+}
+
+/// `note` without its refund method, and moved to `pseudonym` if
+/// `user_id` holds its credit. `usage_ids` are the user's usages; `None` if
+/// the note concerns neither.
+pub fn pseudonymize_credit_note(
+    note: &CreditNote,
+    usage_ids: &[i64],
+    user_id: &UserId,
+    pseudonym: &UserId,
+) -> Option<CreditNote> {
+    let holds = note.payer == Payer::User(user_id.clone());
+    if !holds && !usage_ids.contains(&note.usage_id) {
+        return None;
+    }
+    Some(CreditNote {
+        payer: if holds {
+            Payer::User(pseudonym.clone())
+        } else {
+            note.payer.clone()
+        },
+        refund_to: None,
+        ..note.clone()
+    })
+}
+
+/// `application` moved to `pseudonym` if `user_id` was charged by it.
+pub fn pseudonymize_credit_application(
+    application: &CreditApplication,
+    user_id: &UserId,
+    pseudonym: &UserId,
+) -> Option<CreditApplication> {
+    (application.payer == Payer::User(user_id.clone())).then(|| CreditApplication {
+        payer: Payer::User(pseudonym.clone()),
+        ..application.clone()
+    })
+}
+
+/// `case` moved to `pseudonym` without its payment method, as a new
+/// revision; `None` if it is not `user_id`'s.
+pub fn pseudonymize_dunning_case(
+    case: &DunningCase,
+    user_id: &UserId,
+    pseudonym: &UserId,
+) -> Option<DunningCase> {
+    (&case.user_id == user_id).then(|| DunningCase {
+        user_id: pseudonym.clone(),
+        payment: None,
+        revision: case.revision + 1,
+        ..case.clone()
+    })
+}
+
+/// `account` with `user_id`'s membership moved to `pseudonym`, same role and
+/// position; `None` if they are not a member.
+pub fn pseudonymize_account(
+    account: &Account,
+    user_id: &UserId,
+    pseudonym: &UserId,
+) -> Option<Account> {
+    account.role_of(user_id)?;
+    let mut moved = account.clone();
+    for m in moved.members.iter_mut().filter(|m| &m.user_id == user_id) {
+        m.user_id = pseudonym.clone();
+    }
+    Some(moved)
+}
+
+/// Tokens of the vaulted cards among `payments`, which an erasure deletes.
+pub fn card_tokens<'a>(
+    payments: impl IntoIterator<Item = &'a PaymentMethod>,
+) -> Vec<crate::vault::CardToken> {
+    let mut tokens = Vec::new();
+    for pm in payments {
+        if let PaymentMethod::Card(token) = pm {
+            if !tokens.contains(token) {
+                tokens.push(token.clone());
+            }
+        }
+    }
+    tokens
+}
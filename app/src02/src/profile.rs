@@ -0,0 +1,381 @@
+//! What a user tells us about themselves: email, billing addresses, locale,
+//! timezone and saved payment methods (see `models::Profile`).
+//!
+//! `Email`, `Locale`, `Timezone` and `BillingAddress` are checked when built
+//! and when deserialized, so a stored or posted profile always holds valid
+//! values. An email belongs to one user at most: `Repository::save_user` and
+//! `Repository::update_profile` refuse a taken one with
+//! `RepositoryError::EmailTaken`. `update` loads a user, changes the profile
+//! and stores it, for callers that edit one part of it; a profile changed by
+//! someone else in between is changed again, so parallel edits all land.
+
+use crate::models::{Profile, User, UserId};
+use crate::repository::{Repository, RepositoryError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ProfileError {
+    InvalidEmail(String),
+    InvalidLocale(String),
+    InvalidTimezone(String),
+    /// A required address field is blank.
+    EmptyAddressField(&'static str),
+    /// Not an ISO 3166-1 alpha-2 country code.
+    InvalidCountry(String),
+    NoSuchPaymentMethod(usize),
+    NoSuchAddress(usize),
+    UnknownUser(UserId),
+    /// The stored profile is no longer at the revision the new one was
+    /// based on.
+    Stale(UserId),
+    /// Another user has this email.
+    EmailTaken(Email),
+    Repository(RepositoryError),
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileError::InvalidEmail(s) => write!(f, "{:?} is not a valid email address", s),
+            ProfileError::InvalidLocale(s) => {
+                write!(f, "{:?} is not a locale like \"en\" or \"pt-BR\"", s)
+            }
+            ProfileError::InvalidTimezone(s) => {
+                write!(
+                    f,
+                    "{:?} is not a timezone like \"Europe/Rome\" or \"UTC\"",
+                    s
+                )
+            }
+            ProfileError::EmptyAddressField(field) => {
+                write!(f, "billing address {} must not be empty", field)
+            }
+            ProfileError::InvalidCountry(s) => {
+                write!(f, "{:?} is not a two-letter country code", s)
+            }
+            ProfileError::NoSuchPaymentMethod(i) => write!(f, "no payment method #{}", i),
+            ProfileError::NoSuchAddress(i) => write!(f, "no billing address #{}", i),
+            ProfileError::UnknownUser(id) => write!(f, "unknown user {}", id.0),
+            ProfileError::Stale(id) => write!(
+                f,
+                "the profile of {} changed since it was read; read it again",
+                id.0
+            ),
+            ProfileError::EmailTaken(email) => {
+                write!(f, "email {} is already used by another user", email)
+            }
+            ProfileError::Repository(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProfileError::Repository(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<RepositoryError> for ProfileError {
+    fn from(e: RepositoryError) -> Self {
+        match e {
+            RepositoryError::EmailTaken(email) => ProfileError::EmailTaken(email),
+            e => ProfileError::Repository(e),
+        }
+    }
+}
+
+/// An email address, lower-cased so that two spellings of one address
+/// compare equal.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Email(String);
+
+impl Email {
+    /// `local@domain`: no spaces, at most 254 characters, and a domain of
+    /// dot-separated labels of letters, digits and inner hyphens.
+    pub fn parse(input: &str) -> Result<Self, ProfileError> {
+        let email = input.trim().to_lowercase();
+        let invalid = || ProfileError::InvalidEmail(input.to_string());
+        let (local, domain) = email.split_once('@').ok_or_else(invalid)?;
+        let local_ok = !local.is_empty()
+            && local.len() <= 64
+            && local
+                .chars()
+                .all(|c| c.is_ascii_graphic() && !matches!(c, '@' | '"' | ',' | ';'));
+        let labels: Vec<&str> = domain.split('.').collect();
+        let domain_ok = labels.len() >= 2
+            && labels.iter().all(|l| {
+                !l.is_empty()
+                    && !l.starts_with('-')
+                    && !l.ends_with('-')
+                    && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+        if !local_ok || !domain_ok || email.len() > 254 {
+            return Err(invalid());
+        }
+        Ok(Email(email))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Email {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<String> for Email {
+    type Error = ProfileError;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Email::parse(&s)
+    }
+}
+
+impl From<Email> for String {
+    fn from(email: Email) -> Self {
+        email.0
+    }
+}
+
+/// A language tag such as `en`, `pt-BR` or `zh-Hant-TW`: a language, then
+/// optionally a script and a region. `_` is read as `-` and the case of
+/// each part is normalized.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Locale(String);
+
+impl Locale {
+    pub fn parse(input: &str) -> Result<Self, ProfileError> {
+        let invalid = || ProfileError::InvalidLocale(input.to_string());
+        let mut parts = input.trim().split(['-', '_']);
+        let language = parts.next().unwrap_or_default();
+        if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_alphabetic())
+        {
+            return Err(invalid());
+        }
+        let mut tag = language.to_ascii_lowercase();
+        let mut script_allowed = true;
+        let mut region_allowed = true;
+        for part in parts {
+            let alpha = part.chars().all(|c| c.is_ascii_alphabetic());
+            let digits = part.chars().all(|c| c.is_ascii_digit());
+            tag.push('-');
+            match part.len() {
+                4 if alpha && script_allowed => {
+                    tag.push_str(&part[..1].to_ascii_uppercase());
+                    tag.push_str(&part[1..].to_ascii_lowercase());
+                    script_allowed = false;
+                }
+                2 if alpha && region_allowed => tag.push_str(&part.to_ascii_uppercase()),
+                3 if digits && region_allowed => tag.push_str(part),
+                _ => return Err(invalid()),
+            }
+            if part.len() != 4 {
+                script_allowed = false;
+                region_allowed = false;
+            }
+        }
+        Ok(Locale(tag))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<String> for Locale {
+    type Error = ProfileError;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Locale::parse(&s)
+    }
+}
+
+impl From<Locale> for String {
+    fn from(locale: Locale) -> Self {
+        locale.0
+    }
+}
+
+/// An IANA timezone name such as `Europe/Rome` or `UTC`. Only the form is
+/// checked, not that the tz database has the zone.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Timezone(String);
+
+impl Timezone {
+    pub fn parse(input: &str) -> Result<Self, ProfileError> {
+        let name = input.trim();
+        let valid = name.len() <= 64
+            && name.split('/').all(|part| {
+                part.starts_with(|c: char| c.is_ascii_uppercase())
+                    && part
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+'))
+            });
+        if !valid {
+            return Err(ProfileError::InvalidTimezone(input.to_string()));
+        }
+        Ok(Timezone(name.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Timezone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<String> for Timezone {
+    type Error = ProfileError;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Timezone::parse(&s)
+    }
+}
+
+impl From<Timezone> for String {
+    fn from(timezone: Timezone) -> Self {
+        timezone.0
+    }
+}
+
+/// Where invoices go. Build with `BillingAddress::new`, then set the
+/// optional parts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "AddressFields")]
+pub struct BillingAddress {
+    /// How the user tells their addresses apart, e.g. "office".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub line1: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line2: Option<String>,
+    pub city: String,
+    pub postal_code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// ISO 3166-1 alpha-2, upper case.
+    pub country: String,
+}
+
+impl BillingAddress {
+    pub fn new(
+        line1: &str,
+        city: &str,
+        postal_code: &str,
+        country: &str,
+    ) -> Result<Self, ProfileError> {
+        AddressFields {
+            label: None,
+            line1: line1.to_string(),
+            line2: None,
+            city: city.to_string(),
+            postal_code: postal_code.to_string(),
+            region: None,
+            country: country.to_string(),
+        }
+        .try_into()
+    }
+
+    /// Same address, named `label`.
+    pub fn labeled(self, label: &str) -> Self {
+        BillingAddress {
+            label: Some(label.to_string()),
+            ..self
+        }
+    }
+}
+
+/// A `BillingAddress` before it is checked.
+#[derive(Deserialize)]
+struct AddressFields {
+    #[serde(default)]
+    label: Option<String>,
+    line1: String,
+    #[serde(default)]
+    line2: Option<String>,
+    city: String,
+    postal_code: String,
+    #[serde(default)]
+    region: Option<String>,
+    country: String,
+}
+
+impl TryFrom<AddressFields> for BillingAddress {
+    type Error = ProfileError;
+    fn try_from(a: AddressFields) -> Result<Self, Self::Error> {
+        let required = |value: String, field| match value.trim() {
+            "" => Err(ProfileError::EmptyAddressField(field)),
+            v => Ok(v.to_string()),
+        };
+        let optional = |value: Option<String>| {
+            value
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        let country = a.country.trim().to_ascii_uppercase();
+        if country.len() != 2 || !country.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(ProfileError::InvalidCountry(a.country));
+        }
+        Ok(BillingAddress {
+            label: optional(a.label),
+            line1: required(a.line1, "line1")?,
+            line2: optional(a.line2),
+            city: required(a.city, "city")?,
+            postal_code: required(a.postal_code, "postal_code")?,
+            region: optional(a.region),
+            country,
+        })
+    }
+}
+
+/// What `Repository::update_profile` did.
+#[derive(Debug)]
+pub enum ProfileUpdate {
+    Updated(User),
+    /// The stored profile is not at the revision before the new one: it
+    /// changed since it was loaded. Nothing was written.
+    Stale,
+    UnknownUser,
+}
+
+/// Change `user_id`'s profile with `change` and store it as the next
+/// revision. If another update is stored in between, `change` is applied
+/// again, to the profile that update stored.
+pub async fn update(
+    repo: &dyn Repository,
+    user_id: &UserId,
+    change: impl Fn(&Profile) -> Result<Profile, ProfileError>,
+) -> Result<User, ProfileError> {
+    loop {
+        let user = repo
+            .get_user(user_id)
+            .await?
+            .ok_or_else(|| ProfileError::UnknownUser(user_id.clone()))?;
+        let profile = Profile {
+            revision: user.profile.revision + 1,
+            ..change(&user.profile)?
+        };
+        match repo.update_profile(user_id, &profile).await? {
+            ProfileUpdate::Updated(user) => return Ok(user),
+            ProfileUpdate::Stale => continue,
+            ProfileUpdate::UnknownUser => return Err(ProfileError::UnknownUser(user_id.clone())),
+        }
+    }
+}
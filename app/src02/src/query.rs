@@ -0,0 +1,225 @@
+//! Usage queries shared by every store: filters that combine freely,
+//! cursor-based pagination and count/sum aggregates.
+//!
+//! The same `UsageQuery` runs against a `UsageLog` (`UsageLog::query`,
+//! `UsageLog::aggregate`), SQLite (`persistence::query_usages`,
+//! `persistence::aggregate_usages`) and any `Repository`.
+
+use crate::account::AccountId;
+use crate::models::{ProductId, Service, ServiceId, ServiceUsage, UserId};
+use crate::payment::PaymentKind;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::fmt;
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// Position after which the next page starts. Opaque to clients: pass back
+/// the string from `Page::next_cursor` unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cursor(i64);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidCursor(pub String);
+
+impl fmt::Display for InvalidCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid cursor {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidCursor {}
+
+impl Cursor {
+    pub(crate) fn after_id(id: i64) -> Self {
+        Cursor(id)
+    }
+
+    pub(crate) fn id(self) -> i64 {
+        self.0
+    }
+
+    pub fn encode(self) -> String {
+        format!("c{:x}", self.0)
+    }
+
+    pub fn parse(s: &str) -> Result<Self, InvalidCursor> {
+        s.strip_prefix('c')
+            .and_then(|hex| i64::from_str_radix(hex, 16).ok())
+            .filter(|id| *id >= 0)
+            .map(Cursor)
+            .ok_or_else(|| InvalidCursor(s.to_string()))
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.encode())
+    }
+}
+
+/// Filters are ANDed; an unset filter matches everything. `from` is
+/// inclusive, `until` exclusive. `payment_kind` matches the payment recorded
+/// on the usage itself, not the user's default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageQuery {
+    pub user_id: Option<UserId>,
+    pub service_id: Option<ServiceId>,
+    pub product_id: Option<ProductId>,
+    pub payment_kind: Option<PaymentKind>,
+    pub account_id: Option<AccountId>,
+    pub from: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub after: Option<Cursor>,
+    pub limit: Option<usize>,
+}
+
+impl UsageQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn user(mut self, user_id: &UserId) -> Self {
+        self.user_id = Some(user_id.clone());
+        self
+    }
+
+    pub fn service(mut self, service_id: &ServiceId) -> Self {
+        self.service_id = Some(service_id.clone());
+        self
+    }
+
+    pub fn product(mut self, product_id: &ProductId) -> Self {
+        self.product_id = Some(product_id.clone());
+        self
+    }
+
+    pub fn payment_kind(mut self, kind: PaymentKind) -> Self {
+        self.payment_kind = Some(kind);
+        self
+    }
+
+    pub fn account(mut self, account_id: &AccountId) -> Self {
+        self.account_id = Some(account_id.clone());
+        self
+    }
+
+    pub fn from(mut self, from: DateTime<Utc>) -> Self {
+        self.from = Some(from);
+        self
+    }
+
+    pub fn until(mut self, until: DateTime<Utc>) -> Self {
+        self.until = Some(until);
+        self
+    }
+
+    pub fn after(mut self, cursor: Cursor) -> Self {
+        self.after = Some(cursor);
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Page size actually used: `limit` clamped to `1..=MAX_PAGE_SIZE`.
+    pub fn page_size(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// Whether `usage` passes every filter (the cursor is not considered).
+    pub fn matches(&self, usage: &ServiceUsage) -> bool {
+        self.user_id.as_ref().is_none_or(|u| &usage.user_id == u)
+            && self
+                .service_id
+                .as_ref()
+                .is_none_or(|s| &usage.service_id == s)
+            && self
+                .product_id
+                .as_ref()
+                .is_none_or(|p| &usage.product_id == p)
+            && self
+                .payment_kind
+                .is_none_or(|k| usage.payment_used.as_ref().map(|pm| pm.kind()) == Some(k))
+            && self
+                .account_id
+                .as_ref()
+                .is_none_or(|a| usage.account_id.as_ref() == Some(a))
+            && self.from.is_none_or(|t| usage.occurred_at >= t)
+            && self.until.is_none_or(|t| usage.occurred_at < t)
+    }
+}
+
+/// A stored usage with the id its store assigned (the pagination key).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub id: i64,
+    pub usage: ServiceUsage,
+}
+
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Set when more results follow; pass it to `UsageQuery::after`.
+    pub next_cursor: Option<Cursor>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageAggregate {
+    pub count: u64,
+    /// Sum of catalog prices; usages of unknown products count as 0.
+    pub total_cents: u64,
+}
+
+/// Evaluate `query` over usages that are already in id order.
+pub(crate) fn page_from_iter<U: Borrow<ServiceUsage>>(
+    query: &UsageQuery,
+    usages: impl Iterator<Item = (i64, U)>,
+) -> Page<UsageRecord> {
+    let size = query.page_size();
+    let after = query.after.map(|c| c.id()).unwrap_or(i64::MIN);
+    let mut items: Vec<UsageRecord> = usages
+        .filter(|(id, u)| *id > after && query.matches(u.borrow()))
+        .take(size + 1)
+        .map(|(id, u)| UsageRecord {
+            id,
+            usage: u.borrow().clone(),
+        })
+        .collect();
+    let next_cursor = if items.len() > size {
+        items.truncate(size);
+        items.last().map(|r| Cursor::after_id(r.id))
+    } else {
+        None
+    };
+    Page { items, next_cursor }
+}
+
+/// Count and price the matching usages (cursor and limit are ignored).
+pub(crate) fn aggregate_iter<'a>(
+    query: &UsageQuery,
+    usages: impl Iterator<Item = &'a ServiceUsage>,
+    price_of: impl Fn(&ServiceUsage) -> Option<u64>,
+) -> UsageAggregate {
+    usages
+        .filter(|u| query.matches(u))
+        .fold(UsageAggregate::default(), |acc, u| UsageAggregate {
+            count: acc.count + 1,
+            total_cents: acc.total_cents + price_of(u).unwrap_or(0),
+        })
+}
+
+/// Price of `usage` within `service` when it occurred, for `aggregate_iter`.
+pub(crate) fn price_in(service: &Service, usage: &ServiceUsage) -> Option<u64> {
+    service
+        .products
+        .iter()
+        .find(|p| p.id == usage.product_id)
+        .map(|p| p.price_at(usage.occurred_at))
+}
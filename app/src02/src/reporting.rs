@@ -0,0 +1,281 @@
+//! Revenue reports: usages and revenue per service, product and user for a
+//! period, compared with the period before it.
+//!
+//! Revenue is priced like every aggregate, each usage at the catalog price in
+//! effect when it occurred. `compute` works on usages already in memory;
+//! `build` reads them, the catalog and the users from a `Repository`.
+
+use crate::catalog::Catalog;
+use crate::models::{ServiceUsage, UserMap};
+use crate::query::{UsageQuery, MAX_PAGE_SIZE};
+use crate::repository::{Repository, RepositoryError};
+use crate::validation::load_catalog;
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+
+/// `from` inclusive, `until` exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportPeriod {
+    pub from: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+}
+
+impl ReportPeriod {
+    pub fn new(from: DateTime<Utc>, until: DateTime<Utc>) -> Self {
+        ReportPeriod { from, until }
+    }
+
+    /// One calendar month in UTC; `None` for an invalid month.
+    pub fn month(year: i32, month: u32) -> Option<Self> {
+        let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+        let next = first.checked_add_months(Months::new(1))?;
+        let midnight = |d: NaiveDate| d.and_hms_opt(0, 0, 0).expect("midnight").and_utc();
+        Some(ReportPeriod::new(midnight(first), midnight(next)))
+    }
+
+    /// The period to compare with: the same number of calendar months before
+    /// a period of whole months, otherwise a window of the same length ending
+    /// where this one starts.
+    pub fn previous(&self) -> ReportPeriod {
+        let is_month_start = |t: DateTime<Utc>| {
+            t.day() == 1 && t.date_naive().and_hms_opt(0, 0, 0).map(|m| m.and_utc()) == Some(t)
+        };
+        if is_month_start(self.from) && is_month_start(self.until) {
+            let months = (self.until.year() - self.from.year()) * 12 + self.until.month() as i32
+                - self.from.month() as i32;
+            if let Some(from) = u32::try_from(months)
+                .ok()
+                .and_then(|m| self.from.checked_sub_months(Months::new(m)))
+            {
+                return ReportPeriod::new(from, self.from);
+            }
+        }
+        ReportPeriod::new(self.from - (self.until - self.from), self.from)
+    }
+
+    pub fn query(&self) -> UsageQuery {
+        UsageQuery::new().from(self.from).until(self.until)
+    }
+}
+
+/// Which rows of a report to export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Service,
+    Product,
+    User,
+    TopConsumers,
+}
+
+/// One service, product or user in both periods.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RevenueRow {
+    pub id: String,
+    pub name: String,
+    pub usages: u64,
+    pub revenue_cents: u64,
+    pub previous_usages: u64,
+    pub previous_revenue_cents: u64,
+    pub change_cents: i64,
+    /// Rounded to two decimals; `None` when the previous period had no
+    /// revenue.
+    pub change_percent: Option<f64>,
+}
+
+impl RevenueRow {
+    fn new(id: &str, name: &str, current: Tally, previous: Tally) -> Self {
+        let change_cents = current.cents as i64 - previous.cents as i64;
+        RevenueRow {
+            id: id.to_string(),
+            name: name.to_string(),
+            usages: current.count,
+            revenue_cents: current.cents,
+            previous_usages: previous.count,
+            previous_revenue_cents: previous.cents,
+            change_cents,
+            change_percent: (previous.cents > 0)
+                .then(|| (change_cents as f64 * 10_000.0 / previous.cents as f64).round() / 100.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RevenueReport {
+    pub period: ReportPeriod,
+    pub previous_period: ReportPeriod,
+    pub total: RevenueRow,
+    /// Rows are ordered by revenue, highest first, then by id.
+    pub by_service: Vec<RevenueRow>,
+    pub by_product: Vec<RevenueRow>,
+    pub by_user: Vec<RevenueRow>,
+    /// The users with the most revenue in `period`.
+    pub top_consumers: Vec<RevenueRow>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Tally {
+    count: u64,
+    cents: u64,
+}
+
+impl Tally {
+    fn add(&mut self, cents: u64) {
+        self.count += 1;
+        self.cents = self.cents.saturating_add(cents);
+    }
+}
+
+/// Tallies per key for the current (`.0`) and previous (`.1`) period.
+type Groups = BTreeMap<String, (Tally, Tally)>;
+
+impl RevenueReport {
+    /// Report over `current` (usages in `period`) and `previous` (usages in
+    /// `period.previous()`); at most `top` users are listed as top consumers.
+    pub fn compute(
+        period: ReportPeriod,
+        current: &[ServiceUsage],
+        previous: &[ServiceUsage],
+        catalog: &Catalog,
+        users: &UserMap,
+        top: usize,
+    ) -> Self {
+        let mut total = (Tally::default(), Tally::default());
+        let (mut services, mut products, mut by_user) =
+            (Groups::new(), Groups::new(), Groups::new());
+        for (usages, is_current) in [(current, true), (previous, false)] {
+            for u in usages {
+                let cents = catalog.usage_price(u).unwrap_or(0);
+                let pick = |t: &mut (Tally, Tally)| {
+                    if is_current {
+                        t.0.add(cents)
+                    } else {
+                        t.1.add(cents)
+                    }
+                };
+                pick(&mut total);
+                pick(services.entry(u.service_id.0.clone()).or_default());
+                pick(products.entry(u.product_id.0.clone()).or_default());
+                pick(by_user.entry(u.user_id.0.clone()).or_default());
+            }
+        }
+
+        let service_name = |id: &str| {
+            catalog
+                .get_service(&id.into())
+                .map(|s| s.name.clone())
+                .unwrap_or_default()
+        };
+        let product_name = |id: &str| {
+            catalog
+                .find_product(&id.into())
+                .map(|(_, p)| p.name.clone())
+                .unwrap_or_default()
+        };
+        let user_name = |id: &str| {
+            users
+                .get(&id.into())
+                .map(|u| u.profile.display_name.clone())
+                .unwrap_or_default()
+        };
+        let by_user = rows(by_user, user_name);
+        let top_consumers = by_user
+            .iter()
+            .filter(|r| r.usages > 0)
+            .take(top)
+            .cloned()
+            .collect();
+        RevenueReport {
+            period,
+            previous_period: period.previous(),
+            total: RevenueRow::new("TOTAL", "", total.0, total.1),
+            by_service: rows(services, service_name),
+            by_product: rows(products, product_name),
+            by_user,
+            top_consumers,
+        }
+    }
+
+    pub fn section(&self, section: Section) -> &[RevenueRow] {
+        match section {
+            Section::Service => &self.by_service,
+            Section::Product => &self.by_product,
+            Section::User => &self.by_user,
+            Section::TopConsumers => &self.top_consumers,
+        }
+    }
+}
+
+fn rows(groups: Groups, name: impl Fn(&str) -> String) -> Vec<RevenueRow> {
+    let mut rows: Vec<RevenueRow> = groups
+        .into_iter()
+        .map(|(id, (current, previous))| RevenueRow::new(&id, &name(&id), current, previous))
+        .collect();
+    // BTreeMap order breaks ties by id; the sort is stable
+    rows.sort_by_key(|r| std::cmp::Reverse(r.revenue_cents));
+    rows
+}
+
+/// Every usage matching `query`, read page by page.
+async fn all_usages(
+    repo: &dyn Repository,
+    query: UsageQuery,
+) -> Result<Vec<ServiceUsage>, RepositoryError> {
+    let mut query = query.limit(MAX_PAGE_SIZE);
+    let mut usages = Vec::new();
+    loop {
+        let page = repo.query_usages(&query).await?;
+        usages.extend(page.items.into_iter().map(|r| r.usage));
+        match page.next_cursor {
+            Some(c) => query = query.after(c),
+            None => return Ok(usages),
+        }
+    }
+}
+
+/// Report for `period` from the usages, catalog and users stored in `repo`.
+pub async fn build(
+    repo: &dyn Repository,
+    period: ReportPeriod,
+    top: usize,
+) -> Result<RevenueReport, RepositoryError> {
+    let current = all_usages(repo, period.query()).await?;
+    let previous = all_usages(repo, period.previous().query()).await?;
+    let catalog = load_catalog(repo).await?;
+    let users = repo
+        .get_users()
+        .await?
+        .into_iter()
+        .map(|u| (u.id.clone(), u))
+        .collect();
+    Ok(RevenueReport::compute(
+        period, &current, &previous, &catalog, &users, top,
+    ))
+}
+
+/// Column names of `write_csv`, the fields of `RevenueRow`.
+pub const CSV_HEADER: [&str; 8] = [
+    "id",
+    "name",
+    "usages",
+    "revenue_cents",
+    "previous_usages",
+    "previous_revenue_cents",
+    "change_cents",
+    "change_percent",
+];
+
+/// Write `rows` as CSV with a header line, also when there are no rows;
+/// amounts stay in cents.
+pub fn write_csv<W: io::Write>(rows: &[RevenueRow], writer: W) -> Result<(), csv::Error> {
+    let mut w = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    w.write_record(CSV_HEADER)?;
+    for row in rows {
+        w.serialize(row)?;
+    }
+    w.flush()?;
+    Ok(())
+}
@@ -0,0 +1,528 @@
+use super::{check_usage_references, unknown_card, Repository, RepositoryError};
+use crate::account::Account;
+use crate::budget::Budget;
+use crate::credit::{self, CreditApplication, CreditNote, Issued};
+use crate::dunning::{DunningCase, DunningEvent};
+use crate::idempotency::{self, IdempotencyKey, Recorded, DEFAULT_RETENTION};
+use crate::models::{PaymentMethod, Profile, Service, ServiceUsage, User, UserId};
+use crate::privacy::{self, AuditRecord, ErasureReport, UserExport};
+use crate::profile::ProfileUpdate;
+use crate::query::{self, Page, UsageAggregate, UsageQuery, UsageRecord};
+use crate::vault::{CardToken, CardVault, StoredCard};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+#[derive(Debug, Default)]
+struct State {
+    cards: CardVault,
+    users: BTreeMap<String, User>,
+    services: BTreeMap<String, Service>,
+    accounts: BTreeMap<String, Account>,
+    budgets: BTreeMap<String, Budget>,
+    usages: Vec<ServiceUsage>,
+    /// Index into `usages` of each key's holder, and when it was recorded.
+    keys: HashMap<IdempotencyKey, (usize, DateTime<Utc>)>,
+    audit: Vec<AuditRecord>,
+    credit_notes: Vec<CreditNote>,
+    credit_applications: Vec<CreditApplication>,
+    dunning_cases: Vec<DunningCase>,
+    dunning_events: Vec<DunningEvent>,
+}
+
+impl State {
+    /// `EmailTaken` if a user other than `user_id` has `profile`'s email.
+    fn check_email(&self, user_id: &UserId, profile: &Profile) -> Result<(), RepositoryError> {
+        let Some(email) = &profile.email else {
+            return Ok(());
+        };
+        let taken = self
+            .users
+            .values()
+            .any(|u| u.id != *user_id && u.profile.email.as_ref() == Some(email));
+        if taken {
+            Err(RepositoryError::EmailTaken(email.clone()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// `unknown_card` for `table` if one of `payments` names a card that is
+    /// not stored.
+    fn check_cards<'a>(
+        &self,
+        table: &'static str,
+        payments: impl IntoIterator<Item = &'a PaymentMethod>,
+    ) -> Result<(), RepositoryError> {
+        if privacy::card_tokens(payments)
+            .iter()
+            .all(|t| self.cards.get(t).is_some())
+        {
+            Ok(())
+        } else {
+            Err(unknown_card(table))
+        }
+    }
+
+    fn record(&self, index: usize) -> UsageRecord {
+        UsageRecord {
+            id: index as i64 + 1,
+            usage: self.usages[index].clone(),
+        }
+    }
+
+    /// `check_usage_references` for every usage `push` would write, so a
+    /// refused one leaves nothing half-recorded.
+    fn check_fresh(
+        &self,
+        usages: &[ServiceUsage],
+        now: DateTime<Utc>,
+        retention: Duration,
+    ) -> Result<(), RepositoryError> {
+        let mut seen = HashSet::new();
+        for usage in usages {
+            if let Some(key) = &usage.idempotency_key {
+                let live = matches!(self.keys.get(key),
+                    Some(&(_, at)) if idempotency::is_live(at, now, retention));
+                if !seen.insert(key) || live {
+                    continue;
+                }
+            }
+            self.check_cards("usages", &usage.payment_used)?;
+            check_usage_references(
+                usage,
+                self.users.contains_key(&usage.user_id.0),
+                self.services.get(&usage.service_id.0),
+                usage
+                    .account_id
+                    .as_ref()
+                    .and_then(|a| self.accounts.get(&a.0)),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Record `usage` unless its key is live; an expired key is taken from
+    /// its old holder.
+    fn push(&mut self, usage: &ServiceUsage, now: DateTime<Utc>, retention: Duration) -> Recorded {
+        if let Some(key) = &usage.idempotency_key {
+            match self.keys.get(key) {
+                Some(&(i, at)) if idempotency::is_live(at, now, retention) => {
+                    return Recorded::Replayed(self.record(i));
+                }
+                Some(&(i, _)) => self.usages[i].idempotency_key = None,
+                None => {}
+            }
+            self.keys.insert(key.clone(), (self.usages.len(), now));
+        }
+        self.usages.push(usage.clone());
+        Recorded::New(self.record(self.usages.len() - 1))
+    }
+}
+
+/// Process-local `Repository`; nothing survives the value being dropped.
+#[derive(Debug, Default)]
+pub struct MemoryRepository {
+    state: Mutex<State>,
+}
+
+impl MemoryRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        // a panic while holding the lock cannot leave State half-updated:
+        // every write is a single insert/push, or `State::push`, which
+        // cannot panic between its updates
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl Repository for MemoryRepository {
+    async fn init(&self) -> Result<(), RepositoryError> {
+        Ok(())
+    }
+
+    async fn save_card(&self, card: &StoredCard) -> Result<(), RepositoryError> {
+        self.state().cards.insert(card.clone());
+        Ok(())
+    }
+
+    async fn get_card(&self, token: &CardToken) -> Result<Option<StoredCard>, RepositoryError> {
+        Ok(self.state().cards.get(token).cloned())
+    }
+
+    async fn save_user(&self, user: &User) -> Result<(), RepositoryError> {
+        let mut state = self.state();
+        state.check_cards("users", &user.profile.payment_methods)?;
+        state.check_email(&user.id, &user.profile)?;
+        let revision = state
+            .users
+            .get(&user.id.0)
+            .map_or(0, |stored| stored.profile.revision + 1);
+        let mut user = user.clone();
+        user.profile.revision = revision;
+        state.users.insert(user.id.0.clone(), user);
+        Ok(())
+    }
+
+    async fn get_users(&self) -> Result<Vec<User>, RepositoryError> {
+        Ok(self.state().users.values().cloned().collect())
+    }
+
+    async fn get_user(&self, user_id: &UserId) -> Result<Option<User>, RepositoryError> {
+        Ok(self.state().users.get(&user_id.0).cloned())
+    }
+
+    async fn update_profile(
+        &self,
+        user_id: &UserId,
+        profile: &Profile,
+    ) -> Result<ProfileUpdate, RepositoryError> {
+        let mut state = self.state();
+        let Some(stored) = state.users.get(&user_id.0) else {
+            return Ok(ProfileUpdate::UnknownUser);
+        };
+        if stored.profile.revision.checked_add(1) != Some(profile.revision) {
+            return Ok(ProfileUpdate::Stale);
+        }
+        state.check_cards("users", &profile.payment_methods)?;
+        state.check_email(user_id, profile)?;
+        let user = User {
+            id: user_id.clone(),
+            profile: profile.clone(),
+        };
+        state.users.insert(user_id.0.clone(), user.clone());
+        Ok(ProfileUpdate::Updated(user))
+    }
+
+    async fn save_service(&self, service: &Service) -> Result<(), RepositoryError> {
+        self.save_services(std::slice::from_ref(service)).await
+    }
+
+    async fn save_services(&self, services: &[Service]) -> Result<(), RepositoryError> {
+        let mut state = self.state();
+        // written to a copy, kept only if every service is accepted
+        let mut saved = state.services.clone();
+        for service in services {
+            for (i, p) in service.products.iter().enumerate() {
+                let existing = if service.products[..i].iter().any(|q| q.id == p.id) {
+                    Some(&service.id)
+                } else {
+                    saved
+                        .values()
+                        .find(|s| s.id != service.id && s.products.iter().any(|q| q.id == p.id))
+                        .map(|s| &s.id)
+                };
+                if let Some(existing) = existing {
+                    return Err(RepositoryError::DuplicateProduct {
+                        product_id: p.id.clone(),
+                        existing: existing.clone(),
+                    });
+                }
+            }
+            saved.insert(service.id.0.clone(), service.clone());
+        }
+        state.services = saved;
+        Ok(())
+    }
+
+    async fn get_services(&self) -> Result<Vec<Service>, RepositoryError> {
+        Ok(self.state().services.values().cloned().collect())
+    }
+
+    async fn save_account(&self, account: &Account) -> Result<(), RepositoryError> {
+        let mut state = self.state();
+        state.check_cards("accounts", &account.payment_methods)?;
+        state.accounts.insert(account.id.0.clone(), account.clone());
+        Ok(())
+    }
+
+    async fn get_accounts(&self) -> Result<Vec<Account>, RepositoryError> {
+        Ok(self.state().accounts.values().cloned().collect())
+    }
+
+    async fn save_budget(&self, budget: &Budget) -> Result<(), RepositoryError> {
+        self.state()
+            .budgets
+            .insert(budget.id.0.clone(), budget.clone());
+        Ok(())
+    }
+
+    async fn get_budgets(&self) -> Result<Vec<Budget>, RepositoryError> {
+        Ok(self.state().budgets.values().cloned().collect())
+    }
+
+    async fn save_usage(&self, usage: &ServiceUsage) -> Result<(), RepositoryError> {
+        self.record_usage(usage, DEFAULT_RETENTION).await?;
+        Ok(())
+    }
+
+    async fn save_usages(&self, usages: &[ServiceUsage]) -> Result<usize, RepositoryError> {
+        let now = Utc::now();
+        let mut state = self.state();
+        state.check_fresh(usages, now, DEFAULT_RETENTION)?;
+        Ok(usages
+            .iter()
+            .filter(|u| !state.push(u, now, DEFAULT_RETENTION).is_replay())
+            .count())
+    }
+
+    async fn record_usage(
+        &self,
+        usage: &ServiceUsage,
+        retention: Duration,
+    ) -> Result<Recorded, RepositoryError> {
+        let now = Utc::now();
+        let mut state = self.state();
+        state.check_fresh(std::slice::from_ref(usage), now, retention)?;
+        Ok(state.push(usage, now, retention))
+    }
+
+    async fn find_idempotent_usage(
+        &self,
+        key: &IdempotencyKey,
+        retention: Duration,
+    ) -> Result<Option<UsageRecord>, RepositoryError> {
+        let state = self.state();
+        Ok(state
+            .keys
+            .get(key)
+            .filter(|(_, at)| idempotency::is_live(*at, Utc::now(), retention))
+            .map(|&(i, _)| state.record(i)))
+    }
+
+    async fn get_usages_for_user(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<ServiceUsage>, RepositoryError> {
+        Ok(self
+            .state()
+            .usages
+            .iter()
+            .filter(|u| &u.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn query_usages(&self, query: &UsageQuery) -> Result<Page<UsageRecord>, RepositoryError> {
+        // ids are 1-based positions, as in `UsageLog::query`
+        Ok(query::page_from_iter(
+            query,
+            (1..).zip(&self.state().usages),
+        ))
+    }
+
+    async fn aggregate_usages(
+        &self,
+        query: &UsageQuery,
+    ) -> Result<UsageAggregate, RepositoryError> {
+        let state = self.state();
+        Ok(query::aggregate_iter(query, state.usages.iter(), |u| {
+            query::price_in(state.services.get(&u.service_id.0)?, u)
+        }))
+    }
+
+    async fn export_user(&self, user_id: &UserId) -> Result<Option<UserExport>, RepositoryError> {
+        let mut state = self.state();
+        let Some(user) = state.users.get(&user_id.0) else {
+            return Ok(None);
+        };
+        let accounts: Vec<Account> = state.accounts.values().cloned().collect();
+        let budgets = state
+            .budgets
+            .values()
+            .filter(|b| &b.user_id == user_id)
+            .cloned()
+            .collect();
+        let usages: Vec<UsageRecord> = (0..state.usages.len())
+            .filter(|&i| &state.usages[i].user_id == user_id)
+            .map(|i| state.record(i))
+            .collect();
+        let export = UserExport::build(
+            user,
+            privacy::memberships(&accounts, user_id),
+            budgets,
+            &usages,
+            Utc::now(),
+        );
+        let export = export
+            .with_credit_notes(&state.credit_notes)
+            .with_dunning_cases(&state.dunning_cases);
+        state.audit.push(export.audit_record());
+        Ok(Some(export))
+    }
+
+    async fn erase_user(
+        &self,
+        user_id: &UserId,
+        pseudonym: &UserId,
+    ) -> Result<Option<ErasureReport>, RepositoryError> {
+        let mut guard = self.state();
+        // one lock held throughout: nobody sees the erasure half done
+        let state = &mut *guard;
+        let Some(erased) = state.users.remove(&user_id.0) else {
+            return Ok(None);
+        };
+        let paid_with = state
+            .usages
+            .iter()
+            .filter(|u| &u.user_id == user_id)
+            .filter_map(|u| u.payment_used.as_ref());
+        for token in privacy::card_tokens(erased.profile.payment_methods.iter().chain(paid_with)) {
+            state.cards.remove(&token);
+        }
+        state
+            .users
+            .insert(pseudonym.0.clone(), privacy::erased_user(pseudonym));
+        let mut memberships = 0;
+        for account in state.accounts.values_mut() {
+            if let Some(moved) = privacy::pseudonymize_account(account, user_id, pseudonym) {
+                *account = moved;
+                memberships += 1;
+            }
+        }
+        let usage_ids: Vec<i64> = (0..state.usages.len())
+            .filter(|&i| &state.usages[i].user_id == user_id)
+            .map(|i| i as i64 + 1)
+            .collect();
+        for note in state.credit_notes.iter_mut() {
+            if let Some(moved) =
+                privacy::pseudonymize_credit_note(note, &usage_ids, user_id, pseudonym)
+            {
+                *note = moved;
+            }
+        }
+        for application in state.credit_applications.iter_mut() {
+            if let Some(moved) =
+                privacy::pseudonymize_credit_application(application, user_id, pseudonym)
+            {
+                *application = moved;
+            }
+        }
+        for case in state.dunning_cases.iter_mut() {
+            if let Some(moved) = privacy::pseudonymize_dunning_case(case, user_id, pseudonym) {
+                *case = moved;
+            }
+        }
+        let mut usages = 0;
+        for usage in state.usages.iter_mut().filter(|u| &u.user_id == user_id) {
+            if let Some(key) = &usage.idempotency_key {
+                state.keys.remove(key);
+            }
+            *usage = privacy::pseudonymize_usage(usage, pseudonym);
+            usages += 1;
+        }
+        let before = state.budgets.len();
+        state.budgets.retain(|_, b| &b.user_id != user_id);
+        for record in state.audit.iter_mut().filter(|r| &r.subject == user_id) {
+            record.subject = pseudonym.clone();
+        }
+
+        let report = ErasureReport {
+            user_id: user_id.clone(),
+            pseudonym: pseudonym.clone(),
+            at: Utc::now(),
+            usages,
+            memberships,
+            budgets_deleted: (before - state.budgets.len()) as u64,
+        };
+        state.audit.push(report.audit_record());
+        Ok(Some(report))
+    }
+
+    async fn get_privacy_audit(&self) -> Result<Vec<AuditRecord>, RepositoryError> {
+        Ok(self.state().audit.clone())
+    }
+
+    async fn issue_credit_note(&self, note: &CreditNote) -> Result<Issued, RepositoryError> {
+        let mut state = self.state();
+        // record ids are 1-based positions
+        let usage = usize::try_from(note.usage_id - 1)
+            .ok()
+            .and_then(|i| state.usages.get(i));
+        let Some(usage) = usage else {
+            return Ok(Issued::UnknownUsage);
+        };
+        let price = state
+            .services
+            .get(&usage.service_id.0)
+            .and_then(|s| query::price_in(s, usage));
+        let Some(usage_amount_cents) = price else {
+            return Ok(Issued::UnknownPrice);
+        };
+        let left = credit::remaining(&state.credit_notes, note.usage_id, usage_amount_cents);
+        if note.amount_cents > left {
+            return Ok(Issued::Exceeds {
+                remaining_cents: left,
+            });
+        }
+        let note = CreditNote {
+            usage_amount_cents,
+            ..note.clone()
+        };
+        state.credit_notes.push(note.clone());
+        Ok(Issued::Issued(note))
+    }
+
+    async fn get_credit_notes(&self) -> Result<Vec<CreditNote>, RepositoryError> {
+        Ok(self.state().credit_notes.clone())
+    }
+
+    async fn apply_credit(
+        &self,
+        application: &CreditApplication,
+    ) -> Result<CreditApplication, RepositoryError> {
+        let mut state = self.state();
+        if let Some(stored) = state
+            .credit_applications
+            .iter()
+            .find(|a| a.payer == application.payer && a.period == application.period)
+        {
+            return Ok(stored.clone());
+        }
+        let balance = credit::balance(
+            &state.credit_notes,
+            &state.credit_applications,
+            &application.payer,
+        );
+        let applied = CreditApplication {
+            amount_cents: application.amount_cents.min(balance),
+            ..application.clone()
+        };
+        state.credit_applications.push(applied.clone());
+        Ok(applied)
+    }
+
+    async fn get_credit_applications(&self) -> Result<Vec<CreditApplication>, RepositoryError> {
+        Ok(self.state().credit_applications.clone())
+    }
+
+    async fn save_dunning_case(
+        &self,
+        case: &DunningCase,
+        events: &[DunningEvent],
+    ) -> Result<bool, RepositoryError> {
+        let mut state = self.state();
+        match state.dunning_cases.iter().position(|c| c.id == case.id) {
+            Some(i) if state.dunning_cases[i].revision + 1 == case.revision => {
+                state.dunning_cases[i] = case.clone();
+            }
+            None if case.revision == 0 => state.dunning_cases.push(case.clone()),
+            _ => return Ok(false),
+        }
+        state.dunning_events.extend_from_slice(events);
+        Ok(true)
+    }
+
+    async fn get_dunning_cases(&self) -> Result<Vec<DunningCase>, RepositoryError> {
+        Ok(self.state().dunning_cases.clone())
+    }
+
+    async fn get_dunning_events(&self) -> Result<Vec<DunningEvent>, RepositoryError> {
+        Ok(self.state().dunning_events.clone())
+    }
+}
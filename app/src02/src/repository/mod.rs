@@ -0,0 +1,314 @@
+//! Storage abstraction over users, services/products and usages.
+//!
+//! `open` picks a backend from the URL scheme:
+//! - `sqlite:...` (including `sqlite::memory:`) — `SqliteRepository`
+//! - `sled:<path>` — `SledRepository`, an embedded key/value store
+//! - `memory:` — `MemoryRepository`, process-local, meant for tests
+
+mod memory;
+mod sled;
+mod sqlite;
+
+pub use self::memory::MemoryRepository;
+pub use self::sled::SledRepository;
+pub use self::sqlite::SqliteRepository;
+
+use crate::account::Account;
+use crate::budget::Budget;
+use crate::credit::{CreditApplication, CreditNote, Issued};
+use crate::dunning::{DunningCase, DunningEvent};
+use crate::idempotency::{IdempotencyKey, Recorded};
+use crate::models::{ProductId, Profile, Service, ServiceId, ServiceUsage, User, UserId};
+use crate::persistence::{self, PersistenceError};
+use crate::privacy::{AuditRecord, ErasureReport, UserExport};
+use crate::profile::{Email, ProfileUpdate};
+use crate::query::{Page, UsageAggregate, UsageQuery, UsageRecord};
+use crate::vault::{CardToken, StoredCard};
+use async_trait::async_trait;
+use chrono::Duration;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    Sqlite(sqlx::Error),
+    Persistence(PersistenceError),
+    Sled(::sled::Error),
+    Encoding(serde_json::Error),
+    UnsupportedUrl(String),
+    /// Another user already has this email.
+    EmailTaken(Email),
+    /// Another service already offers this product; products are stored
+    /// by id alone.
+    DuplicateProduct {
+        product_id: ProductId,
+        existing: ServiceId,
+    },
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepositoryError::Sqlite(e) => write!(f, "sqlite error: {}", e),
+            RepositoryError::Persistence(e) => write!(f, "{}", e),
+            RepositoryError::Sled(e) => write!(f, "sled error: {}", e),
+            RepositoryError::Encoding(e) => write!(f, "encoding error: {}", e),
+            RepositoryError::UnsupportedUrl(url) => {
+                write!(
+                    f,
+                    "unsupported storage URL {:?} (expected sqlite:, sled: or memory:)",
+                    url
+                )
+            }
+            RepositoryError::EmailTaken(email) => {
+                write!(f, "email {} is already used by another user", email)
+            }
+            RepositoryError::DuplicateProduct {
+                product_id,
+                existing,
+            } => write!(
+                f,
+                "product {} is already offered by service {}",
+                product_id.0, existing.0
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RepositoryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RepositoryError::Sqlite(e) => Some(e),
+            RepositoryError::Persistence(e) => Some(e),
+            RepositoryError::Sled(e) => Some(e),
+            RepositoryError::Encoding(e) => Some(e),
+            RepositoryError::UnsupportedUrl(_)
+            | RepositoryError::EmailTaken(_)
+            | RepositoryError::DuplicateProduct { .. } => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for RepositoryError {
+    fn from(e: sqlx::Error) -> Self {
+        RepositoryError::Sqlite(e)
+    }
+}
+
+impl From<PersistenceError> for RepositoryError {
+    fn from(e: PersistenceError) -> Self {
+        match e {
+            PersistenceError::EmailTaken(email) => RepositoryError::EmailTaken(email),
+            PersistenceError::DuplicateProduct {
+                product_id,
+                existing,
+            } => RepositoryError::DuplicateProduct {
+                product_id,
+                existing,
+            },
+            e => RepositoryError::Persistence(e),
+        }
+    }
+}
+
+impl From<::sled::Error> for RepositoryError {
+    fn from(e: ::sled::Error) -> Self {
+        RepositoryError::Sled(e)
+    }
+}
+
+impl From<serde_json::Error> for RepositoryError {
+    fn from(e: serde_json::Error) -> Self {
+        RepositoryError::Encoding(e)
+    }
+}
+
+/// Refuse `usage` the way the `usages` triggers of SQLite migrations 5 and 7
+/// do, for the backends without them. `service` and `account` are the stored
+/// ones with the usage's ids.
+pub(crate) fn check_usage_references(
+    usage: &ServiceUsage,
+    user_exists: bool,
+    service: Option<&Service>,
+    account: Option<&Account>,
+) -> Result<(), RepositoryError> {
+    let reason = match (service, &usage.account_id, account) {
+        _ if !user_exists => "usage references unknown user",
+        (None, _, _) => "usage references unknown service",
+        (Some(s), _, _) if !s.products.iter().any(|p| p.id == usage.product_id) => {
+            "usage references a product its service does not offer"
+        }
+        (_, Some(_), None) => "usage references unknown account",
+        (_, Some(_), Some(a)) if !a.members.iter().any(|m| m.user_id == usage.user_id) => {
+            "usage references an account its user is not a member of"
+        }
+        _ => return Ok(()),
+    };
+    Err(RepositoryError::Persistence(PersistenceError::Constraint {
+        table: "usages",
+        reason: reason.to_string(),
+    }))
+}
+
+/// Refusal of a `table` row naming a card missing from the card store, as
+/// the card triggers of SQLite migration 16 word it; for the backends
+/// without them, which look up each of `privacy::card_tokens` of the row.
+pub(crate) fn unknown_card(table: &'static str) -> RepositoryError {
+    RepositoryError::Persistence(PersistenceError::Constraint {
+        table,
+        reason: persistence::CARD_REFERENCE_ERROR.to_string(),
+    })
+}
+
+#[async_trait]
+pub trait Repository: Send + Sync {
+    /// Prepare the backend (apply pending migrations etc.). Safe to call repeatedly.
+    async fn init(&self) -> Result<(), RepositoryError>;
+
+    /// Insert or replace a vaulted card. Payment methods name cards by token
+    /// alone; users, accounts and usages naming a card that is not stored
+    /// here are refused with `PersistenceError::Constraint`.
+    async fn save_card(&self, card: &StoredCard) -> Result<(), RepositoryError>;
+    async fn get_card(&self, token: &CardToken) -> Result<Option<StoredCard>, RepositoryError>;
+
+    /// Insert or replace a user; `RepositoryError::EmailTaken` if another
+    /// user has the same email. The stored revision is the store's, not
+    /// `user.profile.revision`: 0 for a new user, one more on every save.
+    async fn save_user(&self, user: &User) -> Result<(), RepositoryError>;
+    async fn get_users(&self) -> Result<Vec<User>, RepositoryError>;
+    async fn get_user(&self, user_id: &UserId) -> Result<Option<User>, RepositoryError>;
+
+    /// Replace the profile of an existing user with `profile` if the stored
+    /// one is still at the revision before `profile.revision`, checked and
+    /// written in one transaction; nothing is written otherwise. Emails are
+    /// checked as in `save_user`.
+    async fn update_profile(
+        &self,
+        user_id: &UserId,
+        profile: &Profile,
+    ) -> Result<ProfileUpdate, RepositoryError>;
+
+    /// Insert or replace a service together with its products;
+    /// `RepositoryError::DuplicateProduct` if another service (or this one,
+    /// twice) offers one of them.
+    async fn save_service(&self, service: &Service) -> Result<(), RepositoryError>;
+    /// `save_service` for each of `services`, in order, in one transaction:
+    /// if one is refused none is stored.
+    async fn save_services(&self, services: &[Service]) -> Result<(), RepositoryError>;
+    async fn get_services(&self) -> Result<Vec<Service>, RepositoryError>;
+
+    /// Insert or replace an account together with its members and payment
+    /// methods.
+    async fn save_account(&self, account: &Account) -> Result<(), RepositoryError>;
+    async fn get_accounts(&self) -> Result<Vec<Account>, RepositoryError>;
+
+    /// Insert or replace a budget.
+    async fn save_budget(&self, budget: &Budget) -> Result<(), RepositoryError>;
+    async fn get_budgets(&self) -> Result<Vec<Budget>, RepositoryError>;
+
+    /// Store a usage; one whose idempotency key is already stored (within
+    /// `idempotency::DEFAULT_RETENTION`) is not written again. A usage naming
+    /// an unknown user, service, product or account, or an account its user
+    /// is not a member of, is refused with `PersistenceError::Constraint`.
+    async fn save_usage(&self, usage: &ServiceUsage) -> Result<(), RepositoryError>;
+
+    /// Store many usages atomically; returns how many were written. Usages
+    /// skipped like in `save_usage`, or repeating a key earlier in `usages`,
+    /// do not count. One refused like in `save_usage` writes none of them.
+    async fn save_usages(&self, usages: &[ServiceUsage]) -> Result<usize, RepositoryError>;
+
+    /// Store a usage and return it with its id. If a usage with the same
+    /// idempotency key was recorded less than `retention` ago, nothing is
+    /// written and that usage is returned (`Recorded::Replayed`); an older
+    /// holder of the key gives it up.
+    async fn record_usage(
+        &self,
+        usage: &ServiceUsage,
+        retention: Duration,
+    ) -> Result<Recorded, RepositoryError>;
+
+    /// The usage recorded with `key` less than `retention` ago.
+    async fn find_idempotent_usage(
+        &self,
+        key: &IdempotencyKey,
+        retention: Duration,
+    ) -> Result<Option<UsageRecord>, RepositoryError>;
+    async fn get_usages_for_user(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<ServiceUsage>, RepositoryError>;
+
+    /// One page of usages matching `query`, in insertion order.
+    async fn query_usages(&self, query: &UsageQuery) -> Result<Page<UsageRecord>, RepositoryError>;
+
+    /// Count and total price (from the stored products) of matching usages.
+    async fn aggregate_usages(&self, query: &UsageQuery)
+        -> Result<UsageAggregate, RepositoryError>;
+
+    /// Everything held about `user_id` as of one instant, stored atomically
+    /// with an export `AuditRecord`; `None` for an unknown user. No write
+    /// lands between the reads.
+    async fn export_user(&self, user_id: &UserId) -> Result<Option<UserExport>, RepositoryError>;
+
+    /// Pseudonymize `user_id` as `pseudonym` (see `privacy`) atomically with
+    /// storing an erasure `AuditRecord`; `None` for an unknown user. No write
+    /// lands between finding what to change and changing it, so nothing
+    /// written meanwhile keeps the old id.
+    async fn erase_user(
+        &self,
+        user_id: &UserId,
+        pseudonym: &UserId,
+    ) -> Result<Option<ErasureReport>, RepositoryError>;
+
+    /// Every export and erasure, oldest first.
+    async fn get_privacy_audit(&self) -> Result<Vec<AuditRecord>, RepositoryError>;
+
+    /// Store `note` unless the notes of its usage would then add up to more
+    /// than the usage's price (`Issued::Exceeds`). The price is that of the
+    /// stored usage's product at its `occurred_at`, read in the same
+    /// transaction as the check and the write; `note.usage_amount_cents` is
+    /// replaced with it.
+    async fn issue_credit_note(&self, note: &CreditNote) -> Result<Issued, RepositoryError>;
+
+    /// Every credit note, oldest first.
+    async fn get_credit_notes(&self) -> Result<Vec<CreditNote>, RepositoryError>;
+
+    /// Charge up to `application.amount_cents` of the payer's credit balance
+    /// to its period, in one transaction, and return what was charged. A
+    /// period charged before is not charged again: the stored application
+    /// is returned.
+    async fn apply_credit(
+        &self,
+        application: &CreditApplication,
+    ) -> Result<CreditApplication, RepositoryError>;
+
+    /// Every credit application, oldest first.
+    async fn get_credit_applications(&self) -> Result<Vec<CreditApplication>, RepositoryError>;
+
+    /// Store `case` and append `events` in one transaction, if the stored
+    /// case is still at the revision before `case.revision` (none is stored
+    /// for revision 0). Returns false, writing nothing, if it is not.
+    async fn save_dunning_case(
+        &self,
+        case: &DunningCase,
+        events: &[DunningEvent],
+    ) -> Result<bool, RepositoryError>;
+
+    /// Every dunning case, oldest first.
+    async fn get_dunning_cases(&self) -> Result<Vec<DunningCase>, RepositoryError>;
+
+    /// Every dunning event, oldest first.
+    async fn get_dunning_events(&self) -> Result<Vec<DunningEvent>, RepositoryError>;
+}
+
+/// Open a repository for `url`, choosing the backend from its scheme.
+pub async fn open(url: &str) -> Result<Box<dyn Repository>, RepositoryError> {
+    if url.starts_with("sqlite:") {
+        Ok(Box::new(SqliteRepository::connect(url).await?))
+    } else if let Some(path) = url.strip_prefix("sled:") {
+        Ok(Box::new(SledRepository::open(path)?))
+    } else if url == "memory:" {
+        Ok(Box::new(MemoryRepository::new()))
+    } else {
+        Err(RepositoryError::UnsupportedUrl(url.to_string()))
+    }
+}
@@ -0,0 +1,1056 @@
+use super::{check_usage_references, unknown_card, Repository, RepositoryError};
+use crate::account::Account;
+use crate::budget::Budget;
+use crate::credit::{CreditApplication, CreditNote, Issued, Payer, Settlement};
+use crate::dunning::{DunningCase, DunningEvent};
+use crate::idempotency::{self, IdempotencyKey, Recorded, DEFAULT_RETENTION};
+use crate::models::{
+    PaymentMethod, ProductId, Profile, Service, ServiceId, ServiceUsage, User, UserId,
+};
+use crate::privacy::{self, AuditRecord, ErasureReport, UserExport};
+use crate::profile::ProfileUpdate;
+use crate::query::{self, Page, UsageAggregate, UsageQuery, UsageRecord};
+use crate::vault::{self, CardToken, StoredCard};
+use ::sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
+use ::sled::Transactional;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Card token to the vaulted card.
+const CARDS: &str = "cards";
+const USERS: &str = "users";
+const SERVICES: &str = "services";
+const USAGES: &str = "usages";
+/// Usage sequence number (8 bytes, big-endian) to the usage's key in
+/// `USAGES`, so pages across users range from their cursor.
+const USAGE_SEQUENCE: &str = "usage_sequence";
+const ACCOUNTS: &str = "accounts";
+const BUDGETS: &str = "budgets";
+const IDEMPOTENCY_KEYS: &str = "idempotency_keys";
+const PRIVACY_AUDIT: &str = "privacy_audit";
+const CREDIT_NOTES: &str = "credit_notes";
+/// Usage id (8 bytes, big-endian) to the total of its credit notes.
+const CREDITED: &str = "credited";
+const CREDIT_APPLICATIONS: &str = "credit_applications";
+/// Payer key to its credit balance.
+const CREDIT_BALANCES: &str = "credit_balances";
+/// Case id to the case.
+const DUNNING_CASES: &str = "dunning_cases";
+const DUNNING_EVENTS: &str = "dunning_events";
+/// Email to the id of the user who has it.
+const USER_EMAILS: &str = "user_emails";
+/// Product id to the id of the service offering it.
+const PRODUCT_SERVICES: &str = "product_services";
+
+/// Value of an `IDEMPOTENCY_KEYS` entry: the key of the usage holding it.
+#[derive(Serialize, Deserialize)]
+struct KeyHolder {
+    usage_key: Vec<u8>,
+    recorded_at: DateTime<Utc>,
+}
+
+fn json<T: Serialize>(value: &T) -> ConflictableTransactionResult<Vec<u8>, RepositoryError> {
+    serde_json::to_vec(value).map_err(|e| ConflictableTransactionError::Abort(e.into()))
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(
+    bytes: &[u8],
+) -> ConflictableTransactionResult<T, RepositoryError> {
+    serde_json::from_slice(bytes).map_err(|e| ConflictableTransactionError::Abort(e.into()))
+}
+
+fn transaction_error(e: TransactionError<RepositoryError>) -> RepositoryError {
+    match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => RepositoryError::Sled(e),
+    }
+}
+
+/// A usage and its sequence number, read from `USAGES`.
+type SequencedUsage = Result<(i64, ServiceUsage), RepositoryError>;
+
+/// Embedded `Repository` on sled. Records are stored as JSON values; usages
+/// are keyed by `user_id \0 sequence` so a prefix scan returns one user's
+/// usages in insertion order, and `USAGE_SEQUENCE` lists every usage in that
+/// order. Idempotency keys map to the key of the usage holding them.
+///
+/// Transactions cannot scan, so `export_user` and `erase_user` collect keys
+/// first and hold `scans` exclusively until their transaction commits; the
+/// writes to what they read hold it shared. sled opens a database once per
+/// process, so every writer goes through a clone of the same lock.
+#[derive(Debug, Clone)]
+pub struct SledRepository {
+    db: ::sled::Db,
+    scans: Arc<RwLock<()>>,
+}
+
+impl SledRepository {
+    pub fn open(path: &str) -> Result<Self, RepositoryError> {
+        Ok(SledRepository {
+            db: ::sled::open(path)?,
+            scans: Arc::default(),
+        })
+    }
+
+    /// A throwaway database removed when the last handle is dropped.
+    pub fn temporary() -> Result<Self, RepositoryError> {
+        Ok(SledRepository {
+            db: ::sled::Config::new().temporary(true).open()?,
+            scans: Arc::default(),
+        })
+    }
+
+    /// Make every write so far durable. sled's `flush_async` can hang when
+    /// several run beside transactions, so the blocking flush runs on
+    /// tokio's blocking pool instead.
+    async fn flush(&self) -> Result<(), RepositoryError> {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || db.flush())
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))?;
+        Ok(())
+    }
+
+    fn usage_prefix(user_id: &UserId) -> Vec<u8> {
+        let mut key = user_id.0.as_bytes().to_vec();
+        key.push(0);
+        key
+    }
+
+    fn payer_key(payer: &Payer) -> Vec<u8> {
+        let mut key = payer.kind().as_bytes().to_vec();
+        key.push(0);
+        key.extend_from_slice(payer.id().as_bytes());
+        key
+    }
+
+    fn application_key(application: &CreditApplication) -> Vec<u8> {
+        let mut key = Self::payer_key(&application.payer);
+        key.push(0);
+        key.extend_from_slice(&application.period.from.timestamp_millis().to_be_bytes());
+        key.extend_from_slice(&application.period.until.timestamp_millis().to_be_bytes());
+        key
+    }
+
+    fn decode_all<T: serde::de::DeserializeOwned>(
+        iter: impl Iterator<Item = ::sled::Result<(::sled::IVec, ::sled::IVec)>>,
+    ) -> Result<Vec<T>, RepositoryError> {
+        iter.map(|kv| {
+            let (_, v) = kv?;
+            Ok(serde_json::from_slice(&v)?)
+        })
+        .collect()
+    }
+
+    fn sequence(usage_key: &[u8]) -> i64 {
+        let mut seq = [0u8; 8];
+        seq.copy_from_slice(&usage_key[usage_key.len() - 8..]);
+        u64::from_be_bytes(seq) as i64
+    }
+
+    /// Write `usages` in one transaction, replaying those whose key is live
+    /// (including keys taken earlier in `usages`).
+    fn write_usages(
+        &self,
+        usages: &[ServiceUsage],
+        retention: Duration,
+    ) -> Result<Vec<Recorded>, RepositoryError> {
+        let now = Utc::now();
+        // ids are taken outside the transaction, which may run more than
+        // once; replays leave gaps in the sequence
+        let ids = usages
+            .iter()
+            .map(|_| self.db.generate_id())
+            .collect::<Result<Vec<_>, _>>()?;
+        // the referenced trees are read in the transaction, so a usage cannot
+        // land next to the removal of what it references
+        let trees = (
+            &self.db.open_tree(USAGES)?,
+            &self.db.open_tree(IDEMPOTENCY_KEYS)?,
+            &self.db.open_tree(USERS)?,
+            &self.db.open_tree(SERVICES)?,
+            &self.db.open_tree(ACCOUNTS)?,
+            &self.db.open_tree(CARDS)?,
+            &self.db.open_tree(USAGE_SEQUENCE)?,
+        );
+        let recorded = trees
+            .transaction(|(tree, keys, users, services, accounts, cards, sequence)| {
+                let mut out = Vec::with_capacity(usages.len());
+                for (usage, id) in usages.iter().zip(&ids) {
+                    if let Some(stored) = Self::take_key(tree, keys, usage, now, retention)? {
+                        out.push(Recorded::Replayed(stored));
+                        continue;
+                    }
+                    Self::check_references(users, services, accounts, usage)?;
+                    Self::check_cards(cards, "usages", &usage.payment_used)?;
+                    let mut key = Self::usage_prefix(&usage.user_id);
+                    key.extend_from_slice(&id.to_be_bytes());
+                    tree.insert(key.as_slice(), json(usage)?)?;
+                    sequence.insert(&id.to_be_bytes(), key.as_slice())?;
+                    if let Some(k) = &usage.idempotency_key {
+                        let holder = KeyHolder {
+                            usage_key: key.clone(),
+                            recorded_at: now,
+                        };
+                        keys.insert(k.0.as_bytes(), json(&holder)?)?;
+                    }
+                    out.push(Recorded::New(UsageRecord {
+                        id: Self::sequence(&key),
+                        usage: usage.clone(),
+                    }));
+                }
+                Ok(out)
+            })
+            .map_err(transaction_error)?;
+        Ok(recorded)
+    }
+
+    /// `check_usage_references` against the stored user, service and account.
+    fn check_references(
+        users: &TransactionalTree,
+        services: &TransactionalTree,
+        accounts: &TransactionalTree,
+        usage: &ServiceUsage,
+    ) -> ConflictableTransactionResult<(), RepositoryError> {
+        let user_exists = users.get(usage.user_id.0.as_bytes())?.is_some();
+        let service: Option<Service> = match services.get(usage.service_id.0.as_bytes())? {
+            Some(bytes) => Some(from_json(&bytes)?),
+            None => None,
+        };
+        let account: Option<Account> = match &usage.account_id {
+            Some(id) => match accounts.get(id.0.as_bytes())? {
+                Some(bytes) => Some(from_json(&bytes)?),
+                None => None,
+            },
+            None => None,
+        };
+        check_usage_references(usage, user_exists, service.as_ref(), account.as_ref())
+            .map_err(ConflictableTransactionError::Abort)
+    }
+
+    /// `unknown_card` for `table` if one of `payments` names a card missing
+    /// from `cards`.
+    fn check_cards<'a>(
+        cards: &TransactionalTree,
+        table: &'static str,
+        payments: impl IntoIterator<Item = &'a PaymentMethod>,
+    ) -> ConflictableTransactionResult<(), RepositoryError> {
+        for token in privacy::card_tokens(payments) {
+            if cards.get(token.as_str())?.is_none() {
+                return Err(ConflictableTransactionError::Abort(unknown_card(table)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The stored usage holding `usage`'s key if the key is live. An expired
+    /// key is removed from its old holder.
+    fn take_key(
+        tree: &TransactionalTree,
+        keys: &TransactionalTree,
+        usage: &ServiceUsage,
+        now: DateTime<Utc>,
+        retention: Duration,
+    ) -> ConflictableTransactionResult<Option<UsageRecord>, RepositoryError> {
+        let Some(key) = &usage.idempotency_key else {
+            return Ok(None);
+        };
+        let Some(holder) = keys.get(key.0.as_bytes())? else {
+            return Ok(None);
+        };
+        let holder: KeyHolder = from_json(&holder)?;
+        let Some(stored) = tree.get(holder.usage_key.as_slice())? else {
+            return Ok(None);
+        };
+        let mut stored: ServiceUsage = from_json(&stored)?;
+        if idempotency::is_live(holder.recorded_at, now, retention) {
+            return Ok(Some(UsageRecord {
+                id: Self::sequence(&holder.usage_key),
+                usage: stored,
+            }));
+        }
+        stored.idempotency_key = None;
+        tree.insert(holder.usage_key.as_slice(), json(&stored)?)?;
+        Ok(None)
+    }
+
+    /// Store `user` and move their `USER_EMAILS` entry to the new email;
+    /// `EmailTaken` if another user has it.
+    fn write_user(
+        users: &TransactionalTree,
+        emails: &TransactionalTree,
+        cards: &TransactionalTree,
+        user: &User,
+    ) -> ConflictableTransactionResult<(), RepositoryError> {
+        Self::check_cards(cards, "users", &user.profile.payment_methods)?;
+        let id = user.id.0.as_bytes();
+        if let Some(email) = &user.profile.email {
+            if let Some(holder) = emails.get(email.as_str())? {
+                if holder != id {
+                    return Err(ConflictableTransactionError::Abort(
+                        RepositoryError::EmailTaken(email.clone()),
+                    ));
+                }
+            }
+        }
+        if let Some(old) = users.insert(id, json(user)?)? {
+            let old: User = from_json(&old)?;
+            if let Some(email) = old.profile.email {
+                emails.remove(email.as_str())?;
+            }
+        }
+        if let Some(email) = &user.profile.email {
+            emails.insert(email.as_str(), id)?;
+        }
+        Ok(())
+    }
+
+    /// Replace `service`, keeping `PRODUCT_SERVICES` in step; refused if
+    /// another service offers one of its products.
+    fn write_service(
+        services: &TransactionalTree,
+        index: &TransactionalTree,
+        service: &Service,
+    ) -> ConflictableTransactionResult<(), RepositoryError> {
+        let id = service.id.0.as_bytes();
+        let duplicate = |product_id: &ProductId, existing: &ServiceId| {
+            ConflictableTransactionError::Abort(RepositoryError::DuplicateProduct {
+                product_id: product_id.clone(),
+                existing: existing.clone(),
+            })
+        };
+        for (i, p) in service.products.iter().enumerate() {
+            if service.products[..i].iter().any(|q| q.id == p.id) {
+                return Err(duplicate(&p.id, &service.id));
+            }
+            if let Some(holder) = index.get(p.id.0.as_bytes())? {
+                if holder != id {
+                    let holder = String::from_utf8_lossy(&holder).into_owned();
+                    return Err(duplicate(&p.id, &ServiceId(holder)));
+                }
+            }
+        }
+        if let Some(old) = services.insert(id, json(service)?)? {
+            let old: Service = from_json(&old)?;
+            for p in &old.products {
+                index.remove(p.id.0.as_bytes())?;
+            }
+        }
+        for p in &service.products {
+            index.insert(p.id.0.as_bytes(), id)?;
+        }
+        Ok(())
+    }
+
+    /// Usages matching `query`'s user, if any, with their sequence numbers,
+    /// in sequence order from the one after `query.after`; decoded as the
+    /// iterator is consumed. A user's usages are a prefix scan, everyone's
+    /// range over `USAGE_SEQUENCE`.
+    fn usages_from(
+        &self,
+        query: &UsageQuery,
+    ) -> Result<Box<dyn Iterator<Item = SequencedUsage>>, RepositoryError> {
+        let usages = self.db.open_tree(USAGES)?;
+        let first = query
+            .after
+            .map(|c| c.id().saturating_add(1).max(0) as u64)
+            .unwrap_or(0)
+            .to_be_bytes();
+        let decode = |k: &[u8], v: &[u8]| -> Result<(i64, ServiceUsage), RepositoryError> {
+            Ok((Self::sequence(k), serde_json::from_slice(v)?))
+        };
+        if let Some(user_id) = &query.user_id {
+            let prefix = Self::usage_prefix(user_id);
+            let mut start = prefix.clone();
+            start.extend_from_slice(&first);
+            return Ok(Box::new(
+                usages
+                    .range(start..)
+                    .take_while(move |kv| kv.as_ref().map_or(true, |(k, _)| k.starts_with(&prefix)))
+                    .map(move |kv| {
+                        let (k, v) = kv?;
+                        decode(&k, &v)
+                    }),
+            ));
+        }
+        let sequence = self.db.open_tree(USAGE_SEQUENCE)?;
+        Ok(Box::new(sequence.range(first..).filter_map(move |kv| {
+            let found = kv.and_then(|(_, key)| Ok(usages.get(&key)?.map(|v| (key, v))));
+            match found {
+                Ok(Some((k, v))) => Some(decode(&k, &v)),
+                Ok(None) => None,
+                Err(e) => Some(Err(e.into())),
+            }
+        })))
+    }
+}
+
+#[async_trait]
+impl Repository for SledRepository {
+    async fn init(&self) -> Result<(), RepositoryError> {
+        for tree in [
+            CARDS,
+            USERS,
+            SERVICES,
+            USAGES,
+            ACCOUNTS,
+            BUDGETS,
+            IDEMPOTENCY_KEYS,
+            PRIVACY_AUDIT,
+            CREDIT_NOTES,
+            CREDITED,
+            CREDIT_APPLICATIONS,
+            CREDIT_BALANCES,
+            DUNNING_CASES,
+            DUNNING_EVENTS,
+            USER_EMAILS,
+            PRODUCT_SERVICES,
+            USAGE_SEQUENCE,
+        ] {
+            self.db.open_tree(tree)?;
+        }
+        // stores written when payment methods embedded the whole card
+        let cards = self.db.open_tree(CARDS)?;
+        if cards.is_empty() {
+            for tree in [USERS, ACCOUNTS, USAGES, CREDIT_NOTES, DUNNING_CASES] {
+                for kv in self.db.open_tree(tree)?.iter() {
+                    let (_, v) = kv?;
+                    let value: serde_json::Value = serde_json::from_slice(&v)?;
+                    for card in vault::embedded_cards(&value) {
+                        cards.insert(card.token().as_str(), serde_json::to_vec(&card)?)?;
+                    }
+                }
+            }
+        }
+        // stores written before the index existed
+        let index = self.db.open_tree(PRODUCT_SERVICES)?;
+        if index.is_empty() {
+            for service in Self::decode_all::<Service>(self.db.open_tree(SERVICES)?.iter())? {
+                for p in &service.products {
+                    index.insert(p.id.0.as_bytes(), service.id.0.as_bytes())?;
+                }
+            }
+        }
+        let sequence = self.db.open_tree(USAGE_SEQUENCE)?;
+        if sequence.is_empty() {
+            for kv in self.db.open_tree(USAGES)?.iter() {
+                let (k, _) = kv?;
+                sequence.insert((Self::sequence(&k) as u64).to_be_bytes(), k)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn save_card(&self, card: &StoredCard) -> Result<(), RepositoryError> {
+        let value = serde_json::to_vec(card)?;
+        self.db
+            .open_tree(CARDS)?
+            .insert(card.token().as_str(), value)?;
+        self.flush().await?;
+        Ok(())
+    }
+
+    async fn get_card(&self, token: &CardToken) -> Result<Option<StoredCard>, RepositoryError> {
+        match self.db.open_tree(CARDS)?.get(token.as_str())? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn save_user(&self, user: &User) -> Result<(), RepositoryError> {
+        let _writing = self.scans.read().await;
+        let users = self.db.open_tree(USERS)?;
+        let emails = self.db.open_tree(USER_EMAILS)?;
+        let cards = self.db.open_tree(CARDS)?;
+        (&users, &emails, &cards)
+            .transaction(|(users, emails, cards)| {
+                // the stored revision moves on; the caller's is not trusted
+                let revision = match users.get(user.id.0.as_bytes())? {
+                    Some(stored) => from_json::<User>(&stored)?.profile.revision + 1,
+                    None => 0,
+                };
+                let mut user = user.clone();
+                user.profile.revision = revision;
+                Self::write_user(users, emails, cards, &user)
+            })
+            .map_err(transaction_error)?;
+        self.flush().await?;
+        Ok(())
+    }
+
+    async fn get_users(&self) -> Result<Vec<User>, RepositoryError> {
+        Self::decode_all(self.db.open_tree(USERS)?.iter())
+    }
+
+    async fn get_user(&self, user_id: &UserId) -> Result<Option<User>, RepositoryError> {
+        match self.db.open_tree(USERS)?.get(user_id.0.as_bytes())? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn update_profile(
+        &self,
+        user_id: &UserId,
+        profile: &Profile,
+    ) -> Result<ProfileUpdate, RepositoryError> {
+        let _writing = self.scans.read().await;
+        let users = self.db.open_tree(USERS)?;
+        let emails = self.db.open_tree(USER_EMAILS)?;
+        let cards = self.db.open_tree(CARDS)?;
+        let user = User {
+            id: user_id.clone(),
+            profile: profile.clone(),
+        };
+        let updated = (&users, &emails, &cards)
+            .transaction(|(users, emails, cards)| {
+                let Some(stored) = users.get(user_id.0.as_bytes())? else {
+                    return Ok(ProfileUpdate::UnknownUser);
+                };
+                let stored: User = from_json(&stored)?;
+                if stored.profile.revision.checked_add(1) != Some(profile.revision) {
+                    return Ok(ProfileUpdate::Stale);
+                }
+                Self::write_user(users, emails, cards, &user)?;
+                Ok(ProfileUpdate::Updated(user.clone()))
+            })
+            .map_err(transaction_error)?;
+        self.flush().await?;
+        Ok(updated)
+    }
+
+    async fn save_service(&self, service: &Service) -> Result<(), RepositoryError> {
+        self.save_services(std::slice::from_ref(service)).await
+    }
+
+    async fn save_services(&self, services: &[Service]) -> Result<(), RepositoryError> {
+        let trees = (
+            &self.db.open_tree(SERVICES)?,
+            &self.db.open_tree(PRODUCT_SERVICES)?,
+        );
+        trees
+            .transaction(|(tree, index)| {
+                // later services see the writes of earlier ones
+                for service in services {
+                    Self::write_service(tree, index, service)?;
+                }
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        self.flush().await?;
+        Ok(())
+    }
+
+    async fn get_services(&self) -> Result<Vec<Service>, RepositoryError> {
+        Self::decode_all(self.db.open_tree(SERVICES)?.iter())
+    }
+
+    async fn save_account(&self, account: &Account) -> Result<(), RepositoryError> {
+        let _writing = self.scans.read().await;
+        let trees = (&self.db.open_tree(ACCOUNTS)?, &self.db.open_tree(CARDS)?);
+        trees
+            .transaction(|(accounts, cards)| {
+                Self::check_cards(cards, "accounts", &account.payment_methods)?;
+                accounts.insert(account.id.0.as_bytes(), json(account)?)?;
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        self.flush().await?;
+        Ok(())
+    }
+
+    async fn get_accounts(&self) -> Result<Vec<Account>, RepositoryError> {
+        Self::decode_all(self.db.open_tree(ACCOUNTS)?.iter())
+    }
+
+    async fn save_budget(&self, budget: &Budget) -> Result<(), RepositoryError> {
+        let _writing = self.scans.read().await;
+        let value = serde_json::to_vec(budget)?;
+        self.db
+            .open_tree(BUDGETS)?
+            .insert(budget.id.0.as_bytes(), value)?;
+        self.flush().await?;
+        Ok(())
+    }
+
+    async fn get_budgets(&self) -> Result<Vec<Budget>, RepositoryError> {
+        Self::decode_all(self.db.open_tree(BUDGETS)?.iter())
+    }
+
+    async fn save_usage(&self, usage: &ServiceUsage) -> Result<(), RepositoryError> {
+        self.save_usages(std::slice::from_ref(usage)).await?;
+        Ok(())
+    }
+
+    async fn save_usages(&self, usages: &[ServiceUsage]) -> Result<usize, RepositoryError> {
+        let _writing = self.scans.read().await;
+        let recorded = self.write_usages(usages, DEFAULT_RETENTION)?;
+        self.flush().await?;
+        Ok(recorded.iter().filter(|r| !r.is_replay()).count())
+    }
+
+    async fn record_usage(
+        &self,
+        usage: &ServiceUsage,
+        retention: Duration,
+    ) -> Result<Recorded, RepositoryError> {
+        let _writing = self.scans.read().await;
+        let mut recorded = self.write_usages(std::slice::from_ref(usage), retention)?;
+        self.flush().await?;
+        Ok(recorded.remove(0))
+    }
+
+    async fn find_idempotent_usage(
+        &self,
+        key: &IdempotencyKey,
+        retention: Duration,
+    ) -> Result<Option<UsageRecord>, RepositoryError> {
+        let Some(holder) = self.db.open_tree(IDEMPOTENCY_KEYS)?.get(key.0.as_bytes())? else {
+            return Ok(None);
+        };
+        let holder: KeyHolder = serde_json::from_slice(&holder)?;
+        if !idempotency::is_live(holder.recorded_at, Utc::now(), retention) {
+            return Ok(None);
+        }
+        match self.db.open_tree(USAGES)?.get(&holder.usage_key)? {
+            Some(stored) => Ok(Some(UsageRecord {
+                id: Self::sequence(&holder.usage_key),
+                usage: serde_json::from_slice(&stored)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_usages_for_user(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<ServiceUsage>, RepositoryError> {
+        let tree = self.db.open_tree(USAGES)?;
+        Self::decode_all(tree.scan_prefix(Self::usage_prefix(user_id)))
+    }
+
+    async fn query_usages(&self, query: &UsageQuery) -> Result<Page<UsageRecord>, RepositoryError> {
+        // decoded only as far as the page needs
+        let mut failed = None;
+        let usages = self.usages_from(query)?.map_while(|r| match r {
+            Ok(usage) => Some(usage),
+            Err(e) => {
+                failed = Some(e);
+                None
+            }
+        });
+        let page = query::page_from_iter(query, usages);
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(page),
+        }
+    }
+
+    async fn aggregate_usages(
+        &self,
+        query: &UsageQuery,
+    ) -> Result<UsageAggregate, RepositoryError> {
+        let services = self.get_services().await?;
+        let usages = self
+            .usages_from(&UsageQuery {
+                after: None,
+                ..query.clone()
+            })?
+            .map(|r| r.map(|(_, u)| u))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(query::aggregate_iter(query, usages.iter(), |u| {
+            query::price_in(services.iter().find(|svc| svc.id == u.service_id)?, u)
+        }))
+    }
+
+    async fn export_user(&self, user_id: &UserId) -> Result<Option<UserExport>, RepositoryError> {
+        // sled cannot read several trees as of one instant: keep writers out
+        // while the export reads them one after another
+        let _scanning = self.scans.write().await;
+        let Some(user) = self.db.open_tree(USERS)?.get(user_id.0.as_bytes())? else {
+            return Ok(None);
+        };
+        let user: User = serde_json::from_slice(&user)?;
+        let accounts = self.get_accounts().await?;
+        let budgets = self
+            .get_budgets()
+            .await?
+            .into_iter()
+            .filter(|b| &b.user_id == user_id)
+            .collect();
+        let usages = self
+            .db
+            .open_tree(USAGES)?
+            .scan_prefix(Self::usage_prefix(user_id))
+            .map(|kv| {
+                let (k, v) = kv?;
+                Ok(UsageRecord {
+                    id: Self::sequence(&k),
+                    usage: serde_json::from_slice(&v)?,
+                })
+            })
+            .collect::<Result<Vec<_>, RepositoryError>>()?;
+        let export = UserExport::build(
+            &user,
+            privacy::memberships(&accounts, user_id),
+            budgets,
+            &usages,
+            Utc::now(),
+        )
+        .with_credit_notes(&self.get_credit_notes().await?)
+        .with_dunning_cases(&self.get_dunning_cases().await?);
+        let id = self.db.generate_id()?;
+        self.db.open_tree(PRIVACY_AUDIT)?.insert(
+            id.to_be_bytes(),
+            serde_json::to_vec(&export.audit_record())?,
+        )?;
+        self.flush().await?;
+        Ok(Some(export))
+    }
+
+    async fn erase_user(
+        &self,
+        user_id: &UserId,
+        pseudonym: &UserId,
+    ) -> Result<Option<ErasureReport>, RepositoryError> {
+        let _scanning = self.scans.write().await;
+        let users = self.db.open_tree(USERS)?;
+        let usages = self.db.open_tree(USAGES)?;
+        let accounts = self.db.open_tree(ACCOUNTS)?;
+        let budgets = self.db.open_tree(BUDGETS)?;
+        let keys = self.db.open_tree(IDEMPOTENCY_KEYS)?;
+        let audit = self.db.open_tree(PRIVACY_AUDIT)?;
+        let notes = self.db.open_tree(CREDIT_NOTES)?;
+        let applications = self.db.open_tree(CREDIT_APPLICATIONS)?;
+        let balances = self.db.open_tree(CREDIT_BALANCES)?;
+        let cases = self.db.open_tree(DUNNING_CASES)?;
+        let emails = self.db.open_tree(USER_EMAILS)?;
+        let cards = self.db.open_tree(CARDS)?;
+        let sequence = self.db.open_tree(USAGE_SEQUENCE)?;
+        // transactions cannot scan: collect the candidate keys first and
+        // re-read each entry inside the transaction; `scans` keeps writers
+        // from adding keys in between
+        let usage_keys = usages
+            .scan_prefix(Self::usage_prefix(user_id))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        let account_keys = accounts.iter().keys().collect::<Result<Vec<_>, _>>()?;
+        let budget_keys = budgets.iter().keys().collect::<Result<Vec<_>, _>>()?;
+        let audit_keys = audit.iter().keys().collect::<Result<Vec<_>, _>>()?;
+        let note_keys = notes.iter().keys().collect::<Result<Vec<_>, _>>()?;
+        let case_keys = cases.iter().keys().collect::<Result<Vec<_>, _>>()?;
+        let application_keys = applications
+            .scan_prefix(Self::payer_key(&Payer::User(user_id.clone())))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        let usage_ids: Vec<i64> = usage_keys.iter().map(|k| Self::sequence(k)).collect();
+        let audit_id = self.db.generate_id()?;
+        let now = Utc::now();
+
+        let trees = (
+            &users,
+            &usages,
+            &accounts,
+            &budgets,
+            &keys,
+            &audit,
+            &notes,
+            &applications,
+            &balances,
+            &cases,
+            &emails,
+            &cards,
+            &sequence,
+        );
+        let report = trees
+            .transaction(|trees| {
+                let (
+                    users,
+                    usages,
+                    accounts,
+                    budgets,
+                    keys,
+                    audit,
+                    notes,
+                    applications,
+                    balances,
+                    cases,
+                    emails,
+                    cards,
+                    sequence,
+                ) = trees;
+                let Some(erased) = users.remove(user_id.0.as_bytes())? else {
+                    return Ok(None);
+                };
+                let erased: User = from_json(&erased)?;
+                if let Some(email) = &erased.profile.email {
+                    emails.remove(email.as_str())?;
+                }
+                let mut paid_with = erased.profile.payment_methods;
+                users.insert(
+                    pseudonym.0.as_bytes(),
+                    json(&privacy::erased_user(pseudonym))?,
+                )?;
+                let mut memberships = 0;
+                for k in &account_keys {
+                    let Some(v) = accounts.get(k)? else { continue };
+                    let account: Account = from_json(&v)?;
+                    if let Some(moved) = privacy::pseudonymize_account(&account, user_id, pseudonym)
+                    {
+                        accounts.insert(k, json(&moved)?)?;
+                        memberships += 1;
+                    }
+                }
+                // usages are keyed by user: move each under the pseudonym,
+                // keeping its sequence number
+                let mut moved = 0;
+                for k in &usage_keys {
+                    let Some(v) = usages.remove(k)? else { continue };
+                    let usage: ServiceUsage = from_json(&v)?;
+                    if let Some(key) = &usage.idempotency_key {
+                        keys.remove(key.0.as_bytes())?;
+                    }
+                    paid_with.extend(usage.payment_used.clone());
+                    let mut new_key = Self::usage_prefix(pseudonym);
+                    new_key.extend_from_slice(&k[k.len() - 8..]);
+                    let usage = privacy::pseudonymize_usage(&usage, pseudonym);
+                    usages.insert(new_key.as_slice(), json(&usage)?)?;
+                    sequence.insert(&k[k.len() - 8..], new_key)?;
+                    moved += 1;
+                }
+                for token in privacy::card_tokens(&paid_with) {
+                    cards.remove(token.as_str())?;
+                }
+                let mut budgets_deleted = 0;
+                for k in &budget_keys {
+                    let Some(v) = budgets.get(k)? else { continue };
+                    let budget: Budget = from_json(&v)?;
+                    if &budget.user_id == user_id {
+                        budgets.remove(k)?;
+                        budgets_deleted += 1;
+                    }
+                }
+                for k in &note_keys {
+                    let Some(v) = notes.get(k)? else { continue };
+                    let note: CreditNote = from_json(&v)?;
+                    if let Some(moved) =
+                        privacy::pseudonymize_credit_note(&note, &usage_ids, user_id, pseudonym)
+                    {
+                        notes.insert(k, json(&moved)?)?;
+                    }
+                }
+                // applications and balances are keyed by payer: re-key them
+                for k in &application_keys {
+                    let Some(v) = applications.remove(k)? else {
+                        continue;
+                    };
+                    let application: CreditApplication = from_json(&v)?;
+                    if let Some(moved) =
+                        privacy::pseudonymize_credit_application(&application, user_id, pseudonym)
+                    {
+                        applications.insert(Self::application_key(&moved), json(&moved)?)?;
+                    }
+                }
+                let user_payer = Self::payer_key(&Payer::User(user_id.clone()));
+                if let Some(balance) = balances.remove(user_payer)? {
+                    balances.insert(Self::payer_key(&Payer::User(pseudonym.clone())), balance)?;
+                }
+                for k in &case_keys {
+                    let Some(v) = cases.get(k)? else { continue };
+                    let case: DunningCase = from_json(&v)?;
+                    if let Some(moved) =
+                        privacy::pseudonymize_dunning_case(&case, user_id, pseudonym)
+                    {
+                        cases.insert(k, json(&moved)?)?;
+                    }
+                }
+                for k in &audit_keys {
+                    let Some(v) = audit.get(k)? else { continue };
+                    let mut record: AuditRecord = from_json(&v)?;
+                    if &record.subject == user_id {
+                        record.subject = pseudonym.clone();
+                        audit.insert(k, json(&record)?)?;
+                    }
+                }
+
+                let report = ErasureReport {
+                    user_id: user_id.clone(),
+                    pseudonym: pseudonym.clone(),
+                    at: now,
+                    usages: moved,
+                    memberships,
+                    budgets_deleted,
+                };
+                audit.insert(&audit_id.to_be_bytes(), json(&report.audit_record())?)?;
+                Ok(Some(report))
+            })
+            .map_err(transaction_error)?;
+        self.flush().await?;
+        Ok(report)
+    }
+
+    async fn get_privacy_audit(&self) -> Result<Vec<AuditRecord>, RepositoryError> {
+        // keys are big-endian ids from `generate_id`, so oldest first
+        Self::decode_all(self.db.open_tree(PRIVACY_AUDIT)?.iter())
+    }
+
+    async fn issue_credit_note(&self, note: &CreditNote) -> Result<Issued, RepositoryError> {
+        let _writing = self.scans.read().await;
+        let id = self.db.generate_id()?;
+        let trees = (
+            &self.db.open_tree(CREDIT_NOTES)?,
+            &self.db.open_tree(CREDITED)?,
+            &self.db.open_tree(CREDIT_BALANCES)?,
+            &self.db.open_tree(USAGE_SEQUENCE)?,
+            &self.db.open_tree(USAGES)?,
+            &self.db.open_tree(SERVICES)?,
+        );
+        let issued = trees
+            .transaction(|(notes, credited, balances, sequence, usages, services)| {
+                let usage_key = note.usage_id.to_be_bytes();
+                let usage = match sequence.get(usage_key)? {
+                    Some(key) => usages.get(key)?,
+                    None => None,
+                };
+                let Some(usage) = usage else {
+                    return Ok(Issued::UnknownUsage);
+                };
+                let usage: ServiceUsage = from_json(&usage)?;
+                let service = match services.get(usage.service_id.0.as_bytes())? {
+                    Some(v) => Some(from_json::<Service>(&v)?),
+                    None => None,
+                };
+                let Some(usage_amount_cents) = service.and_then(|s| query::price_in(&s, &usage))
+                else {
+                    return Ok(Issued::UnknownPrice);
+                };
+                let note = CreditNote {
+                    usage_amount_cents,
+                    ..note.clone()
+                };
+                let total: u64 = match credited.get(usage_key)? {
+                    Some(v) => from_json(&v)?,
+                    None => 0,
+                };
+                let left = usage_amount_cents.saturating_sub(total);
+                if note.amount_cents > left {
+                    return Ok(Issued::Exceeds {
+                        remaining_cents: left,
+                    });
+                }
+                credited.insert(&usage_key, json(&(total + note.amount_cents))?)?;
+                if note.settlement == Settlement::Credit {
+                    let payer = Self::payer_key(&note.payer);
+                    let balance: u64 = match balances.get(payer.as_slice())? {
+                        Some(v) => from_json(&v)?,
+                        None => 0,
+                    };
+                    balances.insert(payer, json(&(balance + note.amount_cents))?)?;
+                }
+                notes.insert(&id.to_be_bytes(), json(&note)?)?;
+                Ok(Issued::Issued(note))
+            })
+            .map_err(transaction_error)?;
+        self.flush().await?;
+        Ok(issued)
+    }
+
+    async fn get_credit_notes(&self) -> Result<Vec<CreditNote>, RepositoryError> {
+        // keys are big-endian ids from `generate_id`, so oldest first
+        Self::decode_all(self.db.open_tree(CREDIT_NOTES)?.iter())
+    }
+
+    async fn apply_credit(
+        &self,
+        application: &CreditApplication,
+    ) -> Result<CreditApplication, RepositoryError> {
+        let _writing = self.scans.read().await;
+        let trees = (
+            &self.db.open_tree(CREDIT_APPLICATIONS)?,
+            &self.db.open_tree(CREDIT_BALANCES)?,
+        );
+        let key = Self::application_key(application);
+        let payer = Self::payer_key(&application.payer);
+        let applied = trees
+            .transaction(|(applications, balances)| {
+                if let Some(stored) = applications.get(key.as_slice())? {
+                    return from_json(&stored);
+                }
+                let balance: u64 = match balances.get(payer.as_slice())? {
+                    Some(v) => from_json(&v)?,
+                    None => 0,
+                };
+                let applied = CreditApplication {
+                    amount_cents: application.amount_cents.min(balance),
+                    ..application.clone()
+                };
+                balances.insert(payer.as_slice(), json(&(balance - applied.amount_cents))?)?;
+                applications.insert(key.as_slice(), json(&applied)?)?;
+                Ok(applied)
+            })
+            .map_err(transaction_error)?;
+        self.flush().await?;
+        Ok(applied)
+    }
+
+    async fn get_credit_applications(&self) -> Result<Vec<CreditApplication>, RepositoryError> {
+        let mut all: Vec<CreditApplication> =
+            Self::decode_all(self.db.open_tree(CREDIT_APPLICATIONS)?.iter())?;
+        // keyed by payer; oldest first like the other backends
+        all.sort_by_key(|a| a.applied_at);
+        Ok(all)
+    }
+
+    async fn save_dunning_case(
+        &self,
+        case: &DunningCase,
+        events: &[DunningEvent],
+    ) -> Result<bool, RepositoryError> {
+        let _writing = self.scans.read().await;
+        let ids = events
+            .iter()
+            .map(|_| self.db.generate_id())
+            .collect::<Result<Vec<_>, _>>()?;
+        let trees = (
+            &self.db.open_tree(DUNNING_CASES)?,
+            &self.db.open_tree(DUNNING_EVENTS)?,
+        );
+        let saved = trees
+            .transaction(|(cases, stored_events)| {
+                let current = match cases.get(case.id.0.as_bytes())? {
+                    Some(v) => Some(from_json::<DunningCase>(&v)?.revision),
+                    None => None,
+                };
+                let expected = case.revision.checked_sub(1);
+                if current != expected {
+                    return Ok(false);
+                }
+                cases.insert(case.id.0.as_bytes(), json(case)?)?;
+                for (event, id) in events.iter().zip(&ids) {
+                    stored_events.insert(&id.to_be_bytes(), json(event)?)?;
+                }
+                Ok(true)
+            })
+            .map_err(transaction_error)?;
+        self.flush().await?;
+        Ok(saved)
+    }
+
+    async fn get_dunning_cases(&self) -> Result<Vec<DunningCase>, RepositoryError> {
+        let mut all: Vec<DunningCase> = Self::decode_all(self.db.open_tree(DUNNING_CASES)?.iter())?;
+        // keyed by random id; oldest first like the other backends
+        all.sort_by(|a, b| (a.opened_at, &a.id).cmp(&(b.opened_at, &b.id)));
+        Ok(all)
+    }
+
+    async fn get_dunning_events(&self) -> Result<Vec<DunningEvent>, RepositoryError> {
+        // keys are big-endian ids from `generate_id`, so oldest first
+        Self::decode_all(self.db.open_tree(DUNNING_EVENTS)?.iter())
+    }
+}
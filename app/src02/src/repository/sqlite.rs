@@ -0,0 +1,191 @@
+use super::{Repository, RepositoryError};
+use crate::account::Account;
+use crate::budget::Budget;
+use crate::credit::{CreditApplication, CreditNote, Issued};
+use crate::dunning::{DunningCase, DunningEvent};
+use crate::idempotency::{IdempotencyKey, Recorded};
+use crate::models::{Profile, Service, ServiceUsage, User, UserId};
+use crate::persistence;
+use crate::privacy::{AuditRecord, ErasureReport, UserExport};
+use crate::profile::ProfileUpdate;
+use crate::query::{Page, UsageAggregate, UsageQuery, UsageRecord};
+use crate::vault::{CardToken, StoredCard};
+use async_trait::async_trait;
+use chrono::Duration;
+use sqlx::SqlitePool;
+
+/// `Repository` over the SQLite functions in `persistence`.
+#[derive(Debug, Clone)]
+pub struct SqliteRepository {
+    pool: SqlitePool,
+}
+
+impl SqliteRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        SqliteRepository { pool }
+    }
+
+    pub async fn connect(url: &str) -> Result<Self, RepositoryError> {
+        Ok(Self::new(persistence::connect(url).await?))
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+}
+
+#[async_trait]
+impl Repository for SqliteRepository {
+    async fn init(&self) -> Result<(), RepositoryError> {
+        Ok(persistence::init_db(&self.pool).await?)
+    }
+
+    async fn save_card(&self, card: &StoredCard) -> Result<(), RepositoryError> {
+        Ok(persistence::save_card(&self.pool, card).await?)
+    }
+
+    async fn get_card(&self, token: &CardToken) -> Result<Option<StoredCard>, RepositoryError> {
+        Ok(persistence::get_card(&self.pool, token).await?)
+    }
+
+    async fn save_user(&self, user: &User) -> Result<(), RepositoryError> {
+        Ok(persistence::save_user(&self.pool, user).await?)
+    }
+
+    async fn get_users(&self) -> Result<Vec<User>, RepositoryError> {
+        Ok(persistence::get_users(&self.pool).await?)
+    }
+
+    async fn get_user(&self, user_id: &UserId) -> Result<Option<User>, RepositoryError> {
+        Ok(persistence::get_user(&self.pool, user_id).await?)
+    }
+
+    async fn update_profile(
+        &self,
+        user_id: &UserId,
+        profile: &Profile,
+    ) -> Result<ProfileUpdate, RepositoryError> {
+        Ok(persistence::update_profile(&self.pool, user_id, profile).await?)
+    }
+
+    async fn save_service(&self, service: &Service) -> Result<(), RepositoryError> {
+        Ok(persistence::save_service(&self.pool, service).await?)
+    }
+
+    async fn save_services(&self, services: &[Service]) -> Result<(), RepositoryError> {
+        Ok(persistence::save_services(&self.pool, services).await?)
+    }
+
+    async fn get_services(&self) -> Result<Vec<Service>, RepositoryError> {
+        Ok(persistence::get_services(&self.pool).await?)
+    }
+
+    async fn save_account(&self, account: &Account) -> Result<(), RepositoryError> {
+        Ok(persistence::save_account(&self.pool, account).await?)
+    }
+
+    async fn get_accounts(&self) -> Result<Vec<Account>, RepositoryError> {
+        Ok(persistence::get_accounts(&self.pool).await?)
+    }
+
+    async fn save_budget(&self, budget: &Budget) -> Result<(), RepositoryError> {
+        Ok(persistence::save_budget(&self.pool, budget).await?)
+    }
+
+    async fn get_budgets(&self) -> Result<Vec<Budget>, RepositoryError> {
+        Ok(persistence::get_budgets(&self.pool).await?)
+    }
+
+    async fn save_usage(&self, usage: &ServiceUsage) -> Result<(), RepositoryError> {
+        Ok(persistence::save_usage(&self.pool, usage).await?)
+    }
+
+    async fn save_usages(&self, usages: &[ServiceUsage]) -> Result<usize, RepositoryError> {
+        Ok(persistence::save_usages(&self.pool, usages).await?)
+    }
+
+    async fn record_usage(
+        &self,
+        usage: &ServiceUsage,
+        retention: Duration,
+    ) -> Result<Recorded, RepositoryError> {
+        Ok(persistence::record_usage(&self.pool, usage, retention).await?)
+    }
+
+    async fn find_idempotent_usage(
+        &self,
+        key: &IdempotencyKey,
+        retention: Duration,
+    ) -> Result<Option<UsageRecord>, RepositoryError> {
+        Ok(persistence::find_idempotent_usage(&self.pool, key, retention).await?)
+    }
+
+    async fn get_usages_for_user(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<ServiceUsage>, RepositoryError> {
+        Ok(persistence::get_usages_for_user(&self.pool, &user_id.0).await?)
+    }
+
+    async fn query_usages(&self, query: &UsageQuery) -> Result<Page<UsageRecord>, RepositoryError> {
+        Ok(persistence::query_usages(&self.pool, query).await?)
+    }
+
+    async fn aggregate_usages(
+        &self,
+        query: &UsageQuery,
+    ) -> Result<UsageAggregate, RepositoryError> {
+        Ok(persistence::aggregate_usages(&self.pool, query).await?)
+    }
+
+    async fn export_user(&self, user_id: &UserId) -> Result<Option<UserExport>, RepositoryError> {
+        Ok(persistence::export_user(&self.pool, user_id).await?)
+    }
+
+    async fn erase_user(
+        &self,
+        user_id: &UserId,
+        pseudonym: &UserId,
+    ) -> Result<Option<ErasureReport>, RepositoryError> {
+        Ok(persistence::erase_user(&self.pool, user_id, pseudonym).await?)
+    }
+
+    async fn get_privacy_audit(&self) -> Result<Vec<AuditRecord>, RepositoryError> {
+        Ok(persistence::get_privacy_audit(&self.pool).await?)
+    }
+
+    async fn issue_credit_note(&self, note: &CreditNote) -> Result<Issued, RepositoryError> {
+        Ok(persistence::issue_credit_note(&self.pool, note).await?)
+    }
+
+    async fn get_credit_notes(&self) -> Result<Vec<CreditNote>, RepositoryError> {
+        Ok(persistence::get_credit_notes(&self.pool).await?)
+    }
+
+    async fn apply_credit(
+        &self,
+        application: &CreditApplication,
+    ) -> Result<CreditApplication, RepositoryError> {
+        Ok(persistence::apply_credit(&self.pool, application).await?)
+    }
+
+    async fn get_credit_applications(&self) -> Result<Vec<CreditApplication>, RepositoryError> {
+        Ok(persistence::get_credit_applications(&self.pool).await?)
+    }
+
+    async fn save_dunning_case(
+        &self,
+        case: &DunningCase,
+        events: &[DunningEvent],
+    ) -> Result<bool, RepositoryError> {
+        Ok(persistence::save_dunning_case(&self.pool, case, events).await?)
+    }
+
+    async fn get_dunning_cases(&self) -> Result<Vec<DunningCase>, RepositoryError> {
+        Ok(persistence::get_dunning_cases(&self.pool).await?)
+    }
+
+    async fn get_dunning_events(&self) -> Result<Vec<DunningEvent>, RepositoryError> {
+        Ok(persistence::get_dunning_events(&self.pool).await?)
+    }
+}
@@ -0,0 +1,139 @@
+//! A `Catalog` shared by many tasks and replaced while they read it.
+//!
+//! `SharedCatalog` holds the current `CatalogSnapshot` behind an atomic
+//! pointer. Readers take the snapshot with `load` without locking and query
+//! it for as long as they like; a reload builds a whole new catalog and swaps
+//! it in, so a reader sees either the old catalog or the new one, never a mix.
+//! Every swap bumps the snapshot's `version`.
+//!
+//! ```no_run
+//! # async fn demo(repo: &dyn src02::repository::Repository) -> Result<(), Box<dyn std::error::Error>> {
+//! use src02::shared_catalog::SharedCatalog;
+//!
+//! let shared = SharedCatalog::default();
+//! shared.reload_from_repository(repo).await?;
+//! let handle = shared.clone(); // for another task
+//! let snapshot = handle.load();
+//! for product in snapshot.catalog.list_all_products() {
+//!     println!("{} (catalog v{})", product.id.0, snapshot.version);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::catalog::Catalog;
+use crate::catalog_file::{self, CatalogFileError};
+use crate::repository::{Repository, RepositoryError};
+use crate::validation;
+use arc_swap::{ArcSwap, Guard};
+use chrono::{DateTime, Utc};
+use std::path::Path;
+use std::sync::Arc;
+
+/// One version of the catalog; never changes once published.
+#[derive(Debug)]
+pub struct CatalogSnapshot {
+    /// 0 for the empty catalog a `SharedCatalog` starts with, one more at
+    /// every swap.
+    pub version: u64,
+    pub loaded_at: DateTime<Utc>,
+    pub catalog: Catalog,
+}
+
+/// Cheap to clone: every clone is a handle on the same catalog.
+#[derive(Debug, Clone)]
+pub struct SharedCatalog {
+    current: Arc<ArcSwap<CatalogSnapshot>>,
+}
+
+impl Default for SharedCatalog {
+    /// An empty catalog at version 0, for callers that load it later.
+    fn default() -> Self {
+        SharedCatalog::with_version(0, Catalog::default())
+    }
+}
+
+impl SharedCatalog {
+    /// Share `catalog` as version 1.
+    pub fn new(catalog: Catalog) -> Self {
+        SharedCatalog::with_version(1, catalog)
+    }
+
+    fn with_version(version: u64, catalog: Catalog) -> Self {
+        SharedCatalog {
+            current: Arc::new(ArcSwap::from_pointee(CatalogSnapshot {
+                version,
+                loaded_at: Utc::now(),
+                catalog,
+            })),
+        }
+    }
+
+    /// The current snapshot. It stays valid, and unchanged, however many
+    /// reloads happen while it is held.
+    pub fn load(&self) -> Arc<CatalogSnapshot> {
+        self.current.load_full()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.current.load().version
+    }
+
+    /// Publish `catalog` as the next version, whatever was there before.
+    pub fn replace(&self, catalog: Catalog) -> Arc<CatalogSnapshot> {
+        let mut next = None;
+        self.current.rcu(|current| {
+            let snapshot = Arc::new(CatalogSnapshot {
+                version: current.version + 1,
+                loaded_at: Utc::now(),
+                catalog: catalog.clone(),
+            });
+            next = Some(snapshot.clone());
+            snapshot
+        });
+        next.expect("rcu runs its closure at least once")
+    }
+
+    /// Publish what `change` makes of the current catalog, as with
+    /// `Catalog::add_service`. If another swap lands first, `change` runs
+    /// again on the newer catalog, so no update is lost; an error leaves the
+    /// catalog as it is.
+    pub fn update<E>(
+        &self,
+        mut change: impl FnMut(&Catalog) -> Result<Catalog, E>,
+    ) -> Result<Arc<CatalogSnapshot>, E> {
+        let mut current = self.current.load_full();
+        loop {
+            let next = Arc::new(CatalogSnapshot {
+                version: current.version + 1,
+                loaded_at: Utc::now(),
+                catalog: change(&current.catalog)?,
+            });
+            let previous = self.current.compare_and_swap(&current, next.clone());
+            if Arc::ptr_eq(&previous, &current) {
+                return Ok(next);
+            }
+            current = Guard::into_inner(previous);
+        }
+    }
+
+    /// Replace the catalog with the one stored in `repo` (see
+    /// `validation::load_catalog`). On error the old catalog stays.
+    pub async fn reload_from_repository(
+        &self,
+        repo: &dyn Repository,
+    ) -> Result<Arc<CatalogSnapshot>, RepositoryError> {
+        let catalog = validation::load_catalog(repo).await?;
+        Ok(self.replace(catalog))
+    }
+
+    /// Replace the catalog with the one in a catalog file (see
+    /// `catalog_file::load`). On error the old catalog stays.
+    pub fn reload_from_file(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<Arc<CatalogSnapshot>, CatalogFileError> {
+        let catalog = catalog_file::load(path)?;
+        Ok(self.replace(catalog))
+    }
+}
@@ -1,4 +1,8 @@
+use crate::catalog::Catalog;
 use crate::models::{PaymentMethod, ServiceUsage, User};
+use crate::policy::{PaymentContext, PaymentPolicy};
+use crate::query::{self, Page, UsageAggregate, UsageQuery, UsageRecord};
+use crate::wallet::WalletMap;
 
 #[derive(Debug, Clone, Default)]
 pub struct UsageLog {
@@ -18,23 +22,59 @@ impl UsageLog {
             .collect()
     }
 
-    pub fn add_usage(&self, usage: ServiceUsage) -> UsageLog {
-        let mut new = self.clone();
-        new.usages.push(usage);
-        new
+    /// The log with `usage` appended; takes the log by value, so nothing is copied.
+    pub fn add_usage(mut self, usage: ServiceUsage) -> UsageLog {
+        self.usages.push(usage);
+        self
+    }
+
+    /// In-place append, for owners that replay many events (e.g. `journal`).
+    pub fn push(&mut self, usage: ServiceUsage) {
+        self.usages.push(usage);
     }
 
     pub fn usages_len(&self) -> usize {
         self.usages.len()
     }
+
+    /// One page of matching usages. Record ids are 1-based positions in the log.
+    pub fn query(&self, q: &UsageQuery) -> Page<UsageRecord> {
+        query::page_from_iter(q, (1..).zip(&self.usages))
+    }
+
+    /// Count and total price of every matching usage, each priced from
+    /// `catalog` as of when it occurred.
+    pub fn aggregate(&self, q: &UsageQuery, catalog: &Catalog) -> UsageAggregate {
+        query::aggregate_iter(q, self.usages.iter(), |u| catalog.usage_price(u))
+    }
 }
 
 // Clone is derived above; no manual impl required.
 
-// pure helper: resolve payment for a usage using user's default if missing
+// pure helper: resolve payment for a usage using user's default if missing;
+// `policy::PaymentPolicy::default()` without a service
 pub fn resolve_payment_for_usage(
     user: &User,
     usage_payment: Option<PaymentMethod>,
 ) -> Option<PaymentMethod> {
-    usage_payment.or_else(|| user.profile.default_payment.clone())
+    let ctx = PaymentContext::new()
+        .user(user)
+        .usage_payment(usage_payment.as_ref());
+    PaymentPolicy::default().resolve(&ctx).payment
+}
+
+// like `resolve_payment_for_usage`, for a charge of `amount_cents`: a prepaid
+// wallet only qualifies if it belongs to the user and can cover the amount,
+// otherwise the next candidate (the user's default) is tried
+pub fn resolve_payment_for_charge(
+    user: &User,
+    usage_payment: Option<PaymentMethod>,
+    amount_cents: u64,
+    wallets: &WalletMap,
+) -> Option<PaymentMethod> {
+    let ctx = PaymentContext::new()
+        .user(user)
+        .usage_payment(usage_payment.as_ref())
+        .charge(amount_cents, wallets);
+    PaymentPolicy::default().resolve(&ctx).payment
 }
@@ -0,0 +1,328 @@
+//! Referential checks for usages: the user exists, the service exists and
+//! offers the product, the account (if any) exists and has the user as a
+//! member, and a payment method the service accepts resolves for the usage
+//! under the default `policy::PaymentPolicy` (unless the user was erased, see
+//! `privacy`).
+//!
+//! SQLite enforces the references on insert (migrations 5 and 7); payment
+//! resolution and databases written before that migration are only covered
+//! here. `audit` runs the checks over everything a `Repository` holds.
+
+use crate::account::{Account, AccountId, AccountMap};
+use crate::catalog::{Catalog, DuplicatePolicy};
+use crate::models::{ProductId, ServiceId, ServiceUsage, UserId, UserMap};
+use crate::payment::PaymentKind;
+use crate::policy::{Decision, PaymentContext, PaymentPolicy};
+use crate::privacy;
+use crate::query::UsageQuery;
+use crate::repository::{Repository, RepositoryError};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum UsageViolation {
+    UnknownUser {
+        user_id: UserId,
+    },
+    UnknownService {
+        service_id: ServiceId,
+    },
+    ProductNotOffered {
+        service_id: ServiceId,
+        product_id: ProductId,
+    },
+    UnknownAccount {
+        account_id: AccountId,
+    },
+    NotAMember {
+        account_id: AccountId,
+        user_id: UserId,
+    },
+    /// Neither the usage nor the user's profile names a payment method.
+    NoPaymentMethod {
+        user_id: UserId,
+    },
+    /// A usage billed to an account names no payment method and the account
+    /// has none.
+    NoAccountPaymentMethod {
+        account_id: AccountId,
+    },
+    /// The usage's method, or every candidate the payment policy tried, is
+    /// of a kind the service does not accept.
+    PaymentNotAccepted {
+        service_id: ServiceId,
+        accepted: Vec<PaymentKind>,
+    },
+}
+
+impl fmt::Display for UsageViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsageViolation::UnknownUser { user_id } => write!(f, "unknown user {}", user_id.0),
+            UsageViolation::UnknownService { service_id } => {
+                write!(f, "unknown service {}", service_id.0)
+            }
+            UsageViolation::ProductNotOffered {
+                service_id,
+                product_id,
+            } => write!(
+                f,
+                "service {} does not offer product {}",
+                service_id.0, product_id.0
+            ),
+            UsageViolation::UnknownAccount { account_id } => {
+                write!(f, "unknown account {}", account_id.0)
+            }
+            UsageViolation::NotAMember {
+                account_id,
+                user_id,
+            } => write!(
+                f,
+                "user {} is not a member of account {}",
+                user_id.0, account_id.0
+            ),
+            UsageViolation::NoPaymentMethod { user_id } => {
+                write!(f, "no payment method resolves for user {}", user_id.0)
+            }
+            UsageViolation::NoAccountPaymentMethod { account_id } => {
+                write!(f, "account {} has no payment method", account_id.0)
+            }
+            UsageViolation::PaymentNotAccepted {
+                service_id,
+                accepted,
+            } => {
+                let kinds: Vec<&str> = accepted.iter().map(|k| k.as_str()).collect();
+                write!(
+                    f,
+                    "service {} accepts only {}",
+                    service_id.0,
+                    kinds.join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for UsageViolation {}
+
+/// What usages may refer to.
+#[derive(Debug, Clone, Default)]
+pub struct References {
+    pub users: UserMap,
+    pub catalog: Catalog,
+    pub accounts: AccountMap,
+}
+
+impl References {
+    /// References without any accounts.
+    pub fn new(users: UserMap, catalog: Catalog) -> Self {
+        References {
+            users,
+            catalog,
+            accounts: AccountMap::new(),
+        }
+    }
+
+    pub fn with_account(mut self, account: Account) -> Self {
+        self.accounts.insert(account.id.clone(), account);
+        self
+    }
+}
+
+/// Every problem with `usage`, in the order user, service/product, account,
+/// payment.
+pub fn check_usage(usage: &ServiceUsage, refs: &References) -> Vec<UsageViolation> {
+    let (users, catalog) = (&refs.users, &refs.catalog);
+    let mut problems = Vec::new();
+    let user = users.get(&usage.user_id);
+    if user.is_none() {
+        problems.push(UsageViolation::UnknownUser {
+            user_id: usage.user_id.clone(),
+        });
+    }
+    if !catalog.services().contains_key(&usage.service_id) {
+        problems.push(UsageViolation::UnknownService {
+            service_id: usage.service_id.clone(),
+        });
+    } else if catalog
+        .get_product(&usage.service_id, &usage.product_id)
+        .is_none()
+    {
+        problems.push(UsageViolation::ProductNotOffered {
+            service_id: usage.service_id.clone(),
+            product_id: usage.product_id.clone(),
+        });
+    }
+    if privacy::is_pseudonym(&usage.user_id) {
+        // erasure removed the payment details; the usage was paid for
+        return check_account_membership(usage, refs, problems);
+    }
+    let service = catalog.services().get(&usage.service_id);
+    if let (Some(service), Some(pm)) = (service, &usage.payment_used) {
+        // the usage would be stored naming a method its service refuses
+        if !service.accepts(pm.kind()) {
+            problems.push(UsageViolation::PaymentNotAccepted {
+                service_id: service.id.clone(),
+                accepted: service.accepted_payments.clone(),
+            });
+            return check_account_membership(usage, refs, problems);
+        }
+    }
+    let mut ctx = PaymentContext::new().usage_payment(usage.payment_used.as_ref());
+    if let Some(service) = service {
+        ctx = ctx.service(service);
+    }
+    match &usage.account_id {
+        // billed to an account: its payment methods, never the user's own
+        Some(account_id) => match refs.accounts.get(account_id) {
+            None => problems.push(UsageViolation::UnknownAccount {
+                account_id: account_id.clone(),
+            }),
+            Some(account) => {
+                if !account.is_member(&usage.user_id) {
+                    problems.push(UsageViolation::NotAMember {
+                        account_id: account_id.clone(),
+                        user_id: usage.user_id.clone(),
+                    });
+                }
+                let decision = PaymentPolicy::default().resolve(&ctx.account(account));
+                if decision.payment.is_none() {
+                    problems.push(payment_violation(
+                        &decision,
+                        &usage.user_id,
+                        Some(account_id),
+                    ));
+                }
+            }
+        },
+        None => {
+            if let Some(user) = user {
+                let decision = PaymentPolicy::default().resolve(&ctx.user(user));
+                if decision.payment.is_none() {
+                    problems.push(payment_violation(&decision, &usage.user_id, None));
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// Why `decision` found no payment for `user_id`, billed to `account_id`
+/// if given: the service refused a candidate, or there was none.
+pub fn payment_violation(
+    decision: &Decision,
+    user_id: &UserId,
+    account_id: Option<&AccountId>,
+) -> UsageViolation {
+    match (decision.not_accepted(), account_id) {
+        (Some((service_id, accepted)), _) => UsageViolation::PaymentNotAccepted {
+            service_id: service_id.clone(),
+            accepted: accepted.to_vec(),
+        },
+        (None, Some(account_id)) => UsageViolation::NoAccountPaymentMethod {
+            account_id: account_id.clone(),
+        },
+        (None, None) => UsageViolation::NoPaymentMethod {
+            user_id: user_id.clone(),
+        },
+    }
+}
+
+fn check_account_membership(
+    usage: &ServiceUsage,
+    refs: &References,
+    mut problems: Vec<UsageViolation>,
+) -> Vec<UsageViolation> {
+    let Some(account_id) = &usage.account_id else {
+        return problems;
+    };
+    match refs.accounts.get(account_id) {
+        None => problems.push(UsageViolation::UnknownAccount {
+            account_id: account_id.clone(),
+        }),
+        Some(account) if !account.is_member(&usage.user_id) => {
+            problems.push(UsageViolation::NotAMember {
+                account_id: account_id.clone(),
+                user_id: usage.user_id.clone(),
+            })
+        }
+        Some(_) => {}
+    }
+    problems
+}
+
+/// `Ok` if `usage` passes every check, otherwise its first violation.
+pub fn validate_usage(usage: &ServiceUsage, refs: &References) -> Result<(), UsageViolation> {
+    match check_usage(usage, refs).into_iter().next() {
+        Some(v) => Err(v),
+        None => Ok(()),
+    }
+}
+
+/// A stored usage that fails validation.
+#[derive(Debug, Clone, Serialize)]
+pub struct Violation {
+    pub usage_id: i64,
+    pub usage: ServiceUsage,
+    pub problems: Vec<UsageViolation>,
+}
+
+/// Users, catalog and accounts of `repo`, as `check_usage` needs them.
+pub async fn load_references(repo: &dyn Repository) -> Result<References, RepositoryError> {
+    let users = repo
+        .get_users()
+        .await?
+        .into_iter()
+        .map(|u| (u.id.clone(), u))
+        .collect();
+    let accounts = repo
+        .get_accounts()
+        .await?
+        .into_iter()
+        .map(|a| (a.id.clone(), a))
+        .collect();
+    Ok(References {
+        users,
+        catalog: load_catalog(repo).await?,
+        accounts,
+    })
+}
+
+/// The catalog stored in `repo`. Products shared between services are
+/// allowed, so a store that already has them can still be checked; a product
+/// listed twice in one service counts once.
+pub async fn load_catalog(repo: &dyn Repository) -> Result<Catalog, RepositoryError> {
+    let services = repo.get_services().await?.into_iter().map(|mut s| {
+        let mut seen = HashSet::new();
+        s.products.retain(|p| seen.insert(p.id.clone()));
+        s
+    });
+    Ok(Catalog::from_services(DuplicatePolicy::Allow, services)
+        .expect("Allow accepts services without repeated products"))
+}
+
+/// Check every usage in `repo`, page by page, and list the ones that fail.
+pub async fn audit(repo: &dyn Repository) -> Result<Vec<Violation>, RepositoryError> {
+    let refs = load_references(repo).await?;
+    let mut violations = Vec::new();
+    let mut query = UsageQuery::new().limit(crate::query::MAX_PAGE_SIZE);
+    loop {
+        let page = repo.query_usages(&query).await?;
+        for record in page.items {
+            let problems = check_usage(&record.usage, &refs);
+            if !problems.is_empty() {
+                violations.push(Violation {
+                    usage_id: record.id,
+                    usage: record.usage,
+                    problems,
+                });
+            }
+        }
+        match page.next_cursor {
+            Some(c) => query = query.after(c),
+            None => return Ok(violations),
+        }
+    }
+}
@@ -0,0 +1,467 @@
+//! Card vault: a full card number (PAN) is accepted exactly once, validated
+//! (Luhn checksum, brand, expiry) and exchanged for an opaque token.
+//! Only the token and the non-sensitive display data (brand, last4, expiry,
+//! holder) are kept; the PAN itself never leaves `tokenize`.
+
+use chrono::{Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+const TOKEN_PREFIX: &str = "tok_";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CardError {
+    /// The PAN contains something other than digits, spaces or dashes.
+    NonDigit,
+    /// The PAN has fewer than 12 or more than 19 digits.
+    InvalidLength(usize),
+    /// The Luhn checksum does not match.
+    ChecksumMismatch,
+    /// The IIN prefix does not belong to a supported brand.
+    UnsupportedBrand,
+    /// The PAN length is not valid for the detected brand.
+    BrandLengthMismatch(CardBrand, usize),
+    /// Month outside 1..=12 or an implausible year.
+    InvalidExpiry {
+        month: u32,
+        year: i32,
+    },
+    /// The card expired before the reference date.
+    Expired(CardExpiry),
+    EmptyHolder,
+    /// A stored card record (token/last4) is malformed.
+    MalformedStoredCard(String),
+}
+
+impl fmt::Display for CardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CardError::NonDigit => write!(f, "card number must contain only digits"),
+            CardError::InvalidLength(n) => write!(f, "card number has invalid length {}", n),
+            CardError::ChecksumMismatch => write!(f, "card number failed the Luhn check"),
+            CardError::UnsupportedBrand => write!(f, "card brand is not supported"),
+            CardError::BrandLengthMismatch(brand, n) => {
+                write!(f, "{} cards cannot have {} digits", brand, n)
+            }
+            CardError::InvalidExpiry { month, year } => {
+                write!(f, "invalid expiry {:02}/{}", month, year)
+            }
+            CardError::Expired(exp) => write!(f, "card expired {}", exp),
+            CardError::EmptyHolder => write!(f, "card holder must not be empty"),
+            CardError::MalformedStoredCard(why) => write!(f, "malformed stored card: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for CardError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CardBrand {
+    Visa,
+    Mastercard,
+    Amex,
+    Discover,
+}
+
+impl CardBrand {
+    fn accepts_length(self, len: usize) -> bool {
+        match self {
+            CardBrand::Visa => matches!(len, 13 | 16 | 19),
+            CardBrand::Mastercard => len == 16,
+            CardBrand::Amex => len == 15,
+            CardBrand::Discover => (16..=19).contains(&len),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CardBrand::Visa => "Visa",
+            CardBrand::Mastercard => "Mastercard",
+            CardBrand::Amex => "Amex",
+            CardBrand::Discover => "Discover",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<CardBrand> {
+        match s {
+            "Visa" => Some(CardBrand::Visa),
+            "Mastercard" => Some(CardBrand::Mastercard),
+            "Amex" => Some(CardBrand::Amex),
+            "Discover" => Some(CardBrand::Discover),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CardBrand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Card expiry as printed on the card (valid through the end of `month`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "ExpiryRepr")]
+pub struct CardExpiry {
+    month: u32,
+    year: i32,
+}
+
+#[derive(Deserialize)]
+struct ExpiryRepr {
+    month: u32,
+    year: i32,
+}
+
+impl TryFrom<ExpiryRepr> for CardExpiry {
+    type Error = CardError;
+    fn try_from(r: ExpiryRepr) -> Result<Self, Self::Error> {
+        CardExpiry::new(r.month, r.year)
+    }
+}
+
+impl CardExpiry {
+    pub fn new(month: u32, year: i32) -> Result<Self, CardError> {
+        if !(1..=12).contains(&month) || !(2000..=2199).contains(&year) {
+            return Err(CardError::InvalidExpiry { month, year });
+        }
+        Ok(CardExpiry { month, year })
+    }
+
+    pub fn month(&self) -> u32 {
+        self.month
+    }
+
+    pub fn year(&self) -> i32 {
+        self.year
+    }
+
+    /// A card is usable during its whole expiry month.
+    pub fn is_expired_at(&self, today: NaiveDate) -> bool {
+        (today.year(), today.month()) > (self.year, self.month)
+    }
+}
+
+impl fmt::Display for CardExpiry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}/{}", self.month, self.year)
+    }
+}
+
+/// Opaque reference to a vaulted card. Only the vault mints new tokens;
+/// outside the crate, deserialization is the only way to get one back.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "TokenRepr", into = "String")]
+pub struct CardToken(String);
+
+impl CardToken {
+    fn generate() -> Self {
+        CardToken(format!("{}{}", TOKEN_PREFIX, uuid::Uuid::new_v4().simple()))
+    }
+
+    /// Check a token read back from storage.
+    pub(crate) fn parse(s: String) -> Result<Self, CardError> {
+        let body = s
+            .strip_prefix(TOKEN_PREFIX)
+            .ok_or_else(|| CardError::MalformedStoredCard(format!("bad token {:?}", s)))?;
+        if body.is_empty() || !body.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(CardError::MalformedStoredCard(format!("bad token {:?}", s)));
+        }
+        Ok(CardToken(s))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// A `CardToken` as deserialized, before it is checked.
+#[derive(Deserialize)]
+#[serde(transparent)]
+struct TokenRepr(String);
+
+impl TryFrom<TokenRepr> for CardToken {
+    type Error = CardError;
+    fn try_from(r: TokenRepr) -> Result<Self, Self::Error> {
+        CardToken::parse(r.0)
+    }
+}
+
+impl From<CardToken> for String {
+    fn from(t: CardToken) -> Self {
+        t.0
+    }
+}
+
+impl fmt::Display for CardToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A card reference as stored: its token, or the whole `StoredCard` in data
+/// written before payment methods kept only the token.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CardReference {
+    Token(CardToken),
+    Embedded(StoredCard),
+}
+
+/// Deserialize a card reference of either form into its token.
+pub(crate) fn deserialize_card_reference<'de, D>(d: D) -> Result<CardToken, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(match CardReference::deserialize(d)? {
+        CardReference::Token(token) => token,
+        CardReference::Embedded(card) => card.token,
+    })
+}
+
+/// Cards embedded whole in stored JSON `PaymentMethod`s anywhere in `value`,
+/// as written before payment methods kept only the token.
+pub(crate) fn embedded_cards(value: &serde_json::Value) -> Vec<StoredCard> {
+    let mut cards = Vec::new();
+    let mut pending = vec![value];
+    while let Some(v) = pending.pop() {
+        match v {
+            serde_json::Value::Object(map) => {
+                if let Some(card @ serde_json::Value::Object(_)) = map.get("Card") {
+                    if let Ok(card) = serde_json::from_value(card.clone()) {
+                        cards.push(card);
+                        continue;
+                    }
+                }
+                pending.extend(map.values());
+            }
+            serde_json::Value::Array(items) => pending.extend(items),
+            _ => {}
+        }
+    }
+    cards
+}
+
+/// The only card representation the rest of the crate sees. It cannot hold
+/// more than the last four digits: fields are private and both the vault and
+/// deserialization validate `last4`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "StoredCardRepr")]
+pub struct StoredCard {
+    token: CardToken,
+    brand: CardBrand,
+    last4: String,
+    expiry: CardExpiry,
+    holder: String,
+}
+
+#[derive(Deserialize)]
+struct StoredCardRepr {
+    token: CardToken,
+    brand: CardBrand,
+    last4: String,
+    expiry: CardExpiry,
+    holder: String,
+}
+
+impl TryFrom<StoredCardRepr> for StoredCard {
+    type Error = CardError;
+    fn try_from(r: StoredCardRepr) -> Result<Self, Self::Error> {
+        StoredCard::from_parts(r.token, r.brand, &r.last4, r.expiry, &r.holder)
+    }
+}
+
+impl StoredCard {
+    /// Rebuild a stored card from persisted parts, re-checking the invariants.
+    pub(crate) fn from_parts(
+        token: CardToken,
+        brand: CardBrand,
+        last4: &str,
+        expiry: CardExpiry,
+        holder: &str,
+    ) -> Result<Self, CardError> {
+        if last4.len() != 4 || !last4.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(CardError::MalformedStoredCard(format!(
+                "last4 must be 4 digits, got {} chars",
+                last4.len()
+            )));
+        }
+        if holder.trim().is_empty() {
+            return Err(CardError::EmptyHolder);
+        }
+        Ok(StoredCard {
+            token,
+            brand,
+            last4: last4.to_string(),
+            expiry,
+            holder: holder.to_string(),
+        })
+    }
+
+    pub fn token(&self) -> &CardToken {
+        &self.token
+    }
+
+    pub fn brand(&self) -> CardBrand {
+        self.brand
+    }
+
+    pub fn last4(&self) -> &str {
+        &self.last4
+    }
+
+    pub fn expiry(&self) -> CardExpiry {
+        self.expiry
+    }
+
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    /// Display form, e.g. `Visa **** 4242`.
+    pub fn masked(&self) -> String {
+        format!("{} **** {}", self.brand, self.last4)
+    }
+}
+
+/// Strip spaces and dashes and check the PAN is all digits of a sane length.
+fn normalize_pan(pan: &str) -> Result<Vec<u8>, CardError> {
+    let mut digits = Vec::with_capacity(pan.len());
+    for c in pan.chars() {
+        match c {
+            ' ' | '-' => {}
+            '0'..='9' => digits.push(c as u8 - b'0'),
+            _ => return Err(CardError::NonDigit),
+        }
+    }
+    if !(12..=19).contains(&digits.len()) {
+        return Err(CardError::InvalidLength(digits.len()));
+    }
+    Ok(digits)
+}
+
+pub fn luhn_valid(digits: &[u8]) -> bool {
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| {
+            let d = d as u32;
+            if i % 2 == 1 {
+                let dd = d * 2;
+                if dd > 9 {
+                    dd - 9
+                } else {
+                    dd
+                }
+            } else {
+                d
+            }
+        })
+        .sum();
+    !digits.is_empty() && sum.is_multiple_of(10)
+}
+
+pub fn detect_brand(digits: &[u8]) -> Option<CardBrand> {
+    let prefix = |n: usize| -> u32 {
+        digits
+            .iter()
+            .take(n)
+            .fold(0u32, |acc, &d| acc * 10 + d as u32)
+    };
+    if digits.len() < 6 {
+        return None;
+    }
+    let p2 = prefix(2);
+    let p4 = prefix(4);
+    let p6 = prefix(6);
+    if digits[0] == 4 {
+        Some(CardBrand::Visa)
+    } else if (51..=55).contains(&p2) || (2221..=2720).contains(&p4) {
+        Some(CardBrand::Mastercard)
+    } else if p2 == 34 || p2 == 37 {
+        Some(CardBrand::Amex)
+    } else if p4 == 6011
+        || p2 == 65
+        || (644..=649).contains(&prefix(3))
+        || (622126..=622925).contains(&p6)
+    {
+        Some(CardBrand::Discover)
+    } else {
+        None
+    }
+}
+
+/// In-memory vault of tokenized cards. Persist entries with
+/// `Repository::save_card`: payment methods hold only the token, and the
+/// repositories refuse tokens that are not in their card store.
+#[derive(Debug, Clone, Default)]
+pub struct CardVault {
+    cards: HashMap<CardToken, StoredCard>,
+}
+
+impl CardVault {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Validate `pan` against today's date and return the stored card.
+    pub fn tokenize(
+        &mut self,
+        pan: &str,
+        expiry: CardExpiry,
+        holder: &str,
+    ) -> Result<StoredCard, CardError> {
+        self.tokenize_at(pan, expiry, holder, Utc::now().date_naive())
+    }
+
+    /// Same as `tokenize` with an explicit reference date for the expiry check.
+    pub fn tokenize_at(
+        &mut self,
+        pan: &str,
+        expiry: CardExpiry,
+        holder: &str,
+        today: NaiveDate,
+    ) -> Result<StoredCard, CardError> {
+        let digits = normalize_pan(pan)?;
+        if !luhn_valid(&digits) {
+            return Err(CardError::ChecksumMismatch);
+        }
+        let brand = detect_brand(&digits).ok_or(CardError::UnsupportedBrand)?;
+        if !brand.accepts_length(digits.len()) {
+            return Err(CardError::BrandLengthMismatch(brand, digits.len()));
+        }
+        if expiry.is_expired_at(today) {
+            return Err(CardError::Expired(expiry));
+        }
+        let last4: String = digits[digits.len() - 4..]
+            .iter()
+            .map(|d| (b'0' + d) as char)
+            .collect();
+        let card = StoredCard::from_parts(CardToken::generate(), brand, &last4, expiry, holder)?;
+        self.cards.insert(card.token.clone(), card.clone());
+        Ok(card)
+    }
+
+    /// Register an already tokenized card (e.g. loaded from the database).
+    pub fn insert(&mut self, card: StoredCard) {
+        self.cards.insert(card.token.clone(), card);
+    }
+
+    pub fn get(&self, token: &CardToken) -> Option<&StoredCard> {
+        self.cards.get(token)
+    }
+
+    pub fn remove(&mut self, token: &CardToken) -> Option<StoredCard> {
+        self.cards.remove(token)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cards.is_empty()
+    }
+}
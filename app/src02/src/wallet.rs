@@ -0,0 +1,97 @@
+//! Prepaid balance wallets. Operations return a new wallet instead of
+//! mutating, in the same style as `UsageLog::add_usage`.
+
+use crate::models::UserId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct WalletId(pub String);
+impl From<&str> for WalletId {
+    fn from(s: &str) -> Self {
+        WalletId(s.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalletError {
+    ZeroAmount,
+    InsufficientFunds {
+        balance_cents: u64,
+        requested_cents: u64,
+    },
+    Overflow,
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletError::ZeroAmount => write!(f, "amount must be greater than zero"),
+            WalletError::InsufficientFunds {
+                balance_cents,
+                requested_cents,
+            } => write!(
+                f,
+                "insufficient prepaid balance: {}¢ available, {}¢ requested",
+                balance_cents, requested_cents
+            ),
+            WalletError::Overflow => write!(f, "wallet balance overflow"),
+        }
+    }
+}
+
+impl std::error::Error for WalletError {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrepaidWallet {
+    pub id: WalletId,
+    pub owner: UserId,
+    pub balance_cents: u64,
+}
+
+impl PrepaidWallet {
+    pub fn new(id: &str, owner: &UserId) -> Self {
+        PrepaidWallet {
+            id: WalletId(id.to_string()),
+            owner: owner.clone(),
+            balance_cents: 0,
+        }
+    }
+
+    pub fn can_cover(&self, amount_cents: u64) -> bool {
+        self.balance_cents >= amount_cents
+    }
+
+    pub fn top_up(&self, amount_cents: u64) -> Result<PrepaidWallet, WalletError> {
+        if amount_cents == 0 {
+            return Err(WalletError::ZeroAmount);
+        }
+        let balance_cents = self
+            .balance_cents
+            .checked_add(amount_cents)
+            .ok_or(WalletError::Overflow)?;
+        Ok(PrepaidWallet {
+            balance_cents,
+            ..self.clone()
+        })
+    }
+
+    pub fn debit(&self, amount_cents: u64) -> Result<PrepaidWallet, WalletError> {
+        if amount_cents == 0 {
+            return Err(WalletError::ZeroAmount);
+        }
+        if !self.can_cover(amount_cents) {
+            return Err(WalletError::InsufficientFunds {
+                balance_cents: self.balance_cents,
+                requested_cents: amount_cents,
+            });
+        }
+        Ok(PrepaidWallet {
+            balance_cents: self.balance_cents - amount_cents,
+            ..self.clone()
+        })
+    }
+}
+
+pub type WalletMap = HashMap<WalletId, PrepaidWallet>;
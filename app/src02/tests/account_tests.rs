@@ -0,0 +1,226 @@
+mod common;
+
+use common::{on_backends, saas, uid, users, Backend};
+use src02::account::{resolve_payment_for_account, Account, AccountError, Role};
+use src02::catalog::Catalog;
+use src02::models::{PaymentMethod, ServiceUsage, User};
+use src02::persistence::{self, PersistenceError};
+use src02::query::UsageQuery;
+use src02::repository::{Repository, SqliteRepository};
+use src02::validation::{self, References, UsageViolation};
+use std::error::Error;
+
+/// Alice owns it, Bob manages billing, Carol is a member; invoiced by bank transfer.
+fn acme() -> Result<Account, AccountError> {
+    let alice = uid("u-alice");
+    Account::new("acc-acme", "Acme", &alice)
+        .set_member(&alice, &uid("u-bob"), Role::BillingAdmin)?
+        .set_member(&alice, &uid("u-carol"), Role::Member)?
+        .add_payment_method(
+            &uid("u-bob"),
+            PaymentMethod::bank_transfer("ACME-INV", 30).expect("valid terms"),
+            false,
+        )
+}
+
+fn usage(user: &str) -> ServiceUsage {
+    common::usage(user, "s-1", "p-1")
+}
+
+#[test]
+fn test_roles_limit_who_may_change_what() -> Result<(), Box<dyn Error>> {
+    let acme = acme()?;
+    assert_eq!(acme.role_of(&uid("u-bob")), Some(Role::BillingAdmin));
+    assert!(matches!(
+        acme.set_member(&uid("u-bob"), &uid("u-dave"), Role::Member),
+        Err(AccountError::NotPermitted { .. })
+    ));
+    assert!(matches!(
+        acme.add_payment_method(&uid("u-carol"), PaymentMethod::paypal("c@paypal"), true),
+        Err(AccountError::NotPermitted { .. })
+    ));
+    assert_eq!(
+        acme.remove_member(&uid("u-dave"), &uid("u-carol"))
+            .unwrap_err(),
+        AccountError::NotAMember(uid("u-dave"))
+    );
+
+    // members may leave on their own
+    let left = acme.remove_member(&uid("u-carol"), &uid("u-carol"))?;
+    assert!(!left.is_member(&uid("u-carol")));
+
+    // the default payment method goes first
+    let paypal = acme.add_payment_method(&uid("u-bob"), PaymentMethod::paypal("ap@acme"), true)?;
+    assert_eq!(paypal.payment_methods.len(), 2);
+    assert_eq!(
+        paypal.default_payment().map(|pm| pm.kind().as_str()),
+        Some("paypal")
+    );
+    assert_eq!(
+        paypal.remove_payment_method(&uid("u-bob"), 5).unwrap_err(),
+        AccountError::NoSuchPaymentMethod(5)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_account_keeps_an_owner() -> Result<(), Box<dyn Error>> {
+    let alice = uid("u-alice");
+    let acme = acme()?;
+    assert_eq!(
+        acme.remove_member(&alice, &alice).unwrap_err(),
+        AccountError::LastOwner
+    );
+    assert_eq!(
+        acme.set_member(&alice, &alice, Role::Member).unwrap_err(),
+        AccountError::LastOwner
+    );
+    // with a second owner the first may step down
+    let handed_over = acme
+        .set_member(&alice, &uid("u-bob"), Role::Owner)?
+        .set_member(&alice, &alice, Role::Member)?;
+    assert_eq!(handed_over.role_of(&alice), Some(Role::Member));
+    Ok(())
+}
+
+#[test]
+fn test_account_usage_is_paid_by_the_account() -> Result<(), Box<dyn Error>> {
+    let acme = acme()?;
+    let refs = References::new(
+        users().into_iter().map(|u| (u.id.clone(), u)).collect(),
+        Catalog::default().with_service(saas()),
+    )
+    .with_account(acme.clone());
+
+    // Carol has no payment method of her own, the account pays
+    let billed = usage("u-carol").for_account(&acme.id);
+    assert_eq!(validation::validate_usage(&billed, &refs), Ok(()));
+    assert_eq!(
+        resolve_payment_for_account(&acme, None).map(|pm| pm.kind()),
+        acme.default_payment().map(|pm| pm.kind())
+    );
+    // the personal default is never used for account usages
+    let broke = acme.remove_payment_method(&uid("u-alice"), 0)?;
+    let refs = refs.with_account(broke);
+    assert_eq!(
+        validation::check_usage(&usage("u-alice").for_account(&acme.id), &refs),
+        [UsageViolation::NoAccountPaymentMethod {
+            account_id: acme.id.clone()
+        }]
+    );
+
+    let outsider = User::new("u-dave", "Dave", None);
+    let refs = References {
+        users: refs
+            .users
+            .clone()
+            .into_iter()
+            .chain([(outsider.id.clone(), outsider)])
+            .collect(),
+        ..refs
+    };
+    let problems = validation::check_usage(&usage("u-dave").for_account(&acme.id), &refs);
+    assert_eq!(
+        problems[0],
+        UsageViolation::NotAMember {
+            account_id: acme.id.clone(),
+            user_id: uid("u-dave")
+        }
+    );
+    assert_eq!(
+        validation::check_usage(&usage("u-alice").for_account(&"acc-none".into()), &refs),
+        [UsageViolation::UnknownAccount {
+            account_id: "acc-none".into()
+        }]
+    );
+    Ok(())
+}
+
+async fn accounts_round_trip(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    repo.init().await?;
+    for u in users() {
+        repo.save_user(&u).await?;
+    }
+    repo.save_service(&saas()).await?;
+    let acme = acme()?;
+    repo.save_account(&acme).await?;
+
+    let stored = repo.get_accounts().await?;
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].members, acme.members);
+    assert_eq!(stored[0].payment_methods.len(), 1);
+
+    // saving again replaces members and payment methods
+    let smaller = acme.remove_member(&uid("u-alice"), &uid("u-carol"))?;
+    repo.save_account(&smaller).await?;
+    assert_eq!(repo.get_accounts().await?[0].members.len(), 2);
+
+    repo.save_usages(&[
+        usage("u-alice").for_account(&acme.id),
+        usage("u-bob").for_account(&acme.id),
+        usage("u-bob"),
+    ])
+    .await?;
+    let billed = UsageQuery::new().account(&acme.id);
+    let agg = repo.aggregate_usages(&billed).await?;
+    assert_eq!((agg.count, agg.total_cents), (2, 1000));
+    let page = repo
+        .query_usages(&billed.clone().user(&uid("u-bob")))
+        .await?;
+    assert_eq!(page.items.len(), 1);
+    assert_eq!(page.items[0].usage.account_id.as_ref(), Some(&acme.id));
+    // attributed to the user as well
+    assert_eq!(repo.get_usages_for_user(&uid("u-bob")).await?.len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sqlite_stores_accounts() -> Result<(), Box<dyn Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    accounts_round_trip(&SqliteRepository::new(pool.clone())).await?;
+    let members: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM account_members")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(members, 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_memory_and_sled_store_accounts() -> Result<(), Box<dyn Error>> {
+    on_backends(&[Backend::Memory, Backend::Sled], |repo| async move {
+        accounts_round_trip(repo.as_ref()).await
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_sqlite_rejects_usages_of_non_members() -> Result<(), Box<dyn Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    persistence::init_db(&pool).await?;
+    for u in users() {
+        persistence::save_user(&pool, &u).await?;
+    }
+    persistence::save_user(&pool, &User::new("u-dave", "Dave", None)).await?;
+    persistence::save_service(&pool, &saas()).await?;
+    persistence::save_account(&pool, &acme()?).await?;
+
+    for (bad, reason) in [
+        (
+            usage("u-dave").for_account(&"acc-acme".into()),
+            "usage references an account its user is not a member of",
+        ),
+        (
+            usage("u-alice").for_account(&"acc-none".into()),
+            "usage references unknown account",
+        ),
+    ] {
+        match persistence::save_usage(&pool, &bad).await {
+            Err(PersistenceError::Constraint { table, reason: r }) => {
+                assert_eq!((table, r.as_str()), ("usages", reason))
+            }
+            other => panic!("expected a constraint error, got {:?}", other),
+        }
+    }
+    persistence::save_usage(&pool, &usage("u-carol").for_account(&"acc-acme".into())).await?;
+    Ok(())
+}
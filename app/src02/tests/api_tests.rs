@@ -0,0 +1,313 @@
+mod common;
+
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Router;
+use common::call;
+use serde_json::{json, Value};
+use src02::account::{Account, Role};
+use src02::api;
+use src02::budget::{BillingPeriod, Budget};
+use src02::models::{PaymentMethod, User};
+use src02::profile::Email;
+use src02::repository::{MemoryRepository, Repository, RepositoryError, SqliteRepository};
+use std::error::Error;
+use std::sync::Arc;
+
+/// Alice pays by PayPal, Bob has no payment method; s-1 offers p-1 for 5.00.
+async fn app(repo: Arc<dyn Repository>) -> Result<Router, Box<dyn Error>> {
+    repo.init().await?;
+    let app = api::router(repo);
+    let alice = User::new("u-alice", "Alice", Some(PaymentMethod::paypal("a@paypal")));
+    let bob = User::new("u-bob", "Bob", None);
+    for user in [alice, bob] {
+        let (status, _) = call(&app, "POST", "/users", Some(json!(user))).await?;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+    let service = json!({ "id": "s-1", "name": "SaaS", "products": [] });
+    assert_eq!(
+        call(&app, "POST", "/services", Some(service)).await?.0,
+        StatusCode::CREATED
+    );
+    let product = json!({ "id": "p-1", "name": "Email", "price_cents": 500 });
+    assert_eq!(
+        call(&app, "POST", "/services/s-1/products", Some(product))
+            .await?
+            .0,
+        StatusCode::CREATED
+    );
+    Ok(app)
+}
+
+fn usage(user: &str, product: &str) -> Value {
+    json!({
+        "user_id": user,
+        "service_id": "s-1",
+        "product_id": product,
+        "occurred_at": "2025-03-10T12:00:00Z",
+    })
+}
+
+#[tokio::test]
+async fn test_users_and_catalog_round_trip_as_models() -> Result<(), Box<dyn Error>> {
+    let app = app(Arc::new(MemoryRepository::new())).await?;
+    assert_eq!(call(&app, "GET", "/health", None).await?.1["status"], "ok");
+
+    let (status, alice) = call(&app, "GET", "/users/u-alice", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    let alice: User = serde_json::from_value(alice)?;
+    assert_eq!(alice.profile.display_name, "Alice");
+    let (_, users) = call(&app, "GET", "/users", None).await?;
+    assert_eq!(users.as_array().map(Vec::len), Some(2));
+
+    let (_, service) = call(&app, "GET", "/services/s-1", None).await?;
+    assert_eq!(service["products"][0]["price_cents"], 500);
+    let (_, products) = call(&app, "GET", "/products", None).await?;
+    assert_eq!(products[0]["id"], "p-1");
+
+    // a product keyed by another service's product id is refused
+    let other = json!({ "id": "s-2", "name": "Other", "products": [
+        { "id": "p-1", "name": "Copy", "price_cents": 1 }
+    ]});
+    let (status, body) = call(&app, "POST", "/services", Some(other)).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(body["error"].as_str().unwrap_or_default().contains("p-1"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_error_statuses() -> Result<(), Box<dyn Error>> {
+    let app = app(Arc::new(MemoryRepository::new())).await?;
+    let (status, body) = call(&app, "GET", "/users/u-nobody", None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"], "unknown user u-nobody");
+    assert_eq!(
+        call(&app, "GET", "/services/s-9/products", None).await?.0,
+        StatusCode::NOT_FOUND
+    );
+    let product = json!({ "id": "p-2", "name": "Chat", "price_cents": 100 });
+    assert_eq!(
+        call(&app, "POST", "/services/s-9/products", Some(product))
+            .await?
+            .0,
+        StatusCode::NOT_FOUND
+    );
+
+    // malformed bodies and filters
+    let (status, body) = call(&app, "POST", "/users", Some(json!({ "id": 1 }))).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body["error"].is_string());
+    for uri in [
+        "/usages?cursor=nope",
+        "/usages?payment_kind=cash",
+        "/usages?from=yesterday",
+    ] {
+        assert_eq!(
+            call(&app, "GET", uri, None).await?.0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    // usages that fail validation carry the violation
+    let (status, body) = call(&app, "POST", "/usages", Some(usage("u-alice", "p-9"))).await?;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["violation"]["kind"], "product_not_offered");
+    let (status, body) = call(&app, "POST", "/usages", Some(usage("u-bob", "p-1"))).await?;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["violation"]["kind"], "no_payment_method");
+    Ok(())
+}
+
+async fn records_and_lists_usages(repo: Arc<dyn Repository>) -> Result<(), Box<dyn Error>> {
+    let app = app(repo.clone()).await?;
+    for _ in 0..3 {
+        let (status, body) = call(&app, "POST", "/usages", Some(usage("u-alice", "p-1"))).await?;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["occurred_at"], "2025-03-10T12:00:00Z");
+    }
+
+    let (status, page) = call(&app, "GET", "/usages?user=u-alice&limit=2", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(page["items"].as_array().map(Vec::len), Some(2));
+    let cursor = page["next_cursor"].as_str().ok_or("expected a cursor")?;
+    let (_, rest) = call(&app, "GET", &format!("/usages?cursor={}", cursor), None).await?;
+    assert_eq!(rest["items"].as_array().map(Vec::len), Some(1));
+    assert_eq!(rest["next_cursor"], Value::Null);
+
+    let (_, agg) = call(
+        &app,
+        "GET",
+        "/usages/aggregate?service=s-1&from=2025-03-01T00:00:00Z&until=2025-04-01T00:00:00Z",
+        None,
+    )
+    .await?;
+    assert_eq!(agg, json!({ "count": 3, "total_cents": 1500 }));
+
+    // a hard limit refuses the usage that would overrun it
+    repo.save_budget(
+        &Budget::new("b-1", &"u-alice".into(), BillingPeriod::Monthly, 1600).hard_limit(),
+    )
+    .await?;
+    let (status, body) = call(&app, "POST", "/usages", Some(usage("u-alice", "p-1"))).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(body["error"]
+        .as_str()
+        .unwrap_or_default()
+        .starts_with("budget b-1 exceeded"));
+    assert_eq!(repo.aggregate_usages(&Default::default()).await?.count, 3);
+
+    // with room for it, the usage is recorded and lists the thresholds it crossed
+    repo.save_budget(
+        &Budget::new("b-1", &"u-alice".into(), BillingPeriod::Monthly, 2000).hard_limit(),
+    )
+    .await?;
+    let (status, body) = call(&app, "POST", "/usages", Some(usage("u-alice", "p-1"))).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body["user_id"], "u-alice");
+    let crossed: Vec<_> = body["alerts"]
+        .as_array()
+        .ok_or("expected alerts")?
+        .iter()
+        .map(|a| a["threshold_percent"].clone())
+        .collect();
+    assert_eq!(crossed, [json!(80), json!(100)]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_memory_repository_records_usages() -> Result<(), Box<dyn Error>> {
+    records_and_lists_usages(Arc::new(MemoryRepository::new())).await
+}
+
+#[tokio::test]
+async fn test_sqlite_records_usages() -> Result<(), Box<dyn Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    records_and_lists_usages(Arc::new(SqliteRepository::new(pool))).await
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_hard_limit_holds_under_concurrent_usages() -> Result<(), Box<dyn Error>> {
+    // a file database: its reads and writes yield, so requests interleave
+    let dir = tempfile::tempdir()?;
+    let url = format!("sqlite:{}", dir.path().join("api.db").display());
+    let repo: Arc<dyn Repository> = Arc::new(SqliteRepository::connect(&url).await?);
+    let app = app(repo.clone()).await?;
+    // room for two 5.00 usages
+    repo.save_budget(
+        &Budget::new("b-1", &"u-alice".into(), BillingPeriod::Monthly, 1000).hard_limit(),
+    )
+    .await?;
+
+    let requests: Vec<_> = (0..16)
+        .map(|_| {
+            let app = app.clone();
+            tokio::spawn(async move {
+                call(&app, "POST", "/usages", Some(usage("u-alice", "p-1")))
+                    .await
+                    .map(|(status, _)| status)
+                    .map_err(|e| e.to_string())
+            })
+        })
+        .collect();
+    let mut created = 0;
+    for request in requests {
+        match request.await?? {
+            StatusCode::CREATED => created += 1,
+            status => assert_eq!(status, StatusCode::CONFLICT),
+        }
+    }
+    assert_eq!(created, 2);
+    assert_eq!(repo.aggregate_usages(&Default::default()).await?.count, 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resolves_payment_for_users_and_accounts() -> Result<(), Box<dyn Error>> {
+    let repo: Arc<dyn Repository> = Arc::new(MemoryRepository::new());
+    let app = app(repo.clone()).await?;
+
+    let (status, body) = call(&app, "GET", "/users/u-alice/payment", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["payment"], json!(PaymentMethod::paypal("a@paypal")));
+    let (status, body) = call(&app, "GET", "/users/u-bob/payment", None).await?;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["violation"]["kind"], "no_payment_method");
+
+    // the usage's own method wins over the default
+    let named = json!({ "user_id": "u-bob", "payment_used": PaymentMethod::paypal("b@paypal") });
+    let (_, body) = call(&app, "POST", "/payments/resolve", Some(named)).await?;
+    assert_eq!(body["payment"]["Paypal"]["account"], "b@paypal");
+
+    // Bob bills to Acme, which invoices by bank transfer
+    let alice = "u-alice".into();
+    let acme = Account::new("acc-acme", "Acme", &alice)
+        .set_member(&alice, &"u-bob".into(), Role::Member)?
+        .add_payment_method(&alice, PaymentMethod::bank_transfer("ACME-INV", 30)?, true)?;
+    repo.save_account(&acme).await?;
+    let billed = json!({ "user_id": "u-bob", "account_id": "acc-acme" });
+    let (status, body) = call(&app, "POST", "/payments/resolve", Some(billed)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["payment"]["BankTransfer"]["reference"], "ACME-INV");
+    let unknown = json!({ "user_id": "u-bob", "account_id": "acc-none" });
+    let (status, body) = call(&app, "POST", "/payments/resolve", Some(unknown)).await?;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["violation"]["kind"], "unknown_account");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cards_are_vaulted_before_use() -> Result<(), Box<dyn Error>> {
+    let app = app(Arc::new(MemoryRepository::new())).await?;
+    let number = json!({
+        "number": "4242 4242 4242 4242",
+        "expiry": { "month": 12, "year": 2099 },
+        "holder": "Bob",
+    });
+    let (status, card) = call(&app, "POST", "/cards", Some(number)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(card["last4"], "4242");
+    let token = card["token"].as_str().ok_or("expected a token")?;
+    let (status, stored) = call(&app, "GET", &format!("/cards/{}", token), None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(stored, card);
+
+    let vaulted = json!({ "method": { "Card": token } });
+    let (status, bob) = call(&app, "POST", "/users/u-bob/payment-methods", Some(vaulted)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(bob["profile"]["payment_methods"][0]["Card"], token);
+    // well-formed, but never vaulted
+    let forged = json!({ "method": { "Card": "tok_0123456789abcdef" } });
+    let (status, _) = call(&app, "POST", "/users/u-bob/payment-methods", Some(forged)).await?;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let bad = json!({ "number": "4242 4242 4242 4241", "expiry": { "month": 12, "year": 2099 }, "holder": "Bob" });
+    assert_eq!(
+        call(&app, "POST", "/cards", Some(bad)).await?.0,
+        StatusCode::UNPROCESSABLE_ENTITY
+    );
+    assert_eq!(
+        call(&app, "GET", "/cards/tok_0123456789abcdef", None)
+            .await?
+            .0,
+        StatusCode::NOT_FOUND
+    );
+    Ok(())
+}
+
+#[test]
+fn test_server_errors_carry_their_message_for_logging() {
+    let failed = api::ApiError::Repository(RepositoryError::UnsupportedUrl("x:".into()));
+    let message = failed.to_string();
+    let response = failed.into_response();
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let logged = response.extensions().get::<api::ServerError>();
+    assert_eq!(logged.map(|e| e.0.as_str()), Some(message.as_str()));
+
+    let taken = Email::parse("a@example.com").unwrap();
+    let refused = api::ApiError::Repository(RepositoryError::EmailTaken(taken));
+    assert!(refused
+        .into_response()
+        .extensions()
+        .get::<api::ServerError>()
+        .is_none());
+}
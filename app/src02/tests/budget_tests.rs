@@ -0,0 +1,164 @@
+mod common;
+
+use chrono::{DateTime, Utc};
+use common::{alice, day, on_backends, saas, Backend};
+use src02::budget::{self, BillingPeriod, Budget, BudgetError, BudgetStatus};
+use src02::catalog::Catalog;
+use src02::models::{Product, Service, ServiceUsage};
+use src02::persistence;
+use src02::repository::{Repository, SqliteRepository};
+use std::error::Error;
+
+fn catalog() -> Catalog {
+    Catalog::default()
+        .with_service(saas())
+        .with_service(Service::new(
+            "s-2",
+            "Consulting",
+            vec![Product::new("p-3", "On-site", 3000)],
+        ))
+}
+
+fn usage(service: &str, product: &str, at: DateTime<Utc>) -> ServiceUsage {
+    common::usage("u-alice", service, product).at(at)
+}
+
+#[test]
+fn test_period_bounds() {
+    let at = day(2025, 12, 17) + chrono::Duration::hours(15);
+    assert_eq!(
+        BillingPeriod::Monthly.bounds(at),
+        (day(2025, 12, 1), day(2026, 1, 1))
+    );
+    // 2025-12-17 is a Wednesday
+    assert_eq!(
+        BillingPeriod::Weekly.bounds(at),
+        (day(2025, 12, 15), day(2025, 12, 22))
+    );
+    assert_eq!(
+        BillingPeriod::Daily.bounds(at),
+        (day(2025, 12, 17), day(2025, 12, 18))
+    );
+}
+
+#[test]
+fn test_thresholds_fire_once_when_crossed() {
+    let b = Budget::new("b-1", &"u-alice".into(), BillingPeriod::Monthly, 1000);
+    assert_eq!(b.crossed(0, 400), Vec::<u8>::new());
+    assert_eq!(b.crossed(400, 850), [50, 80]);
+    assert_eq!(b.crossed(850, 1000), [100]);
+    assert!(b.crossed(1000, 1500).is_empty());
+    let b = b.with_thresholds(&[100, 25, 25]);
+    assert_eq!(b.thresholds, [25, 100]);
+}
+
+#[test]
+fn test_projection_extrapolates_the_rate_so_far() {
+    let b = Budget::new("b-1", &"u-alice".into(), BillingPeriod::Monthly, 10_000);
+    // 1500¢ in the first 10 of November's 30 days
+    let status = BudgetStatus::new(&b, 1500, day(2025, 11, 11));
+    assert_eq!(status.period_start, day(2025, 11, 1));
+    assert_eq!(status.projected_cents, 4500);
+    assert_eq!(status.percent_used(), 15);
+}
+
+async fn enforce(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    repo.init().await?;
+    repo.save_user(&alice()).await?;
+    for s in catalog().services().values() {
+        repo.save_service(s).await?;
+    }
+    let overall = Budget::new("b-all", &"u-alice".into(), BillingPeriod::Monthly, 4000);
+    let saas = Budget::new("b-saas", &"u-alice".into(), BillingPeriod::Monthly, 1000)
+        .for_service(&"s-1".into())
+        .hard_limit();
+    repo.save_budget(&overall).await?;
+    repo.save_budget(&saas).await?;
+    let budgets = repo.get_budgets().await?;
+    assert_eq!(budgets, [overall.clone(), saas.clone()]);
+
+    let record = |u: ServiceUsage| {
+        let budgets = budgets.clone();
+        async move {
+            let alerts = budget::check_usage(repo, &budgets, &catalog(), &u).await?;
+            repo.save_usage(&u).await?;
+            Ok::<_, BudgetError>(alerts)
+        }
+    };
+    let at = day(2025, 3, 10);
+    let alerts = record(usage("s-1", "p-1", at)).await?;
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(
+        alerts[0].to_string(),
+        "budget b-saas of u-alice reached 50%: 500¢ of 1000¢"
+    );
+    let alerts = record(usage("s-1", "p-1", at)).await?;
+    assert_eq!(
+        alerts
+            .iter()
+            .map(|a| (a.budget_id.0.as_str(), a.threshold_percent))
+            .collect::<Vec<_>>(),
+        [("b-saas", 80), ("b-saas", 100)]
+    );
+
+    // the SaaS budget is used up; other services still pass it
+    match budget::check_usage(repo, &budgets, &catalog(), &usage("s-1", "p-1", at)).await {
+        Err(BudgetError::LimitExceeded {
+            budget_id,
+            spent_cents,
+            price_cents,
+            ..
+        }) => assert_eq!(
+            (budget_id.0.as_str(), spent_cents, price_cents),
+            ("b-saas", 1000, 500)
+        ),
+        other => panic!("expected the hard limit, got {:?}", other),
+    }
+    let alerts = record(usage("s-2", "p-3", at)).await?;
+    assert_eq!(
+        alerts
+            .iter()
+            .map(|a| a.threshold_percent)
+            .collect::<Vec<_>>(),
+        [50, 80, 100]
+    );
+    assert!(alerts.iter().all(|a| a.spent_cents == 4000));
+
+    // a new period starts from zero
+    assert!(budget::check_usage(
+        repo,
+        &budgets,
+        &catalog(),
+        &usage("s-1", "p-1", day(2025, 4, 1))
+    )
+    .await
+    .is_ok());
+    let statuses = budget::statuses(repo, &budgets, day(2025, 3, 20)).await?;
+    assert_eq!(statuses[0].spent_cents, 4000);
+    assert_eq!(statuses[1].spent_cents, 1000);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sqlite_enforces_budgets() -> Result<(), Box<dyn Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    enforce(&SqliteRepository::new(pool.clone())).await?;
+    sqlx::query("UPDATE budgets SET thresholds = 'lots' WHERE id = 'b-all'")
+        .execute(&pool)
+        .await?;
+    assert!(persistence::get_budgets(&pool).await.is_err());
+    let read = persistence::get_budgets_with(&pool, persistence::ReadMode::Lenient).await?;
+    assert_eq!(
+        (read.items.len(), read.skipped[0].column),
+        (1, "thresholds")
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_memory_and_sled_enforce_budgets() -> Result<(), Box<dyn Error>> {
+    on_backends(&[Backend::Memory, Backend::Sled], |repo| async move {
+        enforce(repo.as_ref()).await
+    })
+    .await
+}
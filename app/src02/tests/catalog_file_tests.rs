@@ -0,0 +1,339 @@
+use src02::catalog::Catalog;
+use src02::catalog_file::{self, CatalogChange, CatalogFileError, FileFormat, Problem};
+use src02::models::{Product, Service, ServiceUsage, User};
+use src02::repository::{MemoryRepository, Repository, SledRepository, SqliteRepository};
+use std::error::Error;
+
+const TOML: &str = r#"
+[[services]]
+id = "s-1"
+name = "SaaS Platform"
+
+[[services.products]]
+id = "p-1"
+name = "Email Support"
+price_cents = 500
+
+[[services.products]]
+id = "p-2"
+name = "Premium Analytics"
+price_cents = 1500
+
+[[services]]
+id = "s-2"
+name = "Consulting"
+products = [{ id = "p-3", name = "On-site Consulting", price_cents = 10000 }]
+"#;
+
+const JSON: &str = r#"{
+  "services": [
+    { "id": "s-1", "name": "SaaS Platform", "products": [
+      { "id": "p-1", "name": "Email Support", "price_cents": 500 },
+      { "id": "p-2", "name": "Premium Analytics", "price_cents": 1500 } ] },
+    { "id": "s-2", "name": "Consulting", "products": [
+      { "id": "p-3", "name": "On-site Consulting", "price_cents": 10000 } ] }
+  ]
+}"#;
+
+const YAML: &str = "
+services:
+  - id: s-1
+    name: SaaS Platform
+    products:
+      - { id: p-1, name: Email Support, price_cents: 500 }
+      - { id: p-2, name: Premium Analytics, price_cents: 1500 }
+  - id: s-2
+    name: Consulting
+    products:
+      - { id: p-3, name: On-site Consulting, price_cents: 10000 }
+";
+
+fn problems(text: &str, format: FileFormat) -> Vec<Problem> {
+    match catalog_file::parse(text, format) {
+        Err(CatalogFileError::Invalid { problems, .. }) => problems,
+        other => panic!("expected problems, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_same_catalog_from_every_format() -> Result<(), Box<dyn Error>> {
+    for (text, format) in [
+        (TOML, FileFormat::Toml),
+        (JSON, FileFormat::Json),
+        (YAML, FileFormat::Yaml),
+    ] {
+        let catalog = catalog_file::parse(text, format)?;
+        assert_eq!(catalog.services().len(), 2);
+        let (svc, p) = catalog.find_product(&"p-3".into()).unwrap();
+        assert_eq!((svc.id.0.as_str(), p.price_cents), ("s-2", 10000));
+        assert_eq!(
+            catalog.list_products_for_service(&"s-1".into()).unwrap()[1].name,
+            "Premium Analytics"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_load_picks_the_format_from_the_extension() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("catalog.yml");
+    std::fs::write(&path, YAML)?;
+    assert_eq!(catalog_file::load(&path)?.services().len(), 2);
+
+    let txt = dir.path().join("catalog.txt");
+    std::fs::write(&txt, YAML)?;
+    assert!(matches!(
+        catalog_file::load(&txt),
+        Err(CatalogFileError::UnknownFormat(_))
+    ));
+
+    let broken = dir.path().join("broken.toml");
+    std::fs::write(&broken, "[[services]]\nid = 's-1'\nname = 7\n")?;
+    let err = catalog_file::load(&broken).unwrap_err().to_string();
+    assert!(
+        err.starts_with(&format!("{}:3:8: services[0].name: ", broken.display())),
+        "{}",
+        err
+    );
+    Ok(())
+}
+
+#[test]
+fn test_type_and_syntax_errors_have_a_location() {
+    let toml = "[[services]]\nid = \"s-1\"\nname = \"SaaS\"\n\n[[services.products]]\nid = \"p-1\"\nname = \"Email\"\nprice_cents = -5\n";
+    let p = &problems(toml, FileFormat::Toml)[0];
+    assert_eq!(p.path, "services[0].products[0].price_cents");
+    assert_eq!((p.line, p.column), (Some(8), Some(15)));
+
+    let json = "{\"services\": [\n  {\"id\": \"s-1\", \"name\": \"SaaS\", \"prodcts\": []}\n]}";
+    let p = &problems(json, FileFormat::Json)[0];
+    assert_eq!(p.path, "services[0].prodcts");
+    assert_eq!(p.line, Some(2));
+    assert!(
+        p.message.starts_with("unknown field `prodcts`"),
+        "{}",
+        p.message
+    );
+
+    let yaml = "services:\n  - id: s-1\n    name: SaaS\n    products:\n      - id: p-1\n        name: Email\n        price_cents: lots\n";
+    let p = &problems(yaml, FileFormat::Yaml)[0];
+    assert_eq!(p.path, "services[0].products[0].price_cents");
+    assert_eq!(p.line, Some(7));
+    assert!(p.message.starts_with("invalid type"), "{}", p.message);
+
+    let p = &problems("services = [", FileFormat::Toml)[0];
+    assert_eq!(p.line, Some(1));
+}
+
+#[test]
+fn test_schema_problems_are_reported_together() {
+    let json = r#"{"services": [
+        {"id": "s-1", "name": "SaaS", "products": [
+            {"id": "p-1", "name": "Email", "price_cents": 500},
+            {"id": "p 2", "name": "", "price_cents": 18446744073709551615}]},
+        {"id": "s-1", "name": "Again", "products": [
+            {"id": "p-1", "name": "Email", "price_cents": 500}]}
+    ]}"#;
+    let found: Vec<String> = problems(json, FileFormat::Json)
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+    assert_eq!(
+        found,
+        [
+            "services[0].products[1].id: \"p 2\" must not contain whitespace",
+            "services[0].products[1].name: must not be empty",
+            "services[0].products[1].price_cents: must be at most 9223372036854775807",
+            "services[1].id: service s-1 is already defined at services[0]",
+            "services[1].products[0].id: product p-1 is already defined at services[0].products[0]",
+        ]
+    );
+}
+
+fn stored() -> Vec<Service> {
+    vec![
+        Service::new(
+            "s-1",
+            "SaaS",
+            vec![
+                Product::new("p-1", "Email Support", 400),
+                Product::new("p-3", "On-site Consulting", 10000),
+                Product::new("p-9", "Legacy", 100),
+            ],
+        ),
+        Service::new("s-8", "Old", vec![]),
+    ]
+}
+
+#[test]
+fn test_diff_lists_every_change() -> Result<(), Box<dyn Error>> {
+    let current = Catalog::from_services(Default::default(), stored())?;
+    let desired = catalog_file::parse(TOML, FileFormat::Toml)?;
+    let diff = catalog_file::diff(&current, &desired);
+    let lines: Vec<String> = diff.changes.iter().map(|c| c.to_string()).collect();
+    assert_eq!(
+        lines,
+        [
+            "~ service s-1 name \"SaaS\" -> \"SaaS Platform\"",
+            "~ product s-1/p-1 price 400¢ -> 500¢",
+            "+ product s-1/p-2 \"Premium Analytics\" 1500¢",
+            "= product s-1/p-9 is not in the file, kept",
+            "+ service s-2 \"Consulting\"",
+            "> product p-3 from service s-1 to s-2",
+            "= service s-8 is not in the file, kept",
+        ]
+    );
+    assert!(!diff.changes[3].is_write());
+    let writes: Vec<(&str, Vec<&str>)> = diff
+        .writes
+        .iter()
+        .map(|s| {
+            (
+                s.id.0.as_str(),
+                s.products.iter().map(|p| p.id.0.as_str()).collect(),
+            )
+        })
+        .collect();
+    // p-9 is kept, p-3 leaves s-1
+    assert_eq!(
+        writes,
+        [("s-1", vec!["p-1", "p-2", "p-9"]), ("s-2", vec!["p-3"])]
+    );
+    assert!(matches!(
+        diff.changes[5],
+        CatalogChange::MoveProduct { ref from, .. } if from.0 == "s-1"
+    ));
+    Ok(())
+}
+
+async fn sync_into(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    repo.init().await?;
+    for s in stored() {
+        repo.save_service(&s).await?;
+    }
+    let desired = catalog_file::parse(YAML, FileFormat::Yaml)?;
+
+    let preview = catalog_file::sync(repo, &desired, true).await?;
+    assert_eq!(preview.writes.len(), 2);
+    assert_eq!(
+        repo.get_services().await?.len(),
+        2,
+        "dry run writes nothing"
+    );
+
+    let applied = catalog_file::sync(repo, &desired, false).await?;
+    assert_eq!(applied.changes, preview.changes);
+    let mut services = repo.get_services().await?;
+    services.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut s1: Vec<&str> = services[0]
+        .products
+        .iter()
+        .map(|p| p.id.0.as_str())
+        .collect();
+    s1.sort();
+    assert_eq!(services[0].name, "SaaS Platform");
+    assert_eq!(s1, ["p-1", "p-2", "p-9"]);
+    assert_eq!(services[1].products[0].id.0, "p-3");
+    assert_eq!(services[2].id.0, "s-8");
+
+    // a second sync has nothing left to write
+    let again = catalog_file::sync(repo, &desired, false).await?;
+    assert!(again.is_empty());
+    assert!(again.changes.iter().all(|c| !c.is_write()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sync_into_sqlite() -> Result<(), Box<dyn Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    sync_into(&SqliteRepository::new(pool)).await
+}
+
+#[tokio::test]
+async fn test_sync_into_memory() -> Result<(), Box<dyn Error>> {
+    sync_into(&MemoryRepository::new()).await
+}
+
+#[tokio::test]
+async fn test_sync_swaps_products_between_services() -> Result<(), Box<dyn Error>> {
+    for repo in [
+        Box::new(SqliteRepository::connect("sqlite::memory:").await?) as Box<dyn Repository>,
+        Box::new(MemoryRepository::new()),
+    ] {
+        repo.init().await?;
+        repo.save_service(&Service::new(
+            "s-1",
+            "A",
+            vec![Product::new("p-1", "One", 100)],
+        ))
+        .await?;
+        repo.save_service(&Service::new(
+            "s-2",
+            "B",
+            vec![Product::new("p-2", "Two", 200)],
+        ))
+        .await?;
+        let desired = Catalog::default()
+            .with_service(Service::new(
+                "s-1",
+                "A",
+                vec![Product::new("p-2", "Two", 200)],
+            ))
+            .with_service(Service::new(
+                "s-2",
+                "B",
+                vec![Product::new("p-1", "One", 100)],
+            ));
+
+        catalog_file::sync(repo.as_ref(), &desired, false).await?;
+        let mut services = repo.get_services().await?;
+        services.sort_by(|a, b| a.id.cmp(&b.id));
+        let ids: Vec<&str> = services
+            .iter()
+            .flat_map(|s| s.products.iter().map(|p| p.id.0.as_str()))
+            .collect();
+        assert_eq!(ids, ["p-2", "p-1"]);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sync_refuses_moving_a_product_with_usages() -> Result<(), Box<dyn Error>> {
+    for repo in [
+        Box::new(SqliteRepository::connect("sqlite::memory:").await?) as Box<dyn Repository>,
+        Box::new(SledRepository::temporary()?),
+        Box::new(MemoryRepository::new()),
+    ] {
+        repo.init().await?;
+        repo.save_user(&User::new("u-1", "Ann", None)).await?;
+        let one = Product::new("p-1", "One", 100);
+        repo.save_service(&Service::new("s-1", "A", vec![one.clone()]))
+            .await?;
+        repo.save_service(&Service::new("s-2", "B", vec![])).await?;
+        repo.save_usage(&ServiceUsage::new(
+            &"u-1".into(),
+            &"s-1".into(),
+            &one.id,
+            None,
+        ))
+        .await?;
+        let desired = Catalog::default()
+            .with_service(Service::new("s-1", "A", vec![]))
+            .with_service(Service::new("s-2", "B", vec![one.clone()]));
+
+        for dry_run in [true, false] {
+            match catalog_file::sync(repo.as_ref(), &desired, dry_run).await {
+                Err(CatalogFileError::Invalid { problems, .. }) => {
+                    assert_eq!(problems.len(), 1);
+                    assert!(problems[0].message.contains("p-1"), "{}", problems[0]);
+                }
+                other => panic!("expected the move to be refused, got {:?}", other),
+            }
+        }
+        let services = repo.get_services().await?;
+        let s1 = services.iter().find(|s| s.id.0 == "s-1").expect("s-1");
+        assert_eq!(s1.products, vec![one]);
+    }
+    Ok(())
+}
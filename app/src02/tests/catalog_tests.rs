@@ -1,4 +1,4 @@
-use src02::catalog::Catalog;
+use src02::catalog::{Catalog, CatalogError, DuplicatePolicy};
 use src02::models::{Product, Service};
 
 #[test]
@@ -11,3 +11,162 @@ fn test_get_service_used() {
     let svc2 = got.unwrap();
     assert_eq!(svc2.name, "SaaS");
 }
+
+fn saas() -> Service {
+    Service::new(
+        "s-1",
+        "SaaS",
+        vec![
+            Product::new("p-1", "Email", 100),
+            Product::new("p-2", "Analytics", 1500),
+        ],
+    )
+}
+
+#[test]
+fn test_find_product_without_knowing_its_service() {
+    let catalog = Catalog::default()
+        .with_service(saas())
+        .with_service(Service::new(
+            "s-2",
+            "Consulting",
+            vec![Product::new("p-3", "On-site", 10000)],
+        ));
+    let (svc, p) = catalog.find_product(&"p-3".into()).unwrap();
+    assert_eq!(svc.id.0, "s-2");
+    assert_eq!(p.price_cents, 10000);
+    assert!(catalog.find_product(&"p-9".into()).is_none());
+}
+
+#[test]
+fn test_mutations_keep_the_index_in_sync() -> Result<(), CatalogError> {
+    let catalog = Catalog::default().with_service(saas());
+    let catalog = catalog
+        .add_service(Service::new("s-2", "Consulting", vec![]))?
+        .add_product(&"s-2".into(), Product::new("p-3", "On-site", 10000))?
+        .update_product(&"s-1".into(), Product::new("p-1", "Email Pro", 250))?
+        .rename_service(&"s-2".into(), "Advisory")?;
+    assert_eq!(
+        catalog.find_product(&"p-3".into()).unwrap().0.name,
+        "Advisory"
+    );
+    assert_eq!(
+        catalog.find_product(&"p-1".into()).unwrap().1.price_cents,
+        250
+    );
+
+    let catalog = catalog.remove_product(&"s-1".into(), &"p-2".into())?;
+    assert!(catalog.find_product(&"p-2".into()).is_none());
+    // a removed product id can be reused elsewhere
+    let catalog = catalog.add_product(&"s-2".into(), Product::new("p-2", "Analytics", 900))?;
+    assert_eq!(catalog.product_services(&"p-2".into()), ["s-2".into()]);
+
+    let catalog = catalog.remove_service(&"s-2".into())?;
+    assert!(catalog.find_product(&"p-3".into()).is_none());
+    assert!(catalog.find_product(&"p-2".into()).is_none());
+
+    // replacing a service re-indexes its products
+    let catalog = catalog.add_service(Service::new(
+        "s-1",
+        "SaaS",
+        vec![Product::new("p-4", "Backup", 300)],
+    ))?;
+    assert!(catalog.find_product(&"p-1".into()).is_none());
+    assert_eq!(catalog.find_product(&"p-4".into()).unwrap().0.id.0, "s-1");
+    Ok(())
+}
+
+#[test]
+fn test_duplicate_products_rejected_by_default() {
+    let catalog = Catalog::default().with_service(saas());
+    let err = catalog
+        .add_service(Service::new(
+            "s-2",
+            "Other",
+            vec![Product::new("p-1", "Email", 100)],
+        ))
+        .unwrap_err();
+    assert_eq!(
+        err,
+        CatalogError::DuplicateProduct {
+            product_id: "p-1".into(),
+            existing: "s-1".into()
+        }
+    );
+    // the original catalog is untouched by a rejected change
+    assert_eq!(catalog.services().len(), 1);
+
+    let same_service = catalog.add_product(&"s-1".into(), Product::new("p-2", "Again", 1));
+    assert!(matches!(
+        same_service,
+        Err(CatalogError::DuplicateProduct { .. })
+    ));
+}
+
+#[test]
+fn test_with_service_never_fails() {
+    // the builder skips the policy check, as before there was one
+    let catalog = Catalog::default()
+        .with_service(saas())
+        .with_service(Service::new(
+            "s-2",
+            "Other",
+            vec![Product::new("p-1", "Email", 100)],
+        ));
+    assert_eq!(catalog.product_services(&"p-1".into()).len(), 2);
+    // replacing a service re-indexes its products
+    let catalog = catalog.with_service(Service::new("s-2", "Other", vec![]));
+    assert_eq!(catalog.product_services(&"p-1".into()).len(), 1);
+}
+
+#[test]
+fn test_duplicate_products_allowed_by_policy() -> Result<(), CatalogError> {
+    let catalog = Catalog::from_services(
+        DuplicatePolicy::Allow,
+        [
+            Service::new("s-2", "Other", vec![Product::new("p-1", "Email", 80)]),
+            saas(),
+        ],
+    )?;
+    assert_eq!(
+        catalog.product_services(&"p-1".into()),
+        ["s-1".into(), "s-2".into()]
+    );
+    // the smallest service id wins an ambiguous lookup
+    assert_eq!(
+        catalog.find_product(&"p-1".into()).unwrap().1.price_cents,
+        100
+    );
+    assert_eq!(
+        catalog
+            .get_product(&"s-2".into(), &"p-1".into())
+            .unwrap()
+            .price_cents,
+        80
+    );
+    // still one product id per service
+    assert!(catalog
+        .add_product(&"s-1".into(), Product::new("p-1", "Dup", 1))
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_unknown_service_and_product_errors() {
+    let catalog = Catalog::default().with_service(saas());
+    assert_eq!(
+        catalog
+            .add_product(&"s-9".into(), Product::new("p-9", "X", 1))
+            .unwrap_err(),
+        CatalogError::UnknownService("s-9".into())
+    );
+    assert_eq!(
+        catalog
+            .remove_product(&"s-1".into(), &"p-9".into())
+            .unwrap_err(),
+        CatalogError::UnknownProduct {
+            service_id: "s-1".into(),
+            product_id: "p-9".into()
+        }
+    );
+}
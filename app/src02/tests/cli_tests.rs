@@ -0,0 +1,512 @@
+use std::error::Error;
+use std::process::{Command, Output};
+
+/// Run the `src02` binary against `db_url` and return its output.
+fn src02(db_url: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_src02"))
+        .arg("--db-url")
+        .arg(db_url)
+        .args(args)
+        .env_remove("DB_URL")
+        .output()
+        .expect("run src02")
+}
+
+fn ok(db_url: &str, args: &[&str]) -> String {
+    let out = src02(db_url, args);
+    assert!(
+        out.status.success(),
+        "src02 {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&out.stderr)
+    );
+    String::from_utf8(out.stdout).expect("utf-8 output")
+}
+
+fn json(db_url: &str, args: &[&str]) -> serde_json::Value {
+    let mut args = args.to_vec();
+    args.extend(["--format", "json"]);
+    serde_json::from_str(&ok(db_url, &args)).expect("JSON output")
+}
+
+#[test]
+fn test_cli_manages_domain_in_a_file_database() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let db = format!("sqlite:{}", dir.path().join("cli.db").display());
+
+    ok(
+        &db,
+        &["user", "add", "u-alice", "Alice", "--paypal", "a@paypal"],
+    );
+    ok(
+        &db,
+        &["user", "add", "u-bob", "Bob", "--bank-transfer", "INV-BOB"],
+    );
+    ok(&db, &["service", "add", "s-1", "SaaS"]);
+    ok(
+        &db,
+        &["product", "add", "s-1", "p-1", "Email Support", "500"],
+    );
+    ok(&db, &["product", "add", "s-1", "p-2", "Analytics", "1500"]);
+    // renaming a service keeps its products
+    ok(&db, &["service", "add", "s-1", "SaaS Platform"]);
+
+    let services = json(&db, &["service", "list"]);
+    assert_eq!(services[0]["name"], "SaaS Platform");
+    assert_eq!(services[0]["products"].as_array().unwrap().len(), 2);
+
+    for (user, product, at) in [
+        ("u-alice", "p-1", "2025-06-01"),
+        ("u-bob", "p-2", "2025-06-02"),
+        ("u-alice", "p-2", "2025-06-03T10:00:00Z"),
+    ] {
+        ok(&db, &["usage", "record", user, "s-1", product, "--at", at]);
+    }
+
+    let page = json(&db, &["usage", "list", "--limit", "2"]);
+    assert_eq!(page["items"].as_array().unwrap().len(), 2);
+    let cursor = page["next_cursor"]
+        .as_str()
+        .expect("more pages")
+        .to_string();
+    let rest = json(&db, &["usage", "list", "--limit", "2", "--cursor", &cursor]);
+    assert_eq!(rest["items"][0]["usage"]["user_id"], "u-alice");
+    assert!(rest["next_cursor"].is_null());
+
+    let filtered = json(
+        &db,
+        &["usage", "list", "--user", "u-alice", "--from", "2025-06-02"],
+    );
+    assert_eq!(filtered["items"].as_array().unwrap().len(), 1);
+
+    let report = json(&db, &["report"]);
+    assert_eq!(report["total"]["count"], 3);
+    assert_eq!(report["total"]["total_cents"], 3500);
+    assert_eq!(report["rows"][0]["id"], "u-alice");
+    assert_eq!(report["rows"][0]["total_cents"], 2000);
+
+    let shown = json(&db, &["user", "show", "u-bob"]);
+    assert_eq!(shown["usage"]["count"], 1);
+
+    // usages are validated before they are stored
+    let out = src02(&db, &["usage", "record", "u-alice", "s-1", "p-9"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("does not offer product p-9"));
+    ok(&db, &["validate"]);
+
+    let table = ok(&db, &["user", "list"]);
+    assert!(table.starts_with("ID"));
+    assert!(table.contains("paypal a@paypal"));
+    Ok(())
+}
+
+#[test]
+fn test_cli_reports_errors_without_panicking() -> Result<(), Box<dyn Error>> {
+    let out = src02("memory:", &["user", "show", "nobody"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("error: no user \"nobody\""));
+
+    let out = src02("memory:", &["product", "add", "s-missing", "p-1", "X", "1"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("unknown service s-missing"));
+
+    // at most one default payment method
+    let out = src02(
+        "memory:",
+        &[
+            "user",
+            "add",
+            "u-1",
+            "U",
+            "--paypal",
+            "a",
+            "--bank-transfer",
+            "r",
+        ],
+    );
+    assert!(!out.status.success());
+    Ok(())
+}
+
+#[test]
+fn test_cli_syncs_the_example_catalog() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let db = format!("sqlite:{}", dir.path().join("catalog.db").display());
+    let file = concat!(env!("CARGO_MANIFEST_DIR"), "/catalog.example.toml");
+
+    let preview = json(&db, &["catalog", "sync", "--dry-run", file]);
+    assert_eq!(preview["diff"]["writes"].as_array().unwrap().len(), 2);
+    assert!(json(&db, &["service", "list"])
+        .as_array()
+        .unwrap()
+        .is_empty());
+
+    let table = ok(&db, &["catalog", "sync", file]);
+    assert!(table.contains("+ product s-1/p-2 \"Premium Analytics\" 1500¢"));
+    assert!(table.contains("saved 2 services"));
+    assert!(ok(&db, &["catalog", "sync", file]).contains("saved 0 services"));
+
+    let bad = dir.path().join("bad.json");
+    std::fs::write(&bad, r#"{"services": [{"id": "s-1"}]}"#)?;
+    let out = src02(&db, &["catalog", "check", bad.to_str().unwrap()]);
+    assert!(!out.status.success());
+    assert!(
+        String::from_utf8(out.stderr)?.contains("bad.json:1:27: services[0]: missing field `name`")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_cli_bills_usage_to_an_account() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let db = format!("sqlite:{}", dir.path().join("accounts.db").display());
+    ok(
+        &db,
+        &["user", "add", "u-alice", "Alice", "--paypal", "a@paypal"],
+    );
+    ok(&db, &["user", "add", "u-bob", "Bob"]);
+    ok(&db, &["service", "add", "s-1", "SaaS"]);
+    ok(&db, &["product", "add", "s-1", "p-1", "Email", "500"]);
+    ok(
+        &db,
+        &["account", "add", "acc-acme", "Acme", "--owner", "u-alice"],
+    );
+
+    // Bob is not a member yet and may not add himself
+    let out = src02(
+        &db,
+        &["account", "member", "acc-acme", "u-bob", "--as", "u-bob"],
+    );
+    assert!(!out.status.success());
+    ok(
+        &db,
+        &[
+            "account",
+            "member",
+            "acc-acme",
+            "u-bob",
+            "--role",
+            "billing_admin",
+            "--as",
+            "u-alice",
+        ],
+    );
+    let out = src02(
+        &db,
+        &[
+            "usage",
+            "record",
+            "u-bob",
+            "s-1",
+            "p-1",
+            "--account",
+            "acc-acme",
+        ],
+    );
+    assert!(String::from_utf8(out.stderr)?.contains("account acc-acme has no payment method"));
+    ok(
+        &db,
+        &[
+            "account",
+            "payment",
+            "acc-acme",
+            "--as",
+            "u-bob",
+            "--bank-transfer",
+            "INV-ACME",
+        ],
+    );
+    ok(
+        &db,
+        &[
+            "usage",
+            "record",
+            "u-bob",
+            "s-1",
+            "p-1",
+            "--account",
+            "acc-acme",
+        ],
+    );
+
+    let show = json(&db, &["account", "show", "acc-acme"]);
+    assert_eq!(show["account"]["members"][1]["role"], "billing_admin");
+    assert_eq!(show["usage"]["total_cents"], 500);
+    let report = json(&db, &["report", "--by", "account"]);
+    assert_eq!(report["rows"][0]["id"], "acc-acme");
+    assert_eq!(report["rows"][0]["count"], 1);
+    Ok(())
+}
+
+#[test]
+fn test_cli_enforces_a_hard_budget() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let db = format!("sqlite:{}", dir.path().join("budgets.db").display());
+    ok(
+        &db,
+        &["user", "add", "u-alice", "Alice", "--paypal", "a@paypal"],
+    );
+    ok(&db, &["service", "add", "s-1", "SaaS"]);
+    ok(&db, &["product", "add", "s-1", "p-1", "Email", "500"]);
+    ok(
+        &db,
+        &[
+            "budget",
+            "set",
+            "b-1",
+            "u-alice",
+            "1000",
+            "--service",
+            "s-1",
+            "--hard-limit",
+        ],
+    );
+
+    let out = src02(&db, &["usage", "record", "u-alice", "s-1", "p-1"]);
+    assert!(String::from_utf8(out.stderr)?
+        .contains("alert: budget b-1 of u-alice reached 50%: 500¢ of 1000¢"));
+    ok(&db, &["usage", "record", "u-alice", "s-1", "p-1"]);
+    let out = src02(&db, &["usage", "record", "u-alice", "s-1", "p-1"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("budget b-1 exceeded"));
+
+    let list = json(&db, &["budget", "list"]);
+    assert_eq!(list[0]["spent_cents"], 1000);
+    assert_eq!(list[0]["budget"]["enforcement"], "hard_limit");
+    Ok(())
+}
+
+#[test]
+fn test_cli_exports_revenue() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let db = format!("sqlite:{}", dir.path().join("revenue.db").display());
+    ok(
+        &db,
+        &["user", "add", "u-alice", "Alice", "--paypal", "a@paypal"],
+    );
+    ok(&db, &["service", "add", "s-1", "SaaS"]);
+    ok(&db, &["product", "add", "s-1", "p-1", "Email", "500"]);
+    for at in ["2025-02-10", "2025-03-10", "2025-03-11"] {
+        ok(
+            &db,
+            &["usage", "record", "u-alice", "s-1", "p-1", "--at", at],
+        );
+    }
+
+    let csv = ok(
+        &db,
+        &[
+            "revenue", "--month", "2025-03", "--by", "product", "--format", "csv",
+        ],
+    );
+    assert_eq!(csv.lines().nth(1), Some("p-1,Email,2,1000,1,500,500,100.0"));
+    let table = ok(&db, &["revenue", "--month", "2025-03"]);
+    assert!(table.contains("+5.00"), "{}", table);
+    let report = json(
+        &db,
+        &["revenue", "--from", "2025-03-01", "--until", "2025-03-11"],
+    );
+    assert_eq!(report["total"]["usages"], 1);
+    assert_eq!(report["top_consumers"][0]["id"], "u-alice");
+    // every command can print CSV
+    assert!(ok(&db, &["user", "list", "--format", "csv"]).starts_with("ID,NAME,PAYMENT\n"));
+    Ok(())
+}
+
+#[test]
+fn test_cli_credits_and_invoices() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let db = format!("sqlite:{}", dir.path().join("credit.db").display());
+    ok(
+        &db,
+        &["user", "add", "u-alice", "Alice", "--paypal", "a@paypal"],
+    );
+    ok(&db, &["service", "add", "s-1", "SaaS"]);
+    ok(&db, &["product", "add", "s-1", "p-1", "Email", "500"]);
+    for at in ["2025-03-10", "2025-04-10"] {
+        ok(
+            &db,
+            &["usage", "record", "u-alice", "s-1", "p-1", "--at", at],
+        );
+    }
+
+    let refund = json(
+        &db,
+        &[
+            "credit", "issue", "1", "--amount", "200", "--reason", "outage", "--refund",
+        ],
+    );
+    assert_eq!(refund["refund_to"]["Paypal"]["account"], "a@paypal");
+    let credit = json(&db, &["credit", "issue", "1", "--reason", "goodwill"]);
+    assert_eq!(credit["amount_cents"], 300);
+    let out = src02(&db, &["credit", "issue", "1", "--reason", "again"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("exceeds what is left of usage 1"));
+    assert_eq!(
+        json(&db, &["credit", "list"]).as_array().map(Vec::len),
+        Some(2)
+    );
+
+    let balance = json(&db, &["credit", "balance", "--user", "u-alice"]);
+    assert_eq!(balance["balance_cents"], 300);
+    let invoice = json(&db, &["invoice", "--user", "u-alice", "--month", "2025-04"]);
+    assert_eq!(invoice["credit_applied_cents"], 300);
+    assert_eq!(invoice["total_cents"], 200);
+    let table = ok(&db, &["invoice", "--user", "u-alice", "--month", "2025-04"]);
+    assert!(table.contains("total"), "{}", table);
+    let balance = json(&db, &["credit", "balance", "--user", "u-alice"]);
+    assert_eq!(balance["balance_cents"], 0);
+    Ok(())
+}
+
+#[test]
+fn test_cli_dunning_suspends_and_reinstates() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let db = format!("sqlite:{}", dir.path().join("dunning.db").display());
+    ok(
+        &db,
+        &["user", "add", "u-alice", "Alice", "--paypal", "a@paypal"],
+    );
+    ok(&db, &["service", "add", "s-1", "SaaS"]);
+    ok(&db, &["product", "add", "s-1", "p-1", "Email", "500"]);
+
+    // one retry, due at once
+    let opened = json(
+        &db,
+        &[
+            "dunning",
+            "open",
+            "u-alice",
+            "s-1",
+            "500",
+            "--reason",
+            "card declined",
+            "--retry-hours",
+            "0",
+        ],
+    );
+    assert_eq!(opened["case"]["payment"], serde_json::Value::Null);
+    let id = opened["case"]["id"].as_str().expect("case id").to_string();
+    let due = json(&db, &["dunning", "list", "--due"]);
+    assert_eq!(due.as_array().map(Vec::len), Some(1));
+
+    let failed = json(&db, &["dunning", "retry", &id, "--failed", "declined"]);
+    assert_eq!(failed["case"]["state"], "suspended");
+    let out = src02(&db, &["usage", "record", "u-alice", "s-1", "p-1"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("is suspended from service s-1"));
+
+    let out = src02(&db, &["dunning", "retry", &id]);
+    assert!(!out.status.success(), "--paid or --failed is required");
+    let paid = json(&db, &["dunning", "retry", &id, "--paid"]);
+    assert_eq!(paid["events"][0]["stage"], "reinstated");
+    ok(&db, &["usage", "record", "u-alice", "s-1", "p-1"]);
+
+    let events = json(&db, &["dunning", "events", "--case", &id]);
+    let stages: Vec<&str> = events
+        .as_array()
+        .expect("events")
+        .iter()
+        .filter_map(|e| e["stage"].as_str())
+        .collect();
+    assert_eq!(
+        stages,
+        ["charge_failed", "retry_failed", "suspended", "reinstated"]
+    );
+    let table = ok(&db, &["dunning", "list", "--user", "u-alice"]);
+    assert!(table.contains("settled"), "{}", table);
+    Ok(())
+}
+
+#[test]
+fn test_cli_edits_profiles() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let db = format!("sqlite:{}", dir.path().join("profile.db").display());
+    ok(
+        &db,
+        &[
+            "user",
+            "add",
+            "u-alice",
+            "Alice",
+            "--paypal",
+            "alice@paypal.example",
+            "--email",
+            "Alice@Example.com",
+        ],
+    );
+    ok(
+        &db,
+        &[
+            "user",
+            "add-payment",
+            "u-alice",
+            "--bank-transfer",
+            "INV-1",
+            "--default",
+        ],
+    );
+    ok(&db, &["user", "default-payment", "u-alice", "1"]);
+    ok(
+        &db,
+        &[
+            "user",
+            "profile",
+            "u-alice",
+            "--locale",
+            "it_it",
+            "--timezone",
+            "Europe/Rome",
+        ],
+    );
+    ok(
+        &db,
+        &[
+            "user",
+            "add-address",
+            "u-alice",
+            "Via Roma 1",
+            "Milano",
+            "20121",
+            "IT",
+            "--label",
+            "home",
+        ],
+    );
+
+    let shown = json(&db, &["user", "show", "u-alice"]);
+    let profile = &shown["user"]["profile"];
+    assert_eq!(profile["email"], "alice@example.com");
+    assert_eq!(profile["locale"], "it-IT");
+    assert_eq!(profile["billing_addresses"][0]["label"], "home");
+    assert_eq!(
+        profile["payment_methods"][0]["Paypal"]["account"],
+        "alice@paypal.example"
+    );
+    let table = ok(&db, &["user", "show", "u-alice"]);
+    assert!(table.contains("Europe/Rome"), "{}", table);
+    assert!(table.contains("(default)"), "{}", table);
+
+    // emails are unique; a malformed one is refused before touching storage
+    ok(&db, &["user", "add", "u-bob", "Bob"]);
+    let taken = src02(
+        &db,
+        &["user", "profile", "u-bob", "--email", "alice@example.com"],
+    );
+    assert!(!taken.status.success());
+    assert!(String::from_utf8_lossy(&taken.stderr).contains("already used"));
+    assert!(!src02(&db, &["user", "profile", "u-bob", "--email", "bob"])
+        .status
+        .success());
+    assert!(!src02(&db, &["user", "remove-payment", "u-bob", "0"])
+        .status
+        .success());
+
+    ok(&db, &["user", "remove-address", "u-alice", "0"]);
+    ok(&db, &["user", "remove-payment", "u-alice", "0"]);
+    let shown = json(&db, &["user", "show", "u-alice"]);
+    let methods = &shown["user"]["profile"]["payment_methods"];
+    assert_eq!(methods.as_array().map(Vec::len), Some(1));
+    assert_eq!(methods[0]["BankTransfer"]["reference"], "INV-1");
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! Fixtures shared by the integration tests; each test crate uses a part.
+#![allow(dead_code)]
+
+use axum::body::{to_bytes, Body};
+use axum::http::{Request, StatusCode};
+use axum::Router;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_json::Value;
+use src02::models::{PaymentMethod, Product, Service, ServiceUsage, User, UserId};
+use src02::privacy::{self, UserExport};
+use src02::repository::{MemoryRepository, Repository, SledRepository, SqliteRepository};
+use src02::vault::{CardExpiry, CardVault, StoredCard};
+use std::error::Error;
+use std::future::Future;
+use std::sync::Arc;
+use tower::ServiceExt;
+
+/// A vaulted Visa test card held by `holder`.
+pub fn test_card(holder: &str) -> StoredCard {
+    let expiry = CardExpiry::new(12, 2099).expect("valid test expiry");
+    CardVault::new()
+        .tokenize("4242424242424242", expiry, holder)
+        .expect("valid test card")
+}
+
+pub fn uid(s: &str) -> UserId {
+    s.into()
+}
+
+/// Midnight UTC on the given day.
+pub fn day(y: i32, m: u32, d: u32) -> DateTime<Utc> {
+    NaiveDate::from_ymd_opt(y, m, d)
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .expect("valid date")
+        .and_utc()
+}
+
+/// Alice pays by PayPal.
+pub fn alice() -> User {
+    User::new("u-alice", "Alice", Some(PaymentMethod::paypal("a@paypal")))
+}
+
+/// Alice and Bob pay by PayPal, Carol has no payment method.
+pub fn users() -> Vec<User> {
+    vec![
+        alice(),
+        User::new("u-bob", "Bob", Some(PaymentMethod::paypal("b@paypal"))),
+        User::new("u-carol", "Carol", None),
+    ]
+}
+
+/// s-1 offers p-1 for 5.00.
+pub fn saas() -> Service {
+    Service::new("s-1", "SaaS", vec![Product::new("p-1", "Email", 500)])
+}
+
+/// s-2 offers p-3 for 100.00.
+pub fn consulting() -> Service {
+    Service::new(
+        "s-2",
+        "Consulting",
+        vec![Product::new("p-3", "On-site", 10_000)],
+    )
+}
+
+/// `user` used `product` of `service`, with no payment recorded.
+pub fn usage(user: &str, service: &str, product: &str) -> ServiceUsage {
+    ServiceUsage::new(&user.into(), &service.into(), &product.into(), None)
+}
+
+/// The storage backends, each opened empty by `open`.
+#[derive(Clone, Copy, Debug)]
+pub enum Backend {
+    Sqlite,
+    Sled,
+    Memory,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 3] = [Backend::Sqlite, Backend::Sled, Backend::Memory];
+
+    pub async fn open(self) -> Result<Arc<dyn Repository>, Box<dyn Error>> {
+        Ok(match self {
+            Backend::Sqlite => Arc::new(SqliteRepository::connect("sqlite::memory:").await?),
+            Backend::Sled => Arc::new(SledRepository::temporary()?),
+            Backend::Memory => Arc::new(MemoryRepository::new()),
+        })
+    }
+}
+
+/// Runs `check` against a fresh store of each of `backends`; a failure
+/// names the backend it happened on.
+pub async fn on_backends<F, Fut>(backends: &[Backend], check: F) -> Result<(), Box<dyn Error>>
+where
+    F: Fn(Arc<dyn Repository>) -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn Error>>>,
+{
+    for &backend in backends {
+        check(backend.open().await?)
+            .await
+            .map_err(|e| format!("{:?}: {}", backend, e))?;
+    }
+    Ok(())
+}
+
+/// `on_backends` over `Backend::ALL`.
+pub async fn on_every_backend<F, Fut>(check: F) -> Result<(), Box<dyn Error>>
+where
+    F: Fn(Arc<dyn Repository>) -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn Error>>>,
+{
+    on_backends(&Backend::ALL, check).await
+}
+
+/// Exports `user`, then erases them under a fresh pseudonym; returns both.
+pub async fn export_and_erase(
+    repo: &dyn Repository,
+    user: &str,
+) -> Result<(UserExport, UserId), Box<dyn Error>> {
+    let export = repo
+        .export_user(&uid(user))
+        .await?
+        .ok_or("expected an export")?;
+    let pseudonym = privacy::pseudonym();
+    repo.erase_user(&uid(user), &pseudonym)
+        .await?
+        .ok_or("expected a report")?;
+    Ok((export, pseudonym))
+}
+
+/// Sends `method uri` to `app`, with `body` as JSON if given, and returns the
+/// status and the JSON answer.
+pub async fn call(
+    app: &Router,
+    method: &str,
+    uri: &str,
+    body: Option<Value>,
+) -> Result<(StatusCode, Value), Box<dyn Error>> {
+    let request = Request::builder().method(method).uri(uri);
+    let request = match body {
+        Some(b) => request
+            .header("content-type", "application/json")
+            .body(Body::from(b.to_string()))?,
+        None => request.body(Body::empty())?,
+    };
+    let response = app.clone().oneshot(request).await?;
+    let status = response.status();
+    let bytes = to_bytes(response.into_body(), usize::MAX).await?;
+    Ok((status, serde_json::from_slice(&bytes)?))
+}
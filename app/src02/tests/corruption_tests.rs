@@ -0,0 +1,88 @@
+use sqlx::SqlitePool;
+use src02::models::{PaymentMethod, Product, Service, ServiceUsage, User};
+use src02::persistence::{self, PersistenceError, ReadMode};
+
+async fn seeded() -> Result<SqlitePool, Box<dyn std::error::Error>> {
+    let pool = SqlitePool::connect("sqlite::memory:").await?;
+    persistence::init_db(&pool).await?;
+    let alice = User::new("u-alice", "Alice", Some(PaymentMethod::paypal("a@paypal")));
+    let bob = User::new("u-bob", "Bob", Some(PaymentMethod::paypal("b@paypal")));
+    persistence::save_user(&pool, &alice).await?;
+    persistence::save_user(&pool, &bob).await?;
+    let svc = Service::new(
+        "s-1",
+        "SaaS",
+        vec![
+            Product::new("p-1", "Email", 500),
+            Product::new("p-2", "Analytics", 1500),
+        ],
+    );
+    persistence::save_service(&pool, &svc).await?;
+    for _ in 0..3 {
+        let u = ServiceUsage::new(&alice.id, &"s-1".into(), &"p-1".into(), None);
+        persistence::save_usage(&pool, &u).await?;
+    }
+    Ok(pool)
+}
+
+#[tokio::test]
+async fn test_corrupt_payment_json_is_an_error_in_strict_mode(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = seeded().await?;
+    sqlx::query("UPDATE users SET default_payment = '{not json' WHERE id = 'u-bob'")
+        .execute(&pool)
+        .await?;
+
+    match persistence::get_users(&pool).await {
+        Err(PersistenceError::CorruptRow(c)) => {
+            assert_eq!(c.table, "users");
+            assert_eq!(c.row_id, "u-bob");
+            assert_eq!(c.column, "default_payment");
+        }
+        other => panic!("expected CorruptRow, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lenient_mode_reports_every_skipped_row() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = seeded().await?;
+    sqlx::query("UPDATE usages SET payment_used = '\"Bitcoin\"' WHERE id IN (1, 3)")
+        .execute(&pool)
+        .await?;
+
+    let outcome =
+        persistence::get_usages_for_user_with(&pool, "u-alice", ReadMode::Lenient).await?;
+    assert_eq!(outcome.items.len(), 1);
+    let ids: Vec<_> = outcome.skipped.iter().map(|c| c.row_id.as_str()).collect();
+    assert_eq!(ids, ["1", "3"]);
+    assert!(outcome.skipped.iter().all(|c| c.column == "payment_used"));
+
+    assert!(persistence::get_usages_for_user(&pool, "u-alice")
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_corrupt_product_keeps_service_in_lenient_mode(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = seeded().await?;
+    sqlx::query("UPDATE products SET price_cents = -5 WHERE id = 'p-2'")
+        .execute(&pool)
+        .await?;
+
+    let outcome = persistence::get_services_with(&pool, ReadMode::Lenient).await?;
+    assert_eq!(outcome.items.len(), 1);
+    assert_eq!(outcome.items[0].products.len(), 1);
+    assert_eq!(outcome.skipped.len(), 1);
+    assert_eq!(outcome.skipped[0].table, "products");
+    assert_eq!(outcome.skipped[0].row_id, "p-2");
+    assert_eq!(outcome.skipped[0].column, "price_cents");
+
+    assert!(matches!(
+        persistence::get_services(&pool).await,
+        Err(PersistenceError::CorruptRow(_))
+    ));
+    Ok(())
+}
@@ -0,0 +1,318 @@
+mod common;
+
+use axum::http::StatusCode;
+use chrono::Utc;
+use common::{alice, call, day, export_and_erase, on_every_backend, uid, usage};
+use serde_json::json;
+use src02::account::Account;
+use src02::api;
+use src02::credit::{self, CreditError, CreditNote, CreditNoteId, Issued, Payer, Settlement};
+use src02::idempotency::DEFAULT_RETENTION;
+use src02::models::{PaymentMethod, Product, Service, ServiceUsage, User};
+use src02::reporting::ReportPeriod;
+use src02::repository::{MemoryRepository, Repository, SqliteRepository};
+use std::error::Error;
+use std::sync::Arc;
+
+fn november() -> ReportPeriod {
+    ReportPeriod::month(2025, 11).expect("valid month")
+}
+
+/// Alice pays by PayPal and owns Acme, which pays by bank transfer. The
+/// first usage is Alice's own, the second is billed to Acme, the third has
+/// no payment recorded; each costs 1000¢. Returns their ids.
+async fn seed(repo: &dyn Repository) -> Result<[i64; 3], Box<dyn Error>> {
+    repo.init().await?;
+    repo.save_user(&alice()).await?;
+    let alice = uid("u-alice");
+    repo.save_service(&Service::new(
+        "s-1",
+        "Hosting",
+        vec![Product::new("p-1", "VM", 1000)],
+    ))
+    .await?;
+    let acme = Account::new("acc-acme", "Acme", &alice).add_payment_method(
+        &alice,
+        PaymentMethod::bank_transfer("ACME-INV", 30)?,
+        false,
+    )?;
+    repo.save_account(&acme).await?;
+    let used = |pm: Option<PaymentMethod>, d: u32| {
+        ServiceUsage::new(&alice, &"s-1".into(), &"p-1".into(), pm).at(day(2025, 11, d))
+    };
+    let mut ids = [0; 3];
+    for (id, usage) in ids.iter_mut().zip([
+        used(Some(PaymentMethod::paypal("old@paypal")), 3),
+        used(None, 4).for_account(&"acc-acme".into()),
+        used(None, 5),
+    ]) {
+        *id = repo
+            .record_usage(&usage, DEFAULT_RETENTION)
+            .await?
+            .record()
+            .id;
+    }
+    Ok(ids)
+}
+
+async fn exercise(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    let [own, billed, unpaid] = seed(repo).await?;
+    let alice = Payer::User(uid("u-alice"));
+    let acme = Payer::Account("acc-acme".into());
+
+    // a partial refund goes back to the method the usage was paid with
+    let refund = credit::issue(repo, own, Some(300), "outage", Settlement::Refund).await?;
+    assert_eq!(refund.payer, alice);
+    assert_eq!(refund.usage_amount_cents, 1000);
+    assert_eq!(
+        refund.refund_to.as_ref().map(|pm| pm.masked()),
+        Some("paypal o***@paypal".to_string())
+    );
+    // the rest, as credit; then nothing is left to reverse
+    let rest = credit::issue(repo, own, None, "goodwill", Settlement::Credit).await?;
+    assert_eq!(rest.amount_cents, 700);
+    assert!(rest.refund_to.is_none());
+    assert!(matches!(
+        credit::issue(repo, own, Some(1), "again", Settlement::Credit).await,
+        Err(CreditError::ExceedsUsage {
+            remaining_cents: 0,
+            ..
+        })
+    ));
+
+    // billed to Acme: its credit, refunds resolve to its method
+    assert!(matches!(
+        credit::issue(repo, billed, Some(2000), "typo", Settlement::Credit).await,
+        Err(CreditError::ExceedsUsage {
+            usage_id,
+            amount_cents: 2000,
+            remaining_cents: 1000,
+        }) if usage_id == billed
+    ));
+    let acme_refund = credit::issue(repo, billed, Some(100), "typo", Settlement::Refund).await?;
+    assert_eq!(acme_refund.payer, acme);
+    assert_eq!(
+        acme_refund.refund_to.as_ref().map(|pm| pm.masked()),
+        Some("bank transfer ACME-INV (30 days)".to_string())
+    );
+    credit::issue(repo, billed, Some(250), "typo", Settlement::Credit).await?;
+    assert!(matches!(
+        credit::issue(repo, 999, None, "?", Settlement::Credit).await,
+        Err(CreditError::UnknownUsage { usage_id: 999 })
+    ));
+    assert!(matches!(
+        credit::issue(repo, own, Some(0), "?", Settlement::Credit).await,
+        Err(CreditError::ZeroAmount)
+    ));
+
+    let notes = repo.get_credit_notes().await?;
+    assert_eq!(
+        notes.iter().map(|n| n.amount_cents).collect::<Vec<_>>(),
+        [300, 700, 100, 250]
+    );
+    assert_eq!(credit::credit_balance(repo, &alice).await?, 700);
+    assert_eq!(credit::credit_balance(repo, &acme).await?, 250);
+
+    // Alice's invoice leaves out the usage billed to Acme and takes her credit
+    let invoice = credit::invoice(repo, &alice, november()).await?;
+    assert_eq!(
+        invoice.lines.iter().map(|l| l.usage_id).collect::<Vec<_>>(),
+        [own, unpaid]
+    );
+    assert_eq!(invoice.subtotal_cents, 2000);
+    assert_eq!(invoice.credit_applied_cents, 700);
+    assert_eq!(invoice.total_cents, 1300);
+    assert_eq!(credit::credit_balance(repo, &alice).await?, 0);
+    // invoicing the period again does not charge the balance twice
+    credit::issue(repo, unpaid, Some(50), "late", Settlement::Credit).await?;
+    let again = credit::invoice(repo, &alice, november()).await?;
+    assert_eq!(again.credit_applied_cents, 700);
+    assert_eq!(credit::credit_balance(repo, &alice).await?, 50);
+
+    let invoice = credit::invoice(repo, &acme, november()).await?;
+    assert_eq!(invoice.lines.len(), 1);
+    assert_eq!(invoice.total_cents, 750);
+    let applications = repo.get_credit_applications().await?;
+    assert_eq!(applications.len(), 2);
+    assert_eq!(applications[1].payer, acme);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_credit_notes() -> Result<(), Box<dyn Error>> {
+    on_every_backend(|repo| async move { exercise(repo.as_ref()).await }).await
+}
+
+#[tokio::test]
+async fn test_refund_needs_a_method() -> Result<(), Box<dyn Error>> {
+    let repo = MemoryRepository::new();
+    repo.init().await?;
+    repo.save_user(&User::new("u-carol", "Carol", None)).await?;
+    repo.save_service(&Service::new(
+        "s-1",
+        "Hosting",
+        vec![Product::new("p-1", "VM", 1000)],
+    ))
+    .await?;
+    repo.save_usage(&usage("u-carol", "s-1", "p-1")).await?;
+    assert!(matches!(
+        credit::issue(&repo, 1, None, "outage", Settlement::Refund).await,
+        Err(CreditError::NoRefundMethod { usage_id: 1 })
+    ));
+    // credit needs none
+    credit::issue(&repo, 1, None, "outage", Settlement::Credit).await?;
+    Ok(())
+}
+
+async fn check_erasure(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    let [own, billed, _] = seed(repo).await?;
+    credit::issue(repo, own, Some(300), "outage", Settlement::Refund).await?;
+    credit::issue(repo, own, Some(400), "outage", Settlement::Credit).await?;
+    credit::issue(repo, billed, Some(100), "typo", Settlement::Refund).await?;
+    credit::invoice(
+        repo,
+        &Payer::User(uid("u-alice")),
+        ReportPeriod::month(2025, 10).expect("valid month"),
+    )
+    .await?;
+
+    let (export, pseudonym) = export_and_erase(repo, "u-alice").await?;
+    assert_eq!(export.credit_notes.len(), 3);
+    assert_eq!(
+        export.credit_notes[0].refund_to.as_deref(),
+        Some("paypal o***@paypal")
+    );
+    let notes = repo.get_credit_notes().await?;
+    assert!(notes.iter().all(|n| n.refund_to.is_none()));
+    assert_eq!(notes[0].payer, Payer::User(pseudonym.clone()));
+    assert_eq!(notes[2].payer, Payer::Account("acc-acme".into()));
+    let applications = repo.get_credit_applications().await?;
+    assert_eq!(applications[0].payer, Payer::User(pseudonym.clone()));
+    // the credit stays with the pseudonym, as does the cap per usage
+    assert_eq!(
+        credit::credit_balance(repo, &Payer::User(pseudonym)).await?,
+        400
+    );
+    assert!(matches!(
+        credit::issue(repo, own, Some(301), "?", Settlement::Credit).await,
+        Err(CreditError::ExceedsUsage { .. })
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_erasure_moves_credit() -> Result<(), Box<dyn Error>> {
+    on_every_backend(|repo| async move { check_erasure(repo.as_ref()).await }).await
+}
+
+#[tokio::test]
+async fn test_stores_price_notes_from_the_stored_usage() -> Result<(), Box<dyn Error>> {
+    on_every_backend(|repo| async move {
+        let [own, _, _] = seed(repo.as_ref()).await?;
+        let note = |usage_id, amount_cents| CreditNote {
+            id: CreditNoteId::generate(),
+            usage_id,
+            payer: Payer::User(uid("u-alice")),
+            // a caller's price is never trusted
+            usage_amount_cents: 1_000_000,
+            amount_cents,
+            reason: "forged".into(),
+            settlement: Settlement::Credit,
+            refund_to: None,
+            issued_at: Utc::now(),
+        };
+        assert!(matches!(
+            repo.issue_credit_note(&note(own, 1001)).await?,
+            Issued::Exceeds {
+                remaining_cents: 1000
+            }
+        ));
+        match repo.issue_credit_note(&note(own, 400)).await? {
+            Issued::Issued(n) => assert_eq!(n.usage_amount_cents, 1000),
+            other => panic!("expected the note to be issued, got {:?}", other),
+        }
+        assert_eq!(repo.get_credit_notes().await?[0].usage_amount_cents, 1000);
+        assert!(matches!(
+            repo.issue_credit_note(&note(own + 1000, 1)).await?,
+            Issued::UnknownUsage
+        ));
+        // the product left the catalog after the usage
+        repo.save_service(&Service::new("s-1", "Hosting", vec![]))
+            .await?;
+        assert!(matches!(
+            repo.issue_credit_note(&note(own, 1)).await?,
+            Issued::UnknownPrice
+        ));
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_concurrent_notes_never_exceed_the_usage() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let url = format!("sqlite:{}", dir.path().join("credit.db").display());
+    let repo: Arc<dyn Repository> = Arc::new(SqliteRepository::connect(&url).await?);
+    let [own, _, _] = seed(repo.as_ref()).await?;
+    let tasks: Vec<_> = (0..8)
+        .map(|_| {
+            let repo = repo.clone();
+            tokio::spawn(async move {
+                credit::issue(repo.as_ref(), own, Some(300), "race", Settlement::Credit).await
+            })
+        })
+        .collect();
+    let mut issued = 0;
+    for task in tasks {
+        match task.await? {
+            Ok(_) => issued += 1,
+            Err(CreditError::ExceedsUsage { .. }) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    assert_eq!(issued, 3);
+    let total: u64 = repo
+        .get_credit_notes()
+        .await?
+        .iter()
+        .map(|n| n.amount_cents)
+        .sum();
+    assert_eq!(total, 900);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_api_issues_notes_and_invoices() -> Result<(), Box<dyn Error>> {
+    let repo: Arc<dyn Repository> = Arc::new(MemoryRepository::new());
+    seed(repo.as_ref()).await?;
+    let app = api::router(repo);
+    let post = |uri: &'static str, body| call(&app, "POST", uri, Some(body));
+
+    let (status, note) = post(
+        "/credit-notes",
+        json!({ "usage_id": 1, "amount_cents": 400, "reason": "outage" }),
+    )
+    .await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(note["settlement"], "credit");
+    assert_eq!(note["payer"], json!({ "kind": "user", "id": "u-alice" }));
+
+    let over = json!({ "usage_id": 1, "amount_cents": 700, "reason": "x", "settlement": "refund" });
+    let (status, body) = post("/credit-notes", over).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(body["error"].as_str().unwrap_or_default().contains("600¢"));
+    let (status, _) = post("/credit-notes", json!({ "usage_id": 42, "reason": "x" })).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let request = json!({
+        "payer": { "kind": "user", "id": "u-alice" },
+        "from": "2025-11-01T00:00:00Z",
+        "until": "2025-12-01T00:00:00Z",
+    });
+    let (status, invoice) = post("/invoices", request).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(invoice["subtotal_cents"], 2000);
+    assert_eq!(invoice["credit_applied_cents"], 400);
+    assert_eq!(invoice["total_cents"], 1600);
+    Ok(())
+}
@@ -0,0 +1,368 @@
+mod common;
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use common::{alice, call, day, export_and_erase, on_every_backend, uid};
+use serde_json::json;
+use src02::api;
+use src02::clock::{Clock, ManualClock};
+use src02::dunning::{
+    self, Attempt, DunningCase, DunningError, DunningSchedule, DunningState, FailedCharge, Gateway,
+    Stage,
+};
+use src02::idempotency::DEFAULT_RETENTION;
+use src02::models::{PaymentMethod, Product, Service, ServiceUsage};
+use src02::repository::{MemoryRepository, Repository};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+fn start() -> DateTime<Utc> {
+    day(2025, 11, 3) + Duration::hours(9)
+}
+
+/// Answers each charge with the next scripted outcome, and counts them.
+struct Scripted {
+    outcomes: Mutex<VecDeque<Attempt>>,
+    charged: Mutex<Vec<String>>,
+}
+
+impl Scripted {
+    fn new(outcomes: Vec<Attempt>) -> Self {
+        Scripted {
+            outcomes: Mutex::new(outcomes.into()),
+            charged: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl Gateway for Scripted {
+    async fn charge(&self, case: &DunningCase) -> Attempt {
+        self.charged.lock().unwrap().push(case.id.0.clone());
+        self.outcomes
+            .lock()
+            .unwrap()
+            .pop_front()
+            .expect("a scripted outcome")
+    }
+}
+
+fn declined() -> Attempt {
+    Attempt::Failed {
+        reason: "card declined".to_string(),
+    }
+}
+
+fn charge(service_id: &str) -> FailedCharge {
+    FailedCharge {
+        user_id: uid("u-alice"),
+        service_id: service_id.into(),
+        amount_cents: 1000,
+        payment: Some(PaymentMethod::paypal("alice@paypal")),
+        reason: "insufficient funds".to_string(),
+    }
+}
+
+async fn seed(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    repo.init().await?;
+    repo.save_user(&alice()).await?;
+    for id in ["s-1", "s-2"] {
+        repo.save_service(&Service::new(
+            id,
+            "Hosting",
+            vec![Product::new(&format!("p-{}", id), "VM", 1000)],
+        ))
+        .await?;
+    }
+    Ok(())
+}
+
+fn usage(service_id: &str) -> ServiceUsage {
+    common::usage("u-alice", service_id, &format!("p-{}", service_id))
+}
+
+fn stages(events: &[src02::dunning::DunningEvent]) -> Vec<Stage> {
+    events.iter().map(|e| e.stage).collect()
+}
+
+async fn exercise(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    seed(repo).await?;
+    let clock = ManualClock::new(start());
+    let schedule = DunningSchedule::new(&[24, 72]);
+
+    // s-1 fails every retry and ends suspended
+    let opened = dunning::open(repo, &clock, &charge("s-1"), schedule.clone()).await?;
+    let case_id = opened.case.id.clone();
+    assert_eq!(stages(&opened.events), [Stage::ChargeFailed]);
+    assert_eq!(
+        opened.events[0].next_attempt_at,
+        Some(start() + Duration::hours(24))
+    );
+    // s-2 is paid at its first retry; opened a minute later, so retried second
+    clock.advance(Duration::minutes(1));
+    let recovered = dunning::open(repo, &clock, &charge("s-2"), schedule).await?;
+
+    let gateway = Scripted::new(vec![declined(), Attempt::Paid]);
+    clock.advance(Duration::hours(23));
+    assert!(dunning::run(repo, &clock, &gateway).await?.is_empty());
+    clock.advance(Duration::hours(1));
+    let retried_at = clock.now();
+    let events = dunning::run(repo, &clock, &gateway).await?;
+    assert_eq!(stages(&events), [Stage::RetryFailed, Stage::Recovered]);
+    assert_eq!(events[0].attempt, 2);
+    assert_eq!(
+        events[0].next_attempt_at,
+        Some(retried_at + Duration::hours(72))
+    );
+    assert_eq!(
+        *gateway.charged.lock().unwrap(),
+        [case_id.0.clone(), recovered.case.id.0.clone()]
+    );
+    dunning::check_access(repo, &usage("s-1")).await?;
+
+    let gateway = Scripted::new(vec![declined()]);
+    clock.advance(Duration::hours(72));
+    let events = dunning::run(repo, &clock, &gateway).await?;
+    assert_eq!(stages(&events), [Stage::RetryFailed, Stage::Suspended]);
+    let case = dunning::find_case(repo, &case_id).await?;
+    assert_eq!(case.state, DunningState::Suspended);
+    assert_eq!(case.attempts, 3);
+    assert_eq!(case.next_attempt_at, None);
+    assert_eq!(case.reason, "card declined");
+    match dunning::check_access(repo, &usage("s-1")).await {
+        Err(DunningError::Suspended { case_id: c, .. }) => assert_eq!(c, case_id),
+        other => panic!("expected a suspension, got {:?}", other),
+    }
+    dunning::check_access(repo, &usage("s-2")).await?;
+    // nothing is retried once suspended
+    clock.advance(Duration::days(30));
+    assert!(dunning::run(repo, &clock, &Scripted::new(vec![]))
+        .await?
+        .is_empty());
+    assert!(matches!(
+        dunning::record_attempt(repo, &clock, &case_id, &declined()).await,
+        Err(DunningError::NotRetrying {
+            state: DunningState::Suspended,
+            ..
+        })
+    ));
+
+    // paying reinstates the user
+    let paid = dunning::record_attempt(repo, &clock, &case_id, &Attempt::Paid).await?;
+    assert_eq!(stages(&paid.events), [Stage::Reinstated]);
+    dunning::check_access(repo, &usage("s-1")).await?;
+    assert!(matches!(
+        dunning::record_attempt(repo, &clock, &case_id, &Attempt::Paid).await,
+        Err(DunningError::AlreadySettled { .. })
+    ));
+
+    // a write based on an old revision is refused
+    assert!(!repo.save_dunning_case(&case, &[]).await?);
+    assert_eq!(
+        dunning::find_case(repo, &case_id).await?.state,
+        DunningState::Settled
+    );
+
+    let stored = repo.get_dunning_events().await?;
+    assert_eq!(
+        stages(&stored),
+        [
+            Stage::ChargeFailed,
+            Stage::ChargeFailed,
+            Stage::RetryFailed,
+            Stage::Recovered,
+            Stage::RetryFailed,
+            Stage::Suspended,
+            Stage::Reinstated,
+        ]
+    );
+    assert_eq!(stored.last().map(|e| e.at), Some(clock.now()));
+    let cases = repo.get_dunning_cases().await?;
+    assert_eq!(cases.len(), 2);
+    assert!(cases.iter().all(|c| c.state == DunningState::Settled));
+    assert_eq!(cases[0].schedule.retry_after_hours, [24, 72]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dunning() -> Result<(), Box<dyn Error>> {
+    on_every_backend(|repo| async move { exercise(repo.as_ref()).await }).await
+}
+
+/// Pays every charge after letting other tasks run, and counts them.
+#[derive(Default)]
+struct Yielding {
+    charged: Mutex<u32>,
+}
+
+#[async_trait]
+impl Gateway for Yielding {
+    async fn charge(&self, _: &DunningCase) -> Attempt {
+        *self.charged.lock().unwrap() += 1;
+        tokio::task::yield_now().await;
+        Attempt::Paid
+    }
+}
+
+/// Collects the charge, while the case is recorded as paid another way.
+struct PaidMeanwhile<'a> {
+    repo: &'a dyn Repository,
+    clock: &'a ManualClock,
+}
+
+#[async_trait]
+impl Gateway for PaidMeanwhile<'_> {
+    async fn charge(&self, case: &DunningCase) -> Attempt {
+        dunning::record_attempt(self.repo, self.clock, &case.id, &Attempt::Paid)
+            .await
+            .expect("paid another way");
+        Attempt::Paid
+    }
+}
+
+#[tokio::test]
+async fn test_a_due_case_is_charged_once() -> Result<(), Box<dyn Error>> {
+    on_every_backend(|repo| async move {
+        let repo = repo.as_ref();
+        seed(repo).await?;
+        let clock = ManualClock::new(start());
+        let schedule = DunningSchedule::new(&[24]);
+        let opened = dunning::open(repo, &clock, &charge("s-1"), schedule.clone()).await?;
+        clock.advance(Duration::hours(24));
+
+        let gateway = Yielding::default();
+        let (a, b) = tokio::join!(
+            dunning::run(repo, &clock, &gateway),
+            dunning::run(repo, &clock, &gateway)
+        );
+        assert_eq!(*gateway.charged.lock().unwrap(), 1);
+        assert_eq!(a?.len() + b?.len(), 1);
+        let case = dunning::find_case(repo, &opened.case.id).await?;
+        assert_eq!(case.state, DunningState::Settled);
+
+        // a payment that cannot be recorded is never dropped
+        let opened = dunning::open(repo, &clock, &charge("s-2"), schedule).await?;
+        clock.advance(Duration::hours(24));
+        let gateway = PaidMeanwhile {
+            repo,
+            clock: &clock,
+        };
+        assert!(matches!(
+            dunning::run(repo, &clock, &gateway).await,
+            Err(DunningError::Conflict { case_id }) if case_id == opened.case.id
+        ));
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_open_checks_references() -> Result<(), Box<dyn Error>> {
+    let repo = MemoryRepository::new();
+    seed(&repo).await?;
+    let clock = ManualClock::new(start());
+    let unknown = FailedCharge {
+        user_id: uid("u-nobody"),
+        ..charge("s-1")
+    };
+    assert!(matches!(
+        dunning::open(&repo, &clock, &unknown, DunningSchedule::default()).await,
+        Err(DunningError::UnknownUser { .. })
+    ));
+    assert!(matches!(
+        dunning::open(&repo, &clock, &charge("s-9"), DunningSchedule::default()).await,
+        Err(DunningError::UnknownService { .. })
+    ));
+    // without retries the first failure suspends
+    let opened = dunning::open(&repo, &clock, &charge("s-1"), DunningSchedule::new(&[])).await?;
+    assert_eq!(
+        stages(&opened.events),
+        [Stage::ChargeFailed, Stage::Suspended]
+    );
+    assert!(repo.get_dunning_cases().await?[0].suspends(&uid("u-alice"), &"s-1".into()));
+    Ok(())
+}
+
+async fn check_erasure(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    seed(repo).await?;
+    let clock = ManualClock::new(start());
+    dunning::open(repo, &clock, &charge("s-1"), DunningSchedule::new(&[])).await?;
+
+    let (export, pseudonym) = export_and_erase(repo, "u-alice").await?;
+    assert_eq!(export.dunning_cases.len(), 1);
+    assert_eq!(
+        export.dunning_cases[0].payment.as_deref(),
+        Some("paypal a***@paypal")
+    );
+    let case = &repo.get_dunning_cases().await?[0];
+    assert_eq!(case.user_id, pseudonym);
+    assert!(case.payment.is_none());
+    assert_eq!(case.revision, 1);
+    assert_eq!(case.state, DunningState::Suspended);
+    // the case can still be paid under the pseudonym
+    let paid = dunning::record_attempt(repo, &clock, &case.id, &Attempt::Paid).await?;
+    assert_eq!(paid.case.user_id, pseudonym);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_erasure_moves_dunning() -> Result<(), Box<dyn Error>> {
+    on_every_backend(|repo| async move { check_erasure(repo.as_ref()).await }).await
+}
+
+#[tokio::test]
+async fn test_api_suspends_and_reinstates() -> Result<(), Box<dyn Error>> {
+    let repo: Arc<dyn Repository> = Arc::new(MemoryRepository::new());
+    seed(repo.as_ref()).await?;
+    let clock = Arc::new(ManualClock::new(start()));
+    let app = api::router_with_clock(repo.clone(), DEFAULT_RETENTION, clock.clone());
+    let record = json!({ "user_id": "u-alice", "service_id": "s-1", "product_id": "p-s-1" });
+
+    let new_case = json!({
+        "user_id": "u-alice",
+        "service_id": "s-1",
+        "amount_cents": 1000,
+        "reason": "card declined",
+        "retry_after_hours": [],
+    });
+    let (status, body) = call(&app, "POST", "/dunning/cases", Some(new_case)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body["case"]["state"], "suspended");
+    assert_eq!(body["case"]["opened_at"], json!(start()));
+    assert_eq!(body["events"][1]["stage"], "suspended");
+    let id = body["case"]["id"].as_str().expect("case id").to_string();
+
+    let (status, body) = call(&app, "POST", "/usages", Some(record.clone())).await?;
+    assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+    assert!(body["error"].as_str().unwrap_or_default().contains(&id));
+
+    clock.advance(Duration::hours(5));
+    let attempts = format!("/dunning/cases/{}/attempts", id);
+    let (status, body) = call(&app, "POST", &attempts, Some(json!({ "outcome": "paid" }))).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["events"][0]["stage"], "reinstated");
+    assert_eq!(
+        body["case"]["updated_at"],
+        json!(start() + Duration::hours(5))
+    );
+    let (status, _) = call(&app, "POST", "/usages", Some(record)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let failed = json!({ "outcome": "failed", "reason": "declined" });
+    let (status, _) = call(&app, "POST", &attempts, Some(failed.clone())).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = call(
+        &app,
+        "POST",
+        "/dunning/cases/dc-nope/attempts",
+        Some(failed),
+    )
+    .await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (_, events) = call(&app, "GET", "/dunning/events", None).await?;
+    assert_eq!(events.as_array().map(Vec::len), Some(3));
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use axum::body::{to_bytes, Body};
+use axum::http::{Request, StatusCode};
+use chrono::Duration;
+use serde_json::{json, Value};
+use src02::api;
+use src02::idempotency::{IdempotencyKey, DEFAULT_RETENTION};
+use src02::models::{PaymentMethod, Product, Service, ServiceUsage, User};
+use src02::query::UsageQuery;
+use src02::repository::{MemoryRepository, Repository, SledRepository, SqliteRepository};
+use std::error::Error;
+use std::sync::Arc;
+use tower::ServiceExt;
+
+fn usage(key: Option<&str>) -> ServiceUsage {
+    let u = ServiceUsage::new(&"u-1".into(), &"s-1".into(), &"p-1".into(), None);
+    match key {
+        Some(k) => u.with_idempotency_key(k),
+        None => u,
+    }
+}
+
+async fn count(repo: &dyn Repository) -> Result<u64, Box<dyn Error>> {
+    Ok(repo.aggregate_usages(&UsageQuery::new()).await?.count)
+}
+
+async fn exercise(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    repo.init().await?;
+    repo.save_user(&User::new("u-1", "U", None)).await?;
+    repo.save_service(&Service::new(
+        "s-1",
+        "SaaS",
+        vec![Product::new("p-1", "Email", 500)],
+    ))
+    .await?;
+
+    let first = repo
+        .record_usage(&usage(Some("k-1")), DEFAULT_RETENTION)
+        .await?;
+    assert!(!first.is_replay());
+    let retry = repo
+        .record_usage(&usage(Some("k-1")), DEFAULT_RETENTION)
+        .await?;
+    assert!(retry.is_replay());
+    assert_eq!(retry.record().id, first.record().id);
+    assert_eq!(count(repo).await?, 1);
+
+    let found = repo
+        .find_idempotent_usage(&IdempotencyKey::from("k-1"), DEFAULT_RETENTION)
+        .await?;
+    assert_eq!(found.map(|r| r.id), Some(first.record().id));
+    assert!(repo
+        .find_idempotent_usage(&IdempotencyKey::from("k-none"), DEFAULT_RETENTION)
+        .await?
+        .is_none());
+
+    // batches skip keys already stored and keys repeated within the batch;
+    // unkeyed usages are always written
+    let batch = [
+        usage(Some("k-1")),
+        usage(Some("k-2")),
+        usage(Some("k-2")),
+        usage(None),
+        usage(None),
+    ];
+    assert_eq!(repo.save_usages(&batch).await?, 3);
+    assert_eq!(count(repo).await?, 4);
+
+    // once the key has expired it records a new usage and moves over
+    let reused = repo
+        .record_usage(&usage(Some("k-1")), Duration::zero())
+        .await?;
+    assert!(!reused.is_replay());
+    assert_ne!(reused.record().id, first.record().id);
+    assert_eq!(count(repo).await?, 5);
+    let holder = repo
+        .find_idempotent_usage(&IdempotencyKey::from("k-1"), DEFAULT_RETENTION)
+        .await?;
+    assert_eq!(holder.map(|r| r.id), Some(reused.record().id));
+    let keyed = repo
+        .get_usages_for_user(&"u-1".into())
+        .await?
+        .iter()
+        .filter(|u| u.idempotency_key == Some(IdempotencyKey::from("k-1")))
+        .count();
+    assert_eq!(keyed, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_idempotent_usages_sqlite() -> Result<(), Box<dyn Error>> {
+    exercise(&SqliteRepository::connect("sqlite::memory:").await?).await
+}
+
+#[tokio::test]
+async fn test_idempotent_usages_sled() -> Result<(), Box<dyn Error>> {
+    exercise(&SledRepository::temporary()?).await
+}
+
+#[tokio::test]
+async fn test_idempotent_usages_memory() -> Result<(), Box<dyn Error>> {
+    exercise(&MemoryRepository::new()).await
+}
+
+#[tokio::test]
+async fn test_unique_index_rejects_duplicate_keys() -> Result<(), Box<dyn Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    src02::persistence::init_db(&pool).await?;
+    src02::persistence::save_user(&pool, &User::new("u-1", "U", None)).await?;
+    let svc = Service::new("s-1", "SaaS", vec![Product::new("p-1", "Email", 500)]);
+    src02::persistence::save_service(&pool, &svc).await?;
+    let insert =
+        "INSERT INTO usages (user_id, service_id, product_id, occurred_at, idempotency_key) \
+                  VALUES ('u-1', 's-1', 'p-1', 1, 'k-1')";
+    sqlx::query(insert).execute(&pool).await?;
+    assert!(sqlx::query(insert).execute(&pool).await.is_err());
+    Ok(())
+}
+
+async fn post_usage(app: &axum::Router, key: &str) -> Result<(StatusCode, Value), Box<dyn Error>> {
+    let body = json!({
+        "user_id": "u-1",
+        "service_id": "s-1",
+        "product_id": "p-1",
+        "payment_used": PaymentMethod::paypal("a@paypal"),
+    });
+    let request = Request::builder()
+        .method("POST")
+        .uri("/usages")
+        .header("content-type", "application/json")
+        .header(api::IDEMPOTENCY_KEY_HEADER, key)
+        .body(Body::from(body.to_string()))?;
+    let response = app.clone().oneshot(request).await?;
+    let status = response.status();
+    let bytes = to_bytes(response.into_body(), usize::MAX).await?;
+    Ok((status, serde_json::from_slice(&bytes)?))
+}
+
+#[tokio::test]
+async fn test_api_replays_retried_posts() -> Result<(), Box<dyn Error>> {
+    let repo: Arc<dyn Repository> = Arc::new(MemoryRepository::new());
+    repo.init().await?;
+    repo.save_user(&User::new("u-1", "U", None)).await?;
+    repo.save_service(&Service::new(
+        "s-1",
+        "SaaS",
+        vec![Product::new("p-1", "Email", 500)],
+    ))
+    .await?;
+    let app = api::router(repo.clone());
+
+    let (status, created) = post_usage(&app, "k-1").await?;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, replayed) = post_usage(&app, "k-1").await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(replayed, created);
+    assert_eq!(count(repo.as_ref()).await?, 1);
+
+    let (status, _) = post_usage(&app, "").await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    Ok(())
+}
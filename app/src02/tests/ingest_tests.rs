@@ -0,0 +1,145 @@
+use sqlx::SqlitePool;
+use src02::ingest::{IngestConfig, IngestError, UsageIngestor};
+use src02::models::{Product, Service, ServiceUsage, User, UserId};
+use src02::persistence;
+use std::time::Duration;
+
+async fn pool() -> Result<SqlitePool, Box<dyn std::error::Error>> {
+    let pool = SqlitePool::connect("sqlite::memory:").await?;
+    persistence::init_db(&pool).await?;
+    Ok(pool)
+}
+
+/// `pool` plus the users and product the usages below refer to.
+async fn seeded_pool() -> Result<SqlitePool, Box<dyn std::error::Error>> {
+    let pool = pool().await?;
+    for id in ["u-1", "u-2"] {
+        persistence::save_user(&pool, &User::new(id, id, None)).await?;
+    }
+    let svc = Service::new("s-1", "SaaS", vec![Product::new("p-1", "Email", 500)]);
+    persistence::save_service(&pool, &svc).await?;
+    Ok(pool)
+}
+
+async fn count(pool: &SqlitePool, table: &str) -> i64 {
+    sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {}", table))
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+/// Make any write that mentions product `p-bad` fail inside SQLite.
+async fn poison(pool: &SqlitePool, table: &str, column: &str) {
+    sqlx::query(&format!(
+        "CREATE TRIGGER poison_{t} BEFORE INSERT ON {t} WHEN NEW.{c} = 'p-bad' \
+         BEGIN SELECT RAISE(ABORT, 'poisoned'); END",
+        t = table,
+        c = column
+    ))
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+fn usage(user: &str, product: &str) -> ServiceUsage {
+    ServiceUsage::new(&UserId(user.into()), &"s-1".into(), &product.into(), None)
+}
+
+#[tokio::test]
+async fn test_save_service_is_atomic() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = pool().await?;
+    poison(&pool, "products", "id").await;
+    let svc = Service::new(
+        "s-1",
+        "SaaS",
+        vec![
+            Product::new("p-1", "Email", 500),
+            Product::new("p-bad", "Broken", 1),
+        ],
+    );
+    assert!(persistence::save_service(&pool, &svc).await.is_err());
+    assert_eq!(count(&pool, "services").await, 0);
+    assert_eq!(count(&pool, "products").await, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_save_usages_is_all_or_nothing() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = seeded_pool().await?;
+    let good: Vec<_> = (0..1200).map(|_| usage("u-1", "p-1")).collect();
+    assert_eq!(persistence::save_usages(&pool, &good).await?, 1200);
+    assert_eq!(count(&pool, "usages").await, 1200);
+
+    poison(&pool, "usages", "product_id").await;
+    let mut mixed: Vec<_> = (0..700).map(|_| usage("u-2", "p-1")).collect();
+    mixed.push(usage("u-2", "p-bad"));
+    assert!(persistence::save_usages(&pool, &mixed).await.is_err());
+    assert_eq!(count(&pool, "usages").await, 1200);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ingestor_flushes_on_size_and_shutdown() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = seeded_pool().await?;
+    let ingestor = UsageIngestor::spawn(
+        pool.clone(),
+        IngestConfig {
+            max_batch: 10,
+            flush_interval: Duration::from_secs(3600),
+            queue_capacity: 4,
+        },
+    );
+    for _ in 0..25 {
+        ingestor.record(usage("u-1", "p-1")).await?;
+    }
+    ingestor.flush().await?;
+    assert_eq!(count(&pool, "usages").await, 25);
+
+    ingestor.record(usage("u-1", "p-1")).await?;
+    let stats = ingestor.shutdown().await?;
+    assert_eq!(stats.usages_written, 26);
+    assert_eq!(stats.batches, 4);
+    assert_eq!(count(&pool, "usages").await, 26);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ingestor_flushes_on_interval() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = seeded_pool().await?;
+    let ingestor = UsageIngestor::spawn(
+        pool.clone(),
+        IngestConfig {
+            max_batch: 1000,
+            flush_interval: Duration::from_millis(20),
+            queue_capacity: 100,
+        },
+    );
+    for _ in 0..3 {
+        ingestor.record(usage("u-1", "p-1")).await?;
+    }
+    let mut waited = 0;
+    while count(&pool, "usages").await < 3 && waited < 50 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        waited += 1;
+    }
+    assert_eq!(count(&pool, "usages").await, 3);
+    ingestor.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_failed_flush_returns_unflushed_usages() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = seeded_pool().await?;
+    poison(&pool, "usages", "product_id").await;
+    let ingestor = UsageIngestor::spawn(pool.clone(), IngestConfig::default());
+    ingestor.record(usage("u-1", "p-1")).await?;
+    ingestor.record(usage("u-1", "p-bad")).await?;
+    assert!(ingestor.flush().await.is_err());
+
+    match ingestor.shutdown().await {
+        Err(IngestError::Flush { unflushed, .. }) => assert_eq!(unflushed.len(), 2),
+        other => panic!("expected Flush error, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(count(&pool, "usages").await, 0);
+    Ok(())
+}
@@ -1,13 +1,13 @@
+mod common;
+
+use common::test_card;
 use src02::models::{PaymentMethod, Product, Service, ServiceUsage, User};
 
 // Note: tests are simple unit-style checks using the public API.
 #[tokio::test]
 async fn test_resolve_payment() {
-    let alice = User::new(
-        "u-alice",
-        "Alice",
-        Some(PaymentMethod::card("****", "Alice")),
-    );
+    let card = test_card("Alice");
+    let alice = User::new("u-alice", "Alice", Some(PaymentMethod::card(&card)));
     let usage = ServiceUsage::new(&alice.id, &"s-1".into(), &"p-1".into(), None);
     let resolved = src02::usage::resolve_payment_for_usage(&alice, usage.payment_used.clone());
     assert!(resolved.is_some());
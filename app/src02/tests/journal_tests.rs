@@ -0,0 +1,191 @@
+use src02::journal::{FsyncPolicy, Journal, JournalError};
+use src02::models::ServiceUsage;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+
+fn usage(user: &str) -> ServiceUsage {
+    ServiceUsage::new(&user.into(), &"s-1".into(), &"p-1".into(), None)
+}
+
+fn users(j: &Journal) -> Vec<String> {
+    j.log().usages.iter().map(|u| u.user_id.0.clone()).collect()
+}
+
+fn chop(path: &Path, bytes: u64) -> std::io::Result<()> {
+    let f = OpenOptions::new().write(true).open(path)?;
+    let len = f.metadata()?.len();
+    f.set_len(len - bytes)
+}
+
+#[test]
+fn test_reopen_replays_appended_usages() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("usages.journal");
+    {
+        let mut j = Journal::open(&path, FsyncPolicy::Always)?;
+        assert_eq!(j.append(usage("u-1"))?, 1);
+        assert_eq!(j.append_all(vec![usage("u-2"), usage("u-3")])?, 3);
+    }
+    let j = Journal::open(&path, FsyncPolicy::Always)?;
+    assert_eq!(users(&j), ["u-1", "u-2", "u-3"]);
+    assert_eq!(j.recovery().replayed, 3);
+    assert_eq!(j.recovery().truncated_bytes, 0);
+    assert_eq!(Journal::replay(&path)?.usages_len(), 3);
+    Ok(())
+}
+
+#[test]
+fn test_torn_last_write_is_truncated() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("usages.journal");
+    {
+        let mut j = Journal::open(&path, FsyncPolicy::Always)?;
+        j.append_all(vec![usage("u-1"), usage("u-2")])?;
+    }
+    let full = fs::metadata(&path)?.len();
+    chop(&path, 5)?;
+
+    let mut j = Journal::open(&path, FsyncPolicy::Always)?;
+    assert_eq!(users(&j), ["u-1"]);
+    assert!(j.recovery().truncated_bytes > 0);
+    assert!(fs::metadata(&path)?.len() < full - 5);
+    // appends continue cleanly after the cut
+    j.append(usage("u-3"))?;
+    drop(j);
+    let j = Journal::open(&path, FsyncPolicy::Always)?;
+    assert_eq!(users(&j), ["u-1", "u-3"]);
+    assert_eq!(j.recovery().truncated_bytes, 0);
+    Ok(())
+}
+
+#[test]
+fn test_bad_checksum_on_last_record_counts_as_torn() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("usages.journal");
+    {
+        let mut j = Journal::open(&path, FsyncPolicy::Always)?;
+        j.append_all(vec![usage("u-1"), usage("u-2")])?;
+    }
+    let mut bytes = fs::read(&path)?;
+    let last = bytes.len() - 2;
+    bytes[last] ^= 0xff;
+    fs::write(&path, &bytes)?;
+
+    let j = Journal::open(&path, FsyncPolicy::Always)?;
+    assert_eq!(users(&j), ["u-1"]);
+    assert!(j.recovery().truncated_bytes > 0);
+    Ok(())
+}
+
+#[test]
+fn test_damage_before_the_tail_is_an_error() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("usages.journal");
+    {
+        let mut j = Journal::open(&path, FsyncPolicy::Always)?;
+        j.append_all(vec![usage("u-1"), usage("u-2")])?;
+    }
+    let mut bytes = fs::read(&path)?;
+    // inside the first record's payload (header is 8 bytes, record header 8 more)
+    bytes[20] ^= 0xff;
+    fs::write(&path, &bytes)?;
+
+    match Journal::open(&path, FsyncPolicy::Always) {
+        Err(JournalError::Corrupt { offset, .. }) => assert_eq!(offset, 8),
+        other => panic!("expected corruption, got {:?}", other.map(|_| ())),
+    }
+    // nothing was cut off
+    assert_eq!(fs::read(&path)?, bytes);
+
+    fs::write(&path, b"not a journal")?;
+    assert!(matches!(
+        Journal::open(&path, FsyncPolicy::Always),
+        Err(JournalError::BadHeader(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_oversize_length_before_the_tail_is_an_error() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("usages.journal");
+    {
+        let mut j = Journal::open(&path, FsyncPolicy::Always)?;
+        j.append_all(vec![usage("u-1"), usage("u-2"), usage("u-3")])?;
+    }
+    let mut bytes = fs::read(&path)?;
+    // the second record's length, right after the first record
+    let first = u32::from_le_bytes(bytes[8..12].try_into()?) as usize;
+    let second = 8 + 8 + first;
+    bytes[second..second + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    fs::write(&path, &bytes)?;
+
+    match Journal::open(&path, FsyncPolicy::Always) {
+        Err(JournalError::Corrupt { offset, .. }) => assert_eq!(offset, second as u64),
+        other => panic!("expected corruption, got {:?}", other.map(|_| ())),
+    }
+    assert!(Journal::replay(&path).is_err());
+    // the records after the damage are still there
+    assert_eq!(fs::read(&path)?, bytes);
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_then_append_then_reopen() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("usages.journal");
+    {
+        let mut j = Journal::open(&path, FsyncPolicy::Always)?;
+        j.append_all(vec![usage("u-1"), usage("u-2")])?;
+        j.snapshot()?;
+        j.append(usage("u-3"))?;
+    }
+    let j = Journal::open(&path, FsyncPolicy::Always)?;
+    assert_eq!(users(&j), ["u-1", "u-2", "u-3"]);
+    assert_eq!(j.recovery().from_snapshot, 2);
+    assert_eq!(j.recovery().replayed, 1);
+    Ok(())
+}
+
+#[test]
+fn test_crash_between_snapshot_and_truncate_replays_nothing_twice() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("usages.journal");
+    {
+        let mut j = Journal::open(&path, FsyncPolicy::Always)?;
+        j.append_all(vec![usage("u-1"), usage("u-2")])?;
+    }
+    // keep the journal as it was before the snapshot emptied it
+    let before = fs::read(&path)?;
+    {
+        let mut j = Journal::open(&path, FsyncPolicy::Always)?;
+        j.snapshot()?;
+    }
+    fs::write(&path, &before)?;
+
+    let mut j = Journal::open(&path, FsyncPolicy::Always)?;
+    assert_eq!(users(&j), ["u-1", "u-2"]);
+    assert_eq!(j.recovery().replayed, 0);
+    // sequence numbers continue past the snapshot
+    assert_eq!(j.append(usage("u-3"))?, 3);
+    Ok(())
+}
+
+#[test]
+fn test_every_n_and_manual_policies_keep_appends() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    for policy in [FsyncPolicy::EveryN(2), FsyncPolicy::Manual] {
+        let path = dir.path().join(format!("{:?}.journal", policy));
+        {
+            let mut j = Journal::open(&path, policy)?;
+            for u in ["u-1", "u-2", "u-3"] {
+                j.append(usage(u))?;
+            }
+            j.sync()?;
+        }
+        let j = Journal::open(&path, policy)?;
+        assert_eq!(users(&j), ["u-1", "u-2", "u-3"]);
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use src02::migrations::{self, MigrateOptions, MigrationError};
+use src02::models::{Product, Service, ServiceUsage, User};
+use src02::persistence;
+
+#[tokio::test]
+async fn test_migrate_fresh_database_then_noop() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    assert_eq!(migrations::current_version(&pool).await?, 0);
+
+    let report = migrations::migrate(&pool, MigrateOptions::default()).await?;
+    assert_eq!(report.from_version, 0);
+    assert_eq!(report.to_version, migrations::latest_version());
+    assert_eq!(report.applied.len(), migrations::MIGRATIONS.len());
+    assert_eq!(
+        migrations::current_version(&pool).await?,
+        migrations::latest_version()
+    );
+
+    let again = migrations::migrate(&pool, MigrateOptions::default()).await?;
+    assert!(again.is_up_to_date());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dry_run_changes_nothing() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    let report = migrations::migrate(&pool, MigrateOptions { dry_run: true }).await?;
+    assert!(report.dry_run);
+    assert_eq!(report.applied.len(), migrations::MIGRATIONS.len());
+    assert_eq!(migrations::current_version(&pool).await?, 0);
+    let tables: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(tables, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_legacy_database_is_adopted_and_upgraded() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    // schema as created by the pre-migration init_db, with one usage row
+    sqlx::query(
+        "CREATE TABLE usages (id INTEGER PRIMARY KEY AUTOINCREMENT, user_id TEXT NOT NULL, service_id TEXT NOT NULL, product_id TEXT NOT NULL, payment_used TEXT NULL)",
+    )
+    .execute(&pool)
+    .await?;
+    sqlx::query(
+        "INSERT INTO usages (user_id, service_id, product_id) VALUES ('u-1', 's-1', 'p-1')",
+    )
+    .execute(&pool)
+    .await?;
+
+    persistence::init_db(&pool).await?;
+
+    let usages = persistence::get_usages_for_user(&pool, "u-1").await?;
+    assert_eq!(usages.len(), 1);
+    assert!(usages[0].occurred_at.timestamp() > 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_usage_timestamp_round_trips() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    persistence::init_db(&pool).await?;
+    let alice = User::new("u-alice", "Alice", None);
+    persistence::save_user(&pool, &alice).await?;
+    let svc = Service::new("s-1", "SaaS", vec![Product::new("p-1", "Email", 500)]);
+    persistence::save_service(&pool, &svc).await?;
+    let when = chrono::DateTime::parse_from_rfc3339("2025-03-01T12:30:00.250Z")?.to_utc();
+    let usage = ServiceUsage::new(&alice.id, &"s-1".into(), &"p-1".into(), None).at(when);
+    persistence::save_usage(&pool, &usage).await?;
+
+    let loaded = persistence::get_usages_for_user(&pool, &alice.id.0).await?;
+    assert_eq!(loaded[0].occurred_at, when);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_newer_database_is_refused() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    persistence::init_db(&pool).await?;
+    let future = migrations::latest_version() + 1;
+    sqlx::query(
+        "INSERT INTO schema_version (version, name, applied_at) VALUES (?, 'from_the_future', '')",
+    )
+    .bind(future)
+    .execute(&pool)
+    .await?;
+
+    match migrations::migrate(&pool, MigrateOptions { dry_run: true }).await {
+        Err(MigrationError::DatabaseNewer { database, binary }) => {
+            assert_eq!(database, future);
+            assert_eq!(binary, migrations::latest_version());
+        }
+        other => panic!("expected DatabaseNewer, got {:?}", other),
+    }
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use src02::models::{PaymentMethod, Product, Service, ServiceUsage, User};
+use src02::payment::{Iban, PaymentKind, PaymentMethodError};
+use src02::persistence;
+use src02::usage::resolve_payment_for_charge;
+use src02::wallet::{PrepaidWallet, WalletError, WalletMap};
+
+#[test]
+fn test_iban_validation() {
+    let iban = Iban::parse("de89 3704 0044 0532 0130 00").unwrap();
+    assert_eq!(iban.as_str(), "DE89370400440532013000");
+    assert_eq!(iban.masked(), "DE** **** 3000");
+    assert!(Iban::parse("GB82 WEST 1234 5698 7654 32").is_ok());
+
+    assert_eq!(
+        Iban::parse("DE89370400440532013001"),
+        Err(PaymentMethodError::IbanChecksumMismatch)
+    );
+    assert!(matches!(
+        Iban::parse("DE8937040044053201300"),
+        Err(PaymentMethodError::IbanInvalidLength { .. })
+    ));
+    assert_eq!(
+        Iban::parse("BR1500000000000010932840814P2"),
+        Err(PaymentMethodError::IbanNotSepa("BR".into()))
+    );
+}
+
+#[test]
+fn test_payment_method_constructors_validate() {
+    let sepa = PaymentMethod::sepa_debit("DE89370400440532013000", "Alice", "MANDATE-1").unwrap();
+    assert_eq!(sepa.kind(), PaymentKind::SepaDebit);
+    assert_eq!(
+        PaymentMethod::sepa_debit("DE89370400440532013000", "Alice", " ").unwrap_err(),
+        PaymentMethodError::EmptyMandateReference
+    );
+    let transfer = PaymentMethod::bank_transfer("ACME-PO-778", 30).unwrap();
+    assert_eq!(transfer.kind(), PaymentKind::BankTransfer);
+    assert_eq!(
+        PaymentMethod::bank_transfer("ACME-PO-778", 0).unwrap_err(),
+        PaymentMethodError::InvalidTerms(0)
+    );
+}
+
+#[test]
+fn test_prepaid_wallet_debits_and_top_ups() {
+    let alice = User::new("u-alice", "Alice", None);
+    let wallet = PrepaidWallet::new("w-1", &alice.id);
+    let funded = wallet.top_up(1000).unwrap();
+    assert_eq!(wallet.balance_cents, 0);
+    assert_eq!(funded.balance_cents, 1000);
+
+    let after = funded.debit(400).unwrap();
+    assert_eq!(after.balance_cents, 600);
+    assert_eq!(
+        after.debit(601),
+        Err(WalletError::InsufficientFunds {
+            balance_cents: 600,
+            requested_cents: 601
+        })
+    );
+    assert_eq!(after.top_up(0), Err(WalletError::ZeroAmount));
+}
+
+#[test]
+fn test_resolve_payment_for_charge_skips_underfunded_wallet() {
+    let paypal = PaymentMethod::paypal("alice@paypal");
+    let alice = User::new("u-alice", "Alice", Some(paypal.clone()));
+    let wallet = PrepaidWallet::new("w-1", &alice.id).top_up(500).unwrap();
+    let mut wallets = WalletMap::new();
+    wallets.insert(wallet.id.clone(), wallet.clone());
+    let prepaid = PaymentMethod::prepaid(&wallet.id);
+
+    let usage = ServiceUsage::new(&alice.id, &"s-1".into(), &"p-1".into(), Some(prepaid));
+    let cheap = resolve_payment_for_charge(&alice, usage.payment_used.clone(), 500, &wallets);
+    assert_eq!(cheap.map(|p| p.kind()), Some(PaymentKind::Prepaid));
+
+    let pricey = resolve_payment_for_charge(&alice, usage.payment_used.clone(), 1500, &wallets);
+    assert_eq!(pricey.map(|p| p.kind()), Some(PaymentKind::Paypal));
+}
+
+#[tokio::test]
+async fn test_new_payment_kinds_round_trip_through_db() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    persistence::init_db(&pool).await?;
+
+    let sepa = PaymentMethod::sepa_debit("DE89370400440532013000", "Alice", "MANDATE-1")?;
+    let alice = User::new("u-alice", "Alice", Some(sepa));
+    persistence::save_user(&pool, &alice).await?;
+    let svc = Service::new("s-1", "SaaS", vec![Product::new("p-1", "Email", 500)]);
+    persistence::save_service(&pool, &svc).await?;
+
+    let wallet = PrepaidWallet::new("w-1", &alice.id).top_up(2500)?;
+    persistence::save_wallet(&pool, &wallet).await?;
+    let usage = ServiceUsage::new(
+        &alice.id,
+        &"s-1".into(),
+        &"p-1".into(),
+        Some(PaymentMethod::prepaid(&wallet.id)),
+    );
+    persistence::save_usage(&pool, &usage).await?;
+
+    let users = persistence::get_users(&pool).await?;
+    let kind = users[0].profile.default_payment().map(|p| p.kind());
+    assert_eq!(kind, Some(PaymentKind::SepaDebit));
+
+    let usages = persistence::get_usages_for_user(&pool, &alice.id.0).await?;
+    assert_eq!(
+        usages[0].payment_used.as_ref().map(|p| p.kind()),
+        Some(PaymentKind::Prepaid)
+    );
+
+    let loaded = persistence::get_wallet(&pool, &wallet.id).await?;
+    assert_eq!(loaded, Some(wallet));
+    Ok(())
+}
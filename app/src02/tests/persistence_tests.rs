@@ -1,3 +1,6 @@
+mod common;
+
+use common::test_card;
 use src02::models::{PaymentMethod, Product, Service, ServiceUsage, User};
 use src02::persistence;
 
@@ -7,11 +10,9 @@ async fn test_persistence_in_memory() -> Result<(), Box<dyn std::error::Error>>
     let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
     persistence::init_db(&pool).await?;
 
-    let alice = User::new(
-        "u-alice",
-        "Alice",
-        Some(PaymentMethod::card("****", "Alice")),
-    );
+    let card = test_card("Alice");
+    persistence::save_card(&pool, &card).await?;
+    let alice = User::new("u-alice", "Alice", Some(PaymentMethod::card(&card)));
     persistence::save_user(&pool, &alice).await?;
 
     let p1 = Product::new("p-1", "Email Support", 500);
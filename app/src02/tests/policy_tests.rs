@@ -0,0 +1,253 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{alice, call, on_every_backend, uid};
+use serde_json::json;
+use src02::account::{Account, AccountError, Role};
+use src02::api;
+use src02::catalog::Catalog;
+use src02::catalog_file::{self, CatalogChange, FileFormat};
+use src02::models::{PaymentMethod, Service, ServiceUsage};
+use src02::payment::PaymentKind;
+use src02::policy::{Outcome, PaymentContext, PaymentPolicy, Source};
+use src02::repository::{MemoryRepository, Repository};
+use src02::validation::{self, References, UsageViolation};
+use src02::wallet::{PrepaidWallet, WalletMap};
+use std::error::Error;
+use std::sync::Arc;
+
+/// Consulting is only paid by bank transfer.
+fn consulting() -> Service {
+    common::consulting().accepting(&[PaymentKind::BankTransfer])
+}
+
+/// Alice owns Acme, which pays by PayPal or, second, by bank transfer.
+fn acme() -> Result<Account, Box<dyn Error>> {
+    let alice = uid("u-alice");
+    Ok(Account::new("acc-acme", "Acme", &alice)
+        .add_payment_method(&alice, PaymentMethod::paypal("acme@paypal"), false)?
+        .add_payment_method(&alice, PaymentMethod::bank_transfer("ACME-INV", 30)?, false)?)
+}
+
+fn outcomes(trail: &[src02::policy::Step]) -> Vec<(Source, &Outcome)> {
+    trail.iter().map(|s| (s.source, &s.outcome)).collect()
+}
+
+#[test]
+fn test_policy_follows_chain_and_explains() -> Result<(), Box<dyn Error>> {
+    let (alice, acme, consulting) = (alice(), acme()?, consulting());
+    let policy = PaymentPolicy::default();
+    let refused = Outcome::NotAccepted {
+        service_id: consulting.id.clone(),
+        accepted: vec![PaymentKind::BankTransfer],
+    };
+
+    // neither the named method nor Alice's default is accepted
+    let named = PaymentMethod::paypal("other@paypal");
+    let ctx = PaymentContext::new()
+        .user(&alice)
+        .service(&consulting)
+        .usage_payment(Some(&named));
+    let decision = policy.resolve(&ctx);
+    assert!(decision.payment.is_none());
+    assert_eq!(
+        outcomes(&decision.trail),
+        [(Source::Usage, &refused), (Source::User, &refused)]
+    );
+    assert_eq!(
+        decision.reasons()[1],
+        "user paypal a***@paypal: service s-2 accepts only bank_transfer"
+    );
+
+    // billed to Acme: its PayPal is skipped, its bank transfer pays
+    let decision = policy.resolve(&ctx.usage_payment(None).account(&acme));
+    assert_eq!(decision.source, Some(Source::Account));
+    assert_eq!(
+        decision.payment.map(|pm| pm.kind()),
+        Some(PaymentKind::BankTransfer)
+    );
+    assert_eq!(
+        outcomes(&decision.trail),
+        [
+            (Source::Usage, &Outcome::Empty),
+            (Source::Account, &refused),
+            (Source::Account, &Outcome::Chosen),
+        ]
+    );
+
+    // without a restriction, a preferred kind goes before the account's order
+    let prefer_invoice = PaymentPolicy {
+        preference: vec![PaymentKind::BankTransfer],
+        ..PaymentPolicy::default()
+    };
+    let ctx = PaymentContext::new().user(&alice).account(&acme);
+    assert_eq!(
+        prefer_invoice.resolve(&ctx).payment.map(|pm| pm.kind()),
+        Some(PaymentKind::BankTransfer)
+    );
+    assert_eq!(
+        policy.resolve(&ctx).payment.map(|pm| pm.kind()),
+        Some(PaymentKind::Paypal)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_member_fallback_and_wallets() -> Result<(), AccountError> {
+    let alice = alice();
+    let empty = Account::new("acc-empty", "Empty", &alice.id);
+    let ctx = PaymentContext::new().user(&alice).account(&empty);
+
+    let decision = PaymentPolicy::default().resolve(&ctx);
+    assert!(decision.payment.is_none());
+    assert_eq!(
+        outcomes(&decision.trail),
+        [
+            (Source::Usage, &Outcome::Empty),
+            (Source::Account, &Outcome::Empty),
+            (Source::User, &Outcome::MemberMethodsNotUsed),
+        ]
+    );
+    let fallback = PaymentPolicy {
+        member_fallback: true,
+        ..PaymentPolicy::default()
+    };
+    assert_eq!(fallback.resolve(&ctx).source, Some(Source::User));
+
+    // an underfunded wallet falls through to the default, with the reason
+    let wallet = PrepaidWallet::new("w-1", &alice.id)
+        .top_up(300)
+        .expect("positive amount");
+    let wallets: WalletMap = [(wallet.id.clone(), wallet.clone())].into();
+    let prepaid = PaymentMethod::prepaid(&wallet.id);
+    let ctx = PaymentContext::new()
+        .user(&alice)
+        .usage_payment(Some(&prepaid))
+        .charge(500, &wallets);
+    let decision = PaymentPolicy::default().resolve(&ctx);
+    assert_eq!(decision.source, Some(Source::User));
+    assert_eq!(
+        decision.trail[0].outcome,
+        Outcome::InsufficientBalance {
+            wallet_id: wallet.id.clone(),
+            balance_cents: 300,
+            amount_cents: 500,
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_validation_checks_accepted_kinds() -> Result<(), Box<dyn Error>> {
+    let refs = References::new(
+        [(uid("u-alice"), alice())].into(),
+        Catalog::default().with_service(consulting()),
+    )
+    .with_account(acme()?);
+    let usage = |pm: Option<PaymentMethod>| {
+        ServiceUsage::new(&uid("u-alice"), &"s-2".into(), &"p-3".into(), pm)
+    };
+    let refused = UsageViolation::PaymentNotAccepted {
+        service_id: "s-2".into(),
+        accepted: vec![PaymentKind::BankTransfer],
+    };
+
+    // named on the usage, or only the default to fall back on
+    let named = usage(Some(PaymentMethod::paypal("a@paypal")));
+    assert_eq!(
+        validation::check_usage(&named, &refs),
+        vec![refused.clone()]
+    );
+    assert_eq!(validation::check_usage(&usage(None), &refs), vec![refused]);
+    assert!(
+        validation::check_usage(&usage(None).for_account(&"acc-acme".into()), &refs).is_empty()
+    );
+    let invoice = usage(Some(PaymentMethod::bank_transfer("INV-1", 14)?));
+    assert!(validation::check_usage(&invoice, &refs).is_empty());
+    Ok(())
+}
+
+async fn exercise(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    repo.init().await?;
+    let kinds = [PaymentKind::SepaDebit, PaymentKind::BankTransfer];
+    repo.save_service(&consulting().accepting(&kinds)).await?;
+    let stored = repo.get_services().await?;
+    assert_eq!(stored[0].accepted_payments, kinds);
+    assert!(!stored[0].accepts(PaymentKind::Card));
+
+    repo.save_service(&consulting().accepting(&[])).await?;
+    let stored = repo.get_services().await?;
+    assert!(stored[0].accepted_payments.is_empty());
+    assert!(stored[0].accepts(PaymentKind::Card));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_accepted_kinds_persist() -> Result<(), Box<dyn Error>> {
+    on_every_backend(|repo| async move { exercise(repo.as_ref()).await }).await
+}
+
+#[test]
+fn test_catalog_file_lists_accepted_kinds() -> Result<(), Box<dyn Error>> {
+    let text = r#"
+[[services]]
+id = "s-2"
+name = "Consulting"
+accepts = ["bank_transfer"]
+"#;
+    let desired = catalog_file::parse(text, FileFormat::Toml)?;
+    let stored = Catalog::default().with_service(Service::new("s-2", "Consulting", vec![]));
+    let diff = catalog_file::diff(&stored, &desired);
+    assert_eq!(
+        diff.changes,
+        [CatalogChange::SetAcceptedPayments {
+            service_id: "s-2".into(),
+            from: vec![],
+            to: vec![PaymentKind::BankTransfer],
+        }]
+    );
+    assert_eq!(
+        diff.changes[0].to_string(),
+        "~ service s-2 accepts any -> bank_transfer"
+    );
+    assert_eq!(
+        diff.writes[0].accepted_payments,
+        [PaymentKind::BankTransfer]
+    );
+    assert!(catalog_file::diff(&desired, &desired).is_empty());
+
+    let bad = text.replace("bank_transfer", "cash");
+    let err = catalog_file::parse(&bad, FileFormat::Toml).unwrap_err();
+    assert!(err.to_string().contains("services[0].accepts[0]"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_api_returns_the_trail() -> Result<(), Box<dyn Error>> {
+    let repo: Arc<dyn Repository> = Arc::new(MemoryRepository::new());
+    repo.init().await?;
+    repo.save_user(&alice()).await?;
+    repo.save_service(&consulting()).await?;
+    repo.save_account(&acme()?.set_member(&uid("u-alice"), &uid("u-bob"), Role::Member)?)
+        .await?;
+    let app = api::router(repo);
+    let resolve = |body| call(&app, "POST", "/payments/resolve", Some(body));
+
+    let (status, body) = resolve(json!({ "user_id": "u-alice", "service_id": "s-2" })).await?;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["violation"]["kind"], "payment_not_accepted");
+    assert_eq!(body["trail"][1]["outcome"], "not_accepted");
+    assert_eq!(body["trail"][1]["payment"], "paypal a***@paypal");
+
+    let billed = json!({ "user_id": "u-alice", "service_id": "s-2", "account_id": "acc-acme" });
+    let (status, body) = resolve(billed).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["source"], "account");
+    assert_eq!(body["payment"]["BankTransfer"]["reference"], "ACME-INV");
+    assert_eq!(body["trail"].as_array().map(Vec::len), Some(3));
+
+    let (status, body) = resolve(json!({ "user_id": "u-alice", "service_id": "s-9" })).await?;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["violation"]["kind"], "unknown_service");
+    Ok(())
+}
@@ -0,0 +1,212 @@
+mod common;
+
+use chrono::{DateTime, Utc};
+use common::{alice, day, on_backends, Backend};
+use src02::catalog::Catalog;
+use src02::catalog_file::{self, CatalogFileError, FileFormat};
+use src02::models::{Product, Service, ServiceUsage};
+use src02::persistence;
+use src02::query::UsageQuery;
+use src02::repository::{Repository, SqliteRepository};
+use src02::usage::UsageLog;
+use std::error::Error;
+
+/// 500¢ until March, 600¢ from March, 800¢ from June.
+fn email() -> Product {
+    Product::new("p-1", "Email", 500)
+        .with_price_change(day(2025, 6, 1), 800)
+        .with_price_change(day(2025, 3, 1), 600)
+}
+
+fn saas() -> Service {
+    Service::new("s-1", "SaaS", vec![email()])
+}
+
+fn usage_on(at: DateTime<Utc>) -> ServiceUsage {
+    common::usage("u-alice", "s-1", "p-1").at(at)
+}
+
+fn usages() -> Vec<ServiceUsage> {
+    vec![
+        usage_on(day(2025, 1, 15)),
+        usage_on(day(2025, 3, 1)),
+        usage_on(day(2025, 5, 31)),
+        usage_on(day(2025, 7, 1)),
+    ]
+}
+
+#[test]
+fn test_price_in_effect_at_a_time() {
+    let p = email();
+    assert_eq!(
+        p.price_changes
+            .iter()
+            .map(|c| c.effective_from)
+            .collect::<Vec<_>>(),
+        [day(2025, 3, 1), day(2025, 6, 1)]
+    );
+    assert_eq!(p.price_at(day(2025, 2, 28)), 500);
+    assert_eq!(p.price_at(day(2025, 3, 1)), 600);
+    assert_eq!(p.price_at(day(2030, 1, 1)), 800);
+    // same instant replaces the change
+    let p = p.with_price_change(day(2025, 6, 1), 700);
+    assert_eq!(p.price_changes.len(), 2);
+    assert_eq!(p.price_at(day(2025, 6, 1)), 700);
+}
+
+#[test]
+fn test_scheduled_price_leaves_past_usages_alone() -> Result<(), Box<dyn Error>> {
+    let catalog = Catalog::default().with_service(Service::new(
+        "s-1",
+        "SaaS",
+        vec![Product::new("p-1", "Email", 500)],
+    ));
+    let log = UsageLog::from_vec(vec![usage_on(day(2025, 1, 1))]);
+    let before = log.aggregate(&UsageQuery::new(), &catalog).total_cents;
+
+    let catalog = catalog.schedule_price(&"s-1".into(), &"p-1".into(), day(2025, 2, 1), 900)?;
+    assert_eq!(
+        log.aggregate(&UsageQuery::new(), &catalog).total_cents,
+        before
+    );
+    let log = log.add_usage(usage_on(day(2025, 2, 2)));
+    assert_eq!(
+        log.aggregate(&UsageQuery::new(), &catalog).total_cents,
+        500 + 900
+    );
+    assert_eq!(catalog.usage_price(&usage_on(day(2025, 2, 2))), Some(900));
+    assert!(catalog
+        .schedule_price(&"s-1".into(), &"p-9".into(), day(2025, 2, 1), 1)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_usage_log_prices_each_usage_when_it_occurred() {
+    let catalog = Catalog::default().with_service(saas());
+    let agg = UsageLog::from_vec(usages()).aggregate(&UsageQuery::new(), &catalog);
+    assert_eq!(agg.count, 4);
+    assert_eq!(agg.total_cents, 500 + 600 + 600 + 800);
+}
+
+async fn priced_by_occurrence(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    repo.init().await?;
+    repo.save_user(&alice()).await?;
+    repo.save_service(&saas()).await?;
+    repo.save_usages(&usages()).await?;
+
+    let stored = repo.get_services().await?;
+    assert_eq!(stored[0].products[0], email());
+    let agg = repo.aggregate_usages(&UsageQuery::new()).await?;
+    assert_eq!(agg.total_cents, 500 + 600 + 600 + 800);
+    let spring = UsageQuery::new()
+        .from(day(2025, 3, 1))
+        .until(day(2025, 6, 1));
+    assert_eq!(repo.aggregate_usages(&spring).await?.total_cents, 1200);
+
+    // saving again replaces the history instead of adding to it
+    let repriced = Service::new(
+        "s-1",
+        "SaaS",
+        vec![Product::new("p-1", "Email", 500).with_price_change(day(2025, 7, 1), 1000)],
+    );
+    repo.save_service(&repriced).await?;
+    assert_eq!(
+        repo.get_services().await?[0].products[0]
+            .price_changes
+            .len(),
+        1
+    );
+    let agg = repo.aggregate_usages(&UsageQuery::new()).await?;
+    assert_eq!(agg.total_cents, 500 * 3 + 1000);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sqlite_stores_price_history() -> Result<(), Box<dyn Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    priced_by_occurrence(&SqliteRepository::new(pool.clone())).await?;
+    let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM product_prices")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(rows, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_memory_and_sled_price_by_occurrence() -> Result<(), Box<dyn Error>> {
+    on_backends(&[Backend::Memory, Backend::Sled], |repo| async move {
+        priced_by_occurrence(repo.as_ref()).await
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_lenient_read_skips_a_corrupt_price() -> Result<(), Box<dyn Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    persistence::init_db(&pool).await?;
+    persistence::save_service(&pool, &saas()).await?;
+    sqlx::query("UPDATE product_prices SET price_cents = -1 WHERE effective_from = ?")
+        .bind(day(2025, 6, 1).timestamp_millis())
+        .execute(&pool)
+        .await?;
+
+    assert!(persistence::get_services(&pool).await.is_err());
+    let read = persistence::get_services_with(&pool, persistence::ReadMode::Lenient).await?;
+    assert_eq!(read.skipped.len(), 1);
+    assert_eq!(read.skipped[0].table, "product_prices");
+    assert_eq!(read.items[0].products[0].price_changes.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_catalog_file_prices() -> Result<(), Box<dyn Error>> {
+    let yaml = "
+services:
+  - id: s-1
+    name: SaaS
+    products:
+      - id: p-1
+        name: Email
+        price_cents: 500
+        prices:
+          - { from: 2025-06-01, price_cents: 800 }
+          - { from: '2025-03-01T00:00:00Z', price_cents: 600 }
+";
+    let catalog = catalog_file::parse(yaml, FileFormat::Yaml)?;
+    assert_eq!(
+        catalog.get_product(&"s-1".into(), &"p-1".into()),
+        Some(&email())
+    );
+
+    // a new scheduled price shows up in the diff
+    let stored = Catalog::default().with_service(Service::new(
+        "s-1",
+        "SaaS",
+        vec![Product::new("p-1", "Email", 500).with_price_change(day(2025, 3, 1), 550)],
+    ));
+    let diff = catalog_file::diff(&stored, &catalog);
+    assert_eq!(
+        diff.changes[0].to_string(),
+        "~ product s-1/p-1 price from 2025-03-01T00:00:00+00:00 550¢ -> 600¢, \
+         + price from 2025-06-01T00:00:00+00:00 800¢"
+    );
+
+    // the same instant, written both ways
+    let bad = yaml.replace("2025-03-01T00:00:00Z", "2025-06-01T00:00:00Z");
+    match catalog_file::parse(&bad, FileFormat::Yaml) {
+        Err(CatalogFileError::Invalid { problems, .. }) => assert_eq!(
+            problems[0].to_string(),
+            "services[0].products[0].prices[1].from: a price from 2025-06-01T00:00:00Z is already defined at services[0].products[0].prices[0]"
+        ),
+        other => panic!("expected a duplicate date, got {:?}", other.map(|_| ())),
+    }
+    let bad = yaml.replace("2025-06-01", "soon");
+    match catalog_file::parse(&bad, FileFormat::Yaml) {
+        Err(CatalogFileError::Invalid { problems, .. }) => {
+            assert_eq!(problems[0].path, "services[0].products[0].prices[0].from")
+        }
+        other => panic!("expected a bad date, got {:?}", other.map(|_| ())),
+    }
+    Ok(())
+}
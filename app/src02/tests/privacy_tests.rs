@@ -0,0 +1,252 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{call, on_every_backend, saas, uid};
+use src02::account::{Account, AccountId, Role};
+use src02::api;
+use src02::budget::{BillingPeriod, Budget};
+use src02::idempotency::{IdempotencyKey, DEFAULT_RETENTION};
+use src02::models::{PaymentMethod, ServiceUsage, User};
+use src02::persistence;
+use src02::privacy::{PrivacyAction, ERASED_DISPLAY_NAME};
+use src02::query::UsageQuery;
+use src02::repository::{MemoryRepository, Repository, SledRepository, SqliteRepository};
+use src02::validation;
+use src02::vault::{CardExpiry, CardVault};
+use src02::wallet::{PrepaidWallet, WalletId};
+use std::error::Error;
+use std::sync::Arc;
+
+fn usage(user: &str) -> ServiceUsage {
+    ServiceUsage::new(
+        &uid(user),
+        &"s-1".into(),
+        &"p-1".into(),
+        Some(PaymentMethod::paypal("alice@example.com")),
+    )
+}
+
+/// Alice owns Acme, where Bob is a member; Alice has a budget and three
+/// usages (one billed to Acme, one with an idempotency key), Bob has one.
+async fn seed(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    repo.init().await?;
+    let alice = User::new(
+        "u-alice",
+        "Alice",
+        Some(PaymentMethod::paypal("alice@example.com")),
+    );
+    repo.save_user(&alice).await?;
+    repo.save_user(&User::new("u-bob", "Bob", None)).await?;
+    repo.save_service(&saas()).await?;
+    let acme = Account::new("acc-acme", "Acme", &uid("u-alice")).set_member(
+        &uid("u-alice"),
+        &uid("u-bob"),
+        Role::Member,
+    )?;
+    repo.save_account(&acme).await?;
+    repo.save_budget(&Budget::new(
+        "b-1",
+        &uid("u-alice"),
+        BillingPeriod::Monthly,
+        10_000,
+    ))
+    .await?;
+    repo.save_usages(&[
+        usage("u-alice"),
+        usage("u-alice").for_account(&"acc-acme".into()),
+        usage("u-alice").with_idempotency_key("k-1"),
+        ServiceUsage::new(
+            &uid("u-bob"),
+            &"s-1".into(),
+            &"p-1".into(),
+            Some(PaymentMethod::paypal("bob@example.com")),
+        ),
+    ])
+    .await?;
+    Ok(())
+}
+
+async fn exercise(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    seed(repo).await?;
+
+    let export = repo
+        .export_user(&uid("u-alice"))
+        .await?
+        .ok_or("expected an export")?;
+    assert_eq!(export.display_name, "Alice");
+    assert_eq!(
+        export.default_payment.as_deref(),
+        Some("paypal a***@example.com")
+    );
+    assert_eq!(export.payment_methods, ["paypal a***@example.com"]);
+    assert_eq!(export.memberships.len(), 1);
+    assert_eq!(export.memberships[0].role, Role::Owner);
+    assert_eq!(export.budgets.len(), 1);
+    assert_eq!(export.usages.len(), 3);
+    let archive = serde_json::to_string(&export)?;
+    assert!(!archive.contains("alice@example.com"));
+    assert!(repo.export_user(&uid("u-nobody")).await?.is_none());
+
+    let before = repo.aggregate_usages(&UsageQuery::new()).await?;
+    let acme_before = repo
+        .aggregate_usages(&UsageQuery::new().account(&AccountId::from("acc-acme")))
+        .await?;
+
+    let pseudonym = uid("erased-1");
+    let report = repo
+        .erase_user(&uid("u-alice"), &pseudonym)
+        .await?
+        .ok_or("expected a report")?;
+    assert_eq!(report.usages, 3);
+    assert_eq!(report.memberships, 1);
+    assert_eq!(report.budgets_deleted, 1);
+    assert!(repo
+        .erase_user(&uid("u-alice"), &uid("erased-2"))
+        .await?
+        .is_none());
+
+    // the profile is gone, the usages remain under the pseudonym
+    let users = repo.get_users().await?;
+    assert!(users.iter().all(|u| u.id.0 != "u-alice"));
+    let erased = users
+        .iter()
+        .find(|u| u.id == pseudonym)
+        .ok_or("expected the pseudonym")?;
+    assert_eq!(erased.profile.display_name, ERASED_DISPLAY_NAME);
+    assert!(erased.profile.payment_methods.is_empty());
+    assert!(repo.get_usages_for_user(&uid("u-alice")).await?.is_empty());
+    let moved = repo.get_usages_for_user(&pseudonym).await?;
+    assert_eq!(moved.len(), 3);
+    assert!(moved
+        .iter()
+        .all(|u| u.payment_used.is_none() && u.idempotency_key.is_none()));
+    assert!(repo
+        .find_idempotent_usage(&IdempotencyKey::from("k-1"), DEFAULT_RETENTION)
+        .await?
+        .is_none());
+    assert!(repo.get_budgets().await?.is_empty());
+    assert_eq!(
+        repo.get_accounts().await?[0].role_of(&pseudonym),
+        Some(Role::Owner)
+    );
+    assert_eq!(repo.get_usages_for_user(&uid("u-bob")).await?.len(), 1);
+
+    // accounting still adds up and nothing dangles
+    assert_eq!(repo.aggregate_usages(&UsageQuery::new()).await?, before);
+    assert_eq!(
+        repo.aggregate_usages(&UsageQuery::new().account(&AccountId::from("acc-acme")))
+            .await?,
+        acme_before
+    );
+    assert!(validation::audit(repo).await?.is_empty());
+
+    // the audit trail no longer names the user
+    let audit = repo.get_privacy_audit().await?;
+    let actions: Vec<_> = audit.iter().map(|r| r.action).collect();
+    assert_eq!(actions, [PrivacyAction::Export, PrivacyAction::Erasure]);
+    assert!(audit.iter().all(|r| r.subject == pseudonym));
+    assert!(audit.iter().all(|r| r.usages == 3));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_export_and_erase() -> Result<(), Box<dyn Error>> {
+    on_every_backend(|repo| async move { exercise(repo.as_ref()).await }).await
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_sled_erasure_moves_usages_written_meanwhile() -> Result<(), Box<dyn Error>> {
+    let repo = SledRepository::temporary()?;
+    seed(&repo).await?;
+    // erasure collects usage keys before budget keys: many budgets leave
+    // time for usages to arrive in between
+    for i in 0..5000 {
+        let id = format!("b-bob-{}", i);
+        repo.save_budget(&Budget::new(&id, &uid("u-bob"), BillingPeriod::Monthly, 1))
+            .await?;
+    }
+
+    // usages keep coming until the erasure removes the user they reference
+    let writers: Vec<_> = (0..3)
+        .map(|_| {
+            let repo = repo.clone();
+            tokio::spawn(async move {
+                let mut written = 0;
+                while repo.save_usage(&usage("u-alice")).await.is_ok() {
+                    written += 1;
+                }
+                written
+            })
+        })
+        .collect();
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    let pseudonym = uid("erased-1");
+    let report = repo
+        .erase_user(&uid("u-alice"), &pseudonym)
+        .await?
+        .ok_or("expected a report")?;
+    let mut written = 3;
+    for writer in writers {
+        written += writer.await?;
+    }
+
+    assert!(repo.get_usages_for_user(&uid("u-alice")).await?.is_empty());
+    assert_eq!(report.usages, written);
+    assert_eq!(
+        repo.get_usages_for_user(&pseudonym).await?.len() as u64,
+        written
+    );
+    assert!(validation::audit(&repo).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_erasure_deletes_cards_and_keeps_wallets() -> Result<(), Box<dyn Error>> {
+    let repo = SqliteRepository::connect("sqlite::memory:").await?;
+    seed(&repo).await?;
+    let pool = repo.pool();
+    let card = CardVault::new().tokenize(
+        "4242424242424242",
+        CardExpiry::new(12, 2099)?,
+        "Alice Example",
+    )?;
+    persistence::save_card(pool, &card).await?;
+    let mut carol = User::new("u-carol", "Carol", Some(PaymentMethod::card(&card)));
+    persistence::save_user(pool, &carol).await?;
+    let wallet = PrepaidWallet::new("w-1", &carol.id).top_up(700)?;
+    persistence::save_wallet(pool, &wallet).await?;
+
+    carol.id = uid("erased-carol");
+    repo.erase_user(&uid("u-carol"), &carol.id).await?;
+    assert!(persistence::get_card(pool, card.token()).await?.is_none());
+    let wallet = persistence::get_wallet(pool, &WalletId("w-1".into()))
+        .await?
+        .ok_or("expected the wallet")?;
+    assert_eq!(wallet.owner, carol.id);
+    assert_eq!(wallet.balance_cents, 700);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_api_exports_and_erases() -> Result<(), Box<dyn Error>> {
+    let repo: Arc<dyn Repository> = Arc::new(MemoryRepository::new());
+    seed(repo.as_ref()).await?;
+    let app = api::router(repo.clone());
+    let call = |method, uri| call(&app, method, uri, None);
+
+    let (status, export) = call("GET", "/users/u-alice/export").await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(export["usages"].as_array().map(Vec::len), Some(3));
+    let (status, report) = call("DELETE", "/users/u-alice").await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(report["usages"], 3);
+    assert_eq!(
+        call("GET", "/users/u-alice").await?.0,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        call("DELETE", "/users/u-alice").await?.0,
+        StatusCode::NOT_FOUND
+    );
+    Ok(())
+}
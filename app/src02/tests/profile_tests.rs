@@ -0,0 +1,411 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{call, on_every_backend, uid};
+use serde_json::json;
+use src02::api;
+use src02::models::{PaymentMethod, Profile, User};
+use src02::privacy::ERASED_DISPLAY_NAME;
+use src02::profile::{self, BillingAddress, Email, Locale, ProfileError, ProfileUpdate, Timezone};
+use src02::repository::{MemoryRepository, Repository, RepositoryError};
+use std::error::Error;
+use std::sync::Arc;
+
+fn methods(profile: &Profile) -> Vec<String> {
+    profile
+        .payment_methods
+        .iter()
+        .map(PaymentMethod::masked)
+        .collect()
+}
+
+#[test]
+fn test_values_are_checked_and_normalized() -> Result<(), Box<dyn Error>> {
+    assert_eq!(
+        Email::parse(" Alice@Example.COM ")?.as_str(),
+        "alice@example.com"
+    );
+    for bad in [
+        "alice",
+        "@example.com",
+        "alice@",
+        "alice@example",
+        "a b@x.io",
+    ] {
+        assert!(
+            matches!(Email::parse(bad), Err(ProfileError::InvalidEmail(_))),
+            "{}",
+            bad
+        );
+    }
+    assert_eq!(Locale::parse("pt_br")?.as_str(), "pt-BR");
+    assert_eq!(Locale::parse("zh-hant-tw")?.as_str(), "zh-Hant-TW");
+    assert_eq!(Locale::parse("es-419")?.as_str(), "es-419");
+    for bad in ["e", "english", "en-GB-US", "en-1"] {
+        assert!(Locale::parse(bad).is_err(), "{}", bad);
+    }
+    assert_eq!(
+        Timezone::parse("America/Argentina/Buenos_Aires")?.as_str(),
+        "America/Argentina/Buenos_Aires"
+    );
+    assert!(Timezone::parse("UTC").is_ok());
+    assert!(Timezone::parse("europe/rome").is_err());
+    assert!(Timezone::parse("Europe//Rome").is_err());
+
+    let address = BillingAddress::new(" Via Roma 1 ", "Milano", "20121", "it")?;
+    assert_eq!(address.line1, "Via Roma 1");
+    assert_eq!(address.country, "IT");
+    assert!(matches!(
+        BillingAddress::new("Via Roma 1", " ", "20121", "IT"),
+        Err(ProfileError::EmptyAddressField("city"))
+    ));
+    assert!(matches!(
+        BillingAddress::new("Via Roma 1", "Milano", "20121", "Italy"),
+        Err(ProfileError::InvalidCountry(_))
+    ));
+    // the same checks apply to JSON
+    let posted: Result<BillingAddress, _> = serde_json::from_value(json!({
+        "line1": "Via Roma 1", "city": "Milano", "postal_code": "", "country": "IT"
+    }));
+    assert!(posted.is_err());
+    assert!(serde_json::from_value::<Email>(json!("nope")).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_payment_methods_and_addresses() -> Result<(), Box<dyn Error>> {
+    let profile = Profile::new("Alice")
+        .add_payment_method(PaymentMethod::paypal("alice@example.com"), false)
+        .add_payment_method(PaymentMethod::bank_transfer("INV-1", 30)?, false);
+    assert_eq!(
+        profile.default_payment().map(PaymentMethod::masked),
+        Some("paypal a***@example.com".to_string())
+    );
+    let profile = profile.add_payment_method(PaymentMethod::bank_transfer("INV-2", 14)?, true);
+    assert_eq!(
+        methods(&profile),
+        [
+            "bank transfer INV-2 (14 days)",
+            "paypal a***@example.com",
+            "bank transfer INV-1 (30 days)"
+        ]
+    );
+    let profile = profile.set_default_payment(2)?;
+    assert_eq!(methods(&profile)[0], "bank transfer INV-1 (30 days)");
+    assert_eq!(methods(&profile)[1], "bank transfer INV-2 (14 days)");
+    // removing the default promotes the next one
+    let profile = profile.remove_payment_method(0)?;
+    assert_eq!(methods(&profile)[0], "bank transfer INV-2 (14 days)");
+    assert!(matches!(
+        profile.set_default_payment(2),
+        Err(ProfileError::NoSuchPaymentMethod(2))
+    ));
+
+    let home = BillingAddress::new("Via Roma 1", "Milano", "20121", "IT")?.labeled("home");
+    let profile = profile.add_address(home.clone());
+    assert_eq!(profile.billing_addresses, [home]);
+    assert!(profile.remove_address(0)?.billing_addresses.is_empty());
+    assert!(matches!(
+        profile.remove_address(1),
+        Err(ProfileError::NoSuchAddress(1))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_profiles_with_a_single_default_payment_still_load() -> Result<(), Box<dyn Error>> {
+    let user: User = serde_json::from_value(json!({
+        "id": "u-1",
+        "profile": {
+            "display_name": "Old",
+            "default_payment": { "Paypal": { "account": "old@example.com" } }
+        }
+    }))?;
+    assert_eq!(methods(&user.profile), ["paypal o***@example.com"]);
+    assert!(user.profile.email.is_none());
+    Ok(())
+}
+
+async fn exercise(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    repo.init().await?;
+    let alice_id = uid("u-alice");
+    let alice = User::new(
+        "u-alice",
+        "Alice",
+        Some(PaymentMethod::paypal("alice@example.com")),
+    )
+    .with_email(Email::parse("alice@example.com")?);
+    repo.save_user(&alice).await?;
+    repo.save_user(&User::new("u-bob", "Bob", None)).await?;
+    assert!(repo.get_user(&"u-nobody".into()).await?.is_none());
+
+    let home = BillingAddress::new("Via Roma 1", "Milano", "20121", "IT")?.labeled("home");
+    let office = BillingAddress {
+        line2: Some("Floor 3".into()),
+        region: Some("MI".into()),
+        ..BillingAddress::new("Corso Como 5", "Milano", "20154", "IT")?
+    };
+    let (invoice_a, invoice_b) = (
+        PaymentMethod::bank_transfer("INV-A", 30)?,
+        PaymentMethod::bank_transfer("INV-B", 14)?,
+    );
+    let updated = profile::update(repo, &alice_id, |p| {
+        let mut p = p
+            .add_payment_method(invoice_a.clone(), true)
+            .add_payment_method(invoice_b.clone(), false)
+            .add_address(home.clone())
+            .add_address(office.clone());
+        p.locale = Some(Locale::parse("it-IT")?);
+        p.timezone = Some(Timezone::parse("Europe/Rome")?);
+        Ok(p)
+    })
+    .await?;
+
+    let stored = repo.get_user(&alice_id).await?.ok_or("alice is stored")?;
+    assert_eq!(methods(&stored.profile), methods(&updated.profile));
+    assert_eq!(
+        methods(&stored.profile),
+        [
+            "bank transfer INV-A (30 days)",
+            "paypal a***@example.com",
+            "bank transfer INV-B (14 days)"
+        ]
+    );
+    assert_eq!(stored.profile.billing_addresses, [home, office.clone()]);
+    assert_eq!(stored.profile.locale, Some(Locale::parse("it-IT")?));
+    assert_eq!(
+        stored.profile.timezone,
+        Some(Timezone::parse("Europe/Rome")?)
+    );
+    assert_eq!(
+        stored.profile.email,
+        Some(Email::parse("alice@example.com")?)
+    );
+
+    let stored = profile::update(repo, &alice_id, |p| {
+        p.set_default_payment(2)?.remove_address(0)
+    })
+    .await?;
+    assert_eq!(methods(&stored.profile)[0], "bank transfer INV-B (14 days)");
+    let users = repo.get_users().await?;
+    let listed = users
+        .iter()
+        .find(|u| u.id == alice_id)
+        .ok_or("alice is listed")?;
+    assert_eq!(methods(&listed.profile), methods(&stored.profile));
+    assert_eq!(listed.profile.billing_addresses, [office]);
+
+    // an email belongs to one user, however it is spelled
+    let taken = profile::update(repo, &"u-bob".into(), |p| {
+        Ok(Profile {
+            email: Some(Email::parse("ALICE@example.com")?),
+            ..p.clone()
+        })
+    })
+    .await;
+    assert!(
+        matches!(taken, Err(ProfileError::EmailTaken(_))),
+        "{:?}",
+        taken
+    );
+    let carol = User::new("u-carol", "Carol", None).with_email(Email::parse("alice@example.com")?);
+    assert!(matches!(
+        repo.save_user(&carol).await,
+        Err(RepositoryError::EmailTaken(_))
+    ));
+    assert!(repo.get_user(&"u-carol".into()).await?.is_none());
+    assert!(repo.get_user(&alice_id).await?.is_some());
+    assert!(matches!(
+        profile::update(repo, &"u-nobody".into(), |p| Ok(p.clone())).await,
+        Err(ProfileError::UnknownUser(_))
+    ));
+
+    // once Alice changes hers, the old one is free
+    profile::update(repo, &alice_id, |p| {
+        Ok(Profile {
+            email: Some(Email::parse("alice@work.example")?),
+            ..p.clone()
+        })
+    })
+    .await?;
+    repo.save_user(&carol).await?;
+
+    // the export holds the whole profile, methods masked
+    let export = repo.export_user(&alice_id).await?.ok_or("alice exports")?;
+    assert_eq!(export.email, Some(Email::parse("alice@work.example")?));
+    assert_eq!(export.billing_addresses.len(), 1);
+    assert_eq!(export.timezone, Some(Timezone::parse("Europe/Rome")?));
+    assert_eq!(export.payment_methods.len(), 3);
+
+    // erasure drops the profile and frees the email
+    let report = repo
+        .erase_user(&alice_id, &"u-erased".into())
+        .await?
+        .ok_or("alice is erased")?;
+    assert!(repo.get_user(&alice_id).await?.is_none());
+    let erased = repo
+        .get_user(&report.pseudonym)
+        .await?
+        .ok_or("pseudonym is stored")?;
+    assert_eq!(erased.profile.display_name, ERASED_DISPLAY_NAME);
+    assert!(erased.profile.payment_methods.is_empty());
+    assert!(erased.profile.billing_addresses.is_empty());
+    assert!(erased.profile.email.is_none());
+    let dave = User::new("u-dave", "Dave", None).with_email(Email::parse("alice@work.example")?);
+    repo.save_user(&dave).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_profiles() -> Result<(), Box<dyn Error>> {
+    on_every_backend(|repo| async move { exercise(repo.as_ref()).await }).await
+}
+
+async fn concurrent_adds(repo: Arc<dyn Repository>) -> Result<(), Box<dyn Error>> {
+    repo.init().await?;
+    let alice_id = uid("u-alice");
+    repo.save_user(&User::new("u-alice", "Alice", None)).await?;
+    let tasks: Vec<_> = (0..16)
+        .map(|i| {
+            let (repo, alice_id) = (repo.clone(), alice_id.clone());
+            tokio::spawn(async move {
+                let method = PaymentMethod::paypal(&format!("alice{}@example.com", i));
+                profile::update(repo.as_ref(), &alice_id, |p| {
+                    Ok(p.add_payment_method(method.clone(), false))
+                })
+                .await
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await??;
+    }
+    let stored = repo.get_user(&alice_id).await?.ok_or("alice is stored")?;
+    assert_eq!(stored.profile.payment_methods.len(), 16);
+    assert_eq!(stored.profile.revision, 16);
+
+    // a profile based on an older revision is refused, whole
+    let stale = Profile {
+        revision: 16,
+        ..Profile::new("Alice")
+    };
+    assert!(matches!(
+        repo.update_profile(&alice_id, &stale).await?,
+        ProfileUpdate::Stale
+    ));
+    let kept = repo.get_user(&alice_id).await?.ok_or("alice is stored")?;
+    assert_eq!(methods(&kept.profile), methods(&stored.profile));
+    assert!(matches!(
+        repo.update_profile(&"u-nobody".into(), &stale).await?,
+        ProfileUpdate::UnknownUser
+    ));
+
+    // saving a whole user moves the stored revision on, whatever it says
+    let mut forged = kept.clone();
+    forged.profile.revision = 99;
+    repo.save_user(&forged).await?;
+    let saved = repo.get_user(&alice_id).await?.ok_or("alice is stored")?;
+    assert_eq!(saved.profile.revision, 17);
+    repo.save_user(&User {
+        id: uid("u-bob"),
+        profile: Profile {
+            revision: 5,
+            ..Profile::new("Bob")
+        },
+    })
+    .await?;
+    let bob = repo.get_user(&uid("u-bob")).await?.ok_or("bob is stored")?;
+    assert_eq!(bob.profile.revision, 0);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_parallel_edits_all_land() -> Result<(), Box<dyn Error>> {
+    on_every_backend(concurrent_adds).await
+}
+
+#[tokio::test]
+async fn test_api_edits_profiles() -> Result<(), Box<dyn Error>> {
+    let repo: Arc<dyn Repository> = Arc::new(MemoryRepository::new());
+    repo.save_user(&User::new("u-alice", "Alice", None)).await?;
+    repo.save_user(&User::new("u-bob", "Bob", None).with_email(Email::parse("bob@example.com")?))
+        .await?;
+    let app = api::router(repo.clone());
+    let call = |method, uri, body| call(&app, method, uri, body);
+
+    let paypal = json!({ "Paypal": { "account": "alice@example.com" } });
+    let bank = json!({ "BankTransfer": { "reference": "INV-1", "terms_days": 30 } });
+    let (status, _) = call(
+        "POST",
+        "/users/u-alice/payment-methods",
+        Some(json!({ "method": paypal })),
+    )
+    .await?;
+    assert_eq!(status, StatusCode::CREATED);
+    let (_, user) = call(
+        "POST",
+        "/users/u-alice/payment-methods",
+        Some(json!({ "method": bank, "default": true })),
+    )
+    .await?;
+    assert_eq!(user["profile"]["payment_methods"][0], bank);
+    let (status, user) = call("POST", "/users/u-alice/payment-methods/1/default", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(user["profile"]["payment_methods"][0], paypal);
+    let (status, _) = call("DELETE", "/users/u-alice/payment-methods/5", None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, payment) = call("GET", "/users/u-alice/payment", None).await?;
+    assert_eq!(payment["payment"], paypal);
+
+    let address = json!({
+        "line1": "Via Roma 1", "city": "Milano", "postal_code": "20121", "country": "it"
+    });
+    let (status, user) = call("POST", "/users/u-alice/addresses", Some(address)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(user["profile"]["billing_addresses"][0]["country"], "IT");
+    let (status, _) = call(
+        "POST",
+        "/users/u-alice/addresses",
+        Some(json!({ "line1": "", "city": "Milano", "postal_code": "1", "country": "IT" })),
+    )
+    .await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, user) = call("DELETE", "/users/u-alice/addresses/0", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert!(user["profile"].get("billing_addresses").is_none());
+
+    let mut profile = user["profile"].clone();
+    profile["email"] = json!("Alice@Example.com");
+    profile["locale"] = json!("en_gb");
+    let (status, user) = call("PUT", "/users/u-alice/profile", Some(profile.clone())).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(user["profile"]["email"], "alice@example.com");
+    assert_eq!(user["profile"]["locale"], "en-GB");
+    // the same body again is based on a revision that is gone
+    profile["display_name"] = json!("Alice Smith");
+    let (status, body) = call("PUT", "/users/u-alice/profile", Some(profile.clone())).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(body["error"].as_str().unwrap_or("").contains("changed"));
+    let (_, stored) = call("GET", "/users/u-alice", None).await?;
+    assert_eq!(stored["profile"]["display_name"], "Alice");
+    let mut profile = user["profile"].clone();
+    profile["email"] = json!("bob@example.com");
+    let (status, body) = call("PUT", "/users/u-alice/profile", Some(profile.clone())).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(body["error"]
+        .as_str()
+        .unwrap_or("")
+        .contains("already used"));
+    let (status, _) = call("PUT", "/users/u-nobody/profile", Some(profile)).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = call(
+        "POST",
+        "/users",
+        Some(json!({ "id": "u-carol", "profile": {
+            "display_name": "Carol", "email": "bob@example.com", "payment_methods": []
+        } })),
+    )
+    .await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use sqlx::{Row, SqlitePool};
+use src02::persistence::{self, SERVICES_WITH_PRODUCTS_SQL, USAGES_FOR_USER_SQL};
+
+/// The `detail` column of every step of SQLite's plan for `sql`.
+async fn plan(pool: &SqlitePool, sql: &str, binds: usize) -> Vec<String> {
+    let explain = format!("EXPLAIN QUERY PLAN {}", sql);
+    let mut q = sqlx::query(&explain);
+    for _ in 0..binds {
+        q = q.bind("x");
+    }
+    q.fetch_all(pool)
+        .await
+        .unwrap()
+        .iter()
+        .map(|r| r.get::<String, _>("detail"))
+        .collect()
+}
+
+#[tokio::test]
+async fn test_usages_for_user_uses_index() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = SqlitePool::connect("sqlite::memory:").await?;
+    persistence::init_db(&pool).await?;
+
+    let steps = plan(&pool, USAGES_FOR_USER_SQL, 1).await;
+    assert!(
+        steps
+            .iter()
+            .any(|d| d.contains("USING INDEX idx_usages_user_id")),
+        "plan: {:?}",
+        steps
+    );
+    assert!(
+        !steps.iter().any(|d| d.starts_with("SCAN usages")),
+        "plan: {:?}",
+        steps
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_services_join_uses_product_index() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = SqlitePool::connect("sqlite::memory:").await?;
+    persistence::init_db(&pool).await?;
+
+    let steps = plan(&pool, SERVICES_WITH_PRODUCTS_SQL, 0).await;
+    assert!(
+        steps
+            .iter()
+            .any(|d| d.contains("USING INDEX idx_products_service_id")),
+        "plan: {:?}",
+        steps
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_services_groups_joined_rows() -> Result<(), Box<dyn std::error::Error>> {
+    use src02::models::{Product, Service};
+    let pool = SqlitePool::connect("sqlite::memory:").await?;
+    persistence::init_db(&pool).await?;
+    let saas = Service::new(
+        "s-1",
+        "SaaS",
+        vec![
+            Product::new("p-1", "Email", 500),
+            Product::new("p-2", "Analytics", 1500),
+        ],
+    );
+    let empty = Service::new("s-0", "Coming soon", vec![]);
+    let consulting = Service::new(
+        "s-2",
+        "Consulting",
+        vec![Product::new("p-3", "On-site", 10000)],
+    );
+    for s in [&saas, &empty, &consulting] {
+        persistence::save_service(&pool, s).await?;
+    }
+
+    let services = persistence::get_services(&pool).await?;
+    let shape: Vec<_> = services
+        .iter()
+        .map(|s| (s.id.0.as_str(), s.products.len()))
+        .collect();
+    assert_eq!(shape, [("s-0", 0), ("s-1", 2), ("s-2", 1)]);
+    let s1: Vec<_> = services[1]
+        .products
+        .iter()
+        .map(|p| p.id.0.as_str())
+        .collect();
+    assert_eq!(s1, ["p-1", "p-2"]);
+    Ok(())
+}
@@ -0,0 +1,157 @@
+mod common;
+
+use chrono::{DateTime, Utc};
+use common::{consulting, day, on_every_backend, users};
+use src02::catalog::Catalog;
+use src02::models::{Product, Service, ServiceUsage, UserMap};
+use src02::reporting::{self, ReportPeriod, RevenueReport, Section};
+use src02::repository::Repository;
+use std::error::Error;
+
+fn services() -> Vec<Service> {
+    vec![
+        Service::new(
+            "s-1",
+            "SaaS",
+            vec![
+                Product::new("p-1", "Email", 500),
+                // cheaper from March on
+                Product::new("p-2", "Analytics", 1500).with_price_change(day(2025, 3, 1), 1000),
+            ],
+        ),
+        consulting(),
+    ]
+}
+
+fn usage(user: &str, service: &str, product: &str, at: DateTime<Utc>) -> ServiceUsage {
+    common::usage(user, service, product).at(at)
+}
+
+/// February: Alice 1500 + Bob 500. March: Alice 1000 + 500, Carol 10000.
+fn usages() -> Vec<ServiceUsage> {
+    vec![
+        usage("u-alice", "s-1", "p-2", day(2025, 2, 3)),
+        usage("u-bob", "s-1", "p-1", day(2025, 2, 28)),
+        usage("u-alice", "s-1", "p-2", day(2025, 3, 1)),
+        usage("u-alice", "s-1", "p-1", day(2025, 3, 15)),
+        usage("u-carol", "s-2", "p-3", day(2025, 3, 31)),
+        usage("u-bob", "s-1", "p-1", day(2025, 4, 1)),
+    ]
+}
+
+fn ids(rows: &[reporting::RevenueRow]) -> Vec<(&str, u64)> {
+    rows.iter()
+        .map(|r| (r.id.as_str(), r.revenue_cents))
+        .collect()
+}
+
+#[test]
+fn test_previous_period() {
+    let march = ReportPeriod::month(2025, 3).unwrap();
+    assert_eq!(
+        (march.from, march.until),
+        (day(2025, 3, 1), day(2025, 4, 1))
+    );
+    // whole months go back by calendar months, not by 31 days
+    assert_eq!(
+        march.previous(),
+        ReportPeriod::new(day(2025, 2, 1), day(2025, 3, 1))
+    );
+    let quarter = ReportPeriod::new(day(2025, 1, 1), day(2025, 4, 1));
+    assert_eq!(quarter.previous().from, day(2024, 10, 1));
+    let week = ReportPeriod::new(day(2025, 3, 10), day(2025, 3, 17));
+    assert_eq!(
+        week.previous(),
+        ReportPeriod::new(day(2025, 3, 3), day(2025, 3, 10))
+    );
+    assert!(ReportPeriod::month(2025, 13).is_none());
+}
+
+#[test]
+fn test_revenue_per_service_product_and_user() {
+    let march = ReportPeriod::month(2025, 3).unwrap();
+    let (current, previous): (Vec<_>, Vec<_>) = usages()
+        .into_iter()
+        .filter(|u| u.occurred_at >= day(2025, 2, 1) && u.occurred_at < day(2025, 4, 1))
+        .partition(|u| u.occurred_at >= march.from);
+    let catalog = Catalog::from_services(Default::default(), services()).unwrap();
+    let users: UserMap = users().into_iter().map(|u| (u.id.clone(), u)).collect();
+    let report = RevenueReport::compute(march, &current, &previous, &catalog, &users, 2);
+
+    assert_eq!(
+        (
+            report.total.revenue_cents,
+            report.total.previous_revenue_cents
+        ),
+        (11500, 2000)
+    );
+    assert_eq!(ids(&report.by_service), [("s-2", 10000), ("s-1", 1500)]);
+    assert_eq!(report.by_service[1].name, "SaaS");
+    assert_eq!(report.by_service[1].change_cents, -500);
+    assert_eq!(report.by_service[1].change_percent, Some(-25.0));
+    assert_eq!(report.by_service[0].change_percent, None);
+    assert_eq!(
+        ids(&report.by_product),
+        [("p-3", 10000), ("p-2", 1000), ("p-1", 500)]
+    );
+    // Bob only used something in February
+    assert_eq!(
+        ids(&report.by_user),
+        [("u-carol", 10000), ("u-alice", 1500), ("u-bob", 0)]
+    );
+    assert_eq!(report.by_user[2].previous_revenue_cents, 500);
+    assert_eq!(
+        ids(report.section(Section::TopConsumers)),
+        [("u-carol", 10000), ("u-alice", 1500)]
+    );
+}
+
+#[test]
+fn test_csv_export() -> Result<(), Box<dyn Error>> {
+    let catalog = Catalog::from_services(Default::default(), services())?;
+    let march = ReportPeriod::month(2025, 3).unwrap();
+    let report = RevenueReport::compute(
+        march,
+        &usages()[2..4],
+        &usages()[..1],
+        &catalog,
+        &UserMap::new(),
+        10,
+    );
+    let mut out = Vec::new();
+    reporting::write_csv(&report.by_product, &mut out)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "id,name,usages,revenue_cents,previous_usages,previous_revenue_cents,change_cents,change_percent\n\
+         p-2,Analytics,1,1000,1,1500,-500,-33.33\n\
+         p-1,Email,1,500,0,0,500,\n"
+    );
+    let mut empty = Vec::new();
+    reporting::write_csv(&[], &mut empty)?;
+    assert!(String::from_utf8(empty)?.starts_with("id,name,"));
+    Ok(())
+}
+
+async fn report_from(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    repo.init().await?;
+    for u in users() {
+        repo.save_user(&u).await?;
+    }
+    for s in services() {
+        repo.save_service(&s).await?;
+    }
+    repo.save_usages(&usages()).await?;
+
+    let report = reporting::build(repo, ReportPeriod::month(2025, 3).unwrap(), 1).await?;
+    assert_eq!(report.total.usages, 3);
+    assert_eq!(report.total.previous_usages, 2);
+    assert_eq!(ids(&report.by_service), [("s-2", 10000), ("s-1", 1500)]);
+    assert_eq!(report.top_consumers[0].name, "Carol");
+    assert_eq!(report.top_consumers.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_report_from_a_repository() -> Result<(), Box<dyn Error>> {
+    on_every_backend(|repo| async move { report_from(repo.as_ref()).await }).await
+}
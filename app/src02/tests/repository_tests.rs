@@ -0,0 +1,154 @@
+use src02::account::{Account, AccountId};
+use src02::models::{PaymentMethod, Product, Service, ServiceId, ServiceUsage, User, UserId};
+use src02::persistence::PersistenceError;
+use src02::repository::{
+    self, MemoryRepository, Repository, RepositoryError, SledRepository, SqliteRepository,
+};
+
+async fn exercise(repo: &dyn Repository) -> Result<(), RepositoryError> {
+    repo.init().await?;
+
+    let alice = User::new("u-alice", "Alice", Some(PaymentMethod::paypal("a@paypal")));
+    let bob = User::new("u-bob", "Bob", None);
+    repo.save_user(&alice).await?;
+    repo.save_user(&bob).await?;
+    // saving again replaces, it does not duplicate
+    repo.save_user(&alice).await?;
+
+    let p1 = Product::new("p-1", "Email Support", 500);
+    let p2 = Product::new("p-2", "Premium Analytics", 1500);
+    let svc = Service::new("s-1", "SaaS", vec![p1.clone(), p2.clone()]);
+    repo.save_service(&svc).await?;
+
+    repo.save_usage(&ServiceUsage::new(&alice.id, &svc.id, &p1.id, None))
+        .await?;
+    repo.save_usage(&ServiceUsage::new(&alice.id, &svc.id, &p2.id, None))
+        .await?;
+    repo.save_usage(&ServiceUsage::new(&bob.id, &svc.id, &p1.id, None))
+        .await?;
+
+    assert_eq!(repo.get_users().await?.len(), 2);
+    let services = repo.get_services().await?;
+    assert_eq!(services.len(), 1);
+    assert_eq!(services[0].products.len(), 2);
+
+    let alice_usages = repo.get_usages_for_user(&alice.id).await?;
+    let products: Vec<_> = alice_usages
+        .iter()
+        .map(|u| u.product_id.0.as_str())
+        .collect();
+    assert_eq!(products, ["p-1", "p-2"]);
+    assert_eq!(repo.get_usages_for_user(&bob.id).await?.len(), 1);
+
+    // every backend refuses dangling references the way the SQLite triggers do
+    repo.save_account(&Account::new("a-1", "Team", &bob.id))
+        .await?;
+    let nobody = UserId("u-nobody".into());
+    let unknown = ServiceId("s-9".into());
+    let on_account = |account: &str| {
+        let mut u = ServiceUsage::new(&alice.id, &svc.id, &p1.id, None);
+        u.account_id = Some(AccountId(account.into()));
+        u
+    };
+    for (bad, reason) in [
+        (
+            ServiceUsage::new(&nobody, &svc.id, &p1.id, None),
+            "unknown user",
+        ),
+        (
+            ServiceUsage::new(&alice.id, &unknown, &p1.id, None),
+            "unknown service",
+        ),
+        (
+            ServiceUsage::new(&alice.id, &svc.id, &"p-9".into(), None),
+            "does not offer",
+        ),
+        (on_account("a-9"), "unknown account"),
+        (on_account("a-1"), "not a member"),
+    ] {
+        match repo.save_usage(&bad).await {
+            Err(RepositoryError::Persistence(PersistenceError::Constraint {
+                table,
+                reason: r,
+            })) => {
+                assert_eq!(table, "usages");
+                assert!(r.contains(reason), "{}", r);
+            }
+            other => panic!("expected a constraint error, got {:?}", other),
+        }
+    }
+    let batch = [
+        ServiceUsage::new(&bob.id, &svc.id, &p2.id, None),
+        ServiceUsage::new(&nobody, &svc.id, &p1.id, None),
+    ];
+    assert!(repo.save_usages(&batch).await.is_err());
+    assert_eq!(repo.get_usages_for_user(&bob.id).await?.len(), 1);
+
+    // products are stored by id alone: one service each
+    let other = Service::new("s-2", "Other", vec![Product::new("p-1", "Email", 100)]);
+    assert!(matches!(
+        repo.save_service(&other).await,
+        Err(RepositoryError::DuplicateProduct { ref existing, .. }) if existing.0 == "s-1"
+    ));
+    let twice = Service::new("s-2", "Other", vec![p2.clone(), p2.clone()]);
+    assert!(matches!(
+        repo.save_service(&twice).await,
+        Err(RepositoryError::DuplicateProduct { .. })
+    ));
+    assert_eq!(repo.get_services().await?.len(), 1);
+    // a product dropped from its service is free again
+    repo.save_service(&Service::new("s-1", "SaaS", vec![p2.clone()]))
+        .await?;
+    repo.save_service(&other).await?;
+    let mut services = repo.get_services().await?;
+    services.sort_by(|a, b| a.id.cmp(&b.id));
+    assert_eq!(services[0].products.len(), 1);
+    assert_eq!(services[1].products[0].id, p1.id);
+
+    // a batch is written in order, and not at all if one service is refused
+    let moved = [
+        Service::new("s-2", "Other", vec![]),
+        Service::new("s-1", "SaaS", vec![p1.clone(), p2.clone()]),
+    ];
+    repo.save_services(&moved).await?;
+    let refused = [
+        Service::new("s-3", "New", vec![Product::new("p-3", "New", 100)]),
+        Service::new("s-2", "Other", vec![p1.clone()]),
+    ];
+    assert!(matches!(
+        repo.save_services(&refused).await,
+        Err(RepositoryError::DuplicateProduct { ref existing, .. }) if existing.0 == "s-1"
+    ));
+    let mut services = repo.get_services().await?;
+    services.sort_by(|a, b| a.id.cmp(&b.id));
+    assert_eq!(services.len(), 2);
+    assert_eq!(services[0].products.len(), 2);
+    assert!(services[1].products.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sqlite_repository() -> Result<(), RepositoryError> {
+    exercise(&SqliteRepository::connect("sqlite::memory:").await?).await
+}
+
+#[tokio::test]
+async fn test_sled_repository() -> Result<(), RepositoryError> {
+    exercise(&SledRepository::temporary()?).await
+}
+
+#[tokio::test]
+async fn test_memory_repository() -> Result<(), RepositoryError> {
+    exercise(&MemoryRepository::new()).await
+}
+
+#[tokio::test]
+async fn test_open_picks_backend_from_url() -> Result<(), RepositoryError> {
+    exercise(repository::open("memory:").await?.as_ref()).await?;
+    exercise(repository::open("sqlite::memory:").await?.as_ref()).await?;
+    assert!(matches!(
+        repository::open("postgres://localhost/db").await,
+        Err(RepositoryError::UnsupportedUrl(_))
+    ));
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use axum::body::{to_bytes, Body};
+use axum::http::{Request, StatusCode};
+use axum::Router;
+use serde_json::Value;
+use src02::api;
+use src02::catalog::{Catalog, CatalogError};
+use src02::clock::SystemClock;
+use src02::idempotency::DEFAULT_RETENTION;
+use src02::models::{Product, Service};
+use src02::repository::{MemoryRepository, Repository};
+use src02::shared_catalog::SharedCatalog;
+use std::error::Error;
+use std::sync::Arc;
+use std::thread;
+use tower::ServiceExt;
+
+fn service(id: &str, products: usize) -> Service {
+    Service::new(
+        id,
+        "SaaS",
+        (0..products)
+            .map(|i| Product::new(&format!("{}-p{}", id, i), "Seat", 100))
+            .collect(),
+    )
+}
+
+#[test]
+fn test_replace_bumps_the_version_and_keeps_old_snapshots() {
+    let shared = SharedCatalog::default();
+    assert_eq!(shared.version(), 0);
+    let before = shared.load();
+
+    let handle = shared.clone();
+    let published = handle.replace(Catalog::default().with_service(service("s-1", 2)));
+    assert_eq!(published.version, 1);
+    assert_eq!(shared.version(), 1);
+    assert_eq!(shared.load().catalog.list_all_products().count(), 2);
+    // a snapshot taken earlier still reads the catalog it was taken from
+    assert_eq!(before.version, 0);
+    assert_eq!(before.catalog.list_all_products().count(), 0);
+    assert!(Arc::ptr_eq(&shared.load(), &published));
+}
+
+#[test]
+fn test_update_refuses_without_swapping() {
+    let shared = SharedCatalog::new(Catalog::default().with_service(service("s-1", 1)));
+    let err = shared
+        .update(|c| c.add_product(&"s-9".into(), Product::new("p-9", "Seat", 100)))
+        .unwrap_err();
+    assert_eq!(err, CatalogError::UnknownService("s-9".into()));
+    assert_eq!(shared.version(), 1);
+
+    let next = shared.update(|c| c.add_product(&"s-1".into(), Product::new("p-9", "Seat", 100)));
+    assert_eq!(next.map(|s| s.version), Ok(2));
+    let snapshot = shared.load();
+    let products = snapshot
+        .catalog
+        .list_products_for_service(&"s-1".into())
+        .expect("s-1 exists");
+    assert_eq!(products.len(), 2);
+}
+
+#[test]
+fn test_concurrent_updates_are_not_lost() {
+    let shared = SharedCatalog::new(Catalog::default());
+    let writers: Vec<_> = (0..8)
+        .map(|w| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for i in 0..25 {
+                    shared
+                        .update(|c| c.add_service(service(&format!("s-{}-{}", w, i), 1)))
+                        .expect("distinct products");
+                }
+            })
+        })
+        .collect();
+    // readers only ever see whole catalogs: one product per service
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for _ in 0..200 {
+                    let snapshot = shared.load();
+                    let services = snapshot.catalog.services().len();
+                    assert_eq!(snapshot.catalog.list_all_products().count(), services);
+                    assert_eq!(snapshot.version, services as u64 + 1);
+                }
+            })
+        })
+        .collect();
+    for t in writers.into_iter().chain(readers) {
+        t.join().expect("no thread panicked");
+    }
+    assert_eq!(shared.version(), 201);
+    assert_eq!(shared.load().catalog.services().len(), 200);
+}
+
+#[tokio::test]
+async fn test_reload_from_repository_and_file() -> Result<(), Box<dyn Error>> {
+    let repo = MemoryRepository::new();
+    repo.save_service(&service("s-1", 3)).await?;
+    let shared = SharedCatalog::default();
+    let snapshot = shared.reload_from_repository(&repo).await?;
+    assert_eq!(snapshot.version, 1);
+    assert_eq!(snapshot.catalog.list_all_products().count(), 3);
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("catalog.toml");
+    std::fs::write(&path, include_str!("../catalog.example.toml"))?;
+    let snapshot = shared.reload_from_file(&path)?;
+    assert_eq!(snapshot.version, 2);
+    assert!(snapshot.catalog.get_service(&"s-2".into()).is_some());
+
+    // a file that does not parse leaves the catalog being served
+    std::fs::write(&path, "[[services]]\nid = 7\n")?;
+    assert!(shared.reload_from_file(&path).is_err());
+    assert_eq!(shared.version(), 2);
+    Ok(())
+}
+
+async fn get(app: &Router, method: &str, uri: &str) -> Result<(StatusCode, Value), Box<dyn Error>> {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(Body::empty())?;
+    let response = app.clone().oneshot(request).await?;
+    let status = response.status();
+    let bytes = to_bytes(response.into_body(), usize::MAX).await?;
+    Ok((status, serde_json::from_slice(&bytes)?))
+}
+
+#[tokio::test]
+async fn test_api_serves_the_shared_catalog() -> Result<(), Box<dyn Error>> {
+    let repo: Arc<dyn Repository> = Arc::new(MemoryRepository::new());
+    repo.save_service(&service("s-1", 1)).await?;
+    let shared = SharedCatalog::default();
+    let app = api::router_with_catalog(
+        repo.clone(),
+        DEFAULT_RETENTION,
+        Arc::new(SystemClock),
+        shared.clone(),
+    );
+
+    // loaded on first use
+    let (_, products) = get(&app, "GET", "/products").await?;
+    assert_eq!(products.as_array().map(Vec::len), Some(1));
+    let (_, summary) = get(&app, "GET", "/catalog").await?;
+    assert_eq!(summary["version"], 1);
+    assert_eq!(summary["services"], 1);
+
+    // written behind the server's back: served after a reload
+    repo.save_service(&service("s-2", 2)).await?;
+    let (status, _) = get(&app, "GET", "/services/s-2/products").await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, summary) = get(&app, "POST", "/catalog/reload").await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(summary["version"], 2);
+    assert_eq!(summary["products"], 3);
+    let (_, products) = get(&app, "GET", "/services/s-2/products").await?;
+    assert_eq!(products[1]["id"], "s-2-p1");
+    // the caller's handle sees the same catalog
+    assert_eq!(shared.version(), 2);
+    Ok(())
+}
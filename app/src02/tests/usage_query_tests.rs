@@ -0,0 +1,250 @@
+use chrono::{DateTime, Duration, Utc};
+use pretty_assertions::assert_eq;
+use src02::catalog::Catalog;
+use src02::models::{PaymentMethod, Product, Service, ServiceUsage, User};
+use src02::payment::PaymentKind;
+use src02::persistence;
+use src02::query::{Cursor, UsageAggregate, UsageQuery, UsageRecord};
+use src02::repository::{MemoryRepository, Repository, SledRepository, SqliteRepository};
+use src02::usage::UsageLog;
+use std::error::Error;
+
+fn t0() -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339("2025-06-01T00:00:00Z")
+        .unwrap()
+        .to_utc()
+}
+
+fn service() -> Service {
+    Service::new(
+        "s-1",
+        "SaaS",
+        vec![
+            Product::new("p-1", "Email Support", 500),
+            Product::new("p-2", "Premium Analytics", 1500),
+        ],
+    )
+}
+
+/// Ten usages an hour apart: alice and bob alternate, products alternate
+/// every two, even ones are paid by PayPal.
+fn usages() -> Vec<ServiceUsage> {
+    let alice = User::new("u-alice", "Alice", None);
+    let bob = User::new("u-bob", "Bob", None);
+    (0..10)
+        .map(|i| {
+            let user = if i % 2 == 0 { &alice } else { &bob };
+            let product = if (i / 2) % 2 == 0 { "p-1" } else { "p-2" };
+            let payment = (i % 2 == 0).then(|| PaymentMethod::paypal("a@paypal"));
+            ServiceUsage::new(&user.id, &"s-1".into(), &product.into(), payment)
+                .at(t0() + Duration::hours(i))
+        })
+        .collect()
+}
+
+fn hours(records: &[UsageRecord]) -> Vec<i64> {
+    records
+        .iter()
+        .map(|r| (r.usage.occurred_at - t0()).num_hours())
+        .collect()
+}
+
+/// Follow `next_cursor` until the last page, collecting the hour offsets.
+async fn all_pages(repo: &dyn Repository, q: UsageQuery) -> Result<Vec<i64>, Box<dyn Error>> {
+    let mut seen = Vec::new();
+    let mut q = q;
+    loop {
+        let page = repo.query_usages(&q).await?;
+        assert!(page.items.len() <= q.page_size());
+        seen.extend(hours(&page.items));
+        match page.next_cursor {
+            Some(c) => q = q.after(c),
+            None => return Ok(seen),
+        }
+    }
+}
+
+async fn exercise(repo: &dyn Repository) -> Result<(), Box<dyn Error>> {
+    repo.init().await?;
+    repo.save_service(&service()).await?;
+    repo.save_user(&User::new("u-alice", "Alice", None)).await?;
+    repo.save_user(&User::new("u-bob", "Bob", None)).await?;
+    repo.save_usages(&usages()).await?;
+
+    // filters combine
+    let q = UsageQuery::new()
+        .user(&"u-alice".into())
+        .product(&"p-1".into())
+        .payment_kind(PaymentKind::Paypal);
+    assert_eq!(all_pages(repo, q.clone().limit(50)).await?, [0, 4, 8]);
+
+    // time range is [from, until)
+    let q = UsageQuery::new()
+        .service(&"s-1".into())
+        .from(t0() + Duration::hours(3))
+        .until(t0() + Duration::hours(7));
+    assert_eq!(all_pages(repo, q).await?, [3, 4, 5, 6]);
+
+    // pagination visits every usage once, in order
+    let paged = all_pages(repo, UsageQuery::new().limit(3)).await?;
+    assert_eq!(paged, (0..10).collect::<Vec<_>>());
+
+    let first = repo.query_usages(&UsageQuery::new().limit(3)).await?;
+    assert_eq!(first.items.len(), 3);
+    assert!(first.next_cursor.is_some());
+    let exact = repo.query_usages(&UsageQuery::new().limit(10)).await?;
+    assert_eq!(exact.next_cursor, None);
+
+    // no payment recorded never matches a kind filter
+    let card = UsageQuery::new().payment_kind(PaymentKind::Card);
+    assert!(repo.query_usages(&card).await?.items.is_empty());
+
+    let agg = repo
+        .aggregate_usages(&UsageQuery::new().user(&"u-bob".into()))
+        .await?;
+    // bob: hours 1,3,5,7,9 -> p-1, p-2, p-1, p-2, p-1
+    assert_eq!(
+        agg,
+        UsageAggregate {
+            count: 5,
+            total_cents: 3 * 500 + 2 * 1500
+        }
+    );
+    // aggregates ignore cursor and limit
+    let agg_all = repo
+        .aggregate_usages(&UsageQuery::new().limit(1).after(first.next_cursor.unwrap()))
+        .await?;
+    assert_eq!(agg_all.count, 10);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_query_sqlite() -> Result<(), Box<dyn Error>> {
+    exercise(&SqliteRepository::connect("sqlite::memory:").await?).await
+}
+
+#[tokio::test]
+async fn test_query_sled() -> Result<(), Box<dyn Error>> {
+    exercise(&SledRepository::temporary()?).await
+}
+
+#[tokio::test]
+async fn test_query_memory() -> Result<(), Box<dyn Error>> {
+    exercise(&MemoryRepository::new()).await
+}
+
+#[tokio::test]
+async fn test_query_sled_follows_erased_usages() -> Result<(), Box<dyn Error>> {
+    let repo = SledRepository::temporary()?;
+    exercise(&repo).await?;
+    // alice's usages move under the pseudonym and keep their place
+    repo.erase_user(&"u-alice".into(), &"erased-1".into())
+        .await?
+        .ok_or("expected Alice")?;
+    let paged = all_pages(&repo, UsageQuery::new().limit(3)).await?;
+    assert_eq!(paged, (0..10).collect::<Vec<_>>());
+    let moved = UsageQuery::new().user(&"erased-1".into()).limit(2);
+    assert_eq!(all_pages(&repo, moved).await?, [0, 2, 4, 6, 8]);
+    Ok(())
+}
+
+#[test]
+fn test_query_usage_log_matches_store() {
+    let log = UsageLog::from_vec(usages());
+    let catalog = Catalog::default().with_service(service());
+
+    let q = UsageQuery::new().user(&"u-alice".into()).limit(2);
+    let first = log.query(&q);
+    assert_eq!(hours(&first.items), [0, 2]);
+    let second = log.query(&q.clone().after(first.next_cursor.unwrap()));
+    assert_eq!(hours(&second.items), [4, 6]);
+
+    let agg = log.aggregate(&UsageQuery::new().product(&"p-2".into()), &catalog);
+    assert_eq!(
+        agg,
+        UsageAggregate {
+            count: 4,
+            total_cents: 4 * 1500
+        }
+    );
+}
+
+#[test]
+fn test_unknown_products_count_but_add_nothing() {
+    let alice = User::new("u-alice", "Alice", None);
+    let log = UsageLog::from_vec(vec![ServiceUsage::new(
+        &alice.id,
+        &"s-x".into(),
+        &"p-x".into(),
+        None,
+    )]);
+    let agg = log.aggregate(&UsageQuery::new(), &Catalog::default());
+    assert_eq!(
+        agg,
+        UsageAggregate {
+            count: 1,
+            total_cents: 0
+        }
+    );
+}
+
+#[test]
+fn test_cursor_round_trips_and_rejects_garbage() {
+    let log = UsageLog::from_vec(usages());
+    let cursor = log.query(&UsageQuery::new().limit(4)).next_cursor.unwrap();
+    assert_eq!(Cursor::parse(&cursor.encode()), Ok(cursor));
+    assert!(Cursor::parse("").is_err());
+    assert!(Cursor::parse("42").is_err());
+    assert!(Cursor::parse("czz").is_err());
+}
+
+#[tokio::test]
+async fn test_payment_kind_backfilled_for_existing_rows() -> Result<(), Box<dyn Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    persistence::init_db(&pool).await?;
+    persistence::save_user(&pool, &User::new("u-1", "U", None)).await?;
+    let svc = Service::new("s-1", "SaaS", vec![Product::new("p-1", "Email", 500)]);
+    persistence::save_service(&pool, &svc).await?;
+    // a row written before the payment_kind column was filled in
+    sqlx::query(
+        "INSERT INTO usages (user_id, service_id, product_id, payment_used, occurred_at) \
+         VALUES ('u-1', 's-1', 'p-1', '{\"Paypal\":{\"account\":\"a@paypal\"}}', 1)",
+    )
+    .execute(&pool)
+    .await?;
+    sqlx::query("DELETE FROM schema_version WHERE version >= 4")
+        .execute(&pool)
+        .await?;
+    sqlx::query("ALTER TABLE usages DROP COLUMN payment_kind")
+        .execute(&pool)
+        .await?;
+    sqlx::query("DROP INDEX idx_usages_occurred_at")
+        .execute(&pool)
+        .await?;
+    // and everything migrations 7 and 9 added to `usages`
+    for stmt in [
+        "DROP TRIGGER usages_account_insert",
+        "DROP TRIGGER usages_account_update",
+        "DROP INDEX idx_usages_account_id",
+        "ALTER TABLE usages DROP COLUMN account_id",
+        "DROP INDEX idx_usages_idempotency_key",
+        "ALTER TABLE usages DROP COLUMN idempotency_key",
+        "ALTER TABLE usages DROP COLUMN idempotency_key_at",
+        // and what migration 14 added to `users`
+        "DROP INDEX users_email",
+        "ALTER TABLE users DROP COLUMN email",
+        "ALTER TABLE users DROP COLUMN locale",
+        "ALTER TABLE users DROP COLUMN timezone",
+        // and migration 15
+        "ALTER TABLE users DROP COLUMN revision",
+    ] {
+        sqlx::query(stmt).execute(&pool).await?;
+    }
+
+    persistence::init_db(&pool).await?;
+    let page =
+        persistence::query_usages(&pool, &UsageQuery::new().payment_kind(PaymentKind::Paypal))
+            .await?;
+    assert_eq!(page.items.len(), 1);
+    Ok(())
+}
@@ -0,0 +1,184 @@
+use src02::catalog::Catalog;
+use src02::models::{PaymentMethod, Product, Service, ServiceUsage, User, UserMap};
+use src02::persistence::{self, PersistenceError};
+use src02::repository::{MemoryRepository, Repository, SqliteRepository};
+use src02::validation::{self, References, UsageViolation};
+use std::error::Error;
+
+fn users() -> UserMap {
+    [
+        User::new("u-alice", "Alice", Some(PaymentMethod::paypal("a@paypal"))),
+        User::new("u-bob", "Bob", None),
+    ]
+    .into_iter()
+    .map(|u| (u.id.clone(), u))
+    .collect()
+}
+
+fn saas() -> Service {
+    Service::new("s-1", "SaaS", vec![Product::new("p-1", "Email", 500)])
+}
+
+fn refs(catalog: Catalog) -> References {
+    References::new(users(), catalog)
+}
+
+fn usage(user: &str, service: &str, product: &str) -> ServiceUsage {
+    ServiceUsage::new(&user.into(), &service.into(), &product.into(), None)
+}
+
+#[test]
+fn test_valid_usage_passes() {
+    let catalog = Catalog::default().with_service(saas());
+    let ok = usage("u-alice", "s-1", "p-1");
+    assert_eq!(
+        validation::validate_usage(&ok, &refs(catalog.clone())),
+        Ok(())
+    );
+    // an explicit payment covers a user without a default
+    let paid = ServiceUsage::new(
+        &"u-bob".into(),
+        &"s-1".into(),
+        &"p-1".into(),
+        Some(PaymentMethod::paypal("b@paypal")),
+    );
+    assert!(validation::check_usage(&paid, &refs(catalog)).is_empty());
+}
+
+#[test]
+fn test_each_violation_is_typed() {
+    let refs = refs(Catalog::default().with_service(saas()));
+    let check = |u: ServiceUsage| validation::check_usage(&u, &refs);
+
+    assert_eq!(
+        check(usage("u-nobody", "s-1", "p-1")),
+        [UsageViolation::UnknownUser {
+            user_id: "u-nobody".into()
+        }]
+    );
+    assert_eq!(
+        check(usage("u-alice", "s-9", "p-1")),
+        [UsageViolation::UnknownService {
+            service_id: "s-9".into()
+        }]
+    );
+    assert_eq!(
+        check(usage("u-alice", "s-1", "p-9")),
+        [UsageViolation::ProductNotOffered {
+            service_id: "s-1".into(),
+            product_id: "p-9".into()
+        }]
+    );
+    assert_eq!(
+        check(usage("u-bob", "s-1", "p-1")),
+        [UsageViolation::NoPaymentMethod {
+            user_id: "u-bob".into()
+        }]
+    );
+    // everything wrong at once is reported at once
+    assert_eq!(check(usage("u-nobody", "s-9", "p-9")).len(), 2);
+}
+
+#[tokio::test]
+async fn test_sqlite_rejects_dangling_references() -> Result<(), Box<dyn Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    persistence::init_db(&pool).await?;
+    persistence::save_user(&pool, &User::new("u-alice", "Alice", None)).await?;
+    persistence::save_service(&pool, &saas()).await?;
+    persistence::save_service(&pool, &Service::new("s-2", "Other", vec![])).await?;
+
+    for (bad, reason) in [
+        (usage("u-nobody", "s-1", "p-1"), "unknown user"),
+        (usage("u-alice", "s-9", "p-1"), "unknown service"),
+        // p-1 exists, but not in s-2
+        (usage("u-alice", "s-2", "p-1"), "does not offer"),
+    ] {
+        match persistence::save_usage(&pool, &bad).await {
+            Err(PersistenceError::Constraint { table, reason: r }) => {
+                assert_eq!(table, "usages");
+                assert!(r.contains(reason), "{}", r);
+            }
+            other => panic!("expected a constraint error, got {:?}", other),
+        }
+    }
+    // a batch with one bad row writes nothing
+    let batch = [
+        usage("u-alice", "s-1", "p-1"),
+        usage("u-nobody", "s-1", "p-1"),
+    ];
+    assert!(persistence::save_usages(&pool, &batch).await.is_err());
+    assert!(persistence::get_usages_for_user(&pool, "u-alice")
+        .await?
+        .is_empty());
+
+    persistence::save_usage(&pool, &usage("u-alice", "s-1", "p-1")).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_audit_lists_violations_in_an_existing_database() -> Result<(), Box<dyn Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    // rows written before the reference triggers existed
+    sqlx::query(
+        "CREATE TABLE usages (id INTEGER PRIMARY KEY AUTOINCREMENT, user_id TEXT NOT NULL, service_id TEXT NOT NULL, product_id TEXT NOT NULL, payment_used TEXT NULL)",
+    )
+    .execute(&pool)
+    .await?;
+    sqlx::query(
+        "INSERT INTO usages (user_id, service_id, product_id) VALUES \
+         ('u-alice', 's-1', 'p-1'), ('u-ghost', 's-1', 'p-1'), ('u-alice', 's-1', 'p-gone')",
+    )
+    .execute(&pool)
+    .await?;
+    let repo = SqliteRepository::new(pool);
+    repo.init().await?;
+    repo.save_user(&users()[&"u-alice".into()]).await?;
+    repo.save_service(&saas()).await?;
+
+    let violations = validation::audit(&repo).await?;
+    let found: Vec<(i64, &UsageViolation)> = violations
+        .iter()
+        .flat_map(|v| v.problems.iter().map(move |p| (v.usage_id, p)))
+        .collect();
+    assert_eq!(
+        found,
+        [
+            (
+                2,
+                &UsageViolation::UnknownUser {
+                    user_id: "u-ghost".into()
+                }
+            ),
+            (
+                3,
+                &UsageViolation::ProductNotOffered {
+                    service_id: "s-1".into(),
+                    product_id: "p-gone".into()
+                }
+            ),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_audit_covers_stores_without_constraints() -> Result<(), Box<dyn Error>> {
+    let repo = MemoryRepository::new();
+    repo.save_service(&saas()).await?;
+    for u in users().into_values() {
+        repo.save_user(&u).await?;
+    }
+    repo.save_usage(&usage("u-alice", "s-1", "p-1")).await?;
+    repo.save_usage(&usage("u-bob", "s-1", "p-1")).await?;
+
+    let violations = validation::audit(&repo).await?;
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0].usage.user_id.0, "u-bob");
+    assert_eq!(
+        violations[0].problems,
+        [UsageViolation::NoPaymentMethod {
+            user_id: "u-bob".into()
+        }]
+    );
+    Ok(())
+}
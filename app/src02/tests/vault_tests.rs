@@ -0,0 +1,205 @@
+mod common;
+
+use chrono::NaiveDate;
+use common::{export_and_erase, on_backends, on_every_backend, saas, test_card, uid, Backend};
+use src02::account::Account;
+use src02::models::{PaymentMethod, ServiceUsage, User};
+use src02::persistence::{self, PersistenceError};
+use src02::repository::RepositoryError;
+use src02::vault::{CardBrand, CardError, CardExpiry, CardVault, StoredCard};
+use std::error::Error;
+
+fn today() -> NaiveDate {
+    NaiveDate::from_ymd_opt(2025, 6, 15).unwrap()
+}
+
+#[test]
+fn test_tokenize_keeps_only_masked_data() {
+    let mut vault = CardVault::new();
+    let card = vault
+        .tokenize_at(
+            "4242 4242 4242 4242",
+            CardExpiry::new(12, 2030).unwrap(),
+            "Alice",
+            today(),
+        )
+        .unwrap();
+    assert_eq!(card.brand(), CardBrand::Visa);
+    assert_eq!(card.last4(), "4242");
+    assert!(card.token().as_str().starts_with("tok_"));
+    assert_eq!(vault.get(card.token()), Some(&card));
+
+    // a payment method keeps the token alone
+    let json = serde_json::to_string(&PaymentMethod::card(&card)).unwrap();
+    assert_eq!(json, format!(r#"{{"Card":"{}"}}"#, card.token()));
+}
+
+#[test]
+fn test_tokenize_rejects_invalid_cards() {
+    let mut vault = CardVault::new();
+    let exp = CardExpiry::new(12, 2030).unwrap();
+    assert_eq!(
+        vault.tokenize_at("4242 4242 4242 4241", exp, "A", today()),
+        Err(CardError::ChecksumMismatch)
+    );
+    assert_eq!(
+        vault.tokenize_at("4242-abcd", exp, "A", today()),
+        Err(CardError::NonDigit)
+    );
+    // valid Luhn, unknown IIN
+    assert_eq!(
+        vault.tokenize_at("9999999999999995", exp, "A", today()),
+        Err(CardError::UnsupportedBrand)
+    );
+    let expired = CardExpiry::new(5, 2025).unwrap();
+    assert_eq!(
+        vault.tokenize_at("5555555555554444", expired, "A", today()),
+        Err(CardError::Expired(expired))
+    );
+    // the expiry month itself is still valid
+    let this_month = CardExpiry::new(6, 2025).unwrap();
+    assert!(vault
+        .tokenize_at("378282246310005", this_month, "A", today())
+        .is_ok());
+    assert!(CardExpiry::new(13, 2030).is_err());
+    assert_eq!(vault.len(), 1);
+}
+
+#[test]
+fn test_stored_card_deserialization_enforces_masking() {
+    let forged = r#"{"token":"tok_abc","brand":"Visa","last4":"4242424242424242","expiry":{"month":1,"year":2030},"holder":"Eve"}"#;
+    assert!(serde_json::from_str::<StoredCard>(forged).is_err());
+    let bad_token = r#"{"token":"4242","brand":"Visa","last4":"4242","expiry":{"month":1,"year":2030},"holder":"Eve"}"#;
+    assert!(serde_json::from_str::<StoredCard>(bad_token).is_err());
+}
+
+#[tokio::test]
+async fn test_card_round_trips_through_db() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    persistence::init_db(&pool).await?;
+
+    let mut vault = CardVault::new();
+    let card = vault.tokenize_at(
+        "6011111111111117",
+        CardExpiry::new(3, 2028).unwrap(),
+        "Bob",
+        today(),
+    )?;
+    persistence::save_card(&pool, &card).await?;
+
+    let loaded = persistence::get_card(&pool, card.token()).await?;
+    assert_eq!(loaded, Some(card));
+    Ok(())
+}
+
+fn refused_for_unknown_card(result: Result<(), RepositoryError>, table: &str) -> bool {
+    matches!(result,
+        Err(RepositoryError::Persistence(PersistenceError::Constraint { table: t, reason }))
+            if t == table && reason.contains("unknown card"))
+}
+
+#[tokio::test]
+async fn test_payment_methods_name_stored_cards_only() -> Result<(), Box<dyn Error>> {
+    on_every_backend(|repo| async move {
+        repo.init().await?;
+        repo.save_service(&saas()).await?;
+        let card = test_card("Alice");
+        let pays_by_card = Some(PaymentMethod::card(&card));
+        let alice = User::new("u-alice", "Alice", pays_by_card.clone());
+        // a well-formed token that was never vaulted here
+        assert!(refused_for_unknown_card(
+            repo.save_user(&alice).await,
+            "users"
+        ));
+        assert!(repo.get_user(&alice.id).await?.is_none());
+
+        repo.save_card(&card).await?;
+        assert_eq!(repo.get_card(card.token()).await?, Some(card.clone()));
+        repo.save_user(&alice).await?;
+        let stored = repo.get_user(&alice.id).await?.ok_or("expected Alice")?;
+        assert!(matches!(stored.profile.default_payment(),
+            Some(PaymentMethod::Card(token)) if token == card.token()));
+
+        let other = PaymentMethod::card(&test_card("Alice"));
+        let usage = ServiceUsage::new(&alice.id, &"s-1".into(), &"p-1".into(), Some(other));
+        assert!(refused_for_unknown_card(
+            repo.save_usage(&usage).await,
+            "usages"
+        ));
+        let usage = ServiceUsage::new(&alice.id, &"s-1".into(), &"p-1".into(), pays_by_card);
+        repo.save_usage(&usage).await?;
+
+        let acme = Account::new("acc-acme", "Acme", &alice.id);
+        let unknown = PaymentMethod::card(&test_card("Acme"));
+        let acme = acme.add_payment_method(&alice.id, unknown, true)?;
+        assert!(refused_for_unknown_card(
+            repo.save_account(&acme).await,
+            "accounts"
+        ));
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_erasure_removes_the_users_cards() -> Result<(), Box<dyn Error>> {
+    // SQLite: see privacy_tests
+    on_backends(&[Backend::Sled, Backend::Memory], |repo| async move {
+        let card = test_card("Alice");
+        repo.save_card(&card).await?;
+        let alice = User::new("u-alice", "Alice", Some(PaymentMethod::card(&card)));
+        repo.save_user(&alice).await?;
+        export_and_erase(repo.as_ref(), "u-alice").await?;
+        assert!(repo.get_card(card.token()).await?.is_none());
+        Ok(())
+    })
+    .await
+}
+
+#[test]
+fn test_embedded_cards_still_read_as_their_token() -> Result<(), Box<dyn Error>> {
+    let card = test_card("Alice");
+    let old = format!(r#"{{"Card":{}}}"#, serde_json::to_string(&card)?);
+    let method: PaymentMethod = serde_json::from_str(&old)?;
+    assert!(matches!(method, PaymentMethod::Card(token) if &token == card.token()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_migration_vaults_embedded_cards() -> Result<(), Box<dyn Error>> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    persistence::init_db(&pool).await?;
+    // back to before migration 16, with a user paying by an embedded card
+    for stmt in [
+        "DELETE FROM schema_version WHERE version >= 16",
+        "DROP TRIGGER users_card_insert",
+        "DROP TRIGGER users_card_update",
+        "DROP TRIGGER user_payment_methods_card_insert",
+        "DROP TRIGGER account_payment_methods_card_insert",
+        "DROP TRIGGER usages_card_insert",
+    ] {
+        sqlx::query(stmt).execute(&pool).await?;
+    }
+    let card = test_card("Alice");
+    sqlx::query(
+        "INSERT INTO users (id, display_name, default_payment) VALUES ('u-alice', 'Alice', ?)",
+    )
+    .bind(format!(r#"{{"Card":{}}}"#, serde_json::to_string(&card)?))
+    .execute(&pool)
+    .await?;
+
+    persistence::init_db(&pool).await?;
+    assert_eq!(
+        persistence::get_card(&pool, card.token()).await?,
+        Some(card.clone())
+    );
+    let stored: String = sqlx::query_scalar("SELECT default_payment FROM users")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(stored, format!(r#"{{"Card":"{}"}}"#, card.token()));
+    let alice = persistence::get_user(&pool, &uid("u-alice"))
+        .await?
+        .ok_or("expected Alice")?;
+    persistence::save_user(&pool, &alice).await?;
+    Ok(())
+}